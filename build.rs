@@ -0,0 +1,32 @@
+//! Compiles the CUDA kernel of the `gpu` feature to PTX, which the crate
+//! embeds and loads at runtime
+
+use std::{env, path::PathBuf, process::Command};
+
+const KERNEL: &str = "src/biot_savart.cu";
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if env::var_os("CARGO_FEATURE_GPU").is_none() {
+        return;
+    }
+    println!("cargo:rerun-if-changed={}", KERNEL);
+    println!("cargo:rerun-if-env-changed=NVCC");
+    let nvcc = env::var("NVCC").unwrap_or_else(|_| "nvcc".to_string());
+    let ptx =
+        PathBuf::from(env::var("OUT_DIR").expect("cargo sets OUT_DIR")).join("biot_savart.ptx");
+    // Without fused multiply-adds the kernel rounds as the CPU path does
+    let status = Command::new(&nvcc)
+        .args(["--ptx", "-O3", "--fmad=false", KERNEL, "-o"])
+        .arg(&ptx)
+        .status()
+        .unwrap_or_else(|err| {
+            panic!(
+                "running {}: {}, the gpu feature needs the CUDA toolkit",
+                nvcc, err
+            )
+        });
+    if !status.success() {
+        panic!("{} could not compile {}: {}", nvcc, KERNEL, status);
+    }
+}
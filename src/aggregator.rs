@@ -0,0 +1,207 @@
+use crate::{output::SnapshotWriter, point::Point};
+use mpi::{
+    Rank, Tag,
+    request::{Request, StaticScope},
+    topology::SimpleCommunicator,
+    traits::{Communicator, Destination, Source},
+};
+use std::{cell::Cell, collections::BTreeMap, error::Error};
+
+const HEADER_TAG: Tag = 1;
+const POINTS_TAG: Tag = 2;
+const VELOCITIES_TAG: Tag = 3;
+const FIELDS_TAG: Tag = 4;
+
+/// Point count of the header telling a writer rank that its sender is done
+const DONE: u64 = u64::MAX;
+
+/// Split of the world into compute ranks, which integrate the particles,
+/// and its last `writers` ranks, which only write the snapshots sent to them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriterRanks {
+    pub world_size: Rank,
+    pub writers: Rank,
+}
+
+impl WriterRanks {
+    pub fn compute_size(&self) -> Rank {
+        self.world_size - self.writers
+    }
+
+    pub fn is_writer(&self, world_rank: Rank) -> bool {
+        world_rank >= self.compute_size()
+    }
+
+    /// World rank writing the snapshots of compute rank `rank`
+    pub fn writer_of(&self, rank: Rank) -> Rank {
+        self.compute_size() + rank % self.writers
+    }
+
+    /// Compute ranks whose snapshots the writer `world_rank` writes
+    pub fn senders_of(&self, world_rank: Rank) -> Vec<Rank> {
+        (0..self.compute_size())
+            .filter(|&rank| self.writer_of(rank) == world_rank)
+            .collect()
+    }
+}
+
+/// Sends the snapshots of a compute rank to its writer rank
+pub struct Forwarder {
+    world: SimpleCommunicator,
+    writer: Rank,
+    /// Sends of the last snapshot, completed before the next one is sent
+    pending: Cell<Option<PendingSends>>,
+}
+
+/// Non-blocking sends of one snapshot, each from a buffer of its own that
+/// `PendingSends::wait` frees
+struct PendingSends {
+    header: Request<'static, [u64], StaticScope>,
+    points: Request<'static, [Point], StaticScope>,
+    velocities: Option<Request<'static, [Point], StaticScope>>,
+    fields: Option<Request<'static, [Point], StaticScope>>,
+}
+
+impl PendingSends {
+    fn wait(self) {
+        wait_and_free(self.header);
+        wait_and_free(self.points);
+        if let Some(velocities) = self.velocities {
+            wait_and_free(velocities);
+        }
+        if let Some(fields) = self.fields {
+            wait_and_free(fields);
+        }
+    }
+}
+
+/// Buffer owned by a non-blocking send until `wait_and_free` completes it
+fn leak<T: Clone>(values: &[T]) -> &'static [T] {
+    Box::leak(values.to_vec().into_boxed_slice())
+}
+
+fn wait_and_free<T>(request: Request<'static, [T], StaticScope>) {
+    let data = request.wait_for_data();
+    // SAFETY: `data` was boxed by `leak` for this send alone, which has
+    // completed, so nothing else refers to it
+    drop(unsafe { Box::from_raw(data as *const [T] as *mut [T]) });
+}
+
+impl Forwarder {
+    pub fn new(world: SimpleCommunicator, ranks: WriterRanks, rank: Rank) -> Self {
+        Forwarder {
+            writer: ranks.writer_of(rank),
+            world,
+            pending: Cell::new(None),
+        }
+    }
+
+    /// Posts non-blocking sends of a copy of the snapshot of `step` and
+    /// returns at once, so that this rank carries on stepping while the
+    /// writer rank receives and writes it. The sends of the previous
+    /// snapshot are completed first, at most one snapshot is in flight.
+    pub fn send(
+        &self,
+        points: &[Point],
+        velocities: Option<&[Point]>,
+        fields: Option<&[Point]>,
+        step: u32,
+    ) {
+        self.wait_pending();
+        let header = [
+            step as u64,
+            points.len() as u64,
+            velocities.is_some() as u64,
+            fields.is_some() as u64,
+        ];
+        let process = self.world.process_at_rank(self.writer);
+        let send =
+            |values: &[Point], tag| process.immediate_send_with_tag(StaticScope, leak(values), tag);
+        let pending = PendingSends {
+            header: process.immediate_send_with_tag(StaticScope, leak(&header), HEADER_TAG),
+            points: send(points, POINTS_TAG),
+            velocities: velocities.map(|velocities| send(velocities, VELOCITIES_TAG)),
+            fields: fields.map(|fields| send(fields, FIELDS_TAG)),
+        };
+        self.pending.set(Some(pending));
+    }
+
+    /// Completes the sends of the last snapshot and tells the writer rank
+    /// that no more snapshots follow
+    pub fn finish(&self) {
+        self.wait_pending();
+        let header = [0, DONE, 0, 0];
+        self.world
+            .process_at_rank(self.writer)
+            .send_with_tag(&header[..], HEADER_TAG);
+    }
+
+    fn wait_pending(&self) {
+        if let Some(pending) = self.pending.take() {
+            pending.wait();
+        }
+    }
+}
+
+/// The writer rank receives every snapshot until told that none follow, so
+/// runs that fail before `finish` still complete their last sends
+impl Drop for Forwarder {
+    fn drop(&mut self) {
+        self.wait_pending();
+    }
+}
+
+/// Writes the snapshots sent by compute ranks, through one `SnapshotWriter`
+/// per sender from `writer_for`, until every sender finished
+pub fn serve(
+    world: &SimpleCommunicator,
+    ranks: WriterRanks,
+    mut writer_for: impl FnMut(Rank) -> Result<SnapshotWriter, Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut writers = BTreeMap::new();
+    for rank in ranks.senders_of(world.rank()) {
+        writers.insert(rank, writer_for(rank)?);
+    }
+    let mut remaining = writers.len();
+    while remaining > 0 {
+        let (header, status) = world.any_process().receive_vec_with_tag::<u64>(HEADER_TAG);
+        let source = status.source_rank();
+        if header[1] == DONE {
+            remaining -= 1;
+            continue;
+        }
+        let process = world.process_at_rank(source);
+        let (points, _) = process.receive_vec_with_tag::<Point>(POINTS_TAG);
+        let velocities =
+            (header[2] != 0).then(|| process.receive_vec_with_tag::<Point>(VELOCITIES_TAG).0);
+        let fields = (header[3] != 0).then(|| process.receive_vec_with_tag::<Point>(FIELDS_TAG).0);
+        let writer = writers
+            .get_mut(&source)
+            .ok_or_else(|| format!("Unexpected snapshot from rank {}", source))?;
+        writer.write_files(
+            &points,
+            velocities.as_deref(),
+            fields.as_deref(),
+            header[0] as u32,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compute_ranks_spread_over_writers() {
+        let ranks = WriterRanks {
+            world_size: 7,
+            writers: 2,
+        };
+        assert_eq!(ranks.compute_size(), 5);
+        assert!(!ranks.is_writer(4));
+        assert!(ranks.is_writer(5));
+        assert_eq!(ranks.senders_of(5), vec![0, 2, 4]);
+        assert_eq!(ranks.senders_of(6), vec![1, 3]);
+    }
+}
@@ -1,27 +1,643 @@
-use clap::Parser;
+use crate::{
+    boundary::{BoundaryKind, LossBoundary},
+    coil_format::CoilFormat,
+    collisions::Collisions,
+    commands::ColorBy,
+    compression::Compression,
+    constants::{I, MAJOR_RADIUS, MINOR_RADIUS, MIU, PhysicsParams},
+    field_source::BackgroundField,
+    fieldmap::{FieldMapFormat, Grid, GridCoordinates},
+    integrator::{Integrator, IntegratorKind, Tolerances},
+    output::{
+        BinaryPrecision, Decimation, Notation, OutputFormat, OutputLayout, Retention, TextFormat,
+    },
+    particle::{ELEMENTARY_CHARGE, OrbitSettings, PROTON_MASS, Species},
+    point::Point,
+    restart::ParticleFilter,
+    seeding::{SeedMode, Seeding},
+    simulation::{Backend, Precision, Schedule, Summation},
+    utils::parse_id_ranges,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    arg_required_else_help = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Options of `simulate`, which also run without the subcommand
+    #[command(flatten)]
+    pub run: Option<Args>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Trace particles through the field of the coils, over MPI
+    Simulate(Box<Args>),
+
+    /// Generate starting particles into a particles file for `simulate`
+    Seed {
+        /// Generator of the starting particles
+        #[arg(long, value_enum)]
+        mode: SeedMode,
+
+        /// Number of particles
+        #[arg(long)]
+        count: usize,
+
+        /// Distance to the magnetic axis particles start within
+        #[arg(long, default_value_t = MINOR_RADIUS)]
+        radius: f64,
+
+        /// Toroidal angle in radians of the `grid` and `line` seeds
+        #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+        phi: f64,
+
+        /// Seed of the random number generator of `torus` seeding
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Major radius of the magnetic axis
+        #[arg(long, default_value_t = MAJOR_RADIUS)]
+        major_radius: f64,
+
+        /// Particles file to write
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+
+    /// Compare the snapshots of a finished run with reference snapshots
+    Validate {
+        /// Output directory of the run
+        run_dir: PathBuf,
+
+        /// Directory of the reference snapshots, `merged_<step>.csv` or
+        /// `out_all_<step>` files in global particle order
+        #[arg(long)]
+        reference: PathBuf,
+
+        /// Distance from its reference position a particle may have
+        #[arg(long, default_value_t = 1e-9)]
+        abs_tol: f64,
+
+        /// Distance from its reference position a particle may have in
+        /// addition, relative to the norm of that position
+        #[arg(long, default_value_t = 1e-6)]
+        rel_tol: f64,
+    },
+
+    /// Extract the Poincaré punctures of every particle from the snapshots of a run
+    Poincare {
+        /// Output directory of the run
+        run_dir: PathBuf,
+
+        /// CSV file for the punctures
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+
+    /// Compare two run configurations (run.json files or output directories)
+    ConfigDiff { left: PathBuf, right: PathBuf },
+
+    /// Merge per-rank outputs into one file per step in global particle order
+    Merge {
+        /// Output directory of the run
+        run_dir: PathBuf,
+
+        /// Directory for the merged files (default: the run directory)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Also write every step into a single HDF5 file
+        #[arg(long)]
+        hdf5: Option<PathBuf>,
+    },
+
+    /// Report run metadata, snapshots and missing or corrupt files of an output directory
+    Inspect {
+        /// Output directory of the run
+        run_dir: PathBuf,
+    },
+
+    /// Export the snapshots of a run as a VTK time series for ParaView or Blender
+    Animate {
+        /// Output directory of the run
+        run_dir: PathBuf,
+
+        /// Directory for the animation frames
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Per-particle values to color the frames by
+        #[arg(long, value_enum, default_value_t = ColorBy::None)]
+        color_by: ColorBy,
+
+        /// Keep every n-th particle
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        particle_stride: u32,
+    },
+
+    /// Export the coils and a subset of the trajectories of a run as a glTF scene
+    Scene {
+        /// Output directory of the run
+        run_dir: PathBuf,
+
+        /// glTF file to write
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Keep every n-th snapshot
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        step_stride: u32,
+
+        /// Keep every n-th particle
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        particle_stride: u32,
+    },
+
+    /// Trace a single particle serially, printing diagnostics after every step
+    TraceOne {
+        /// Path to resource folder
+        #[arg(short, long)]
+        resource_path: PathBuf,
+
+        /// Starting point as "x,y,z"
+        #[arg(long, value_parser = parse_point, allow_hyphen_values = true)]
+        start: Point,
+
+        /// Total simulation steps
+        #[arg(long, default_value_t = 1000)]
+        steps: u32,
+
+        /// Size of time step
+        #[arg(long, default_value_t = 0.001)]
+        step_size: f64,
+    },
+
+    /// Print the magnetic field and its Jacobian at the given points
+    FieldJacobian {
+        /// Path to resource folder
+        #[arg(short, long)]
+        resource_path: PathBuf,
+
+        /// Point as "x,y,z", may be repeated
+        #[arg(long = "point", required = true, value_parser = parse_point, allow_hyphen_values = true)]
+        points: Vec<Point>,
+    },
+
+    /// Estimate field line Lyapunov exponents per starting point
+    Lyapunov {
+        /// Path to resource folder
+        #[arg(short, long)]
+        resource_path: PathBuf,
+
+        /// Particles file with the starting points
+        #[arg(short, long)]
+        particles_file: PathBuf,
+
+        /// Total points
+        #[arg(long, default_value_t = usize::MAX)]
+        num_particles: usize,
+
+        /// CSV file for the chaos map
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Total simulation steps
+        #[arg(long, default_value_t = 10000)]
+        steps: u32,
+
+        /// Size of time step
+        #[arg(long, default_value_t = 0.001)]
+        step_size: f64,
+
+        /// Initial distance between the field lines of a pair
+        #[arg(long, default_value_t = 1e-6)]
+        separation: f64,
+
+        /// Steps between renormalisations of the pair separation
+        #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..))]
+        renormalize_every: u32,
+    },
+
+    /// Detect island chains in the Poincaré punctures of a run and report their widths
+    Islands {
+        /// Output directory of the run
+        run_dir: PathBuf,
+
+        /// JSON file for the report (default: standard output)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Largest number of islands per chain to look for
+        #[arg(long, default_value_t = 10)]
+        max_mode: usize,
+    },
+
+    /// Trace a flux surface and report the ripple and Fourier content of |B| on it
+    Ripple {
+        /// Path to resource folder
+        #[arg(short, long)]
+        resource_path: PathBuf,
+
+        /// Point on the surface as "x,y,z"
+        #[arg(long, value_parser = parse_point, allow_hyphen_values = true)]
+        start: Point,
+
+        /// Total simulation steps
+        #[arg(long, default_value_t = 50000)]
+        steps: u32,
+
+        /// Size of time step
+        #[arg(long, default_value_t = 0.001)]
+        step_size: f64,
+
+        /// Poloidal bins of the grid
+        #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u32).range(1..))]
+        theta_bins: u32,
+
+        /// Toroidal bins of the grid
+        #[arg(long, default_value_t = 64, value_parser = clap::value_parser!(u32).range(1..))]
+        phi_bins: u32,
+
+        /// Number of Fourier modes to report
+        #[arg(long, default_value_t = 10)]
+        modes: usize,
+
+        /// Major radius of the magnetic axis
+        #[arg(long, default_value_t = MAJOR_RADIUS)]
+        major_radius: f64,
+
+        /// JSON file for the report (default: standard output)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Evaluate the magnetic field on a grid, optionally with its derivatives
+    /// by coil current, dividing the grid points among the MPI ranks
+    #[command(alias = "field-grid")]
+    Fieldmap(FieldmapArgs),
+
+    /// Report how loss fraction and iota respond to ±delta changes of each coil group current
+    Sensitivity {
+        /// Path to resource folder
+        #[arg(short, long)]
+        resource_path: PathBuf,
+
+        /// Particles file
+        #[arg(short, long)]
+        particles_file: PathBuf,
+
+        /// Total points
+        #[arg(long, default_value_t = usize::MAX)]
+        num_particles: usize,
+
+        /// Total simulation steps
+        #[arg(long, default_value_t = 10000)]
+        steps: u32,
+
+        /// Size of time step
+        #[arg(long, default_value_t = 0.001)]
+        step_size: f64,
+
+        /// Relative current perturbation, between 0 and 1
+        #[arg(long, default_value_t = 0.01, value_parser = parse_delta)]
+        delta: f64,
+
+        /// Coil indices such as "0-5" whose currents vary together, may be repeated (default: every coil)
+        #[arg(long = "coil-group", value_parser = parse_coil_group)]
+        coil_groups: Vec<Vec<usize>>,
+
+        /// JSON file for the report (default: standard output)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Combine the summaries of the runs of a parameter scan into a campaign report
+    Aggregate {
+        /// Directory holding the output directories of the runs
+        campaign_dir: PathBuf,
+
+        /// JSON file for the report (default: standard output)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Also merge the Poincaré punctures of every run into this CSV file
+        #[arg(long)]
+        poincare: Option<PathBuf>,
+    },
+
+    /// Rewrite the outputs of a run in another format, from per-rank snapshots
+    /// or from the single HDF5, netCDF or Parquet file of an earlier conversion
+    Convert {
+        /// Output directory of the run
+        run_dir: PathBuf,
+
+        /// Directory for the converted outputs
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Format to convert to
+        #[arg(long, value_enum)]
+        to: OutputFormat,
+
+        /// Coordinate width when converting to `binary`
+        #[arg(long, value_enum, default_value_t = BinaryPrecision::F64)]
+        binary_precision: BinaryPrecision,
+
+        /// Keep every n-th snapshot
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        step_stride: u32,
+
+        /// Keep every n-th particle
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        particle_stride: u32,
+
+        #[command(flatten)]
+        text: TextFormatArgs,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+pub struct FieldmapArgs {
+    /// Path to resource folder, or to a single multi-coil file
+    #[arg(short, long)]
+    pub resource_path: PathBuf,
+
+    /// Layout of the coil input, detected from the resource path by default
+    #[arg(long, value_enum, default_value_t = CoilFormat::Auto)]
+    pub coil_format: CoilFormat,
+
+    /// JSON file of transforms applied in order to the coils after reading them
+    #[arg(long)]
+    pub coil_transforms: Option<PathBuf>,
+
+    /// Resample every coil along a periodic cubic spline to this many
+    /// segments of equal length after the transforms, as for `simulate`
+    #[arg(long, value_parser = clap::value_parser!(u32).range(3..))]
+    pub coil_segments: Option<u32>,
+
+    /// Coil current in amperes, as for `simulate`
+    #[arg(long, default_value_t = I, allow_negative_numbers = true)]
+    pub current: f64,
+
+    /// File of per-coil currents in amperes, as for `simulate`
+    #[arg(long)]
+    pub currents: Option<PathBuf>,
+
+    /// Field periods of the device, as for `simulate`. Coil groups then
+    /// index the coils of the first period, each standing for its copies in
+    /// every period.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub field_periods: Option<u32>,
+
+    /// Coordinates of the grid axes and field components
+    #[arg(long, value_enum, default_value_t = GridCoordinates::Cartesian)]
+    pub coordinates: GridCoordinates,
+
+    /// Lower corner of the grid as "x,y,z", or "r,phi,z" in cylindrical coordinates
+    #[arg(long, value_parser = parse_point, allow_hyphen_values = true)]
+    pub min: Point,
+
+    /// Upper corner of the grid as "x,y,z", or "r,phi,z" in cylindrical coordinates
+    #[arg(long, value_parser = parse_point, allow_hyphen_values = true)]
+    pub max: Point,
+
+    /// Points per axis as "n1,n2,n3", both corners included
+    #[arg(long, value_parser = parse_shape)]
+    pub shape: [usize; 3],
+
+    /// File to write
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Format of the field map
+    #[arg(long, value_enum, default_value_t = FieldMapFormat::Csv)]
+    pub format: FieldMapFormat,
+
+    /// Also write dB/dI of every coil, or of every --coil-group
+    #[arg(long)]
+    pub sensitivities: bool,
+
+    /// Coil indices such as "0-5" whose currents vary together, may be repeated
+    #[arg(long = "coil-group", value_parser = parse_coil_group)]
+    pub coil_groups: Vec<Vec<usize>>,
+
+    /// Analytic field added to that of the coils, as for `simulate`
+    #[arg(
+        long = "background-field",
+        value_parser = parse_background_field,
+        allow_hyphen_values = true
+    )]
+    pub background_fields: Vec<BackgroundField>,
+}
+
+impl FieldmapArgs {
+    pub fn grid(&self) -> Grid {
+        Grid {
+            coordinates: self.coordinates,
+            min: self.min,
+            max: self.max,
+            shape: self.shape,
+        }
+    }
+
+    /// Coil groups to write dB/dI of, `Some(&[])` for one group per coil
+    pub fn coil_groups(&self) -> Option<&[Vec<usize>]> {
+        (self.sensitivities || !self.coil_groups.is_empty()).then_some(self.coil_groups.as_slice())
+    }
+}
+
+#[derive(clap::Args, Debug)]
 pub struct Args {
-    /// Path to resource folder
+    /// Path to resource folder, or to a single multi-coil file
     #[arg(short, long)]
     pub resource_path: String,
 
-    /// Particles file
-    #[arg(short, long)]
-    pub particles_file: String,
+    /// Layout of the coil input, detected from the resource path by default
+    #[arg(long, value_enum, default_value_t = CoilFormat::Auto)]
+    pub coil_format: CoilFormat,
+
+    /// Particles file, not read by restarted or resumed runs
+    #[arg(
+        short,
+        long,
+        required_unless_present_any = ["seed_mode", "restart_from", "resume"]
+    )]
+    pub particles_file: Option<String>,
+
+    /// Generate `--num-particles` starting particles instead of reading a
+    /// particles file
+    #[arg(
+        long,
+        value_enum,
+        conflicts_with_all = ["particles_file", "mmap_particles"],
+        requires = "num_particles"
+    )]
+    pub seed_mode: Option<SeedMode>,
+
+    /// Distance to the magnetic axis seeded particles start within, the
+    /// minor radius by default
+    #[arg(long, requires = "seed_mode")]
+    pub seed_radius: Option<f64>,
+
+    /// Toroidal angle in radians of the `grid` and `line` seeds
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        requires = "seed_mode",
+        allow_negative_numbers = true
+    )]
+    pub seed_phi: f64,
+
+    /// Seed of the random number generator of `torus` seeding
+    #[arg(long, default_value_t = 0, requires = "seed_mode")]
+    pub seed: u64,
 
     /// Total simulation steps
     #[arg(long, default_value_t = 10000)]
     pub steps: u32,
 
-    /// Size of time step
+    /// Size of time step, in seconds with the `boris` integrator
     #[arg(long, default_value_t = 0.001)]
     pub step_size: f64,
 
-    /// Precision to use
-    #[arg(long, default_value_t = 5)]
-    pub precision: u8,
+    /// Integration scheme, `rk45` adapts substeps within every step to the
+    /// tolerances and `boris` pushes charged particles along their full orbit
+    #[arg(long, value_enum, default_value_t = IntegratorKind::Rk4)]
+    pub integrator: IntegratorKind,
+
+    /// Absolute error tolerance per coordinate of the adaptive integrator
+    #[arg(long, default_value_t = 1e-9)]
+    pub abs_tol: f64,
+
+    /// Relative error tolerance per coordinate of the adaptive integrator
+    #[arg(long, default_value_t = 1e-6)]
+    pub rel_tol: f64,
+
+    /// Coil current in amperes
+    #[arg(long, default_value_t = I, allow_negative_numbers = true)]
+    pub current: f64,
+
+    /// How the field contributions of the coil segments are added up,
+    /// `compensated` reduces the rounding error of long traces
+    #[arg(long, value_enum, default_value_t = Summation::Naive)]
+    pub summation: Summation,
+
+    /// Floating point width of the field evaluation, `f32` sums the coil
+    /// segments in single precision while positions stay in double. `f32`
+    /// does not combine with `--summation compensated`.
+    #[arg(long, value_enum, default_value_t = Precision::F64)]
+    pub precision: Precision,
+
+    /// Device the field is evaluated on. `gpu` needs a build with the `gpu`
+    /// feature, the `rk4` integrator and naive double precision sums, and
+    /// falls back to the CPU otherwise or without a CUDA device.
+    #[arg(long, value_enum, default_value_t = Backend::Cpu)]
+    pub backend: Backend,
+
+    /// Field periods of the device, the coils of the first one are rotated
+    /// into the others on the fly instead of storing every coil. The coils
+    /// must be ordered by period.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub field_periods: Option<u32>,
+
+    /// File of per-coil currents in amperes, one coil file name or coil index
+    /// and its current per line, coils not listed carry --current
+    #[arg(long)]
+    pub currents: Option<String>,
+
+    /// JSON file of rotations, translations, scalings and seeded random
+    /// perturbations applied in order to the coils after reading them
+    #[arg(long)]
+    pub coil_transforms: Option<String>,
+
+    /// Resample every coil along a periodic cubic spline through its points
+    /// to this many segments of equal length, after the transforms. Smooths
+    /// the field of coarse coils near their windings. Coils must be closed.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(3..))]
+    pub coil_segments: Option<u32>,
+
+    /// Analytic field added to that of the coils, repeatable:
+    /// `uniform:BX,BY,BZ` and `vertical:BZ` in teslas, or `toroidal:B0,R0`
+    /// for B0 teslas at the major radius R0 falling off as 1/R
+    #[arg(
+        long = "background-field",
+        value_parser = parse_background_field,
+        allow_hyphen_values = true
+    )]
+    pub background_fields: Vec<BackgroundField>,
+
+    /// Vacuum permeability
+    #[arg(long, default_value_t = MIU)]
+    pub miu: f64,
+
+    /// Major radius of the device, the magnetic axis is the circle of this radius
+    #[arg(long, default_value_t = MAJOR_RADIUS)]
+    pub major_radius: f64,
+
+    /// Distance to the magnetic axis beyond which particles are lost with the
+    /// `torus` boundary
+    #[arg(long, default_value_t = MINOR_RADIUS)]
+    pub minor_radius: f64,
+
+    /// Wall of the vessel particles are lost at
+    #[arg(long, value_enum, default_value_t = BoundaryKind::Torus)]
+    pub boundary: BoundaryKind,
+
+    /// CSV file of the `r` and `z` vertices of the `polygon` boundary
+    #[arg(long, required_if_eq("boundary", "polygon"))]
+    pub boundary_file: Option<String>,
+
+    /// Major radius and height ranges of the `box` boundary
+    #[arg(
+        long,
+        value_name = "R_MIN,R_MAX,Z_MIN,Z_MAX",
+        value_delimiter = ',',
+        allow_negative_numbers = true,
+        required_if_eq("boundary", "box")
+    )]
+    pub boundary_box: Option<Vec<f64>>,
+
+    /// Particle mass in proton masses, for the `boris` integrator
+    #[arg(long, default_value_t = 1.0)]
+    pub mass: f64,
+
+    /// Particle charge in elementary charges, for the `boris` integrator
+    #[arg(long, default_value_t = 1.0)]
+    pub charge: f64,
+
+    /// Kinetic energy in eV of particles without vx, vy and vz columns in the
+    /// particles file, for the `boris` integrator
+    #[arg(long, default_value_t = 100.0)]
+    pub energy: f64,
+
+    /// Cosine of the angle between the initial velocity and the field of
+    /// particles without vx, vy and vz columns, for the `boris` integrator
+    #[arg(long, default_value_t = 0.5, allow_negative_numbers = true)]
+    pub pitch: f64,
+
+    /// Pitch-angle scattering frequency in 1/s of Monte Carlo collisions
+    /// after every push, for the `boris` integrator
+    #[arg(long)]
+    pub collision_frequency: Option<f64>,
+
+    /// Seed of the random collisions, the same seed scatters every particle
+    /// the same way whatever the number of ranks
+    #[arg(long, default_value_t = 0, requires = "collision_frequency")]
+    pub collision_seed: u64,
 
     /// Total particles to use
     #[arg(long, default_value_t = 1)]
@@ -54,4 +670,424 @@ pub struct Args {
     /// How often to write output files
     #[arg(short, default_value_t = 10)]
     pub write_frequency: u32,
+
+    /// Which steps to write: every n-th step (see -w) or whenever a particle turned enough
+    #[arg(long, value_enum, default_value_t = DecimationMode::Every)]
+    pub decimation: DecimationMode,
+
+    /// Turning angle in degrees that triggers a write with curvature decimation
+    #[arg(long, default_value_t = 5.0)]
+    pub max_turn_angle: f64,
+
+    /// Let every rank memory-map the particles file and parse only the records
+    /// of its own byte range of it, so ranks hold as many particles as their
+    /// ranges have records (requires the file on a filesystem shared by all
+    /// ranks)
+    #[arg(long, conflicts_with = "restart_from")]
+    pub mmap_particles: bool,
+
+    /// Continue the run in the output directory from its last snapshot, which
+    /// must be the step it finished or stopped at, with the losses, connection
+    /// lengths and swept angles of its particles
+    #[arg(long, conflicts_with_all = ["restart_from", "mmap_particles"])]
+    pub resume: bool,
+
+    /// Output directory of an earlier run to take the particles from instead of the particles file
+    #[arg(long)]
+    pub restart_from: Option<String>,
+
+    /// Snapshot step of the earlier run to restart from (default: its last snapshot)
+    #[arg(long, requires = "restart_from")]
+    pub restart_step: Option<u32>,
+
+    /// Particles to restart: "all", "confined" or ids such as "0-99,150"
+    #[arg(long, default_value = "all", requires = "restart_from")]
+    pub restart_particles: ParticleFilter,
+
+    /// Lend active particles of ranks with many of them to ranks with few at
+    /// least every N steps and around every written step
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rebalance_every: Option<u32>,
+
+    /// Log the steps done, active particles, steps per second and estimated
+    /// remaining time on rank 0 every N steps
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub progress_interval: Option<u32>,
+
+    /// Time the phases of the run on every rank and report their minimum,
+    /// mean and maximum over the ranks on rank 0 and in profile.json
+    #[arg(long)]
+    pub profile: bool,
+
+    /// Wall time budget of the run as SECONDS, MM:SS or HH:MM:SS. Past it
+    /// the run stops with a snapshot of the step reached that --resume
+    /// continues from, as it does on SIGTERM. Ranks check for either every
+    /// 50 steps, leave room for those steps and the snapshot below the
+    /// limit of the job.
+    #[arg(long, value_parser = parse_walltime)]
+    pub max_walltime: Option<Duration>,
+
+    /// Keep only the N most recent snapshots on disk, deleting older ones during the run
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub keep_last: Option<u32>,
+
+    /// Never delete the snapshots of steps that are multiples of this
+    #[arg(long, requires = "keep_last", value_parser = clap::value_parser!(u32).range(1..))]
+    pub checkpoint_every: Option<u32>,
+
+    /// Format of the per-rank snapshots, `vtk` also writes a snapshots.pvd
+    /// collection. `netcdf` gathers every step into one trajectories.nc.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+
+    /// Write one file per rank and written step, or append the positions of
+    /// each particle to its own trajectories/particle_<index>.csv file
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputLayout::Snapshots,
+        conflicts_with_all = ["output_format", "single_file", "reproducible", "writer_ranks", "keep_last", "resume", "validate", "compress"],
+    )]
+    pub output_layout: OutputLayout,
+
+    /// Coordinate width of `binary` snapshots
+    #[arg(long, value_enum, default_value_t = BinaryPrecision::F64)]
+    pub binary_precision: BinaryPrecision,
+
+    /// Compress the text or binary snapshots, velocities and fields,
+    /// appending `.zst` or `.gz` to their names. Needs building with the
+    /// feature of the same name.
+    #[arg(long, value_enum)]
+    pub compress: Option<Compression>,
+
+    /// Gather every snapshot on rank 0 into one file per step, in global
+    /// particle order. Every particle passes through the memory of rank 0,
+    /// there is no collective MPI-IO write.
+    #[arg(long)]
+    pub single_file: bool,
+
+    /// Also write Bx, By, Bz and |B| at every particle with each snapshot,
+    /// into field_* files or the VTK point data
+    #[arg(long)]
+    pub write_fields: bool,
+
+    /// Record how far every step strays from an exact integration into
+    /// drift.csv: the error of the step length, the drift towards or away
+    /// from the magnetic axis, and steps ending at NaN or infinity
+    #[arg(long)]
+    pub drift_diagnostics: bool,
+
+    /// Record where every particle crosses the Poincaré plane, interpolated
+    /// within the step it crossed in, into punctures_<rank>.csv for the
+    /// poincare, islands and aggregate subcommands
+    #[arg(long)]
+    pub record_punctures: bool,
+
+    /// Write the rotational transform of the field line of every particle
+    /// over the steps of this run into iota.csv, as its poloidal turns
+    /// about the circle of major radius over its toroidal turns
+    #[arg(long)]
+    pub rotational_transform: bool,
+
+    /// Rayon threads of every rank, by default the cores of a node shared
+    /// among the compute ranks on it unless RAYON_NUM_THREADS is set
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub threads_per_rank: Option<u32>,
+
+    /// Dedicate the last N ranks to writing the snapshots the other ranks send
+    /// them, so those carry on stepping while the files are written
+    #[arg(long, conflicts_with_all = ["single_file", "reproducible"], value_parser = clap::value_parser!(u32).range(1..))]
+    pub writer_ranks: Option<u32>,
+
+    /// Publish the particles of every snapshot on this `host:port` from rank
+    /// 0, one JSON line `{"step": .., "positions": [..]}` per snapshot to
+    /// every TCP client connected at the time
+    #[arg(long, value_name = "ENDPOINT")]
+    pub stream: Option<String>,
+
+    /// Write output that is identical on any number of ranks: single-file
+    /// snapshots and one lost_all.csv, every row keyed by its global particle index
+    #[arg(long)]
+    pub reproducible: bool,
+
+    /// Compare the snapshots of the run with the reference snapshots in this
+    /// directory, `merged_<step>.csv` or `out_all_<step>` files in global
+    /// particle order, and exit with status 1 if they diverge
+    #[arg(long)]
+    pub validate: Option<String>,
+
+    /// Distance from its reference position a particle may have
+    #[arg(long, default_value_t = 1e-9, requires = "validate")]
+    pub validate_abs_tol: f64,
+
+    /// Distance from its reference position a particle may have in addition,
+    /// relative to the norm of that position
+    #[arg(long, default_value_t = 1e-6, requires = "validate")]
+    pub validate_rel_tol: f64,
+
+    // Kept inline rather than flattening `TextFormatArgs`, clap does not
+    // detect the optional `Cli::run` group through a nested flatten
+    /// Digits after the decimal point in text outputs (default: shortest exact value)
+    #[arg(long)]
+    pub output_precision: Option<usize>,
+
+    /// Notation for values in text outputs
+    #[arg(long, value_enum, default_value_t = Notation::Fixed)]
+    pub notation: Notation,
+
+    /// Field delimiter for text outputs (a single ASCII character or "tab")
+    #[arg(long, default_value = ",", value_parser = parse_delimiter)]
+    pub delimiter: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DecimationMode {
+    Every,
+    Curvature,
+}
+
+impl Args {
+    pub fn decimation(&self) -> Decimation {
+        match self.decimation {
+            DecimationMode::Every => Decimation::Every(self.write_frequency),
+            DecimationMode::Curvature => Decimation::Curvature {
+                max_angle: self.max_turn_angle.to_radians(),
+            },
+        }
+    }
+
+    pub fn integrator(&self) -> Box<dyn Integrator> {
+        self.integrator
+            .integrator(self.step_size, self.tolerances(), self.orbit())
+    }
+
+    pub fn physics(&self) -> PhysicsParams {
+        PhysicsParams {
+            current: self.current,
+            miu: self.miu,
+            major_radius: self.major_radius,
+            minor_radius: self.minor_radius,
+        }
+    }
+
+    /// Loss boundary of `--boundary`, reading the polygon file if needed
+    pub fn loss_boundary(&self) -> Result<LossBoundary, Box<dyn Error>> {
+        match self.boundary {
+            BoundaryKind::Torus => Ok(LossBoundary::Torus),
+            BoundaryKind::Polygon => {
+                let path = self.boundary_file.as_deref().unwrap_or_default();
+                LossBoundary::read_polygon(Path::new(path))
+            }
+            BoundaryKind::Box => match self.boundary_box.as_deref() {
+                Some(&[r_min, r_max, z_min, z_max]) => {
+                    Ok(LossBoundary::radial_box(r_min, r_max, z_min, z_max)?)
+                }
+                _ => Err("the box boundary needs --boundary-box R_MIN,R_MAX,Z_MIN,Z_MAX".into()),
+            },
+        }
+    }
+
+    pub fn orbit(&self) -> OrbitSettings {
+        OrbitSettings {
+            species: Species {
+                mass: self.mass * PROTON_MASS,
+                charge: self.charge * ELEMENTARY_CHARGE,
+            },
+            energy: self.energy,
+            pitch: self.pitch,
+            collisions: self.collision_frequency.map(|frequency| Collisions {
+                frequency,
+                seed: self.collision_seed,
+            }),
+        }
+    }
+
+    pub fn seeding(&self) -> Option<Seeding> {
+        self.seed_mode.map(|mode| Seeding {
+            mode,
+            radius: self.seed_radius.unwrap_or(self.minor_radius),
+            phi: self.seed_phi,
+            seed: self.seed,
+        })
+    }
+
+    pub fn schedule(&self) -> Schedule {
+        Schedule {
+            first_step: 0,
+            total_steps: self.steps,
+            rebalance_every: self.rebalance_every,
+            progress_every: self.progress_interval,
+            collisions: self.orbit().collisions,
+            shutdown: None,
+        }
+    }
+
+    pub fn tolerances(&self) -> Tolerances {
+        Tolerances {
+            absolute: self.abs_tol,
+            relative: self.rel_tol,
+        }
+    }
+
+    pub fn validation_tolerances(&self) -> Tolerances {
+        Tolerances {
+            absolute: self.validate_abs_tol,
+            relative: self.validate_rel_tol,
+        }
+    }
+
+    pub fn retention(&self) -> Retention {
+        Retention {
+            keep_last: self.keep_last,
+            checkpoint_every: self.checkpoint_every,
+        }
+    }
+
+    pub fn text_format(&self) -> TextFormat {
+        TextFormat {
+            precision: self.output_precision,
+            notation: self.notation,
+            delimiter: self.delimiter,
+        }
+    }
+
+    /// Whether snapshots are gathered into one file per step
+    pub fn writes_single_files(&self) -> bool {
+        self.single_file || self.reproducible
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct TextFormatArgs {
+    /// Digits after the decimal point in text outputs (default: shortest exact value)
+    #[arg(long)]
+    pub output_precision: Option<usize>,
+
+    /// Notation for values in text outputs
+    #[arg(long, value_enum, default_value_t = Notation::Fixed)]
+    pub notation: Notation,
+
+    /// Field delimiter for text outputs (a single ASCII character or "tab")
+    #[arg(long, default_value = ",", value_parser = parse_delimiter)]
+    pub delimiter: u8,
+}
+
+impl TextFormatArgs {
+    pub fn text_format(&self) -> TextFormat {
+        TextFormat {
+            precision: self.output_precision,
+            notation: self.notation,
+            delimiter: self.delimiter,
+        }
+    }
+}
+
+fn parse_delimiter(value: &str) -> Result<u8, String> {
+    match value {
+        "tab" | "\\t" => Ok(b'\t'),
+        _ if value.len() == 1 && value.is_ascii() => Ok(value.as_bytes()[0]),
+        _ => Err(format!(
+            "delimiter must be a single ASCII character, got {:?}",
+            value
+        )),
+    }
+}
+
+fn parse_point(value: &str) -> Result<Point, String> {
+    let coordinates = value
+        .split(',')
+        .map(|coordinate| coordinate.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("invalid coordinate in {:?}: {}", value, err))?;
+    match coordinates[..] {
+        [x, y, z] => Ok(Point { x, y, z }),
+        _ => Err(format!(
+            "expected three coordinates \"x,y,z\", got {:?}",
+            value
+        )),
+    }
+}
+
+fn parse_background_field(value: &str) -> Result<BackgroundField, String> {
+    let (kind, values) = value.split_once(':').ok_or_else(|| {
+        format!(
+            "expected \"uniform:BX,BY,BZ\", \"vertical:BZ\" or \"toroidal:B0,R0\", got {:?}",
+            value
+        )
+    })?;
+    let values = values
+        .split(',')
+        .map(|component| component.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("invalid value in {:?}: {}", value, err))?;
+    match (kind, &values[..]) {
+        ("uniform", &[bx, by, bz]) => Ok(BackgroundField::Uniform { bx, by, bz }),
+        ("vertical", &[bz]) => Ok(BackgroundField::Uniform {
+            bx: 0.0,
+            by: 0.0,
+            bz,
+        }),
+        ("toroidal", &[b0, r0]) if r0 > 0.0 => Ok(BackgroundField::Toroidal { b0, r0 }),
+        _ => Err(format!(
+            "expected \"uniform:BX,BY,BZ\", \"vertical:BZ\" or \"toroidal:B0,R0\" \
+             with R0 > 0, got {:?}",
+            value
+        )),
+    }
+}
+
+fn parse_walltime(value: &str) -> Result<Duration, String> {
+    let fields = value
+        .split(':')
+        .map(|field| field.trim().parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("invalid wall time {:?}: {}", value, err))?;
+    let seconds = match fields[..] {
+        [seconds] => seconds,
+        [minutes, seconds] if seconds < 60 => minutes * 60 + seconds,
+        [hours, minutes, seconds] if minutes < 60 && seconds < 60 => {
+            (hours * 60 + minutes) * 60 + seconds
+        }
+        _ => {
+            return Err(format!(
+                "expected SECONDS, MM:SS or HH:MM:SS, got {:?}",
+                value
+            ));
+        }
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+fn parse_shape(value: &str) -> Result<[usize; 3], String> {
+    let counts = value
+        .split(',')
+        .map(|count| count.trim().parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("invalid count in {:?}: {}", value, err))?;
+    match counts[..] {
+        [nx, ny, nz] if nx > 0 && ny > 0 && nz > 0 => Ok([nx, ny, nz]),
+        _ => Err(format!(
+            "expected three positive counts \"nx,ny,nz\", got {:?}",
+            value
+        )),
+    }
+}
+
+fn parse_coil_group(value: &str) -> Result<Vec<usize>, String> {
+    Ok(parse_id_ranges(value)?.into_iter().flatten().collect())
+}
+
+fn parse_delta(value: &str) -> Result<f64, String> {
+    let delta = value
+        .trim()
+        .parse::<f64>()
+        .map_err(|err| format!("invalid delta {:?}: {}", value, err))?;
+    if delta > 0.0 && delta < 1.0 {
+        Ok(delta)
+    } else {
+        Err(format!(
+            "expected a relative perturbation between 0 and 1, got {}",
+            delta
+        ))
+    }
 }
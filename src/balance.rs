@@ -0,0 +1,209 @@
+use crate::{
+    collectives::Collectives,
+    diagnostics::SweptAngles,
+    integrator::{IntegrationState, StepCounts},
+    particle::ParticleState,
+    partition::loan_plan,
+    point::Point,
+};
+
+/// Values sent per particle: position, substep, velocity flag, velocity,
+/// step counts, loss step, negative while active, length, field flag, field,
+/// global index and swept angles
+const PACKED_LEN: usize = 19;
+
+/// Active particles temporarily integrated by other ranks so every rank
+/// advances about the same number of them. Particles stay owned, and are
+/// written, by the rank they were scattered to: `settle` hands them back.
+pub struct Loans {
+    /// Whether each local particle is being integrated by another rank
+    pub lent: Vec<bool>,
+    lent_indices: Vec<usize>,
+    lent_counts: Vec<usize>,
+    pub borrowed: Vec<Point>,
+    pub borrowed_states: Vec<IntegrationState>,
+    borrowed_counts: Vec<usize>,
+}
+
+impl Loans {
+    /// Lends the last active particles of ranks holding more than their share
+    /// to ranks holding less. Collective.
+    pub fn lend(particles: &[Point], states: &[IntegrationState], comm: &impl Collectives) -> Self {
+        let active: Vec<usize> = (0..particles.len())
+            .filter(|&index| states[index].status.is_active())
+            .collect();
+        let plan = loan_plan(&comm.all_gather_count(active.len()));
+        let lent_counts = plan[comm.local_rank()].clone();
+        let num_lent: usize = lent_counts.iter().sum();
+        let lent_indices = active[active.len() - num_lent..].to_vec();
+        let send: Vec<f64> = lent_indices
+            .iter()
+            .flat_map(|&index| pack(&particles[index], &states[index]))
+            .collect();
+        let (received, received_counts) = comm.exchange(&send, &packed_counts(&lent_counts));
+        let (borrowed, borrowed_states) = received.chunks_exact(PACKED_LEN).map(unpack).unzip();
+        let mut lent = vec![false; particles.len()];
+        for &index in &lent_indices {
+            lent[index] = true;
+        }
+        Loans {
+            lent,
+            lent_indices,
+            lent_counts,
+            borrowed,
+            borrowed_states,
+            borrowed_counts: received_counts
+                .iter()
+                .map(|count| count / PACKED_LEN)
+                .collect(),
+        }
+    }
+
+    /// Returns the borrowed particles to their owners and takes back the
+    /// lent ones. Collective.
+    pub fn settle(
+        self,
+        particles: &mut [Point],
+        states: &mut [IntegrationState],
+        comm: &impl Collectives,
+    ) {
+        let send: Vec<f64> = self
+            .borrowed
+            .iter()
+            .zip(&self.borrowed_states)
+            .flat_map(|(particle, state)| pack(particle, state))
+            .collect();
+        let (received, _) = comm.exchange(&send, &packed_counts(&self.borrowed_counts));
+        debug_assert_eq!(
+            received.len(),
+            PACKED_LEN * self.lent_counts.iter().sum::<usize>()
+        );
+        for (&index, values) in self
+            .lent_indices
+            .iter()
+            .zip(received.chunks_exact(PACKED_LEN))
+        {
+            (particles[index], states[index]) = unpack(values);
+        }
+    }
+}
+
+fn packed_counts(counts: &[usize]) -> Vec<usize> {
+    counts.iter().map(|count| count * PACKED_LEN).collect()
+}
+
+fn pack(particle: &Point, state: &IntegrationState) -> [f64; PACKED_LEN] {
+    let velocity = state.velocity.unwrap_or_default();
+    let field = state.field.unwrap_or_default();
+    [
+        particle.x,
+        particle.y,
+        particle.z,
+        state.substep,
+        if state.velocity.is_some() { 1.0 } else { 0.0 },
+        velocity.x,
+        velocity.y,
+        velocity.z,
+        state.counts.accepted as f64,
+        state.counts.rejected as f64,
+        match state.status {
+            ParticleState::Active => -1.0,
+            ParticleState::Lost { step } => step as f64,
+        },
+        state.length,
+        if state.field.is_some() { 1.0 } else { 0.0 },
+        field.x,
+        field.y,
+        field.z,
+        // Exact below 2^53 particles
+        state.index as f64,
+        state.angles.toroidal,
+        state.angles.poloidal,
+    ]
+}
+
+fn unpack(values: &[f64]) -> (Point, IntegrationState) {
+    let particle = Point {
+        x: values[0],
+        y: values[1],
+        z: values[2],
+    };
+    let velocity = Point {
+        x: values[5],
+        y: values[6],
+        z: values[7],
+    };
+    let field = Point {
+        x: values[13],
+        y: values[14],
+        z: values[15],
+    };
+    let state = IntegrationState {
+        status: if values[10] < 0.0 {
+            ParticleState::Active
+        } else {
+            ParticleState::Lost {
+                step: values[10] as u32,
+            }
+        },
+        substep: values[3],
+        velocity: (values[4] != 0.0).then_some(velocity),
+        counts: StepCounts {
+            accepted: values[8] as u64,
+            rejected: values[9] as u64,
+        },
+        length: values[11],
+        field: (values[12] != 0.0).then_some(field),
+        index: values[16] as u64,
+        angles: SweptAngles {
+            toroidal: values[17],
+            poloidal: values[18],
+        },
+        puncture: None,
+        next: None,
+    };
+    (particle, state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn particles_and_states_survive_packing() {
+        let particle = Point {
+            x: 0.2,
+            y: -0.1,
+            z: 0.05,
+        };
+        let state = IntegrationState {
+            status: ParticleState::Lost { step: 7 },
+            substep: 1e-4,
+            velocity: Some(Point {
+                x: 1.0,
+                y: 2.0,
+                z: 3.0,
+            }),
+            counts: StepCounts {
+                accepted: 12,
+                rejected: 3,
+            },
+            length: 0.75,
+            field: Some(Point {
+                x: 1e-3,
+                y: -2e-3,
+                z: 0.5,
+            }),
+            index: 42,
+            angles: SweptAngles {
+                toroidal: 12.5,
+                poloidal: -4.0,
+            },
+            puncture: None,
+            next: None,
+        };
+        assert_eq!(unpack(&pack(&particle, &state)), (particle, state));
+        let state = IntegrationState::new(1e-3);
+        assert_eq!(unpack(&pack(&particle, &state)), (particle, state));
+    }
+}
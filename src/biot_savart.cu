@@ -0,0 +1,60 @@
+// Biot–Savart field of the straight segments of a coil set at a batch of
+// points, one thread per point, as `stored_coils_field` sums it on the CPU.
+// Points and fields are interleaved x, y, z triples. Segment j runs from
+// coil point j to j + 1 and carries the current of `multipliers[j]`, which
+// is 0 at the last point of every coil.
+
+extern "C" __global__ void biot_savart(
+    const double *points,
+    unsigned int num_points,
+    const double *x,
+    const double *y,
+    const double *z,
+    const double *e_x,
+    const double *e_y,
+    const double *e_z,
+    const double *lengths,
+    const double *multipliers,
+    unsigned int num_segments,
+    double *fields)
+{
+    unsigned int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i >= num_points) {
+        return;
+    }
+    double px = points[3 * i];
+    double py = points[3 * i + 1];
+    double pz = points[3 * i + 2];
+    double bx = 0.0;
+    double by = 0.0;
+    double bz = 0.0;
+    for (unsigned int j = 0; j < num_segments; ++j) {
+        double multiplier = multipliers[j];
+        if (multiplier == 0.0) {
+            continue;
+        }
+        double rmi_x = px - x[j];
+        double rmi_y = py - y[j];
+        double rmi_z = pz - z[j];
+        double rmf_x = px - x[j + 1];
+        double rmf_y = py - y[j + 1];
+        double rmf_z = pz - z[j + 1];
+        double ux = multiplier * e_x[j];
+        double uy = multiplier * e_y[j];
+        double uz = multiplier * e_z[j];
+        double length = lengths[j];
+        double rmi = sqrt(rmi_x * rmi_x + rmi_y * rmi_y + rmi_z * rmi_z);
+        double rmf = sqrt(rmf_x * rmf_x + rmf_y * rmf_y + rmf_z * rmf_z);
+        double c = ((2.0 * length * (rmi + rmf)) / (rmi * rmf))
+            * (1.0 / ((rmi + rmf) * (rmi + rmf) - length * length));
+        double vx = rmi_x * c;
+        double vy = rmi_y * c;
+        double vz = rmi_z * c;
+        bx += (uy * vz) - (uz * vy);
+        by += -((ux * vz) - (uz * vx));
+        bz += (ux * vy) - (uy * vx);
+    }
+    fields[3 * i] = bx;
+    fields[3 * i + 1] = by;
+    fields[3 * i + 2] = bz;
+}
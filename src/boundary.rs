@@ -0,0 +1,153 @@
+use crate::{constants::PhysicsParams, point::Point, simulation::distance_to_axis};
+use clap::ValueEnum;
+use std::{error::Error, path::Path};
+
+/// Shapes of the wall particles are lost at, selected with `--boundary`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BoundaryKind {
+    /// Circular cross-section of the minor radius around the magnetic axis
+    #[default]
+    Torus,
+    /// Poloidal polygon read from `--boundary-file`
+    Polygon,
+    /// Range of major radius and height given by `--boundary-box`
+    Box,
+}
+
+/// Wall of the vacuum vessel, the same in every poloidal cross-section.
+/// Particles are lost when they leave it.
+#[derive(Debug, Default, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum LossBoundary {
+    /// Within the minor radius of the magnetic axis of the physics parameters
+    #[default]
+    Torus,
+    /// Inside the polygon of `(R, Z)` vertices, by the even-odd rule
+    Polygon { vertices: Vec<[f64; 2]> },
+    /// `r_min <= R <= r_max` and `z_min <= Z <= z_max`
+    Box {
+        r_min: f64,
+        r_max: f64,
+        z_min: f64,
+        z_max: f64,
+    },
+}
+
+#[derive(serde::Deserialize)]
+struct VertexRecord {
+    r: f64,
+    z: f64,
+}
+
+impl LossBoundary {
+    /// Reads a polygon of one `r, z` vertex per row from a CSV file with a
+    /// header, closing it from the last vertex back to the first
+    pub fn read_polygon(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)?;
+        let mut vertices = Vec::new();
+        for result in rdr.deserialize() {
+            let VertexRecord { r, z } = result?;
+            vertices.push([r, z]);
+        }
+        LossBoundary::polygon(vertices).map_err(|err| format!("{}: {}", path.display(), err).into())
+    }
+
+    /// Fails for fewer than three vertices
+    pub fn polygon(vertices: Vec<[f64; 2]>) -> Result<Self, String> {
+        if vertices.len() < 3 {
+            return Err(format!(
+                "a boundary polygon needs at least 3 vertices, not {}",
+                vertices.len()
+            ));
+        }
+        Ok(LossBoundary::Polygon { vertices })
+    }
+
+    /// Fails unless both ranges hold at least one point
+    pub fn radial_box(r_min: f64, r_max: f64, z_min: f64, z_max: f64) -> Result<Self, String> {
+        if !(r_min <= r_max && z_min <= z_max) {
+            return Err(format!(
+                "empty boundary box R in [{}, {}], Z in [{}, {}]",
+                r_min, r_max, z_min, z_max
+            ));
+        }
+        Ok(LossBoundary::Box {
+            r_min,
+            r_max,
+            z_min,
+            z_max,
+        })
+    }
+
+    /// Whether `point` is confined, on the wall counting as inside
+    pub fn contains(&self, point: &Point, physics: &PhysicsParams) -> bool {
+        let r = point.x.hypot(point.y);
+        match self {
+            LossBoundary::Torus => distance_to_axis(point, physics) <= physics.minor_radius,
+            LossBoundary::Polygon { vertices } => {
+                let mut inside = false;
+                let mut previous = vertices[vertices.len() - 1];
+                for &vertex in vertices {
+                    let ([r1, z1], [r2, z2]) = (previous, vertex);
+                    if (z1 > point.z) != (z2 > point.z)
+                        && r < r1 + (point.z - z1) * (r2 - r1) / (z2 - z1)
+                    {
+                        inside = !inside;
+                    }
+                    previous = vertex;
+                }
+                inside
+            }
+            LossBoundary::Box {
+                r_min,
+                r_max,
+                z_min,
+                z_max,
+            } => (*r_min..=*r_max).contains(&r) && (*z_min..=*z_max).contains(&point.z),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boundaries_confine_their_cross_section() {
+        let physics = PhysicsParams::default();
+        let at = |r: f64, z: f64, phi: f64| Point {
+            x: r * phi.cos(),
+            y: r * phi.sin(),
+            z,
+        };
+        let axis = physics.major_radius;
+        let torus = LossBoundary::Torus;
+        assert!(torus.contains(&at(axis + 0.09, 0.0, 1.0), &physics));
+        assert!(!torus.contains(&at(axis + 0.07, 0.07, 1.0), &physics));
+
+        // D-shaped vessel: flat inboard wall, pointed outboard
+        let polygon = LossBoundary::polygon(vec![
+            [axis - 0.05, -0.08],
+            [axis + 0.05, -0.08],
+            [axis + 0.1, 0.0],
+            [axis + 0.05, 0.08],
+            [axis - 0.05, 0.08],
+        ])
+        .unwrap();
+        for phi in [0.0, 2.0, 4.0] {
+            assert!(polygon.contains(&at(axis, 0.0, phi), &physics));
+            assert!(polygon.contains(&at(axis + 0.09, 0.0, phi), &physics));
+            assert!(!polygon.contains(&at(axis + 0.09, 0.05, phi), &physics));
+            assert!(!polygon.contains(&at(axis - 0.06, 0.0, phi), &physics));
+        }
+
+        let radial_box = LossBoundary::radial_box(0.2, 0.3, -0.05, 0.05).unwrap();
+        assert!(radial_box.contains(&at(0.25, 0.05, 3.0), &physics));
+        assert!(!radial_box.contains(&at(0.25, 0.06, 3.0), &physics));
+        assert!(!radial_box.contains(&at(0.31, 0.0, 3.0), &physics));
+        assert!(LossBoundary::radial_box(0.3, 0.2, 0.0, 0.0).is_err());
+        assert!(LossBoundary::polygon(vec![[0.0, 0.0], [1.0, 1.0]]).is_err());
+    }
+}
@@ -0,0 +1,328 @@
+use crate::point::{Point, read_points_fast};
+use clap::ValueEnum;
+use log::debug;
+use rayon::prelude::*;
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// First bytes of a binary coil file
+const BINARY_MAGIC: &[u8; 8] = b"BSCOILS1";
+
+/// Layouts coil geometry can be read from
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum CoilFormat {
+    /// `multi` or `binary` for a file, by extension, and `csv` or `text` for
+    /// each file of a directory, also by extension
+    #[default]
+    Auto,
+    /// A directory with one CSV file per coil, with a header line
+    Csv,
+    /// A directory with one file per coil of whitespace or comma separated
+    /// coordinates and no header
+    Text,
+    /// A single text file starting with the number of coils, then the number
+    /// of points of each coil followed by its points
+    Multi,
+    /// A single little-endian file: magic, coil count, then the point count
+    /// and `x y z` values of each coil as `u64` and `f64`
+    Binary,
+}
+
+impl CoilFormat {
+    /// Format of a file or directory when auto-detecting
+    pub fn detect(path: &Path) -> CoilFormat {
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        match (path.is_dir(), extension) {
+            (true, _) => CoilFormat::Auto,
+            (false, Some("bin")) => CoilFormat::Binary,
+            (false, _) => CoilFormat::Multi,
+        }
+    }
+}
+
+/// Files a coil input is read from, in coil order for directories
+pub fn list_coil_files(path: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut coil_files = fs::read_dir(path)?
+        .map(|res| res.map(|e| e.path()))
+        .collect::<Result<Vec<_>, io::Error>>()?;
+    coil_files.sort();
+    Ok(coil_files)
+}
+
+/// Reads every coil of a directory or single coil file in `format`
+pub fn read_coils(path: &Path, format: CoilFormat) -> Result<Vec<Vec<Point>>, Box<dyn Error>> {
+    let format = match format {
+        CoilFormat::Auto => CoilFormat::detect(path),
+        format => format,
+    };
+    let coils = match format {
+        CoilFormat::Multi => read_multi_coil_file(path)?,
+        CoilFormat::Binary => read_binary_coil_file(path)?,
+        format => list_coil_files(path)?
+            .par_iter()
+            .map(|coil_file| match format {
+                CoilFormat::Text => read_text_coil(coil_file),
+                CoilFormat::Auto
+                    if coil_file
+                        .extension()
+                        .is_none_or(|extension| extension != "csv") =>
+                {
+                    read_text_coil(coil_file)
+                }
+                _ => read_points_fast(coil_file),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| err as Box<dyn Error>)?,
+    };
+    debug!("Read {} coils from {:?} as {:?}", coils.len(), path, format);
+    Ok(coils)
+}
+
+/// Coordinates of a line of a text coil file, `None` for blank lines
+fn parse_text_point(line: &str) -> Option<Result<Point, String>> {
+    let values: Vec<&str> = line
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|value| !value.is_empty())
+        .collect();
+    if values.is_empty() {
+        return None;
+    }
+    if values.len() < 3 {
+        return Some(Err(format!("too few coordinates in {:?}", line)));
+    }
+    let parse = |value: &str| {
+        value
+            .parse::<f64>()
+            .map_err(|err| format!("{} in {:?}", err, line))
+    };
+    let coordinates = values[..3]
+        .iter()
+        .map(|value| parse(value))
+        .collect::<Result<Vec<_>, _>>();
+    Some(coordinates.map(|c| Point {
+        x: c[0],
+        y: c[1],
+        z: c[2],
+    }))
+}
+
+fn read_text_coil(path: &Path) -> Result<Vec<Point>, Box<dyn Error + Send + Sync>> {
+    let mut points = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        if let Some(point) = parse_text_point(&line?) {
+            points.push(point.map_err(|err| format!("{:?}: {}", path, err))?);
+        }
+    }
+    Ok(points)
+}
+
+fn next_count<'a>(
+    lines: &mut impl Iterator<Item = &'a str>,
+    path: &Path,
+    what: &str,
+) -> Result<usize, Box<dyn Error>> {
+    let line = lines
+        .next()
+        .ok_or_else(|| format!("{:?}: missing {}", path, what))?;
+    let count = line
+        .trim()
+        .parse()
+        .map_err(|err| format!("{:?}: invalid {} {:?}: {}", path, what, line, err))?;
+    Ok(count)
+}
+
+fn read_multi_coil_file(path: &Path) -> Result<Vec<Vec<Point>>, Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+    let num_coils = next_count(&mut lines, path, "coil count")?;
+    // The counts come from the file, so the vectors grow with the points
+    // actually read rather than being sized from them
+    let mut coils = Vec::new();
+    for coil in 0..num_coils {
+        let num_points = next_count(&mut lines, path, &format!("point count of coil {}", coil))?;
+        let mut points = Vec::new();
+        for _ in 0..num_points {
+            let line = lines
+                .next()
+                .ok_or_else(|| format!("{:?}: coil {} ends early", path, coil))?;
+            let point = parse_text_point(line)
+                .unwrap_or_else(|| Err("empty line".to_string()))
+                .map_err(|err| format!("{:?}: {}", path, err))?;
+            points.push(point);
+        }
+        coils.push(points);
+    }
+    Ok(coils)
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_binary_coil_file(path: &Path) -> Result<Vec<Vec<Point>>, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != BINARY_MAGIC {
+        return Err(format!("{:?} is not a binary coil file", path).into());
+    }
+    let num_coils = read_u64(&mut reader)?;
+    let mut coils = Vec::new();
+    for _ in 0..num_coils {
+        let num_points = read_u64(&mut reader)?;
+        let mut coil = Vec::new();
+        for _ in 0..num_points {
+            let mut next = || read_u64(&mut reader).map(f64::from_bits);
+            coil.push(Point {
+                x: next()?,
+                y: next()?,
+                z: next()?,
+            });
+        }
+        coils.push(coil);
+    }
+    Ok(coils)
+}
+
+/// Writes coils in the `binary` format
+pub fn write_binary_coil_file(path: &Path, coils: &[Vec<Point>]) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(BINARY_MAGIC)?;
+    writer.write_all(&(coils.len() as u64).to_le_bytes())?;
+    for coil in coils {
+        writer.write_all(&(coil.len() as u64).to_le_bytes())?;
+        for point in coil {
+            for value in [point.x, point.y, point.z] {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the current in amperes of each of `num_coils` coils. Every line of
+/// the file names a coil, by the file name of one of `coil_files` or by its
+/// index, followed by its current, and `#` starts a comment. Coils that are
+/// not listed carry `default`.
+pub fn read_currents(
+    path: &Path,
+    coil_files: &[PathBuf],
+    num_coils: usize,
+    default: f64,
+) -> Result<Vec<f64>, Box<dyn Error>> {
+    let mut currents = vec![None; num_coils];
+    for line in fs::read_to_string(path)?.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let values: Vec<&str> = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|value| !value.is_empty())
+            .collect();
+        let (coil, current) = match values[..] {
+            [] => continue,
+            [coil, current] => (coil, current),
+            _ => {
+                return Err(
+                    format!("{:?}: expected a coil and a current in {:?}", path, line).into(),
+                );
+            }
+        };
+        let index = coil_files
+            .iter()
+            .position(|file| file.file_name().is_some_and(|name| name == coil))
+            .or_else(|| coil.parse().ok())
+            .filter(|&index| index < num_coils)
+            .ok_or_else(|| format!("{:?}: unknown coil {:?}", path, coil))?;
+        let current = current
+            .parse::<f64>()
+            .map_err(|err| format!("{:?}: invalid current {:?}: {}", path, current, err))?;
+        if currents[index].replace(current).is_some() {
+            return Err(format!("{:?}: coil {:?} is listed twice", path, coil).into());
+        }
+    }
+    Ok(currents
+        .into_iter()
+        .map(|current| current.unwrap_or(default))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_format_reads_the_same_coils() {
+        let csv_dir = Path::new("tests/test-resources/resources");
+        let coils = read_coils(csv_dir, CoilFormat::Auto).unwrap();
+        let dir = std::env::temp_dir().join("bs_solctra_coil_format_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("text")).unwrap();
+
+        let binary = dir.join("coils.bin");
+        write_binary_coil_file(&binary, &coils).unwrap();
+        let mut multi = format!("{}\n", coils.len());
+        for (index, coil) in coils.iter().enumerate() {
+            multi.push_str(&format!("{}\n", coil.len()));
+            let mut text = String::new();
+            for point in coil {
+                multi.push_str(&format!("{} {} {}\n", point.x, point.y, point.z));
+                text.push_str(&format!("{}\t{}\t{}\n", point.x, point.y, point.z));
+            }
+            fs::write(dir.join("text").join(format!("coil{:02}.txt", index)), text).unwrap();
+        }
+        let multi_path = dir.join("coils.txt");
+        fs::write(&multi_path, multi).unwrap();
+
+        let read_binary = read_coils(&binary, CoilFormat::Auto).unwrap();
+        let read_multi = read_coils(&multi_path, CoilFormat::Auto).unwrap();
+        let read_text = read_coils(&dir.join("text"), CoilFormat::Auto).unwrap();
+        let explicit_text = read_coils(&dir.join("text"), CoilFormat::Text).unwrap();
+        let wrong = read_coils(&multi_path, CoilFormat::Binary);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(read_binary, coils);
+        assert_eq!(read_multi, coils);
+        assert_eq!(read_text, coils);
+        assert_eq!(explicit_text, coils);
+        assert!(wrong.is_err());
+    }
+
+    #[test]
+    fn huge_counts_in_multi_coil_files_fail_without_allocating() {
+        let path = std::env::temp_dir().join("bs_solctra_huge_counts.txt");
+        fs::write(&path, format!("{}\n{}\n1 0 0\n", usize::MAX, usize::MAX)).unwrap();
+        let result = read_coils(&path, CoilFormat::Multi);
+        fs::remove_file(&path).unwrap();
+        assert!(result.unwrap_err().to_string().contains("ends early"));
+    }
+
+    #[test]
+    fn currents_name_coils_by_file_or_index() {
+        let dir = std::env::temp_dir().join("bs_solctra_currents_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let coil_files = [dir.join("coil_a.txt"), dir.join("coil_b.txt")];
+        let path = dir.join("currents.txt");
+        fs::write(&path, "# coil current\ncoil_b.txt -2.5e3\n\n2, 100.0\n").unwrap();
+        let currents = read_currents(&path, &coil_files, 3, 1.0);
+        fs::write(&path, "coil_a.txt 1\n0 2\n").unwrap();
+        let twice = read_currents(&path, &coil_files, 3, 1.0);
+        fs::write(&path, "coil_c.txt 1\n").unwrap();
+        let unknown = read_currents(&path, &coil_files, 3, 1.0);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(currents.unwrap(), vec![1.0, -2.5e3, 100.0]);
+        assert!(twice.is_err());
+        assert!(unknown.is_err());
+    }
+}
@@ -0,0 +1,229 @@
+use crate::point::Point;
+
+/// Closed curve through the points of a coil, a periodic cubic spline in
+/// each coordinate over the cumulative chord length. The spline has
+/// continuous curvature all the way around, across the first point too.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeriodicSpline {
+    /// Chord length at each knot, from 0 at the first to the length of
+    /// the closed polygon at the last, which is the first again
+    knots: Vec<f64>,
+    points: Vec<Point>,
+    /// Second derivatives at the knots, periodic like the points
+    curvatures: Vec<Point>,
+}
+
+impl PeriodicSpline {
+    /// Spline through the points of a closed coil, whose last point repeats
+    /// its first. Fails for open coils, for fewer than three distinct
+    /// points and for consecutive points that coincide.
+    pub fn new(coil: &[Point]) -> Result<Self, String> {
+        let (Some(first), Some(last)) = (coil.first(), coil.last()) else {
+            return Err("a coil without points".to_string());
+        };
+        if first.get_distance(last) > CLOSED_TOLERANCE {
+            return Err(format!(
+                "only closed coils can be resampled, this one ends {} m from its start",
+                first.get_distance(last)
+            ));
+        }
+        let points = coil.to_vec();
+        let n = points.len() - 1;
+        if n < 3 {
+            return Err(format!(
+                "a closed coil needs at least 3 distinct points, not {}",
+                n
+            ));
+        }
+        let mut knots = vec![0.0];
+        for pair in points.windows(2) {
+            let chord = pair[0].get_distance(&pair[1]);
+            if chord == 0.0 {
+                return Err("a coil repeats a point".to_string());
+            }
+            knots.push(knots[knots.len() - 1] + chord);
+        }
+        // Continuity of the first derivative at every knot rows up into a
+        // cyclic tridiagonal system for the second derivatives
+        let h = |i: usize| knots[i + 1] - knots[i];
+        let slope = |i: usize, coordinate: fn(&Point) -> f64| {
+            (coordinate(&points[i + 1]) - coordinate(&points[i])) / h(i)
+        };
+        let below: Vec<f64> = (0..n).map(|i| h((i + n - 1) % n)).collect();
+        let diagonal: Vec<f64> = (0..n).map(|i| 2.0 * (h((i + n - 1) % n) + h(i))).collect();
+        let above: Vec<f64> = (0..n).map(h).collect();
+        let solve = |coordinate: fn(&Point) -> f64| {
+            let rhs: Vec<f64> = (0..n)
+                .map(|i| 6.0 * (slope(i, coordinate) - slope((i + n - 1) % n, coordinate)))
+                .collect();
+            solve_cyclic_tridiagonal(&below, &diagonal, &above, &rhs)
+        };
+        let (x, y, z) = (solve(|p| p.x), solve(|p| p.y), solve(|p| p.z));
+        let mut curvatures: Vec<Point> = (0..n)
+            .map(|i| Point {
+                x: x[i],
+                y: y[i],
+                z: z[i],
+            })
+            .collect();
+        curvatures.push(curvatures[0]);
+        Ok(PeriodicSpline {
+            knots,
+            points,
+            curvatures,
+        })
+    }
+
+    /// Length of the closed polygon through the points, the parameter range
+    pub fn chord_length(&self) -> f64 {
+        self.knots[self.knots.len() - 1]
+    }
+
+    /// Point at chord length `t`, wrapped into the parameter range
+    pub fn at(&self, t: f64) -> Point {
+        let t = t.rem_euclid(self.chord_length());
+        let i = self
+            .knots
+            .partition_point(|&knot| knot <= t)
+            .clamp(1, self.knots.len() - 1)
+            - 1;
+        let h = self.knots[i + 1] - self.knots[i];
+        let (before, after) = (self.knots[i + 1] - t, t - self.knots[i]);
+        let value = |p0: f64, p1: f64, m0: f64, m1: f64| {
+            m0 * before.powi(3) / (6.0 * h)
+                + m1 * after.powi(3) / (6.0 * h)
+                + (p0 / h - m0 * h / 6.0) * before
+                + (p1 / h - m1 * h / 6.0) * after
+        };
+        let (p0, p1) = (&self.points[i], &self.points[i + 1]);
+        let (m0, m1) = (&self.curvatures[i], &self.curvatures[i + 1]);
+        Point {
+            x: value(p0.x, p1.x, m0.x, m1.x),
+            y: value(p0.y, p1.y, m0.y, m1.y),
+            z: value(p0.z, p1.z, m0.z, m1.z),
+        }
+    }
+}
+
+/// Distance in meters between the first and last point of a coil below
+/// which it counts as closed
+const CLOSED_TOLERANCE: f64 = 1e-9;
+
+/// Solves `below[i] x[i - 1] + diagonal[i] x[i] + above[i] x[i + 1] = rhs[i]`
+/// with indices wrapping around, by the Thomas algorithm and the
+/// Sherman–Morrison formula for the corners. Needs at least 3 rows and a
+/// diagonally dominant matrix, as that of a spline is.
+fn solve_cyclic_tridiagonal(
+    below: &[f64],
+    diagonal: &[f64],
+    above: &[f64],
+    rhs: &[f64],
+) -> Vec<f64> {
+    let n = diagonal.len();
+    let gamma = -diagonal[0];
+    let mut modified = diagonal.to_vec();
+    modified[0] -= gamma;
+    modified[n - 1] -= below[0] * above[n - 1] / gamma;
+    let x = solve_tridiagonal(below, &modified, above, rhs);
+    let mut u = vec![0.0; n];
+    u[0] = gamma;
+    u[n - 1] = above[n - 1];
+    let z = solve_tridiagonal(below, &modified, above, &u);
+    let factor = (x[0] + below[0] * x[n - 1] / gamma) / (1.0 + z[0] + below[0] * z[n - 1] / gamma);
+    x.iter().zip(&z).map(|(x, z)| x - factor * z).collect()
+}
+
+/// Thomas algorithm, ignoring `below[0]` and `above[n - 1]`
+fn solve_tridiagonal(below: &[f64], diagonal: &[f64], above: &[f64], rhs: &[f64]) -> Vec<f64> {
+    let n = diagonal.len();
+    let mut upper = vec![0.0; n];
+    let mut solution = vec![0.0; n];
+    upper[0] = above[0] / diagonal[0];
+    solution[0] = rhs[0] / diagonal[0];
+    for i in 1..n {
+        let pivot = diagonal[i] - below[i] * upper[i - 1];
+        upper[i] = above[i] / pivot;
+        solution[i] = (rhs[i] - below[i] * solution[i - 1]) / pivot;
+    }
+    for i in (0..n - 1).rev() {
+        solution[i] -= upper[i] * solution[i + 1];
+    }
+    solution
+}
+
+/// Closed coil of `segments` segments of equal chord length along the
+/// spline through `coil`, starting at its first point and ending back at it
+pub fn resample_coil(coil: &[Point], segments: usize) -> Result<Vec<Point>, String> {
+    let spline = PeriodicSpline::new(coil)?;
+    let step = spline.chord_length() / segments as f64;
+    let mut resampled: Vec<Point> = (0..segments)
+        .map(|segment| spline.at(segment as f64 * step))
+        .collect();
+    resampled.push(resampled[0]);
+    Ok(resampled)
+}
+
+/// Resamples every coil to `segments` segments, see `resample_coil`
+pub fn resample_coils(coils: &mut [Vec<Point>], segments: usize) -> Result<(), String> {
+    if segments < 3 {
+        return Err(format!("coils need at least 3 segments, not {}", segments));
+    }
+    for (index, coil) in coils.iter_mut().enumerate() {
+        *coil = resample_coil(coil, segments).map_err(|err| format!("coil {}: {}", index, err))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    fn circle(points: usize, radius: f64) -> Vec<Point> {
+        (0..=points)
+            .map(|index| {
+                let angle = 2.0 * PI * (index % points) as f64 / points as f64;
+                Point {
+                    x: radius * angle.cos(),
+                    y: radius * angle.sin(),
+                    z: 0.1,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn resampled_circles_stay_on_the_circle() {
+        let coarse = circle(24, 0.5);
+        let fine = resample_coil(&coarse, 240).unwrap();
+        assert_eq!(fine.len(), 241);
+        assert!(fine[0].get_distance(&coarse[0]) < 1e-15);
+        assert_eq!(fine[240], fine[0]);
+        let radial_error = fine
+            .iter()
+            .map(|point| (point.x.hypot(point.y) - 0.5).abs())
+            .fold(0.0, f64::max);
+        // The chords of the coarse polygon sag by 4e-3 m, the spline far less
+        assert!(radial_error < 1e-5, "{}", radial_error);
+        assert!(fine.iter().all(|point| (point.z - 0.1).abs() < 1e-15));
+        let chords: Vec<f64> = fine
+            .windows(2)
+            .map(|pair| pair[0].get_distance(&pair[1]))
+            .collect();
+        let (shortest, longest) = chords.iter().fold((f64::MAX, 0.0f64), |(min, max), &c| {
+            (min.min(c), max.max(c))
+        });
+        assert!(longest - shortest < 1e-3 * longest);
+    }
+
+    #[test]
+    fn only_closed_coils_resample() {
+        let mut open = circle(24, 0.5);
+        open.pop();
+        assert!(resample_coil(&open, 100).is_err());
+        let mut coils = vec![circle(24, 0.5), open];
+        let err = resample_coils(&mut coils, 100).unwrap_err();
+        assert!(err.starts_with("coil 1"), "{}", err);
+        assert!(resample_coils(&mut coils[..1], 2).is_err());
+    }
+}
@@ -0,0 +1,281 @@
+use crate::{constants::PI, point::Point, seeding::SplitMix64};
+use std::{error::Error, fs::File, io::BufReader, path::Path};
+
+/// Change of the coil geometry for design studies, applied to the coils
+/// listed in `coils` by index, or to every coil when the list is empty.
+/// Centers default to the centroid of each coil.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum CoilTransform {
+    /// Rotation by `angle` radians about `axis` through `center`, right-handed
+    Rotate {
+        #[serde(default)]
+        coils: Vec<usize>,
+        axis: [f64; 3],
+        angle: f64,
+        #[serde(default)]
+        center: Option<[f64; 3]>,
+    },
+    /// Shift by `offset` metres
+    Translate {
+        #[serde(default)]
+        coils: Vec<usize>,
+        offset: [f64; 3],
+    },
+    /// Scaling by `factor` about `center`
+    Scale {
+        #[serde(default)]
+        coils: Vec<usize>,
+        factor: f64,
+        #[serde(default)]
+        center: Option<[f64; 3]>,
+    },
+    /// Random rigid misalignment of each coil: a shift with normally
+    /// distributed components of standard deviation `shift` metres and a
+    /// rotation about its centroid around a random axis, by a normally
+    /// distributed angle of standard deviation `tilt` radians. The same
+    /// `seed` always draws the same misalignments.
+    Perturb {
+        #[serde(default)]
+        coils: Vec<usize>,
+        #[serde(default)]
+        shift: f64,
+        #[serde(default)]
+        tilt: f64,
+        seed: u64,
+    },
+}
+
+/// Reads a JSON array of transforms
+pub fn read_coil_transforms(path: &Path) -> Result<Vec<CoilTransform>, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    let transforms = serde_json::from_reader(reader)
+        .map_err(|err| format!("parsing {}: {}", path.display(), err))?;
+    Ok(transforms)
+}
+
+/// Applies `transforms` in order to the points of `coils`. Fails if one
+/// lists a coil that does not exist, leaving the coils unchanged.
+pub fn apply_coil_transforms(
+    coils: &mut [Vec<Point>],
+    transforms: &[CoilTransform],
+) -> Result<(), Box<dyn Error>> {
+    for transform in transforms {
+        let indices = transform.coils();
+        if let Some(index) = indices.iter().find(|&&index| index >= coils.len()) {
+            return Err(format!(
+                "coil transform of coil {}, there are {} coils",
+                index,
+                coils.len()
+            )
+            .into());
+        }
+    }
+    for transform in transforms {
+        let indices: Vec<usize> = match transform.coils() {
+            [] => (0..coils.len()).collect(),
+            indices => indices.to_vec(),
+        };
+        match transform {
+            CoilTransform::Rotate {
+                axis,
+                angle,
+                center,
+                ..
+            } => {
+                let axis = to_point(*axis).get_unit_vector();
+                for &index in &indices {
+                    let center = center.map_or_else(|| centroid(&coils[index]), to_point);
+                    rotate(&mut coils[index], &axis, *angle, &center);
+                }
+            }
+            CoilTransform::Translate { offset, .. } => {
+                for &index in &indices {
+                    translate(&mut coils[index], &to_point(*offset));
+                }
+            }
+            CoilTransform::Scale { factor, center, .. } => {
+                for &index in &indices {
+                    let center = center.map_or_else(|| centroid(&coils[index]), to_point);
+                    for point in coils[index].iter_mut() {
+                        *point = Point {
+                            x: center.x + factor * (point.x - center.x),
+                            y: center.y + factor * (point.y - center.y),
+                            z: center.z + factor * (point.z - center.z),
+                        };
+                    }
+                }
+            }
+            CoilTransform::Perturb {
+                shift, tilt, seed, ..
+            } => {
+                let mut rng = SplitMix64(*seed);
+                for &index in &indices {
+                    let offset = Point {
+                        x: shift * normal(&mut rng),
+                        y: shift * normal(&mut rng),
+                        z: shift * normal(&mut rng),
+                    };
+                    // Uniform on the sphere
+                    let cos_theta = 2.0 * rng.next_f64() - 1.0;
+                    let phi = 2.0 * PI * rng.next_f64();
+                    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                    let axis = Point {
+                        x: sin_theta * phi.cos(),
+                        y: sin_theta * phi.sin(),
+                        z: cos_theta,
+                    };
+                    let angle = tilt * normal(&mut rng);
+                    let center = centroid(&coils[index]);
+                    rotate(&mut coils[index], &axis, angle, &center);
+                    translate(&mut coils[index], &offset);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+impl CoilTransform {
+    fn coils(&self) -> &[usize] {
+        match self {
+            CoilTransform::Rotate { coils, .. }
+            | CoilTransform::Translate { coils, .. }
+            | CoilTransform::Scale { coils, .. }
+            | CoilTransform::Perturb { coils, .. } => coils,
+        }
+    }
+}
+
+fn to_point([x, y, z]: [f64; 3]) -> Point {
+    Point { x, y, z }
+}
+
+fn centroid(coil: &[Point]) -> Point {
+    let count = coil.len().max(1) as f64;
+    let sum = coil.iter().fold(Point::default(), |sum, point| Point {
+        x: sum.x + point.x,
+        y: sum.y + point.y,
+        z: sum.z + point.z,
+    });
+    Point {
+        x: sum.x / count,
+        y: sum.y / count,
+        z: sum.z / count,
+    }
+}
+
+fn translate(coil: &mut [Point], offset: &Point) {
+    for point in coil {
+        point.x += offset.x;
+        point.y += offset.y;
+        point.z += offset.z;
+    }
+}
+
+/// Rodrigues' rotation about the unit vector `axis` through `center`
+fn rotate(coil: &mut [Point], axis: &Point, angle: f64, center: &Point) {
+    let (sin, cos) = angle.sin_cos();
+    for point in coil {
+        let v = point.get_displacement(center);
+        let cross = axis.cross(&v);
+        let along = axis.dot(&v) * (1.0 - cos);
+        *point = Point {
+            x: center.x + v.x * cos + cross.x * sin + axis.x * along,
+            y: center.y + v.y * cos + cross.y * sin + axis.y * along,
+            z: center.z + v.z * cos + cross.z * sin + axis.z * along,
+        };
+    }
+}
+
+/// Standard normal sample by the Box–Muller transform
+fn normal(rng: &mut SplitMix64) -> f64 {
+    // 1 - u is in (0, 1], away from the pole of the logarithm
+    let u = 1.0 - rng.next_f64();
+    let v = rng.next_f64();
+    (-2.0 * u.ln()).sqrt() * (2.0 * PI * v).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(z: f64) -> Vec<Point> {
+        [(1.0, 0.0), (0.0, 1.0), (-1.0, 0.0), (0.0, -1.0)]
+            .iter()
+            .map(|&(x, y)| Point { x, y, z })
+            .collect()
+    }
+
+    fn close(a: &Point, b: &Point) -> bool {
+        a.get_distance(b) < 1e-12
+    }
+
+    #[test]
+    fn transforms_apply_in_order_to_their_coils() {
+        let mut coils = vec![square(0.0), square(1.0)];
+        let transforms: Vec<CoilTransform> = serde_json::from_str(
+            r#"[
+                {"kind": "rotate", "axis": [0, 0, 1], "angle": 1.5707963267948966, "center": [0, 0, 0]},
+                {"kind": "translate", "coils": [1], "offset": [0.5, 0, 0]},
+                {"kind": "scale", "coils": [1], "factor": 2}
+            ]"#,
+        )
+        .unwrap();
+        apply_coil_transforms(&mut coils, &transforms).unwrap();
+        // A quarter turn maps the square onto itself, shifted by one point
+        assert!(close(&coils[0][0], &square(0.0)[1]));
+        assert!(close(
+            &coils[1][0],
+            &Point {
+                x: 0.5,
+                y: 2.0,
+                z: 1.0
+            }
+        ));
+        assert!(close(
+            &centroid(&coils[1]),
+            &Point {
+                x: 0.5,
+                y: 0.0,
+                z: 1.0
+            }
+        ));
+
+        let unknown = [CoilTransform::Translate {
+            coils: vec![2],
+            offset: [1.0, 0.0, 0.0],
+        }];
+        let before = coils.clone();
+        assert!(apply_coil_transforms(&mut coils, &unknown).is_err());
+        assert_eq!(coils, before);
+    }
+
+    #[test]
+    fn perturbations_are_rigid_and_seeded() {
+        let perturb = |seed| {
+            let mut coils = vec![square(0.0), square(0.5)];
+            let transforms = [CoilTransform::Perturb {
+                coils: Vec::new(),
+                shift: 1e-3,
+                tilt: 1e-2,
+                seed,
+            }];
+            apply_coil_transforms(&mut coils, &transforms).unwrap();
+            coils
+        };
+        let perturbed = perturb(3);
+        assert_eq!(perturbed, perturb(3));
+        assert_ne!(perturbed, perturb(4));
+        for (coil, original) in perturbed.iter().zip([square(0.0), square(0.5)]) {
+            assert_ne!(*coil, original);
+            for i in 0..coil.len() {
+                for j in 0..i {
+                    let distance = coil[i].get_distance(&coil[j]);
+                    assert!((distance - original[i].get_distance(&original[j])).abs() < 1e-12);
+                }
+            }
+            assert!(centroid(coil).get_distance(&centroid(&original)) < 1e-2);
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use crate::{
+    constants::{PI, PhysicsParams},
+    point::Point,
+    simulation::{CoilSet, compute_displacements, compute_magnetic_field},
+};
+use std::fmt;
+
+/// Samples of the magnetic axis used to estimate enclosed currents
+const AXIS_SAMPLES: usize = 720;
+
+/// Geometric and electrical summary of a single coil
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoilStats {
+    pub num_points: usize,
+    pub length: f64,
+    pub min: Point,
+    pub max: Point,
+    pub min_segment: f64,
+    pub max_segment: f64,
+    /// Current linked by the coil, estimated with Ampère's law as the line
+    /// integral of its field along the magnetic axis divided by µ0
+    pub enclosed_current: f64,
+}
+
+impl fmt::Display for CoilStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} points, length {:.6}, bounds {} to {}, segments {:.3e} to {:.3e}, enclosed current {:.1}",
+            self.num_points,
+            self.length,
+            self.min,
+            self.max,
+            self.min_segment,
+            self.max_segment,
+            self.enclosed_current
+        )
+    }
+}
+
+pub fn coil_stats(coil: &[Point], physics: &PhysicsParams) -> CoilStats {
+    let displacements = compute_displacements(coil);
+    let segments: Vec<f64> = displacements.iter().map(|d| d.get_norm()).collect();
+    let first = coil.first().copied().unwrap_or_default();
+    let (min, max) = coil.iter().fold((first, first), |(min, max), point| {
+        (
+            Point {
+                x: min.x.min(point.x),
+                y: min.y.min(point.y),
+                z: min.z.min(point.z),
+            },
+            Point {
+                x: max.x.max(point.x),
+                y: max.y.max(point.y),
+                z: max.z.max(point.z),
+            },
+        )
+    });
+    CoilStats {
+        num_points: coil.len(),
+        length: segments.iter().sum(),
+        min,
+        max,
+        min_segment: segments.iter().copied().fold(f64::INFINITY, f64::min),
+        max_segment: segments.iter().copied().fold(0.0, f64::max),
+        enclosed_current: enclosed_current(&CoilSet::new(&[coil.to_vec()]).with_physics(*physics)),
+    }
+}
+
+/// Stats of every coil, carrying its entry of `currents` or, past their
+/// end, the current of `physics`
+pub fn coil_set_stats(
+    coils: &[Vec<Point>],
+    physics: &PhysicsParams,
+    currents: &[f64],
+) -> Vec<CoilStats> {
+    coils
+        .iter()
+        .enumerate()
+        .map(|(index, coil)| {
+            let current = currents.get(index).copied().unwrap_or(physics.current);
+            coil_stats(
+                coil,
+                &PhysicsParams {
+                    current,
+                    ..*physics
+                },
+            )
+        })
+        .collect()
+}
+
+fn enclosed_current(coils: &CoilSet) -> f64 {
+    let major_radius = coils.physics.major_radius;
+    let d_phi = 2.0 * PI / AXIS_SAMPLES as f64;
+    let circulation: f64 = (0..AXIS_SAMPLES)
+        .map(|sample| {
+            let phi = (sample as f64 + 0.5) * d_phi;
+            let position = Point {
+                x: major_radius * phi.cos(),
+                y: major_radius * phi.sin(),
+                z: 0.0,
+            };
+            let tangent = Point {
+                x: -major_radius * phi.sin() * d_phi,
+                y: major_radius * phi.cos() * d_phi,
+                z: 0.0,
+            };
+            compute_magnetic_field(&position, coils).dot(&tangent)
+        })
+        .sum();
+    circulation / coils.physics.miu
+}
+
+/// Distance in meters a coil point of the next field period may be from
+/// the rotated point of the previous one
+pub const PERIOD_TOLERANCE: f64 = 1e-6;
+
+/// Coils of the first of `periods` field periods, out of all coils ordered
+/// by period, checked to give those of every next period when rotated about
+/// the z axis by 2π / `periods`. Currents, if any, must repeat as well.
+pub fn fold_field_periods(
+    coils: &[Vec<Point>],
+    currents: &[f64],
+    periods: usize,
+) -> Result<(Vec<Vec<Point>>, Vec<f64>), String> {
+    if periods == 0 || !coils.len().is_multiple_of(periods) {
+        return Err(format!(
+            "{} coils do not split into {} field periods",
+            coils.len(),
+            periods
+        ));
+    }
+    let per_period = coils.len() / periods;
+    let (sin, cos) = (2.0 * PI / periods as f64).sin_cos();
+    for (index, (coil, next)) in coils.iter().zip(&coils[per_period..]).enumerate() {
+        let rotated = coil.iter().map(|point| Point {
+            x: cos * point.x - sin * point.y,
+            y: sin * point.x + cos * point.y,
+            z: point.z,
+        });
+        let matches = coil.len() == next.len()
+            && rotated
+                .zip(next)
+                .all(|(point, next)| point.get_distance(next) <= PERIOD_TOLERANCE);
+        if !matches {
+            return Err(format!(
+                "coil {} is not coil {} rotated by one of {} field periods",
+                index + per_period,
+                index,
+                periods
+            ));
+        }
+    }
+    if let Some(index) =
+        (per_period..currents.len()).find(|&i| currents[i] != currents[i - per_period])
+    {
+        return Err(format!(
+            "coil {} carries another current than coil {} of the previous field period",
+            index,
+            index - per_period
+        ));
+    }
+    Ok((
+        coils[..per_period].to_vec(),
+        currents.iter().take(per_period).copied().collect(),
+    ))
+}
+
+/// Coils whose currents are varied together, with their precomputed geometry
+pub struct CoilGroup {
+    pub coil_indices: Vec<usize>,
+    pub coils: CoilSet,
+}
+
+impl CoilGroup {
+    /// Derivative of the field at `point` with respect to the current of the
+    /// group, the field is linear in it so this is the group's field per ampere
+    pub fn field_sensitivity(&self, point: &Point) -> Point {
+        let b = compute_magnetic_field(point, &self.coils);
+        let current = self.coils.physics.current;
+        Point {
+            x: b.x / current,
+            y: b.y / current,
+            z: b.z / current,
+        }
+    }
+}
+
+/// Splits a coil set into groups of the given coil indices, every coil is
+/// its own group when `groups` is empty
+pub fn coil_groups(coils: &CoilSet, groups: &[Vec<usize>]) -> Result<Vec<CoilGroup>, String> {
+    let singletons: Vec<Vec<usize>>;
+    let groups = if groups.is_empty() {
+        singletons = (0..coils.len()).map(|index| vec![index]).collect();
+        &singletons
+    } else {
+        groups
+    };
+    groups
+        .iter()
+        .map(|indices| {
+            if let Some(index) = indices.iter().find(|&&index| index >= coils.len()) {
+                return Err(format!(
+                    "coil {} does not exist, there are {} coils",
+                    index,
+                    coils.len()
+                ));
+            }
+            Ok(CoilGroup {
+                coil_indices: indices.clone(),
+                coils: coils.select(indices),
+            })
+        })
+        .collect()
+}
+
+/// Coil set with the currents of the coils in `indices` scaled by `factor`.
+/// Each segment contributes to the field in proportion to its unit vector,
+/// so this reuses the rest of the precomputed geometry.
+pub fn scale_currents(coils: &CoilSet, indices: &[usize], factor: f64) -> CoilSet {
+    let mut scaled = coils.clone();
+    for &index in indices {
+        for j in coils.offsets[index]..coils.offsets[index + 1] {
+            scaled.e_x[j] *= factor;
+            scaled.e_y[j] *= factor;
+            scaled.e_z[j] *= factor;
+        }
+    }
+    scaled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{I, MAJOR_RADIUS};
+
+    #[test]
+    fn circular_coil_around_axis_links_configured_current() {
+        let samples = 256;
+        let coil: Vec<Point> = (0..=samples)
+            .map(|sample| {
+                let theta = 2.0 * PI * sample as f64 / samples as f64;
+                Point {
+                    x: MAJOR_RADIUS + 0.15 * theta.cos(),
+                    y: 0.0,
+                    z: 0.15 * theta.sin(),
+                }
+            })
+            .collect();
+        let stats = coil_stats(&coil, &PhysicsParams::default());
+        assert_eq!(stats.num_points, samples + 1);
+        assert!((stats.length - 2.0 * PI * 0.15).abs() < 1e-3);
+        assert!((stats.enclosed_current.abs() - I.abs()).abs() < 0.02 * I.abs());
+    }
+
+    #[test]
+    fn group_sensitivities_sum_to_field_per_ampere() {
+        let coil = |offset: f64| -> Vec<Point> {
+            (0..=64)
+                .map(|sample| {
+                    let theta = 2.0 * PI * sample as f64 / 64.0;
+                    Point {
+                        x: MAJOR_RADIUS + 0.15 * theta.cos(),
+                        y: offset,
+                        z: 0.15 * theta.sin(),
+                    }
+                })
+                .collect()
+        };
+        let coils = CoilSet::new(&[coil(-0.05), coil(0.0), coil(0.05)]);
+        let groups = coil_groups(&coils, &[vec![0, 2], vec![1]]).unwrap();
+        let point = Point {
+            x: MAJOR_RADIUS,
+            y: 0.01,
+            z: 0.02,
+        };
+        let b = compute_magnetic_field(&point, &coils);
+        let total = groups
+            .iter()
+            .map(|group| group.field_sensitivity(&point))
+            .fold(Point::default(), |sum, db| Point {
+                x: sum.x + db.x,
+                y: sum.y + db.y,
+                z: sum.z + db.z,
+            });
+        assert!((total.x * I - b.x).abs() < 1e-12);
+        assert!((total.y * I - b.y).abs() < 1e-12);
+        assert!(coil_groups(&coils, &[vec![3]]).is_err());
+    }
+
+    #[test]
+    fn folded_field_periods_give_the_field_of_every_coil() {
+        let coils = crate::simulation::read_coil_data_directory(std::path::Path::new(
+            "tests/test-resources/resources",
+        ))
+        .unwrap();
+        let (period, currents) = fold_field_periods(&coils, &[], 2).unwrap();
+        assert_eq!(period.len(), coils.len() / 2);
+        assert!(currents.is_empty());
+        let full = CoilSet::new(&coils);
+        let folded = CoilSet::new(&period).with_field_periods(2);
+        let point = Point {
+            x: 0.21,
+            y: -0.05,
+            z: 0.02,
+        };
+        let b = compute_magnetic_field(&point, &full);
+        assert!(compute_magnetic_field(&point, &folded).get_distance(&b) < 1e-9 * b.get_norm());
+
+        assert!(fold_field_periods(&coils, &[], 3).is_err());
+        assert!(fold_field_periods(&coils, &[], 5).is_err());
+        let mut currents = vec![I; coils.len()];
+        assert!(fold_field_periods(&coils, &currents, 2).is_ok());
+        currents[7] = -I;
+        assert!(fold_field_periods(&coils, &currents, 2).is_err());
+    }
+}
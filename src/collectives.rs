@@ -0,0 +1,240 @@
+#[cfg(feature = "mpi")]
+use crate::partition::{particle_offsets, to_mpi_counts};
+use crate::point::Point;
+#[cfg(feature = "mpi")]
+use mpi::{
+    Count,
+    collective::SystemOperation,
+    datatype::{Equivalence, Partition, PartitionMut},
+    topology::SimpleCommunicator,
+    traits::{Communicator, CommunicatorCollectives, Root},
+};
+use std::error::Error;
+
+/// Collective operations needed inside the step loop, so the simulation can
+/// run over MPI or in a single process
+pub trait Collectives {
+    /// Index of this rank among all ranks
+    fn local_rank(&self) -> usize;
+
+    /// Number of ranks
+    fn ranks(&self) -> usize;
+
+    /// True on every rank if `local` is true on any rank
+    fn any(&self, local: bool) -> bool;
+
+    /// The points of every rank in rank order on rank 0, `None` on the
+    /// others. Fails on every rank when they are more than an MPI count.
+    fn gather_points(&self, local: &[Point]) -> Result<Option<Vec<Point>>, Box<dyn Error>>;
+
+    /// The values of every rank in rank order on rank 0, `None` on the
+    /// others. Fails on every rank when they are more than an MPI count.
+    fn gather_values(&self, local: &[f64]) -> Result<Option<Vec<f64>>, Box<dyn Error>>;
+
+    /// `local` of every rank, in rank order
+    fn all_gather_count(&self, local: usize) -> Vec<usize>;
+
+    /// Sum of `local` over every rank on rank 0, `None` on the others
+    fn sum_count(&self, local: usize) -> Option<usize>;
+
+    /// Sum of `local` over the ranks before this one, 0 on rank 0
+    fn exclusive_sum_count(&self, local: usize) -> usize;
+
+    /// Elementwise sum of `local` over every rank on rank 0, `None` on the
+    /// others. Every rank passes as many values.
+    fn sum_values(&self, local: &[f64]) -> Option<Vec<f64>>;
+
+    /// Elementwise maximum of `local` over every rank on rank 0, `None` on
+    /// the others. Every rank passes as many values.
+    fn max_values(&self, local: &[f64]) -> Option<Vec<f64>>;
+
+    /// Elementwise minimum of `local` over every rank on rank 0, `None` on
+    /// the others. Every rank passes as many values.
+    fn min_values(&self, local: &[f64]) -> Option<Vec<f64>>;
+
+    /// Sends `send_counts[r]` consecutive values of `send` to every rank `r`
+    /// and returns the values received, in rank order, with their counts
+    fn exchange(&self, send: &[f64], send_counts: &[usize]) -> (Vec<f64>, Vec<usize>);
+}
+
+/// Collectives of a run without other ranks
+pub struct SingleProcess;
+
+impl Collectives for SingleProcess {
+    fn local_rank(&self) -> usize {
+        0
+    }
+
+    fn ranks(&self) -> usize {
+        1
+    }
+
+    fn any(&self, local: bool) -> bool {
+        local
+    }
+
+    fn gather_points(&self, local: &[Point]) -> Result<Option<Vec<Point>>, Box<dyn Error>> {
+        Ok(Some(local.to_vec()))
+    }
+
+    fn gather_values(&self, local: &[f64]) -> Result<Option<Vec<f64>>, Box<dyn Error>> {
+        Ok(Some(local.to_vec()))
+    }
+
+    fn all_gather_count(&self, local: usize) -> Vec<usize> {
+        vec![local]
+    }
+
+    fn sum_count(&self, local: usize) -> Option<usize> {
+        Some(local)
+    }
+
+    fn exclusive_sum_count(&self, _local: usize) -> usize {
+        0
+    }
+
+    fn sum_values(&self, local: &[f64]) -> Option<Vec<f64>> {
+        Some(local.to_vec())
+    }
+
+    fn max_values(&self, local: &[f64]) -> Option<Vec<f64>> {
+        Some(local.to_vec())
+    }
+
+    fn min_values(&self, local: &[f64]) -> Option<Vec<f64>> {
+        Some(local.to_vec())
+    }
+
+    fn exchange(&self, send: &[f64], send_counts: &[usize]) -> (Vec<f64>, Vec<usize>) {
+        (send.to_vec(), send_counts.to_vec())
+    }
+}
+
+#[cfg(feature = "mpi")]
+impl Collectives for SimpleCommunicator {
+    fn local_rank(&self) -> usize {
+        self.rank() as usize
+    }
+
+    fn ranks(&self) -> usize {
+        self.size() as usize
+    }
+
+    fn any(&self, local: bool) -> bool {
+        let mut global = false;
+        self.all_reduce_into(&local, &mut global, SystemOperation::logical_or());
+        global
+    }
+
+    fn gather_points(&self, local: &[Point]) -> Result<Option<Vec<Point>>, Box<dyn Error>> {
+        gather_varcount(self, local)
+    }
+
+    fn gather_values(&self, local: &[f64]) -> Result<Option<Vec<f64>>, Box<dyn Error>> {
+        gather_varcount(self, local)
+    }
+
+    fn all_gather_count(&self, local: usize) -> Vec<usize> {
+        let mut counts = vec![0u64; self.size() as usize];
+        self.all_gather_into(&(local as u64), &mut counts[..]);
+        counts.into_iter().map(|count| count as usize).collect()
+    }
+
+    fn sum_count(&self, local: usize) -> Option<usize> {
+        let root = self.process_at_rank(0);
+        if self.rank() != 0 {
+            root.reduce_into(&(local as u64), SystemOperation::sum());
+            return None;
+        }
+        let mut total = 0u64;
+        root.reduce_into_root(&(local as u64), &mut total, SystemOperation::sum());
+        Some(total as usize)
+    }
+
+    fn exclusive_sum_count(&self, local: usize) -> usize {
+        let mut before = 0u64;
+        self.exclusive_scan_into(&(local as u64), &mut before, SystemOperation::sum());
+        // MPI leaves the result of rank 0 undefined
+        if self.rank() == 0 { 0 } else { before as usize }
+    }
+
+    fn sum_values(&self, local: &[f64]) -> Option<Vec<f64>> {
+        reduce_values(self, local, SystemOperation::sum())
+    }
+
+    fn max_values(&self, local: &[f64]) -> Option<Vec<f64>> {
+        reduce_values(self, local, SystemOperation::max())
+    }
+
+    fn min_values(&self, local: &[f64]) -> Option<Vec<f64>> {
+        reduce_values(self, local, SystemOperation::min())
+    }
+
+    fn exchange(&self, send: &[f64], send_counts: &[usize]) -> (Vec<f64>, Vec<usize>) {
+        let send_displs = to_mpi_counts(&particle_offsets(send_counts));
+        let send_counts = to_mpi_counts(send_counts);
+        let mut receive_counts = vec![0 as Count; send_counts.len()];
+        self.all_to_all_into(&send_counts[..], &mut receive_counts[..]);
+        let receive_counts: Vec<usize> =
+            receive_counts.iter().map(|&count| count as usize).collect();
+        let mut received = vec![0.0; receive_counts.iter().sum()];
+        let partition = Partition::new(send, send_counts, send_displs);
+        let mut receive_partition = PartitionMut::new(
+            &mut received[..],
+            to_mpi_counts(&receive_counts),
+            to_mpi_counts(&particle_offsets(&receive_counts)),
+        );
+        self.all_to_all_varcount_into(&partition, &mut receive_partition);
+        (received, receive_counts)
+    }
+}
+
+/// Reduces `local` elementwise over every rank with `op` on rank 0
+#[cfg(feature = "mpi")]
+fn reduce_values(
+    comm: &SimpleCommunicator,
+    local: &[f64],
+    op: SystemOperation,
+) -> Option<Vec<f64>> {
+    let root = comm.process_at_rank(0);
+    if comm.rank() != 0 {
+        root.reduce_into(local, op);
+        return None;
+    }
+    let mut reduced = vec![0.0; local.len()];
+    root.reduce_into_root(local, &mut reduced[..], op);
+    Some(reduced)
+}
+
+/// Gathers `local` of every rank in rank order on rank 0. Every rank
+/// learns the counts, so that all of them fail alike when the total is
+/// beyond what the counts and displacements of MPI can address.
+#[cfg(feature = "mpi")]
+fn gather_varcount<T: Equivalence + Default + Clone>(
+    comm: &SimpleCommunicator,
+    local: &[T],
+) -> Result<Option<Vec<T>>, Box<dyn Error>> {
+    let counts = comm.all_gather_count(local.len());
+    let total: usize = counts.iter().sum();
+    if total > Count::MAX as usize {
+        return Err(format!(
+            "cannot gather {} values on rank 0, MPI counts end at {}",
+            total,
+            Count::MAX
+        )
+        .into());
+    }
+    let root = comm.process_at_rank(0);
+    if comm.rank() != 0 {
+        root.gather_varcount_into(local);
+        return Ok(None);
+    }
+    let mut values = vec![T::default(); total];
+    let mut partition = PartitionMut::new(
+        &mut values[..],
+        to_mpi_counts(&counts),
+        to_mpi_counts(&particle_offsets(&counts)),
+    );
+    root.gather_varcount_into_root(local, &mut partition);
+    Ok(Some(values))
+}
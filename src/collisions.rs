@@ -0,0 +1,138 @@
+use crate::{particle::perpendicular_to, point::Point, seeding::SplitMix64};
+
+/// Monte Carlo pitch-angle scattering of full orbit particles off a
+/// background plasma by the Lorentz collision operator, applied after every
+/// push. The energy of the particles is kept, only their pitch diffuses.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Collisions {
+    /// Pitch-angle scattering frequency ν in 1/s
+    pub frequency: f64,
+    /// Seed the random stream of every particle in every step is derived
+    /// from, together with the global index of the particle and the step
+    pub seed: u64,
+}
+
+impl Collisions {
+    /// Fails unless ν is finite and not negative and ν times the step of
+    /// `step_size` seconds is at most 1, beyond which the operator overshoots
+    pub fn check(&self, step_size: f64) -> Result<(), String> {
+        if !(self.frequency.is_finite() && self.frequency >= 0.0) {
+            return Err(format!("collision frequency {} 1/s", self.frequency));
+        }
+        if self.frequency * step_size > 1.0 {
+            return Err(format!(
+                "collision frequency {} 1/s is too high for steps of {} s, \
+                 their product must be at most 1",
+                self.frequency, step_size
+            ));
+        }
+        Ok(())
+    }
+
+    /// Velocity of particle `particle` after the collisions of `step`, which
+    /// lasted `dt` seconds, in the field `b`. The speed and the direction
+    /// across the field are kept while the pitch λ = v∥/v becomes
+    /// λ(1 − ν dt) ± √((1 − λ²) ν dt), with the sign drawn at random.
+    pub fn scatter(&self, velocity: &Point, b: &Point, dt: f64, particle: u64, step: u32) -> Point {
+        let speed = velocity.get_norm();
+        if speed == 0.0 {
+            return *velocity;
+        }
+        let along = b.get_unit_vector();
+        let pitch = (velocity.dot(&along) / speed).clamp(-1.0, 1.0);
+        let across = Point {
+            x: velocity.x - pitch * speed * along.x,
+            y: velocity.y - pitch * speed * along.y,
+            z: velocity.z - pitch * speed * along.z,
+        };
+        let across = if across.get_norm() > 1e-12 * speed {
+            across.get_unit_vector()
+        } else {
+            perpendicular_to(&along)
+        };
+        let nu_dt = self.frequency * dt;
+        let kick = ((1.0 - pitch * pitch) * nu_dt).sqrt();
+        let kick = if self.stream(particle, step).next_u64() & 1 == 0 {
+            kick
+        } else {
+            -kick
+        };
+        let scattered = (pitch * (1.0 - nu_dt) + kick).clamp(-1.0, 1.0);
+        let perpendicular = (1.0 - scattered * scattered).sqrt();
+        Point {
+            x: speed * (scattered * along.x + perpendicular * across.x),
+            y: speed * (scattered * along.y + perpendicular * across.y),
+            z: speed * (scattered * along.z + perpendicular * across.z),
+        }
+    }
+
+    /// Random stream of one particle in one step. Keyed by the global
+    /// particle index rather than drawn from one stream per rank, so the
+    /// draws do not depend on the number of ranks, the threads or on which
+    /// rank a rebalanced particle is integrated by.
+    fn stream(&self, particle: u64, step: u32) -> SplitMix64 {
+        let particle_key = SplitMix64(self.seed).next_u64() ^ particle;
+        let step_key = SplitMix64(particle_key).next_u64() ^ step as u64;
+        SplitMix64(step_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mean_pitch_decays_at_the_collision_frequency_and_speed_is_kept() {
+        let collisions = Collisions {
+            frequency: 1e3,
+            seed: 11,
+        };
+        let b = Point {
+            x: 0.1,
+            y: 0.0,
+            z: 0.3,
+        };
+        let along = b.get_unit_vector();
+        let across = perpendicular_to(&along);
+        let speed = 1e5;
+        let start = Point {
+            x: speed * (0.5 * along.x + 0.75f64.sqrt() * across.x),
+            y: speed * (0.5 * along.y + 0.75f64.sqrt() * across.y),
+            z: speed * (0.5 * along.z + 0.75f64.sqrt() * across.z),
+        };
+        let (dt, steps, particles) = (1e-5, 100, 2000);
+        let mut mean_pitch = 0.0;
+        for particle in 0..particles {
+            let mut velocity = start;
+            for step in 1..=steps {
+                velocity = collisions.scatter(&velocity, &b, dt, particle, step);
+            }
+            assert!((velocity.get_norm() - speed).abs() < 1e-9 * speed);
+            mean_pitch += velocity.dot(&along) / speed / particles as f64;
+        }
+        // <λ> = λ0 exp(-ν t), ν t = 1 here
+        assert!((mean_pitch - 0.5 * (-1.0f64).exp()).abs() < 0.05);
+
+        let again = collisions.scatter(&start, &b, dt, 7, 3);
+        assert_eq!(again, collisions.scatter(&start, &b, dt, 7, 3));
+        let draws: Vec<Point> = (0..16)
+            .map(|particle| collisions.scatter(&start, &b, dt, particle, 3))
+            .collect();
+        assert!(draws.iter().any(|draw| *draw != again));
+    }
+
+    #[test]
+    fn frequencies_must_resolve_the_step() {
+        let collisions = Collisions {
+            frequency: 1e6,
+            seed: 0,
+        };
+        assert!(collisions.check(1e-7).is_ok());
+        assert!(collisions.check(1e-5).is_err());
+        let negative = Collisions {
+            frequency: -1.0,
+            ..collisions
+        };
+        assert!(negative.check(1e-7).is_err());
+    }
+}
@@ -0,0 +1,1415 @@
+use crate::{
+    coils::{coil_groups, scale_currents},
+    config::RunConfig,
+    constants::PhysicsParams,
+    diagnostics::{
+        ConfinementSummary, IslandChain, LyapunovSettings, SurfaceGrid, confinement_summary,
+        detect_island_chain, lyapunov_exponent, read_punctures,
+    },
+    gltf::{LineSet, write_gltf_scene},
+    integrator::Tolerances,
+    manifest::Manifest,
+    output::{
+        ALL_RANKS, BinaryPrecision, HDF5_FILE, NETCDF_FILE, OutputFormat, PARQUET_FILE, TextFormat,
+        binary_snapshot_file_name, list_snapshots, merged_file_name, rank_label, read_snapshot,
+        read_trajectories, vtk_snapshot_file_name, write_binary_points, write_hdf5_trajectories,
+        write_netcdf_trajectories, write_parquet_trajectories, write_points, write_points_to_file,
+        write_snapshot_collection,
+    },
+    particle::read_run_losses,
+    partition,
+    point::{Point, read_from_file},
+    seeding::Seeding,
+    simulation::{
+        CoilSet, compute_field_jacobian, compute_magnetic_field, distance_to_axis,
+        read_coil_data_directory, simulate_step,
+    },
+    summary::{Histogram, RunSummary, SUMMARY_FILE, Statistic},
+    utils::format_size,
+    validation,
+    vtk::{DataSet, Scalars, write_pvd, write_vtp_points},
+};
+use clap::ValueEnum;
+use log::{error, info, warn};
+use rayon::prelude::*;
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+/// Prints the differences between two run configurations, returns whether a
+/// run with the `right` configuration can resume from `left`
+pub fn config_diff(left: &Path, right: &Path) -> Result<bool, Box<dyn Error>> {
+    let left_config = RunConfig::read(left)?;
+    let right_config = RunConfig::read(right)?;
+    let differences = left_config.diff(&right_config);
+    if differences.is_empty() {
+        println!("Configurations are identical");
+    }
+    for difference in &differences {
+        println!("{}", difference);
+    }
+    let compatible = left_config
+        .check_restart_compatibility(&right_config)
+        .is_ok();
+    println!(
+        "Restart compatible: {}",
+        if compatible { "yes" } else { "no" }
+    );
+    Ok(compatible)
+}
+
+/// Writes `count` starting particles of `seeding` into a particles file
+pub fn seed(
+    seeding: &Seeding,
+    count: usize,
+    physics: &PhysicsParams,
+    output_file: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let particles = seeding.generate(count, physics);
+    write_points(output_file, &particles, &TextFormat::default())?;
+    info!(
+        "Wrote {} {:?} seeded particles to {}",
+        particles.len(),
+        seeding.mode,
+        output_file.display()
+    );
+    Ok(())
+}
+
+/// Compares every reference snapshot with the snapshot of the run in
+/// `run_dir` at the same step, logging the error of each, and returns
+/// whether all of them match
+pub fn validate(
+    run_dir: &Path,
+    reference_dir: &Path,
+    tolerances: Tolerances,
+) -> Result<bool, Box<dyn Error>> {
+    let manifests = (
+        Manifest::read_if_present(run_dir)?,
+        Manifest::read_if_present(reference_dir)?,
+    );
+    if let (Some(run), Some(reference)) = manifests {
+        if run.version != reference.version {
+            info!(
+                "Validating version {} against a reference of version {}",
+                run.version, reference.version
+            );
+        }
+        for difference in run.trajectory_differences(&reference) {
+            warn!("The reference was computed differently: {}", difference);
+        }
+    }
+    let errors = validation::validate(run_dir, reference_dir, tolerances)?;
+    for error in &errors {
+        if error.passed() {
+            info!("Validation {}", error);
+        } else {
+            error!("Validation {}", error);
+        }
+    }
+    let diverged = errors.iter().filter(|error| !error.passed()).count();
+    if diverged == 0 {
+        info!(
+            "Validation passed: {} steps match {:?}",
+            errors.len(),
+            reference_dir
+        );
+    } else {
+        error!(
+            "Validation failed: {} of {} steps diverge from {:?}",
+            diverged,
+            errors.len(),
+            reference_dir
+        );
+    }
+    Ok(diverged == 0)
+}
+
+/// Combines the per-rank snapshots of a run into one file per step, with
+/// particles in the order of the original particle file
+pub fn merge(
+    run_dir: &Path,
+    output_dir: Option<&Path>,
+    hdf5_file: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let config = RunConfig::read(run_dir)?;
+    let format = config.text_format();
+    let output_dir = output_dir.unwrap_or(run_dir);
+    let mut steps = Vec::new();
+    let mut snapshots = Vec::new();
+    for (step, rank_files) in list_snapshots(run_dir)? {
+        let points = read_global_snapshot(&config, step, &rank_files)?;
+        write_points(&output_dir.join(merged_file_name(step)), &points, &format)?;
+        if hdf5_file.is_some() {
+            steps.push(step);
+            snapshots.push(points);
+        }
+    }
+    info!("Merged {} steps into {}", steps.len(), output_dir.display());
+    if let Some(path) = hdf5_file {
+        let particles: Vec<usize> = (0..config.num_particles).collect();
+        write_hdf5_trajectories(path, &steps, &particles, &snapshots)?;
+        info!("Wrote {}", path.display());
+    }
+    Ok(())
+}
+
+/// Prints a health report of an output directory, returns whether every
+/// expected snapshot is present and readable
+pub fn inspect(run_dir: &Path) -> Result<bool, Box<dyn Error>> {
+    let config = match RunConfig::read(run_dir) {
+        Ok(config) => Some(config),
+        Err(err) => {
+            println!("Run metadata: unavailable ({})", err);
+            None
+        }
+    };
+    if let Some(config) = &config {
+        println!("Run metadata:");
+        println!(
+            "  coils: {} files in {}",
+            config.coil_files.len(),
+            config.resource_path
+        );
+        match (&config.particles_file, &config.seeding) {
+            (_, Some(seeding)) => println!(
+                "  particles: {} seeded on a {:?} within {} of the axis",
+                config.num_particles, seeding.mode, seeding.radius
+            ),
+            (particles_file, None) => println!(
+                "  particles: {} from {}",
+                config.num_particles,
+                particles_file.as_deref().unwrap_or("an unknown file")
+            ),
+        }
+        if let Some(restart) = &config.restart {
+            println!(
+                "  restarted from: step {} of {}",
+                restart.step, restart.run_dir
+            );
+        }
+        println!("  ranks: {}", config.world_size);
+        println!(
+            "  steps: {} of size {}, written every {}",
+            config.steps, config.step_size, config.write_frequency
+        );
+    }
+
+    let snapshots = list_snapshots(run_dir)?;
+    match (snapshots.keys().next(), snapshots.keys().last()) {
+        (Some(first), Some(last)) => println!(
+            "Snapshots: {}, steps {} to {}",
+            snapshots.len(),
+            first,
+            last
+        ),
+        _ => println!("Snapshots: none"),
+    }
+
+    let mut problems = Vec::new();
+    let verified = config
+        .as_ref()
+        .filter(|config| config.particles_checksum.is_some())
+        .map(|config| config.verify_input_checksums());
+    if let Some(Err(err)) = verified {
+        problems.push(err.to_string());
+    }
+    if let Some(config) = config
+        .as_ref()
+        .filter(|config| config.max_turn_angle.is_none())
+    {
+        let written: Vec<u32> = (0..=config.steps)
+            .step_by(config.write_frequency.max(1) as usize)
+            .collect();
+        let expected_steps = config.retention().retained(&written);
+        for step in expected_steps
+            .into_iter()
+            .filter(|step| !snapshots.contains_key(step))
+        {
+            problems.push(format!("missing step {}", step));
+        }
+    }
+    let mut last_counts = BTreeMap::new();
+    for (step, rank_files) in &snapshots {
+        let single_file = rank_files.contains_key(&ALL_RANKS);
+        if let Some(config) = config.as_ref().filter(|_| !single_file) {
+            let ranks = config.particle_counts_at(*step).len() as i32;
+            for rank in (0..ranks).filter(|rank| !rank_files.contains_key(rank)) {
+                problems.push(format!("missing rank {} at step {}", rank, step));
+            }
+        }
+        for (rank, path) in rank_files {
+            let delimiter = config
+                .as_ref()
+                .map_or(b',', |config| config.delimiter as u8);
+            match read_snapshot(path, delimiter) {
+                Ok(points) => {
+                    let mismatch = config
+                        .as_ref()
+                        .and_then(|config| {
+                            if *rank == ALL_RANKS {
+                                Some(&config.num_particles)
+                            } else {
+                                config.particle_counts_at(*step).get(*rank as usize)
+                            }
+                        })
+                        .filter(|&&expected| expected != points.len());
+                    if let Some(expected) = mismatch {
+                        problems.push(format!(
+                            "{} holds {} particles, expected {}",
+                            path.display(),
+                            points.len(),
+                            expected
+                        ));
+                    }
+                    last_counts.insert(*rank, points.len());
+                }
+                Err(err) => problems.push(format!("corrupt {}: {}", path.display(), err)),
+            }
+        }
+    }
+    println!("Particles per rank:");
+    for (rank, count) in &last_counts {
+        println!("  rank {}: {}", rank_label(*rank), count);
+    }
+
+    let mut total_size = 0;
+    for entry in fs::read_dir(run_dir)? {
+        total_size += entry?.metadata()?.len();
+    }
+    println!("Total size: {}", format_size(total_size));
+
+    if problems.is_empty() {
+        println!("No problems found");
+    } else {
+        println!("Problems:");
+        for problem in &problems {
+            println!("  {}", problem);
+        }
+    }
+    Ok(problems.is_empty())
+}
+
+/// Downsampling applied by `convert`
+#[derive(Debug, Clone, Copy)]
+pub struct Stride {
+    pub steps: usize,
+    pub particles: usize,
+}
+
+/// Rewrites the outputs of a run in another format, keeping every
+/// `stride.steps`-th snapshot and every `stride.particles`-th particle
+pub fn convert(
+    run_dir: &Path,
+    output_dir: &Path,
+    to: OutputFormat,
+    binary_precision: BinaryPrecision,
+    format: &TextFormat,
+    stride: Stride,
+) -> Result<(), Box<dyn Error>> {
+    let config = RunConfig::read(run_dir)?;
+    let kept: Vec<usize> = (0..config.num_particles)
+        .step_by(stride.particles)
+        .collect();
+    let particle_counts = config
+        .particle_offsets()
+        .iter()
+        .zip(&config.particle_counts)
+        .map(|(&offset, &count)| {
+            (offset + count).div_ceil(stride.particles) - offset.div_ceil(stride.particles)
+        })
+        .collect();
+    // Every converted snapshot is split like the last ones, uncompressed
+    let converted = RunConfig {
+        num_particles: kept.len(),
+        particle_counts,
+        earlier_decompositions: Vec::new(),
+        write_frequency: config.write_frequency * stride.steps as u32,
+        output_format: to,
+        binary_precision: Some(binary_precision).filter(|_| to == OutputFormat::Binary),
+        compression: None,
+        ..config.clone()
+    }
+    .with_text_format(format);
+    let offsets = converted.particle_offsets();
+
+    fs::create_dir_all(output_dir)?;
+    let mut steps = Vec::new();
+    let mut snapshots = Vec::new();
+    // Per-rank snapshots are read a step at a time, single files at once.
+    // Single files keep the global indices of an earlier conversion.
+    type Snapshot = Result<(u32, Vec<Point>), Box<dyn Error>>;
+    let (particles, source): (Vec<usize>, Box<dyn Iterator<Item = Snapshot>>) =
+        if config.output_format.snapshot_extension().is_some() {
+            let snapshots = list_snapshots(run_dir)?
+                .into_iter()
+                .map(|(step, rank_files)| {
+                    Ok((step, read_global_snapshot(&config, step, &rank_files)?))
+                });
+            ((0..config.num_particles).collect(), Box::new(snapshots))
+        } else {
+            let trajectories = read_trajectories(run_dir, config.output_format)?;
+            if trajectories.particles.len() != config.num_particles {
+                return Err(format!(
+                    "{} holds {} particles, expected {}",
+                    run_dir.display(),
+                    trajectories.particles.len(),
+                    config.num_particles
+                )
+                .into());
+            }
+            (
+                trajectories.particles,
+                Box::new(trajectories.snapshots.into_iter().map(Ok)),
+            )
+        };
+    let kept_particles: Vec<usize> = kept.iter().map(|&index| particles[index]).collect();
+    for snapshot in source.step_by(stride.steps) {
+        let (step, points) = snapshot?;
+        if points.len() != config.num_particles {
+            return Err(format!(
+                "the snapshot of step {} holds {} particles, expected {}",
+                step,
+                points.len(),
+                config.num_particles
+            )
+            .into());
+        }
+        let points: Vec<Point> = kept.iter().map(|&index| points[index]).collect();
+        match to {
+            OutputFormat::Text => {
+                for (rank, (&offset, &count)) in
+                    offsets.iter().zip(&converted.particle_counts).enumerate()
+                {
+                    let rank_points = &points[offset..offset + count];
+                    write_points_to_file(rank_points, output_dir, step, rank as i32, format)?;
+                }
+            }
+            OutputFormat::Vtk => {
+                for (rank, (&offset, &count)) in
+                    offsets.iter().zip(&converted.particle_counts).enumerate()
+                {
+                    let path = output_dir.join(vtk_snapshot_file_name(rank as i32, step));
+                    write_vtp_points(&path, &points[offset..offset + count], &[])?;
+                }
+            }
+            OutputFormat::Binary => {
+                for (rank, (&offset, &count)) in
+                    offsets.iter().zip(&converted.particle_counts).enumerate()
+                {
+                    let path = output_dir.join(binary_snapshot_file_name(rank as i32, step));
+                    write_binary_points(&path, &points[offset..offset + count], binary_precision)?;
+                }
+            }
+            OutputFormat::Hdf5 | OutputFormat::Netcdf | OutputFormat::Parquet => {
+                steps.push(step);
+                snapshots.push(points);
+            }
+        }
+    }
+    match to {
+        OutputFormat::Hdf5 => {
+            let path = output_dir.join(HDF5_FILE);
+            write_hdf5_trajectories(&path, &steps, &kept_particles, &snapshots)?
+        }
+        OutputFormat::Netcdf => write_netcdf_trajectories(
+            &output_dir.join(NETCDF_FILE),
+            &converted,
+            &steps,
+            &kept_particles,
+            &snapshots,
+        )?,
+        OutputFormat::Parquet => {
+            let path = output_dir.join(PARQUET_FILE);
+            write_parquet_trajectories(&path, &steps, &kept_particles, &snapshots)?
+        }
+        OutputFormat::Vtk => write_snapshot_collection(output_dir, config.step_size)?,
+        OutputFormat::Text | OutputFormat::Binary => {}
+    }
+    converted.write(output_dir)?;
+    info!(
+        "Converted {} to {}",
+        run_dir.display(),
+        output_dir.display()
+    );
+    Ok(())
+}
+
+/// Per-particle values attached to exported frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorBy {
+    None,
+    /// 1 for particles lost by the frame, 0 otherwise
+    Status,
+    /// Magnitude of the magnetic field at each particle
+    Field,
+}
+
+/// Exports the snapshots of a run as a VTK time series, an `animation.pvd`
+/// collection referencing one `.vtp` frame per written step
+pub fn animate(
+    run_dir: &Path,
+    output_dir: &Path,
+    color_by: ColorBy,
+    particle_stride: usize,
+) -> Result<(), Box<dyn Error>> {
+    let config = RunConfig::read(run_dir)?;
+    let field = match color_by {
+        ColorBy::Field => Some(
+            CoilSet::new(&config.read_coils()?)
+                .with_currents(config.currents.clone().unwrap_or_default()),
+        ),
+        _ => None,
+    };
+
+    let losses: Vec<Option<u32>> = read_run_losses(run_dir, &config)?
+        .into_iter()
+        .step_by(particle_stride)
+        .map(|loss| loss.map(|loss| loss.step))
+        .collect();
+
+    fs::create_dir_all(output_dir)?;
+    let mut frames = Vec::new();
+    for (step, rank_files) in list_snapshots(run_dir)? {
+        let points: Vec<Point> = read_global_snapshot(&config, step, &rank_files)?
+            .into_iter()
+            .step_by(particle_stride)
+            .collect();
+        let lost = |index: usize| losses[index].is_some_and(|loss| loss <= step);
+        let values: Vec<f64> = match (&field, color_by) {
+            (Some(coils), _) => points
+                .par_iter()
+                .enumerate()
+                .map(|(index, point)| {
+                    if lost(index) {
+                        f64::NAN
+                    } else {
+                        compute_magnetic_field(point, coils).get_norm()
+                    }
+                })
+                .collect(),
+            (None, ColorBy::Status) => (0..points.len())
+                .map(|index| lost(index) as u8 as f64)
+                .collect(),
+            (None, _) => Vec::new(),
+        };
+        let scalars = match color_by {
+            ColorBy::None => Vec::new(),
+            ColorBy::Status => vec![Scalars {
+                name: "lost",
+                values: &values,
+            }],
+            ColorBy::Field => vec![Scalars {
+                name: "B",
+                values: &values,
+            }],
+        };
+        let file = format!("frame_{}.vtp", step);
+        write_vtp_points(&output_dir.join(&file), &points, &scalars)?;
+        frames.push(DataSet {
+            time: step as f64 * config.step_size,
+            part: 0,
+            file,
+        });
+    }
+    write_pvd(&output_dir.join("animation.pvd"), &frames)?;
+    info!("Wrote {} frames to {}", frames.len(), output_dir.display());
+    Ok(())
+}
+
+/// Integrates a single particle serially and prints its position, |B| and
+/// step diagnostics after every step, for checking coil data and integrator
+/// settings before launching a large run
+pub fn trace_one(
+    resource_path: &Path,
+    start: Point,
+    steps: u32,
+    step_size: f64,
+) -> Result<(), Box<dyn Error>> {
+    let coils = CoilSet::new(&read_coil_data_directory(resource_path)?);
+    info!(
+        "Loaded {} coils with {} points",
+        coils.len(),
+        coils.num_points()
+    );
+
+    let print_line = |step: u32, particle: &Point, step_length: f64| {
+        let field = compute_magnetic_field(particle, &coils).get_norm();
+        println!(
+            "{}\t{:e}\t{:e}\t{:e}\t{:e}\t{:e}\t{:e}",
+            step,
+            particle.x,
+            particle.y,
+            particle.z,
+            field,
+            step_length,
+            distance_to_axis(particle, &coils.physics)
+        );
+    };
+
+    println!("step\tx\ty\tz\t|B|\tstep_length\taxis_distance");
+    let mut particle = start;
+    print_line(0, &particle, 0.0);
+    for step in 1..=steps {
+        let Some(next) = simulate_step(&particle, &coils, step_size) else {
+            println!("Particle left the minor radius at step {}", step);
+            break;
+        };
+        let step_length = next.get_distance(&particle);
+        particle = next;
+        print_line(step, &particle, step_length);
+    }
+    Ok(())
+}
+
+/// Prints B and its Jacobian at each point, with the divergence and curl of
+/// the field as a check of the coil data (both vanish in vacuum)
+pub fn field_jacobian(resource_path: &Path, points: &[Point]) -> Result<(), Box<dyn Error>> {
+    let coils = CoilSet::new(&read_coil_data_directory(resource_path)?);
+    for point in points {
+        let b = compute_magnetic_field(point, &coils);
+        let j = compute_field_jacobian(point, &coils);
+        let divergence = j[0][0] + j[1][1] + j[2][2];
+        let curl = Point {
+            x: j[2][1] - j[1][2],
+            y: j[0][2] - j[2][0],
+            z: j[1][0] - j[0][1],
+        };
+        println!("Point: {}", point);
+        println!("  B: {} (|B| = {:e})", b, b.get_norm());
+        println!("  grad B:");
+        for row in &j {
+            println!("    {:+.6e} {:+.6e} {:+.6e}", row[0], row[1], row[2]);
+        }
+        println!("  div B: {:e}", divergence);
+        println!("  |curl B|: {:e}", curl.get_norm());
+    }
+    Ok(())
+}
+
+/// Estimates the finite-time Lyapunov exponent of the field line through
+/// every starting point and writes them as a CSV chaos map
+pub fn lyapunov(
+    resource_path: &Path,
+    particles_file: &Path,
+    num_particles: usize,
+    output_file: &Path,
+    settings: &LyapunovSettings,
+) -> Result<(), Box<dyn Error>> {
+    let coils = CoilSet::new(&read_coil_data_directory(resource_path)?);
+    let starts = read_from_file(particles_file, num_particles)?;
+    info!(
+        "Estimating Lyapunov exponents of {} field lines",
+        starts.len()
+    );
+    let estimates: Vec<_> = starts
+        .par_iter()
+        .map(|start| lyapunov_exponent(start, &coils, settings))
+        .collect();
+
+    let mut wtr = csv::Writer::from_path(output_file)?;
+    wtr.write_record(["x", "y", "z", "lyapunov", "length", "lost"])?;
+    for (start, estimate) in starts.iter().zip(&estimates) {
+        wtr.write_record([
+            start.x.to_string(),
+            start.y.to_string(),
+            start.z.to_string(),
+            estimate.exponent.to_string(),
+            estimate.length.to_string(),
+            (estimate.lost as u8).to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    let lost = estimates.iter().filter(|estimate| estimate.lost).count();
+    info!(
+        "Wrote {} ({} field lines lost before the last step)",
+        output_file.display(),
+        lost
+    );
+    Ok(())
+}
+
+/// Island chain of one rational surface in the `islands` report
+#[derive(Debug, serde::Serialize)]
+pub struct IslandReport {
+    /// Field lines found on the chain
+    pub field_lines: usize,
+    /// Measurements of the field line with the widest islands
+    #[serde(flatten)]
+    pub chain: IslandChain,
+}
+
+/// Writes the Poincaré punctures every particle of a run recorded into a
+/// CSV file, one row per puncture in the order they were made
+pub fn poincare(run_dir: &Path, output_file: &Path) -> Result<(), Box<dyn Error>> {
+    let config = RunConfig::read(run_dir)?;
+    let punctures = read_punctures(run_dir, config.num_particles)?;
+    let mut wtr = csv::Writer::from_path(output_file)?;
+    wtr.write_record(["particle", "r", "z"])?;
+    for (particle, line) in punctures.iter().enumerate() {
+        for puncture in line {
+            wtr.write_record([
+                particle.to_string(),
+                puncture.r.to_string(),
+                puncture.z.to_string(),
+            ])?;
+        }
+    }
+    wtr.flush()?;
+    info!(
+        "Wrote {} Poincaré punctures to {}",
+        punctures.iter().map(|line| line.len()).sum::<usize>(),
+        output_file.display()
+    );
+    Ok(())
+}
+
+/// Collects the Poincaré punctures every particle of a run recorded,
+/// detects island chains and writes a JSON report of their widths and O/X
+/// points per rational surface
+pub fn islands(
+    run_dir: &Path,
+    output_file: Option<&Path>,
+    max_mode: usize,
+) -> Result<(), Box<dyn Error>> {
+    let config = RunConfig::read(run_dir)?;
+    let punctures = read_punctures(run_dir, config.num_particles)?;
+    info!(
+        "Collected {} Poincaré punctures",
+        punctures.iter().map(|line| line.len()).sum::<usize>()
+    );
+
+    let mut surfaces: BTreeMap<(usize, i64), IslandReport> = BTreeMap::new();
+    for chain in punctures
+        .par_iter()
+        .filter_map(|line| detect_island_chain(line, max_mode, config.major_radius))
+        .collect::<Vec<_>>()
+    {
+        let key = (chain.poloidal_mode, chain.toroidal_mode);
+        match surfaces.get_mut(&key) {
+            Some(report) => {
+                report.field_lines += 1;
+                if chain.width > report.chain.width {
+                    report.chain = chain;
+                }
+            }
+            None => {
+                surfaces.insert(
+                    key,
+                    IslandReport {
+                        field_lines: 1,
+                        chain,
+                    },
+                );
+            }
+        }
+    }
+    let reports: Vec<IslandReport> = surfaces.into_values().collect();
+    for report in &reports {
+        info!(
+            "iota = {}/{}: {} islands of width {:.3e} on {} field lines",
+            report.chain.toroidal_mode,
+            report.chain.poloidal_mode,
+            report.chain.poloidal_mode,
+            report.chain.width,
+            report.field_lines
+        );
+    }
+    match output_file {
+        Some(path) => serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), &reports)?,
+        None => println!("{}", serde_json::to_string_pretty(&reports)?),
+    }
+    Ok(())
+}
+
+/// Tracing and grid settings of `ripple`
+pub struct RippleSettings {
+    pub steps: u32,
+    pub step_size: f64,
+    pub theta_bins: usize,
+    pub phi_bins: usize,
+    pub num_modes: usize,
+    /// Of the magnetic axis the surface is lost around and binned by
+    /// poloidal angle around
+    pub major_radius: f64,
+}
+
+/// Traces the flux surface through `start`, bins |B| over a (theta, phi)
+/// grid and writes its ripple metrics and largest Fourier modes as JSON
+pub fn ripple(
+    resource_path: &Path,
+    start: Point,
+    settings: &RippleSettings,
+    output_file: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let coils =
+        CoilSet::new(&read_coil_data_directory(resource_path)?).with_physics(PhysicsParams {
+            major_radius: settings.major_radius,
+            ..PhysicsParams::default()
+        });
+    let mut points = vec![start];
+    for _ in 0..settings.steps {
+        let Some(next) = simulate_step(points.last().unwrap(), &coils, settings.step_size) else {
+            return Err(format!(
+                "Field line left the minor radius after {} steps",
+                points.len() - 1
+            )
+            .into());
+        };
+        points.push(next);
+    }
+    let fields: Vec<f64> = points
+        .par_iter()
+        .map(|point| compute_magnetic_field(point, &coils).get_norm())
+        .collect();
+    let report = SurfaceGrid::from_samples(
+        &points,
+        &fields,
+        settings.theta_bins,
+        settings.phi_bins,
+        settings.major_radius,
+    )
+    .ripple_report(settings.num_modes);
+    info!(
+        "Ripple {:.3e}, symmetry breaking {:.3}, {:.0}% of the grid visited",
+        report.ripple,
+        report.symmetry_breaking,
+        report.coverage * 100.0
+    );
+    match output_file {
+        Some(path) => serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), &report)?,
+        None => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
+
+/// Response of the confinement to the current of one coil group in the
+/// `sensitivity` report
+#[derive(Debug, serde::Serialize)]
+pub struct GroupSensitivity {
+    pub coil_indices: Vec<usize>,
+    /// Summaries with the group current scaled by 1 + delta and 1 - delta
+    pub increased: ConfinementSummary,
+    pub decreased: ConfinementSummary,
+    /// Central differences per relative current change
+    pub loss_fraction_derivative: f64,
+    pub iota_derivative: f64,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SensitivityReport {
+    pub delta: f64,
+    pub baseline: ConfinementSummary,
+    pub groups: Vec<GroupSensitivity>,
+}
+
+/// Perturbs the current of every coil group by ±`delta` (relative) and
+/// reports the change in loss fraction and rotational transform, tracing
+/// the same starting points with the same coil geometry every time
+pub fn sensitivity(
+    resource_path: &Path,
+    starts: &[Point],
+    steps: u32,
+    step_size: f64,
+    delta: f64,
+    groups: &[Vec<usize>],
+    output_file: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let coils = CoilSet::new(&read_coil_data_directory(resource_path)?);
+    let groups = coil_groups(&coils, groups)?;
+    let summarize = |coils: &CoilSet| confinement_summary(starts, coils, steps, step_size);
+    let baseline = summarize(&coils);
+    info!(
+        "Baseline: loss fraction {:.4}, iota {:.5}",
+        baseline.loss_fraction, baseline.iota
+    );
+    let mut sensitivities = Vec::new();
+    for group in &groups {
+        let increased = summarize(&scale_currents(&coils, &group.coil_indices, 1.0 + delta));
+        let decreased = summarize(&scale_currents(&coils, &group.coil_indices, 1.0 - delta));
+        let sensitivity = GroupSensitivity {
+            coil_indices: group.coil_indices.clone(),
+            increased,
+            decreased,
+            loss_fraction_derivative: (increased.loss_fraction - decreased.loss_fraction)
+                / (2.0 * delta),
+            iota_derivative: (increased.iota - decreased.iota) / (2.0 * delta),
+        };
+        info!(
+            "Coils {:?}: d(loss fraction)/dI {:.4}, d(iota)/dI {:.5} per relative current",
+            sensitivity.coil_indices,
+            sensitivity.loss_fraction_derivative,
+            sensitivity.iota_derivative
+        );
+        sensitivities.push(sensitivity);
+    }
+    let report = SensitivityReport {
+        delta,
+        baseline,
+        groups: sensitivities,
+    };
+    match output_file {
+        Some(path) => serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), &report)?,
+        None => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
+
+/// One run of a campaign in the `aggregate` report
+#[derive(Debug, serde::Serialize)]
+pub struct CampaignRun {
+    pub run_dir: String,
+    #[serde(flatten)]
+    pub summary: RunSummary,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct CampaignReport {
+    pub runs: Vec<CampaignRun>,
+    pub total_particles: u64,
+    pub total_lost: u64,
+    /// Lost particles over all particles of all runs
+    pub pooled_loss_fraction: f64,
+    /// Statistics of the per-run loss fractions
+    pub loss_fraction: Statistic,
+    pub simulation_time: Statistic,
+    pub radius_histogram: Histogram,
+}
+
+/// Combines the summaries of every run below `campaign_dir` (the directory
+/// itself and its direct subdirectories) into a campaign report, optionally
+/// merging their Poincaré punctures into one CSV file
+pub fn aggregate(
+    campaign_dir: &Path,
+    output_file: Option<&Path>,
+    poincare_file: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let mut run_dirs = vec![campaign_dir.to_path_buf()];
+    for entry in fs::read_dir(campaign_dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            run_dirs.push(path);
+        }
+    }
+    run_dirs.retain(|dir| dir.join(SUMMARY_FILE).is_file());
+    run_dirs.sort();
+    if run_dirs.is_empty() {
+        return Err(format!("No {} found in {}", SUMMARY_FILE, campaign_dir.display()).into());
+    }
+
+    let mut runs = Vec::new();
+    for run_dir in &run_dirs {
+        runs.push(CampaignRun {
+            run_dir: run_dir.display().to_string(),
+            summary: RunSummary::read(run_dir)?,
+        });
+    }
+    let mut radius_histogram = runs[0].summary.radius_histogram.clone();
+    for run in &runs[1..] {
+        radius_histogram.merge(&run.summary.radius_histogram)?;
+    }
+    let total_particles = runs
+        .iter()
+        .map(|run| run.summary.num_particles)
+        .sum::<u64>();
+    let total_lost = runs.iter().map(|run| run.summary.lost).sum::<u64>();
+    let loss_fractions: Vec<f64> = runs.iter().map(|run| run.summary.loss_fraction).collect();
+    let times: Vec<f64> = runs.iter().map(|run| run.summary.simulation_time).collect();
+    let report = CampaignReport {
+        total_particles,
+        total_lost,
+        pooled_loss_fraction: total_lost as f64 / total_particles.max(1) as f64,
+        loss_fraction: Statistic::from_samples(&loss_fractions),
+        simulation_time: Statistic::from_samples(&times),
+        radius_histogram,
+        runs,
+    };
+    info!(
+        "Aggregated {} runs, loss fraction {:.4} ± {:.4}",
+        report.runs.len(),
+        report.loss_fraction.mean,
+        report.loss_fraction.std_dev
+    );
+
+    if let Some(poincare_file) = poincare_file {
+        let mut wtr = csv::Writer::from_path(poincare_file)?;
+        wtr.write_record(["run", "particle", "r", "z"])?;
+        for (run, run_dir) in run_dirs.iter().enumerate() {
+            let config = RunConfig::read(run_dir)?;
+            for (particle, line) in read_punctures(run_dir, config.num_particles)?
+                .iter()
+                .enumerate()
+            {
+                for puncture in line {
+                    wtr.write_record([
+                        run.to_string(),
+                        particle.to_string(),
+                        puncture.r.to_string(),
+                        puncture.z.to_string(),
+                    ])?;
+                }
+            }
+        }
+        wtr.flush()?;
+        info!("Wrote merged Poincaré data to {}", poincare_file.display());
+    }
+
+    match output_file {
+        Some(path) => serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), &report)?,
+        None => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
+
+/// Exports the coils and a decimated subset of the trajectories of a run as
+/// a glTF scene that can be shared and viewed in a browser
+pub fn export_scene(
+    run_dir: &Path,
+    output_file: &Path,
+    stride: Stride,
+) -> Result<(), Box<dyn Error>> {
+    let config = RunConfig::read(run_dir)?;
+    let coils = config.read_coils()?;
+    let kept: Vec<usize> = (0..config.num_particles)
+        .step_by(stride.particles)
+        .collect();
+    let losses = read_run_losses(run_dir, &config)?;
+    let mut trajectories = vec![Vec::new(); kept.len()];
+    for (step, rank_files) in list_snapshots(run_dir)?.into_iter().step_by(stride.steps) {
+        let points = read_global_snapshot(&config, step, &rank_files)?;
+        for (trajectory, &index) in trajectories.iter_mut().zip(&kept) {
+            if losses[index].is_none_or(|loss| loss.step > step) {
+                trajectory.push(points[index]);
+            }
+        }
+    }
+    let line_sets = [
+        LineSet {
+            name: "coils".to_string(),
+            color: [0.8, 0.5, 0.2, 1.0],
+            lines: coils,
+        },
+        LineSet {
+            name: "trajectories".to_string(),
+            color: [0.2, 0.4, 0.9, 1.0],
+            lines: trajectories,
+        },
+    ];
+    write_gltf_scene(output_file, &line_sets)?;
+    info!("Wrote {}", output_file.display());
+    Ok(())
+}
+
+pub(crate) fn read_global_snapshot(
+    config: &RunConfig,
+    step: u32,
+    rank_files: &BTreeMap<i32, PathBuf>,
+) -> Result<Vec<Point>, Box<dyn Error>> {
+    if let Some(path) = rank_files.get(&ALL_RANKS) {
+        let points = read_snapshot(path, config.delimiter as u8)?;
+        if points.len() != config.num_particles {
+            return Err(format!(
+                "{} holds {} particles, expected {}",
+                path.display(),
+                points.len(),
+                config.num_particles
+            )
+            .into());
+        }
+        return Ok(points);
+    }
+    let mut points = vec![Point::default(); config.num_particles];
+    let counts = config.particle_counts_at(step);
+    let offsets = partition::particle_offsets(counts);
+    for (rank, (&offset, &count)) in offsets.iter().zip(counts).enumerate() {
+        let path = rank_files
+            .get(&(rank as i32))
+            .ok_or_else(|| format!("Missing output of rank {} at step {}", rank, step))?;
+        let rank_points = read_snapshot(path, config.delimiter as u8)?;
+        if rank_points.len() != count {
+            return Err(format!(
+                "{} holds {} particles, expected {}",
+                path.display(),
+                rank_points.len(),
+                count
+            )
+            .into());
+        }
+        points[offset..offset + count].copy_from_slice(&rank_points);
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        collectives::SingleProcess,
+        output::{
+            Decimation, SnapshotWriter, TextFormat, snapshot_file_name, write_points_to_file,
+        },
+        particle::{ParticleState, lost_particles_file_name, write_lost_particles},
+        simulation::DIVERGENT_PARTICLE,
+        vtk::read_vtp_points,
+    };
+    use std::fs;
+
+    #[test]
+    fn merge_restores_global_order() {
+        let run_dir = std::env::temp_dir().join("bs_solctra_merge_test");
+        let _ = fs::remove_dir_all(&run_dir);
+        fs::create_dir_all(&run_dir).unwrap();
+        let points: Vec<Point> = (0..5)
+            .map(|i| Point {
+                x: i as f64,
+                y: 0.0,
+                z: 0.0,
+            })
+            .collect();
+        let format = TextFormat::default();
+        write_points_to_file(&points[3..], &run_dir, 10, 1, &format).unwrap();
+        write_points_to_file(&points[..3], &run_dir, 10, 0, &format).unwrap();
+
+        let config = RunConfig {
+            num_particles: 5,
+            world_size: 2,
+            particle_counts: vec![3, 2],
+            delimiter: ',',
+            ..Default::default()
+        };
+        config.write(&run_dir).unwrap();
+        merge(&run_dir, None, None).unwrap();
+
+        let merged = crate::point::read_from_file(&run_dir.join(merged_file_name(10)), 10);
+        fs::remove_dir_all(&run_dir).unwrap();
+        assert_eq!(merged.unwrap(), points);
+    }
+
+    #[test]
+    fn inspect_reports_missing_and_corrupt_snapshots() {
+        let run_dir = std::env::temp_dir().join("bs_solctra_inspect_test");
+        let _ = fs::remove_dir_all(&run_dir);
+        fs::create_dir_all(&run_dir).unwrap();
+        let points = [Point::default(); 3];
+        let format = TextFormat::default();
+        for step in [0, 10] {
+            write_points_to_file(&points[..2], &run_dir, step, 0, &format).unwrap();
+            write_points_to_file(&points[2..], &run_dir, step, 1, &format).unwrap();
+        }
+        let config = RunConfig {
+            num_particles: 3,
+            world_size: 2,
+            particle_counts: vec![2, 1],
+            steps: 20,
+            write_frequency: 10,
+            delimiter: ',',
+            ..Default::default()
+        };
+        config.write(&run_dir).unwrap();
+        // Step 20 is missing
+        let missing = inspect(&run_dir).unwrap();
+        for rank in 0..2 {
+            let range = if rank == 0 { 0..2 } else { 2..3 };
+            write_points_to_file(&points[range], &run_dir, 20, rank, &format).unwrap();
+        }
+        let complete = inspect(&run_dir).unwrap();
+        fs::write(
+            run_dir.join(snapshot_file_name(1, 10)),
+            "x,y,z\n0,unreadable,0\n",
+        )
+        .unwrap();
+        let corrupt = inspect(&run_dir).unwrap();
+        fs::remove_dir_all(&run_dir).unwrap();
+        assert!(!missing);
+        assert!(complete);
+        assert!(!corrupt);
+    }
+
+    #[test]
+    fn animate_writes_a_frame_per_snapshot() {
+        let run_dir = std::env::temp_dir().join("bs_solctra_animate_test");
+        let output_dir = run_dir.join("animation");
+        let _ = fs::remove_dir_all(&run_dir);
+        fs::create_dir_all(&run_dir).unwrap();
+        let points: Vec<Point> = (0..4)
+            .map(|i| Point {
+                x: i as f64,
+                y: 0.5,
+                z: 0.0,
+            })
+            .collect();
+        let format = TextFormat::default();
+        write_points_to_file(&points, &run_dir, 0, 0, &format).unwrap();
+        let lost = [points[0], points[1], DIVERGENT_PARTICLE, points[3]];
+        write_points_to_file(&lost, &run_dir, 10, 0, &format).unwrap();
+        let mut statuses = [ParticleState::Active; 4];
+        statuses[2] = ParticleState::Lost { step: 7 };
+        write_lost_particles(
+            &run_dir.join(lost_particles_file_name(0)),
+            0,
+            &points,
+            &statuses,
+            &[0.0; 4],
+            &format,
+        )
+        .unwrap();
+        let config = RunConfig {
+            num_particles: 4,
+            world_size: 1,
+            particle_counts: vec![4],
+            step_size: 0.5,
+            delimiter: ',',
+            ..Default::default()
+        };
+        config.write(&run_dir).unwrap();
+        animate(&run_dir, &output_dir, ColorBy::Status, 2).unwrap();
+
+        let first = read_vtp_points(&output_dir.join("frame_0.vtp")).unwrap();
+        let last = fs::read_to_string(output_dir.join("frame_10.vtp")).unwrap();
+        let collection = fs::read_to_string(output_dir.join("animation.pvd")).unwrap();
+        fs::remove_dir_all(&run_dir).unwrap();
+        assert_eq!(first, [points[0], points[2]]);
+        assert!(last.contains("Name=\"lost\" format=\"ascii\">\n          0\n          1\n"));
+        assert!(collection.contains("timestep=\"0\" group=\"\" part=\"0\" file=\"frame_0.vtp\""));
+        assert!(collection.contains("timestep=\"5\" group=\"\" part=\"0\" file=\"frame_10.vtp\""));
+    }
+
+    #[test]
+    fn strided_conversions_round_trip_through_binary() {
+        let run_dir = std::env::temp_dir().join("bs_solctra_convert_test");
+        let binary_dir = run_dir.join("binary");
+        let text_dir = run_dir.join("text");
+        let _ = fs::remove_dir_all(&run_dir);
+        fs::create_dir_all(&run_dir).unwrap();
+        let format = TextFormat::default();
+        let snapshot = |step: u32| -> Vec<Point> {
+            (0..5)
+                .map(|i| Point {
+                    x: i as f64 + 0.1,
+                    y: step as f64 / 3.0,
+                    z: -(i as f64),
+                })
+                .collect()
+        };
+        for step in [0, 10, 20, 30] {
+            let points = snapshot(step);
+            write_points_to_file(&points[..3], &run_dir, step, 0, &format).unwrap();
+            write_points_to_file(&points[3..], &run_dir, step, 1, &format).unwrap();
+        }
+        let config = RunConfig {
+            num_particles: 5,
+            world_size: 2,
+            particle_counts: vec![3, 2],
+            write_frequency: 10,
+            delimiter: ',',
+            ..Default::default()
+        };
+        config.write(&run_dir).unwrap();
+
+        let stride = Stride {
+            steps: 2,
+            particles: 2,
+        };
+        let binary = OutputFormat::Binary;
+        convert(
+            &run_dir,
+            &binary_dir,
+            binary,
+            BinaryPrecision::F64,
+            &format,
+            stride,
+        )
+        .unwrap();
+        let every = Stride {
+            steps: 1,
+            particles: 1,
+        };
+        let text = OutputFormat::Text;
+        convert(
+            &binary_dir,
+            &text_dir,
+            text,
+            BinaryPrecision::F64,
+            &format,
+            every,
+        )
+        .unwrap();
+
+        let converted = RunConfig::read(&text_dir).unwrap();
+        let snapshots = list_snapshots(&text_dir).unwrap();
+        let steps: Vec<u32> = snapshots.keys().copied().collect();
+        let read: Vec<Vec<Point>> = steps
+            .iter()
+            .map(|step| read_global_snapshot(&converted, *step, &snapshots[step]).unwrap())
+            .collect();
+        fs::remove_dir_all(&run_dir).unwrap();
+        assert_eq!(steps, [0, 20]);
+        assert_eq!(converted.write_frequency, 20);
+        assert_eq!(converted.particle_counts, [2, 1]);
+        for (step, points) in steps.iter().zip(read) {
+            let expected: Vec<Point> = snapshot(*step).into_iter().step_by(2).collect();
+            assert_eq!(points, expected);
+        }
+    }
+
+    /// Converts a run into the single file of `to` keeping every other
+    /// particle, then that file again, and reads both back
+    #[cfg(any(feature = "hdf5", feature = "netcdf", feature = "parquet"))]
+    fn strided_conversions_round_trip_through(to: OutputFormat, name: &str) {
+        let run_dir = std::env::temp_dir().join(format!("bs_solctra_convert_{}_test", name));
+        let first_dir = run_dir.join("first");
+        let second_dir = run_dir.join("second");
+        let _ = fs::remove_dir_all(&run_dir);
+        fs::create_dir_all(&run_dir).unwrap();
+        let format = TextFormat::default();
+        let snapshot = |step: u32| -> Vec<Point> {
+            (0..5)
+                .map(|i| Point {
+                    x: i as f64 + 0.1,
+                    y: step as f64 / 3.0,
+                    z: -(i as f64),
+                })
+                .collect()
+        };
+        for step in [0, 10, 20] {
+            let points = snapshot(step);
+            write_points_to_file(&points[..3], &run_dir, step, 0, &format).unwrap();
+            write_points_to_file(&points[3..], &run_dir, step, 1, &format).unwrap();
+        }
+        let config = RunConfig {
+            num_particles: 5,
+            world_size: 2,
+            particle_counts: vec![3, 2],
+            write_frequency: 10,
+            delimiter: ',',
+            ..Default::default()
+        };
+        config.write(&run_dir).unwrap();
+
+        let stride = Stride {
+            steps: 1,
+            particles: 2,
+        };
+        let precision = BinaryPrecision::F64;
+        convert(&run_dir, &first_dir, to, precision, &format, stride).unwrap();
+        convert(&first_dir, &second_dir, to, precision, &format, stride).unwrap();
+
+        let first = read_trajectories(&first_dir, to).unwrap();
+        let second = read_trajectories(&second_dir, to).unwrap();
+        fs::remove_dir_all(&run_dir).unwrap();
+        assert_eq!(first.particles, [0, 2, 4]);
+        assert_eq!(second.particles, [0, 4]);
+        for (trajectories, particles) in [(first, [0, 2, 4].as_slice()), (second, &[0, 4])] {
+            let steps: Vec<u32> = trajectories
+                .snapshots
+                .iter()
+                .map(|(step, _)| *step)
+                .collect();
+            assert_eq!(steps, [0, 10, 20]);
+            for (step, points) in trajectories.snapshots {
+                let all = snapshot(step);
+                let expected: Vec<Point> = particles.iter().map(|&index| all[index]).collect();
+                assert_eq!(points, expected);
+            }
+        }
+    }
+
+    #[cfg(feature = "hdf5")]
+    #[test]
+    fn strided_conversions_round_trip_through_hdf5() {
+        strided_conversions_round_trip_through(OutputFormat::Hdf5, "hdf5");
+    }
+
+    #[cfg(feature = "netcdf")]
+    #[test]
+    fn strided_conversions_round_trip_through_netcdf() {
+        strided_conversions_round_trip_through(OutputFormat::Netcdf, "netcdf");
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn strided_conversions_round_trip_through_parquet() {
+        strided_conversions_round_trip_through(OutputFormat::Parquet, "parquet");
+    }
+
+    #[test]
+    fn snapshots_before_a_resume_keep_their_split() {
+        let run_dir = std::env::temp_dir().join("bs_solctra_repartition_test");
+        let _ = fs::remove_dir_all(&run_dir);
+        fs::create_dir_all(&run_dir).unwrap();
+        let points: Vec<Point> = (0..5)
+            .map(|i| Point {
+                x: i as f64,
+                y: 0.0,
+                z: 0.0,
+            })
+            .collect();
+        let format = TextFormat::default();
+        write_points_to_file(&points[..3], &run_dir, 10, 0, &format).unwrap();
+        write_points_to_file(&points[3..], &run_dir, 10, 1, &format).unwrap();
+        for (rank, range) in [0..2, 2..4, 4..5].into_iter().enumerate() {
+            write_points_to_file(&points[range], &run_dir, 20, rank as i32, &format).unwrap();
+        }
+
+        let interrupted = RunConfig {
+            num_particles: 5,
+            world_size: 2,
+            particle_counts: vec![3, 2],
+            delimiter: ',',
+            ..Default::default()
+        };
+        let config = RunConfig {
+            world_size: 3,
+            particle_counts: vec![2, 2, 1],
+            ..interrupted.clone()
+        }
+        .with_resume(interrupted, 10);
+        assert_eq!(config.particle_counts_at(10), [3, 2]);
+        assert_eq!(config.particle_counts_at(20), [2, 2, 1]);
+
+        let snapshots = list_snapshots(&run_dir).unwrap();
+        let before = read_global_snapshot(&config, 10, &snapshots[&10]);
+        let after = read_global_snapshot(&config, 20, &snapshots[&20]);
+        fs::remove_dir_all(&run_dir).unwrap();
+        assert_eq!(before.unwrap(), points);
+        assert_eq!(after.unwrap(), points);
+    }
+
+    #[test]
+    fn single_file_snapshots_hold_every_rank() {
+        let run_dir = std::env::temp_dir().join("bs_solctra_single_file_test");
+        let _ = fs::remove_dir_all(&run_dir);
+        fs::create_dir_all(&run_dir).unwrap();
+        let points: Vec<Point> = (0..5)
+            .map(|i| Point {
+                x: i as f64,
+                y: 0.0,
+                z: 0.0,
+            })
+            .collect();
+        let mut writer =
+            SnapshotWriter::new(&run_dir, 0, TextFormat::default(), Decimation::Every(1))
+                .with_single_file(true);
+        writer
+            .write(&points, &[false; 5], None, None, 10, &SingleProcess)
+            .unwrap();
+
+        let config = RunConfig {
+            num_particles: 5,
+            world_size: 2,
+            particle_counts: vec![3, 2],
+            delimiter: ',',
+            single_file: true,
+            ..Default::default()
+        };
+        let snapshots = list_snapshots(&run_dir).unwrap();
+        let read = read_global_snapshot(&config, 10, &snapshots[&10]);
+        fs::remove_dir_all(&run_dir).unwrap();
+        assert_eq!(snapshots[&10].keys().collect::<Vec<_>>(), vec![&ALL_RANKS]);
+        assert_eq!(read.unwrap(), points);
+    }
+}
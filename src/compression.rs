@@ -0,0 +1,247 @@
+use clap::ValueEnum;
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Compression of the snapshot files of a run, told apart by the extension
+/// appended to their names
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Zstandard `.zst` files, needs the `zstd` feature
+    Zstd,
+    /// gzip `.gz` files, needs the `gzip` feature
+    Gzip,
+}
+
+/// Level of zstd files, the default of the zstd tool
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+impl Compression {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Zstd => "zst",
+            Compression::Gzip => "gz",
+        }
+    }
+
+    /// Compression named by the last extension of `path`, `None` for plain files
+    pub fn of_path(path: &Path) -> Option<Compression> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("zst") => Some(Compression::Zstd),
+            Some("gz") => Some(Compression::Gzip),
+            _ => None,
+        }
+    }
+
+    /// Fails unless this build has the feature of the compression
+    pub fn check_available(&self) -> Result<(), String> {
+        let (available, feature) = match self {
+            Compression::Zstd => (cfg!(feature = "zstd"), "zstd"),
+            Compression::Gzip => (cfg!(feature = "gzip"), "gzip"),
+        };
+        if available {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} compression requires building with the `{}` feature",
+                feature, feature
+            ))
+        }
+    }
+}
+
+/// `path` with the extension of `compression` appended, unchanged for `None`
+pub fn compressed_path(path: PathBuf, compression: Option<Compression>) -> PathBuf {
+    match compression {
+        Some(compression) => {
+            let mut path = path.into_os_string();
+            path.push(".");
+            path.push(compression.extension());
+            PathBuf::from(path)
+        }
+        None => path,
+    }
+}
+
+/// File name `name` without the extension of its compression, if any
+pub fn uncompressed_name(name: &str) -> &str {
+    [Compression::Zstd, Compression::Gzip]
+        .iter()
+        .find_map(|compression| {
+            name.strip_suffix(compression.extension())?
+                .strip_suffix('.')
+        })
+        .unwrap_or(name)
+}
+
+/// File being written, compressed as its extension says. Must be finished
+/// for compressed files to be complete.
+pub enum FileWriter {
+    Plain(BufWriter<File>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<BufWriter<File>>),
+}
+
+impl FileWriter {
+    /// Fails for compressions this build does not have
+    pub fn create(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file = BufWriter::new(File::create(path)?);
+        match Compression::of_path(path) {
+            None => Ok(FileWriter::Plain(file)),
+            Some(Compression::Zstd) => zstd_writer(file),
+            Some(Compression::Gzip) => gzip_writer(file),
+        }
+    }
+
+    /// Ends the compressed stream and flushes the file
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            FileWriter::Plain(mut file) => file.flush(),
+            #[cfg(feature = "zstd")]
+            FileWriter::Zstd(encoder) => encoder.finish()?.flush(),
+            #[cfg(feature = "gzip")]
+            FileWriter::Gzip(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            FileWriter::Plain(file) => file.write(buf),
+            #[cfg(feature = "zstd")]
+            FileWriter::Zstd(encoder) => encoder.write(buf),
+            #[cfg(feature = "gzip")]
+            FileWriter::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            FileWriter::Plain(file) => file.flush(),
+            #[cfg(feature = "zstd")]
+            FileWriter::Zstd(encoder) => encoder.flush(),
+            #[cfg(feature = "gzip")]
+            FileWriter::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// CSV writer of `path`, compressed as its extension says
+pub fn create_csv(path: &Path, delimiter: u8) -> Result<csv::Writer<FileWriter>, Box<dyn Error>> {
+    Ok(csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(FileWriter::create(path)?))
+}
+
+/// Flushes a writer of `create_csv` and finishes its file
+pub fn finish_csv(wtr: csv::Writer<FileWriter>) -> Result<(), Box<dyn Error>> {
+    wtr.into_inner().map_err(|err| err.into_error())?.finish()?;
+    Ok(())
+}
+
+/// Opens `path` for reading, decompressing it as its extension says
+pub fn open(path: &Path) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    let file = BufReader::new(File::open(path)?);
+    match Compression::of_path(path) {
+        None => Ok(Box::new(file)),
+        Some(Compression::Zstd) => zstd_reader(file),
+        Some(Compression::Gzip) => gzip_reader(file),
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_writer(file: BufWriter<File>) -> Result<FileWriter, Box<dyn Error>> {
+    Ok(FileWriter::Zstd(zstd::Encoder::new(file, ZSTD_LEVEL)?))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_writer(_file: BufWriter<File>) -> Result<FileWriter, Box<dyn Error>> {
+    Err("zstd compression requires building with the `zstd` feature".into())
+}
+
+#[cfg(feature = "gzip")]
+fn gzip_writer(file: BufWriter<File>) -> Result<FileWriter, Box<dyn Error>> {
+    Ok(FileWriter::Gzip(flate2::write::GzEncoder::new(
+        file,
+        flate2::Compression::default(),
+    )))
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gzip_writer(_file: BufWriter<File>) -> Result<FileWriter, Box<dyn Error>> {
+    Err("gzip compression requires building with the `gzip` feature".into())
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_reader(file: BufReader<File>) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    Ok(Box::new(zstd::Decoder::with_buffer(file)?))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_reader(_file: BufReader<File>) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    Err("reading zstd files requires building with the `zstd` feature".into())
+}
+
+#[cfg(feature = "gzip")]
+fn gzip_reader(file: BufReader<File>) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    // Multi-member, as concatenated gzip files are still one gzip file
+    Ok(Box::new(flate2::bufread::MultiGzDecoder::new(file)))
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gzip_reader(_file: BufReader<File>) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    Err("reading gzip files requires building with the `gzip` feature".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_is_named_by_the_last_extension() {
+        let path = compressed_path(PathBuf::from("out/out_0_10.csv"), Some(Compression::Zstd));
+        assert_eq!(path, Path::new("out/out_0_10.csv.zst"));
+        assert_eq!(Compression::of_path(&path), Some(Compression::Zstd));
+        assert_eq!(uncompressed_name("out_0_10.csv.zst"), "out_0_10.csv");
+        assert_eq!(uncompressed_name("out_0_10.csv.gz"), "out_0_10.csv");
+        let plain = compressed_path(PathBuf::from("out_0_10.csv"), None);
+        assert_eq!(Compression::of_path(&plain), None);
+        assert_eq!(uncompressed_name("out_0_10.csv"), "out_0_10.csv");
+    }
+
+    #[test]
+    fn files_read_back_as_written() {
+        let dir = std::env::temp_dir().join("bs_solctra_compression_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut compressions = vec![None];
+        if cfg!(feature = "zstd") {
+            compressions.push(Some(Compression::Zstd));
+        }
+        if cfg!(feature = "gzip") {
+            compressions.push(Some(Compression::Gzip));
+        }
+        for compression in compressions {
+            let path = compressed_path(dir.join("values.csv"), compression);
+            let mut wtr = create_csv(&path, b',').unwrap();
+            wtr.write_record(["x", "y"]).unwrap();
+            wtr.write_record(["1", "2"]).unwrap();
+            finish_csv(wtr).unwrap();
+            let mut text = String::new();
+            open(&path).unwrap().read_to_string(&mut text).unwrap();
+            assert_eq!(text, "x,y\n1,2\n");
+        }
+        if !cfg!(feature = "zstd") {
+            assert!(FileWriter::create(&dir.join("values.csv.zst")).is_err());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,618 @@
+use crate::{
+    args::Args,
+    boundary::LossBoundary,
+    coil_format::{CoilFormat, read_coils},
+    coil_spline::resample_coils,
+    coil_transform::{CoilTransform, apply_coil_transforms},
+    compression::Compression,
+    field_source::BackgroundField,
+    integrator::{IntegratorKind, Tolerances},
+    output::{
+        BinaryPrecision, Decimation, Notation, OutputFormat, OutputLayout, Retention, TextFormat,
+    },
+    particle::OrbitSettings,
+    partition,
+    point::Point,
+    restart::RestartSource,
+    seeding::Seeding,
+    simulation::{Precision, Summation},
+    utils::checksum_file,
+};
+use std::{
+    error::Error,
+    fmt::{self, Debug},
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+/// Name of the resolved run configuration written into every output directory
+pub const RUN_CONFIG_FILE: &str = "run.json";
+
+/// Resolved configuration of a run, as recorded in `run.json`
+#[derive(Debug, Default, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RunConfig {
+    pub resource_path: String,
+    pub coil_files: Vec<String>,
+    /// Particles file, `None` when the particles were generated by `seeding`
+    pub particles_file: Option<String>,
+    #[serde(default)]
+    pub seeding: Option<Seeding>,
+    pub num_particles: usize,
+    pub world_size: i32,
+    /// Checksum of the particles file, `None` in runs recorded without checksums
+    pub particles_checksum: Option<String>,
+    /// Layout of the coil files, detected from `resource_path` when `auto`
+    #[serde(default)]
+    pub coil_format: CoilFormat,
+    /// Checksums of `coil_files`, in the same order
+    #[serde(default)]
+    pub coil_checksums: Vec<String>,
+    /// Changes of the coil geometry applied after reading the coils
+    #[serde(default)]
+    pub coil_transforms: Vec<CoilTransform>,
+    /// Segments every coil was resampled to after the transforms, `None`
+    /// when the coils were used as read
+    #[serde(default)]
+    pub coil_segments: Option<u32>,
+    /// Snapshot the particles were taken from instead of `particles_file`
+    pub restart: Option<RestartSource>,
+    /// Step the run was last resumed from after being interrupted
+    #[serde(default)]
+    pub resumed_from: Option<u32>,
+    /// Particles held by each rank, in rank order of the global particle list
+    pub particle_counts: Vec<usize>,
+    /// Particles per rank of the snapshots written before the run was
+    /// resumed on a different number of ranks, oldest first
+    #[serde(default)]
+    pub earlier_decompositions: Vec<Decomposition>,
+    /// Steps between rebalancing active particles across ranks, `None` if never
+    #[serde(default)]
+    pub rebalance_every: Option<u32>,
+    pub steps: u32,
+    pub step_size: f64,
+    #[serde(default)]
+    pub integrator: IntegratorKind,
+    /// Summation of the segment contributions to the field
+    #[serde(default)]
+    pub summation: Summation,
+    /// Floating point width of the field evaluation
+    #[serde(default)]
+    pub precision: Precision,
+    /// Field periods the field was computed by rotating the first one into,
+    /// `None` when every coil was stored
+    #[serde(default)]
+    pub field_periods: Option<u32>,
+    /// Error tolerances of adaptive integration, `None` for fixed step schemes
+    #[serde(default)]
+    pub tolerances: Option<Tolerances>,
+    /// Species and initial energy of full orbit runs, `None` when following field lines
+    #[serde(default)]
+    pub orbit: Option<OrbitSettings>,
+    pub current: f64,
+    /// Current of each coil, `None` when every coil carries `current`
+    #[serde(default)]
+    pub currents: Option<Vec<f64>>,
+    pub miu: f64,
+    pub major_radius: f64,
+    pub minor_radius: f64,
+    /// Wall particles were lost at
+    #[serde(default)]
+    pub boundary: LossBoundary,
+    /// Analytic fields added to that of the coils
+    #[serde(default)]
+    pub background_fields: Vec<BackgroundField>,
+    pub write_frequency: u32,
+    /// Turning angle in degrees of curvature decimation, `None` when every
+    /// `write_frequency`-th step is written
+    pub max_turn_angle: Option<f64>,
+    /// Snapshots kept on disk, older ones are deleted unless they are checkpoints
+    pub keep_last: Option<u32>,
+    pub checkpoint_every: Option<u32>,
+    /// Format of the per-rank snapshots
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    /// Whether positions were written per step or per particle
+    #[serde(default)]
+    pub output_layout: OutputLayout,
+    /// Coordinate width of binary snapshots, `None` for the other formats
+    #[serde(default)]
+    pub binary_precision: Option<BinaryPrecision>,
+    /// Compression of the snapshot files, `None` for plain files
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Snapshots of every rank are gathered into one file per step
+    #[serde(default)]
+    pub single_file: bool,
+    /// The magnetic field at the particles was written with every snapshot
+    #[serde(default)]
+    pub write_fields: bool,
+    /// Integrator drift statistics of every step were written to drift.csv
+    #[serde(default)]
+    pub drift_diagnostics: bool,
+    /// Poincaré punctures were recorded to punctures_<rank>.csv
+    #[serde(default)]
+    pub record_punctures: bool,
+    /// The rotational transform of every field line was written to iota.csv
+    #[serde(default)]
+    pub rotational_transform: bool,
+    /// Snapshots and lost particles were written keyed by global particle
+    /// index, independently of the ranks
+    #[serde(default)]
+    pub reproducible: bool,
+    /// Ranks that only wrote snapshots, on top of the `world_size` compute ranks
+    #[serde(default)]
+    pub writer_ranks: Option<u32>,
+    pub output_precision: Option<usize>,
+    pub notation: Notation,
+    pub delimiter: char,
+}
+
+/// Particles held by each rank in the snapshots up to and including
+/// `last_step`
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Decomposition {
+    pub last_step: u32,
+    pub particle_counts: Vec<usize>,
+}
+
+/// What a configuration difference affects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DifferenceKind {
+    /// Coils or physical constants, runs are not comparable or resumable
+    Physics,
+    /// Integration settings such as step count and size
+    Integration,
+    /// Particle input and decomposition
+    Input,
+    /// Output layout and formatting only
+    Output,
+}
+
+impl fmt::Display for DifferenceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DifferenceKind::Physics => "physics",
+            DifferenceKind::Integration => "integration",
+            DifferenceKind::Input => "input",
+            DifferenceKind::Output => "output",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigDifference {
+    pub field: &'static str,
+    pub kind: DifferenceKind,
+    pub left: String,
+    pub right: String,
+}
+
+impl fmt::Display for ConfigDifference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {}: {} -> {}",
+            self.kind, self.field, self.left, self.right
+        )
+    }
+}
+
+fn compare<T: Debug + PartialEq>(
+    differences: &mut Vec<ConfigDifference>,
+    field: &'static str,
+    kind: DifferenceKind,
+    left: &T,
+    right: &T,
+) {
+    if left != right {
+        differences.push(ConfigDifference {
+            field,
+            kind,
+            left: format!("{:?}", left),
+            right: format!("{:?}", right),
+        });
+    }
+}
+
+macro_rules! compare_fields {
+    ($left:expr, $right:expr, $differences:expr, $kind:expr => $($field:ident),+) => {
+        $(compare(&mut $differences, stringify!($field), $kind, &$left.$field, &$right.$field);)+
+    };
+}
+
+impl RunConfig {
+    pub fn new(args: &Args, coil_files: &[PathBuf], particle_counts: Vec<usize>) -> Self {
+        RunConfig {
+            resource_path: args.resource_path.clone(),
+            coil_files: coil_files
+                .iter()
+                .filter_map(|path| path.file_name())
+                .map(|name| name.to_string_lossy().into_owned())
+                .collect(),
+            // Restarted and resumed runs take their particles elsewhere
+            particles_file: args
+                .particles_file
+                .clone()
+                .filter(|_| args.restart_from.is_none() && !args.resume),
+            seeding: args.seeding(),
+            particles_checksum: None,
+            coil_format: args.coil_format,
+            coil_checksums: Vec::new(),
+            coil_transforms: Vec::new(),
+            coil_segments: args.coil_segments,
+            restart: None,
+            resumed_from: None,
+            num_particles: particle_counts.iter().sum(),
+            world_size: particle_counts.len() as i32,
+            particle_counts,
+            earlier_decompositions: Vec::new(),
+            rebalance_every: args.rebalance_every,
+            steps: args.steps,
+            step_size: args.step_size,
+            integrator: args.integrator,
+            summation: args.summation,
+            precision: args.precision,
+            field_periods: args.field_periods,
+            tolerances: Some(args.tolerances()).filter(|_| args.integrator.is_adaptive()),
+            orbit: Some(args.orbit()).filter(|_| args.integrator.is_orbit()),
+            current: args.current,
+            currents: None,
+            miu: args.miu,
+            major_radius: args.major_radius,
+            minor_radius: args.minor_radius,
+            boundary: LossBoundary::Torus,
+            background_fields: args.background_fields.clone(),
+            write_frequency: args.write_frequency,
+            max_turn_angle: match args.decimation() {
+                Decimation::Every(_) | Decimation::Never => None,
+                Decimation::Curvature { .. } => Some(args.max_turn_angle),
+            },
+            keep_last: args.keep_last,
+            checkpoint_every: args.checkpoint_every,
+            output_format: args.output_format,
+            output_layout: args.output_layout,
+            binary_precision: Some(args.binary_precision)
+                .filter(|_| args.output_format == OutputFormat::Binary),
+            compression: args.compress,
+            single_file: args.writes_single_files(),
+            write_fields: args.write_fields,
+            drift_diagnostics: args.drift_diagnostics,
+            record_punctures: args.record_punctures,
+            rotational_transform: args.rotational_transform,
+            reproducible: args.reproducible,
+            writer_ranks: args.writer_ranks,
+            output_precision: None,
+            notation: Notation::Fixed,
+            delimiter: ',',
+        }
+        .with_text_format(&args.text_format())
+    }
+
+    /// Records checksums of the particles file and every coil file
+    pub fn with_input_checksums(self) -> Result<Self, Box<dyn Error>> {
+        let particles_checksum = self
+            .particles_file
+            .as_ref()
+            .map(|file| checksum_file(Path::new(file)))
+            .transpose()?;
+        let coil_checksums = self
+            .coil_files
+            .iter()
+            .map(|file| checksum_file(&self.coil_file_path(file)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(RunConfig {
+            particles_checksum,
+            coil_checksums,
+            ..self
+        })
+    }
+
+    /// Path of one of `coil_files`, `resource_path` itself for single-file coil formats
+    fn coil_file_path(&self, file: &str) -> PathBuf {
+        let resource_path = Path::new(&self.resource_path);
+        if resource_path.is_file() {
+            resource_path.to_path_buf()
+        } else {
+            resource_path.join(file)
+        }
+    }
+
+    /// Coils of the run, read from `resource_path` in `coil_format`,
+    /// transformed by `coil_transforms` and resampled to `coil_segments`
+    pub fn read_coils(&self) -> Result<Vec<Vec<Point>>, Box<dyn Error>> {
+        let mut coils = read_coils(Path::new(&self.resource_path), self.coil_format)?;
+        apply_coil_transforms(&mut coils, &self.coil_transforms)?;
+        if let Some(segments) = self.coil_segments {
+            resample_coils(&mut coils, segments as usize)?;
+        }
+        Ok(coils)
+    }
+
+    /// Fails if an input file changed since its checksum was recorded, so
+    /// that a restart never continues a run from different inputs
+    pub fn verify_input_checksums(&self) -> Result<(), Box<dyn Error>> {
+        let current = self.clone().with_input_checksums()?;
+        let mut changed = Vec::new();
+        if self
+            .particles_checksum
+            .as_ref()
+            .is_some_and(|checksum| Some(checksum) != current.particles_checksum.as_ref())
+        {
+            changed.extend(self.particles_file.clone());
+        }
+        for ((file, recorded), checksum) in self
+            .coil_files
+            .iter()
+            .zip(&self.coil_checksums)
+            .zip(&current.coil_checksums)
+        {
+            if recorded != checksum {
+                changed.push(
+                    Path::new(&self.resource_path)
+                        .join(file)
+                        .display()
+                        .to_string(),
+                );
+            }
+        }
+        if changed.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Input files changed since the run started:\n{}",
+                changed.join("\n")
+            )
+            .into())
+        }
+    }
+
+    /// Configuration of a run resumed at `step` in the output directory of
+    /// the interrupted run described by `interrupted`, keeping its particles
+    /// file and checksum, where it started from, and how its snapshots were
+    /// split if this one splits the particles differently
+    pub fn with_resume(self, interrupted: RunConfig, step: u32) -> Self {
+        let mut earlier_decompositions = interrupted.earlier_decompositions;
+        if interrupted.particle_counts != self.particle_counts {
+            earlier_decompositions.push(Decomposition {
+                last_step: step,
+                particle_counts: interrupted.particle_counts,
+            });
+        }
+        RunConfig {
+            particles_file: interrupted.particles_file,
+            particles_checksum: interrupted.particles_checksum,
+            restart: interrupted.restart,
+            resumed_from: Some(step),
+            earlier_decompositions,
+            ..self
+        }
+    }
+
+    pub fn with_currents(self, currents: Option<Vec<f64>>) -> Self {
+        RunConfig { currents, ..self }
+    }
+
+    pub fn with_coil_transforms(self, coil_transforms: Vec<CoilTransform>) -> Self {
+        RunConfig {
+            coil_transforms,
+            ..self
+        }
+    }
+
+    pub fn with_boundary(self, boundary: LossBoundary) -> Self {
+        RunConfig { boundary, ..self }
+    }
+
+    pub fn with_restart(self, restart: RestartSource) -> Self {
+        RunConfig {
+            restart: Some(restart),
+            ..self
+        }
+    }
+
+    pub fn with_text_format(self, format: &TextFormat) -> Self {
+        RunConfig {
+            output_precision: format.precision,
+            notation: format.notation,
+            delimiter: format.delimiter as char,
+            ..self
+        }
+    }
+
+    pub fn text_format(&self) -> TextFormat {
+        TextFormat {
+            precision: self.output_precision,
+            notation: self.notation,
+            delimiter: self.delimiter as u8,
+        }
+    }
+
+    pub fn retention(&self) -> Retention {
+        Retention {
+            keep_last: self.keep_last,
+            checkpoint_every: self.checkpoint_every,
+        }
+    }
+
+    /// Global index of the first particle held by each rank
+    pub fn particle_offsets(&self) -> Vec<usize> {
+        partition::particle_offsets(&self.particle_counts)
+    }
+
+    /// Particles held by each rank in the snapshots of `step`, which may
+    /// predate a resume on a different number of ranks
+    pub fn particle_counts_at(&self, step: u32) -> &[usize] {
+        self.earlier_decompositions
+            .iter()
+            .find(|decomposition| step <= decomposition.last_step)
+            .map_or(&self.particle_counts, |decomposition| {
+                &decomposition.particle_counts
+            })
+    }
+
+    /// Reads a configuration from a `run.json` file or an output directory containing one
+    pub fn read(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = if path.is_dir() {
+            path.join(RUN_CONFIG_FILE)
+        } else {
+            path.to_path_buf()
+        };
+        let reader = BufReader::new(File::open(&path)?);
+        let config = serde_json::from_reader(reader)
+            .map_err(|err| format!("Error parsing {}: {}", path.display(), err))?;
+        Ok(config)
+    }
+
+    pub fn write(&self, output_dir: &Path) -> Result<(), Box<dyn Error>> {
+        let writer = BufWriter::new(File::create(output_dir.join(RUN_CONFIG_FILE))?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    /// Lists every field that differs between two configurations
+    pub fn diff(&self, other: &RunConfig) -> Vec<ConfigDifference> {
+        use DifferenceKind::*;
+        let mut differences = Vec::new();
+        compare_fields!(self, other, differences, Physics =>
+            coil_files, coil_format, coil_checksums, coil_transforms, coil_segments, current, currents, miu, major_radius, minor_radius, boundary, background_fields, orbit);
+        compare_fields!(self, other, differences, Integration =>
+            steps, step_size, integrator, tolerances, summation, precision, field_periods);
+        compare_fields!(self, other, differences, Input =>
+            resource_path, particles_file, seeding, particles_checksum, restart, resumed_from, num_particles, world_size,
+            particle_counts, rebalance_every);
+        compare_fields!(self, other, differences, Output =>
+            write_frequency, max_turn_angle, keep_last, checkpoint_every, output_format, output_layout, binary_precision, compression, single_file, write_fields, drift_diagnostics, record_punctures, rotational_transform, reproducible, writer_ranks,
+            output_precision, notation, delimiter);
+        differences
+    }
+
+    /// Fails if resuming `self` with the configuration `other` would mix incompatible runs
+    pub fn check_restart_compatibility(&self, other: &RunConfig) -> Result<(), Box<dyn Error>> {
+        let incompatible: Vec<String> = self
+            .diff(other)
+            .iter()
+            .filter(|difference| difference.kind == DifferenceKind::Physics)
+            .map(|difference| difference.to_string())
+            .collect();
+        if incompatible.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Run configurations are not restart compatible:\n{}",
+                incompatible.join("\n")
+            )
+            .into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{I, MAJOR_RADIUS, MINOR_RADIUS, MIU};
+
+    fn config() -> RunConfig {
+        RunConfig {
+            resource_path: "resources".to_string(),
+            coil_files: vec!["Bobina00m.csv".to_string(), "Bobina01m.csv".to_string()],
+            particles_file: Some("input.csv".to_string()),
+            seeding: None,
+            particles_checksum: None,
+            coil_format: CoilFormat::Auto,
+            coil_checksums: Vec::new(),
+            coil_transforms: Vec::new(),
+            coil_segments: None,
+            restart: None,
+            resumed_from: None,
+            num_particles: 10,
+            world_size: 2,
+            particle_counts: vec![5, 5],
+            earlier_decompositions: Vec::new(),
+            rebalance_every: None,
+            steps: 100,
+            step_size: 0.001,
+            integrator: IntegratorKind::Rk4,
+            summation: Summation::Naive,
+            precision: Precision::F64,
+            field_periods: None,
+            tolerances: None,
+            orbit: None,
+            current: I,
+            currents: None,
+            miu: MIU,
+            major_radius: MAJOR_RADIUS,
+            minor_radius: MINOR_RADIUS,
+            boundary: LossBoundary::Torus,
+            background_fields: Vec::new(),
+            write_frequency: 10,
+            max_turn_angle: None,
+            keep_last: None,
+            checkpoint_every: None,
+            output_format: OutputFormat::Text,
+            output_layout: OutputLayout::Snapshots,
+            binary_precision: None,
+            compression: None,
+            single_file: false,
+            write_fields: false,
+            drift_diagnostics: false,
+            record_punctures: false,
+            rotational_transform: false,
+            reproducible: false,
+            writer_ranks: None,
+            output_precision: None,
+            notation: Notation::Fixed,
+            delimiter: ',',
+        }
+    }
+
+    #[test]
+    fn output_differences_are_restart_compatible() {
+        let left = config();
+        let right = RunConfig {
+            steps: 200,
+            notation: Notation::Scientific,
+            ..config()
+        };
+        let differences = left.diff(&right);
+        assert_eq!(differences.len(), 2);
+        assert_eq!(differences[0].field, "steps");
+        assert_eq!(differences[1].kind, DifferenceKind::Output);
+        assert!(left.check_restart_compatibility(&right).is_ok());
+    }
+
+    #[test]
+    fn changed_inputs_fail_verification() {
+        let dir = std::env::temp_dir().join("bs_solctra_checksum_config_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("Bobina00m.csv"), "x,y,z\n1,0,0\n").unwrap();
+        std::fs::write(dir.join("input.csv"), "x,y,z\n0.2,0,0\n").unwrap();
+        let config = RunConfig {
+            resource_path: dir.to_string_lossy().into_owned(),
+            coil_files: vec!["Bobina00m.csv".to_string()],
+            particles_file: Some(dir.join("input.csv").to_string_lossy().into_owned()),
+            ..Default::default()
+        }
+        .with_input_checksums()
+        .unwrap();
+        assert!(config.verify_input_checksums().is_ok());
+
+        std::fs::write(dir.join("Bobina00m.csv"), "x,y,z\n2,0,0\n").unwrap();
+        let err = config.verify_input_checksums().unwrap_err().to_string();
+        assert!(err.contains("Bobina00m.csv"));
+        assert!(!err.contains("input.csv"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn coil_differences_block_restart() {
+        let left = config();
+        let right = RunConfig {
+            coil_files: vec!["Bobina00m.csv".to_string()],
+            ..config()
+        };
+        assert!(left.check_restart_compatibility(&right).is_err());
+    }
+}
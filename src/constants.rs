@@ -3,3 +3,34 @@ pub(crate) const MAJOR_RADIUS: f64 = 0.2381;
 pub(crate) const MIU: f64 = 1.2566e-06;
 pub(crate) const PI: f64 = std::f64::consts::PI;
 pub(crate) const I: f64 = -4350.0;
+
+/// Device and coil parameters a run can override, defaulting to the constants
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PhysicsParams {
+    /// Coil current in amperes
+    pub current: f64,
+    /// Vacuum permeability
+    pub miu: f64,
+    pub major_radius: f64,
+    /// Distance to the magnetic axis beyond which particles are lost with
+    /// the default torus boundary
+    pub minor_radius: f64,
+}
+
+impl Default for PhysicsParams {
+    fn default() -> Self {
+        PhysicsParams {
+            current: I,
+            miu: MIU,
+            major_radius: MAJOR_RADIUS,
+            minor_radius: MINOR_RADIUS,
+        }
+    }
+}
+
+impl PhysicsParams {
+    /// Factor of the Biot-Savart law applied to every segment
+    pub fn field_multiplier(&self) -> f64 {
+        (self.miu * self.current) / (4.0 * PI)
+    }
+}
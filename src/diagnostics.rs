@@ -0,0 +1,1025 @@
+use crate::{
+    collectives::Collectives,
+    constants::{PI, PhysicsParams},
+    integrator::IntegrationState,
+    point::Point,
+    simulation::{CoilSet, distance_to_axis, simulate_step},
+};
+use rayon::prelude::*;
+use std::{
+    error::Error,
+    fs::{self, OpenOptions},
+    path::{Path, PathBuf},
+};
+
+/// Settings of the finite-time Lyapunov exponent estimate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LyapunovSettings {
+    pub steps: u32,
+    pub step_size: f64,
+    /// Initial distance between the two field lines of a pair
+    pub separation: f64,
+    /// Steps between renormalisations of the pair separation
+    pub renormalize_every: u32,
+}
+
+/// Finite-time Lyapunov exponent of the field line through one starting point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LyapunovEstimate {
+    /// Mean exponential growth rate of the separation per unit field line length
+    pub exponent: f64,
+    /// Field line length the estimate covers
+    pub length: f64,
+    /// Whether either line of the pair left the minor radius before `steps`
+    pub lost: bool,
+}
+
+/// Traces the field line through `start` together with a neighbour displaced
+/// by `settings.separation` and averages the logarithmic growth of their
+/// distance, renormalising it back to the initial separation periodically
+pub fn lyapunov_exponent(
+    start: &Point,
+    coils: &CoilSet,
+    settings: &LyapunovSettings,
+) -> LyapunovEstimate {
+    let mut line = *start;
+    let mut neighbour = Point {
+        z: start.z + settings.separation,
+        ..*start
+    };
+    let mut log_growth = 0.0;
+    let mut renormalized_steps = 0;
+    let mut lost = false;
+    for step in 1..=settings.steps {
+        let moved = (
+            simulate_step(&line, coils, settings.step_size),
+            simulate_step(&neighbour, coils, settings.step_size),
+        );
+        let (Some(next_line), Some(next_neighbour)) = moved else {
+            lost = true;
+            break;
+        };
+        (line, neighbour) = (next_line, next_neighbour);
+        if step.is_multiple_of(settings.renormalize_every) || step == settings.steps {
+            let offset = neighbour.get_displacement(&line);
+            let distance = offset.get_norm();
+            log_growth += (distance / settings.separation).ln();
+            renormalized_steps = step;
+            let scale = settings.separation / distance;
+            neighbour = Point {
+                x: line.x + offset.x * scale,
+                y: line.y + offset.y * scale,
+                z: line.z + offset.z * scale,
+            };
+        }
+    }
+    let length = renormalized_steps as f64 * settings.step_size;
+    LyapunovEstimate {
+        exponent: if length > 0.0 {
+            log_growth / length
+        } else {
+            f64::NAN
+        },
+        length,
+        lost,
+    }
+}
+
+/// Largest ratio between the mean distance of punctures `m` toroidal turns
+/// apart and of consecutive punctures for a field line to count as lying on
+/// an island chain with `m` islands
+const ISLAND_RETURN_RATIO: f64 = 0.25;
+
+/// Minimum number of punctures per island needed to measure it
+const MIN_PUNCTURES_PER_ISLAND: usize = 3;
+
+/// Position in the poloidal plane at toroidal angle zero, as major radius
+/// `r` and height `z`
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct PoloidalPoint {
+    pub r: f64,
+    pub z: f64,
+}
+
+impl PoloidalPoint {
+    /// Distance to the magnetic axis at `major_radius`
+    fn minor_radius(&self, major_radius: f64) -> f64 {
+        (self.r - major_radius).hypot(self.z)
+    }
+
+    /// Angle around the magnetic axis at `major_radius`
+    fn poloidal_angle(&self, major_radius: f64) -> f64 {
+        self.z.atan2(self.r - major_radius)
+    }
+}
+
+/// Where a field line segment crosses the half plane y = 0, x > 0, by
+/// linear interpolation
+pub fn poincare_crossing(from: &Point, to: &Point) -> Option<PoloidalPoint> {
+    if (from.y < 0.0) == (to.y < 0.0) {
+        return None;
+    }
+    let t = -from.y / (to.y - from.y);
+    let x = from.x + t * (to.x - from.x);
+    let z = from.z + t * (to.z - from.z);
+    (x > 0.0).then_some(PoloidalPoint { r: x, z })
+}
+
+/// Island chain found on one rational surface
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct IslandChain {
+    /// Number of islands in the chain, the poloidal mode number m
+    pub poloidal_mode: usize,
+    /// Toroidal mode number n of the rational rotational transform n/m
+    pub toroidal_mode: i64,
+    /// Measured rotational transform of the field line
+    pub iota: f64,
+    /// Mean radial extent of the islands
+    pub width: f64,
+    pub o_points: Vec<PoloidalPoint>,
+    pub x_points: Vec<PoloidalPoint>,
+}
+
+/// Angle in [-π, π)
+fn wrap_angle(angle: f64) -> f64 {
+    (angle + PI).rem_euclid(2.0 * PI) - PI
+}
+
+/// Toroidal angle about the z axis and poloidal angle about the circle of
+/// major radius in the z = 0 plane that a particle swept while confined,
+/// unwrapped, in radians
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SweptAngles {
+    pub toroidal: f64,
+    pub poloidal: f64,
+}
+
+impl SweptAngles {
+    /// Adds the angles swept by a step from `from` to `to`, which must turn
+    /// by less than half a turn either way
+    pub fn advance(&mut self, from: &Point, to: &Point, major_radius: f64) {
+        let poloidal = |point: &Point| point.z.atan2(point.x.hypot(point.y) - major_radius);
+        self.toroidal += wrap_angle(to.y.atan2(to.x) - from.y.atan2(from.x));
+        self.poloidal += wrap_angle(poloidal(to) - poloidal(from));
+    }
+
+    /// Poloidal over toroidal turns, NaN before any toroidal motion
+    pub fn rotational_transform(&self) -> f64 {
+        if self.toroidal == 0.0 {
+            f64::NAN
+        } else {
+            self.poloidal / self.toroidal
+        }
+    }
+}
+
+/// Rotational transform of a field line from its consecutive punctures,
+/// as the mean poloidal angle advance around the axis at `major_radius` per
+/// toroidal turn over 2π. Sampling once per turn aliases it into [-1/2, 1/2)
+pub fn rotational_transform(punctures: &[PoloidalPoint], major_radius: f64) -> f64 {
+    let advance: f64 = punctures
+        .windows(2)
+        .map(|pair| {
+            wrap_angle(pair[1].poloidal_angle(major_radius) - pair[0].poloidal_angle(major_radius))
+        })
+        .sum();
+    advance / (2.0 * PI * (punctures.len() - 1) as f64)
+}
+
+/// Detects whether the punctures of one field line, in the order they were
+/// made, wind around an island chain of at most `max_mode` islands, and
+/// measures its islands around the axis at `major_radius`
+pub fn detect_island_chain(
+    punctures: &[PoloidalPoint],
+    max_mode: usize,
+    major_radius: f64,
+) -> Option<IslandChain> {
+    let mean_distance = |gap: usize| {
+        let distances: Vec<f64> = punctures
+            .iter()
+            .zip(&punctures[gap..])
+            .map(|(a, b)| (a.r - b.r).hypot(a.z - b.z))
+            .collect();
+        distances.iter().sum::<f64>() / distances.len() as f64
+    };
+    (2..=max_mode)
+        .take_while(|m| punctures.len() >= m * MIN_PUNCTURES_PER_ISLAND)
+        .filter(|&m| mean_distance(m) < ISLAND_RETURN_RATIO * mean_distance(1))
+        .find_map(|m| measure_island_chain(punctures, m, major_radius))
+}
+
+/// Splits the punctures into `mode` islands by toroidal turn and measures
+/// them, `None` if the islands overlap poloidally, as they do for a field
+/// line on a flux surface close to but not at a rational one
+fn measure_island_chain(
+    punctures: &[PoloidalPoint],
+    mode: usize,
+    major_radius: f64,
+) -> Option<IslandChain> {
+    let angle = |point: &PoloidalPoint| point.poloidal_angle(major_radius);
+    let radius = |point: &PoloidalPoint| point.minor_radius(major_radius);
+    let islands: Vec<Vec<PoloidalPoint>> = (0..mode)
+        .map(|island| {
+            punctures
+                .iter()
+                .skip(island)
+                .step_by(mode)
+                .copied()
+                .collect()
+        })
+        .collect();
+    let mut o_points: Vec<PoloidalPoint> = islands
+        .iter()
+        .map(|island| PoloidalPoint {
+            r: island.iter().map(|p| p.r).sum::<f64>() / island.len() as f64,
+            z: island.iter().map(|p| p.z).sum::<f64>() / island.len() as f64,
+        })
+        .collect();
+    let separated = islands.iter().zip(&o_points).all(|(island, o_point)| {
+        island
+            .iter()
+            .all(|p| wrap_angle(angle(p) - angle(o_point)).abs() < PI / mode as f64)
+    });
+    if !separated {
+        return None;
+    }
+    o_points.sort_by(|a, b| angle(a).total_cmp(&angle(b)));
+    let width = islands
+        .iter()
+        .map(|island| {
+            let radii = island.iter().map(radius);
+            let (min, max) = radii.fold((f64::INFINITY, 0.0_f64), |(min, max), r| {
+                (min.min(r), max.max(r))
+            });
+            max - min
+        })
+        .sum::<f64>()
+        / mode as f64;
+    let x_points = o_points
+        .iter()
+        .zip(o_points.iter().cycle().skip(1))
+        .map(|(a, b)| {
+            let mut delta = angle(b) - angle(a);
+            if delta <= 0.0 {
+                delta += 2.0 * PI;
+            }
+            let between = angle(a) + delta / 2.0;
+            let distance = (radius(a) + radius(b)) / 2.0;
+            PoloidalPoint {
+                r: major_radius + distance * between.cos(),
+                z: distance * between.sin(),
+            }
+        })
+        .collect();
+    let iota = rotational_transform(punctures, major_radius);
+    Some(IslandChain {
+        poloidal_mode: mode,
+        toroidal_mode: (iota * mode as f64).round() as i64,
+        iota,
+        width,
+        o_points,
+        x_points,
+    })
+}
+
+/// Fourier mode of |B| on a flux surface, |B| ~ amplitude cos(m theta - n phi + phase)
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct FieldMode {
+    pub m: i64,
+    pub n: i64,
+    pub amplitude: f64,
+}
+
+/// Field ripple of a traced flux surface
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct RippleReport {
+    pub theta_bins: usize,
+    pub phi_bins: usize,
+    /// Fraction of grid cells the field line visited
+    pub coverage: f64,
+    pub mean_field: f64,
+    pub min_field: f64,
+    pub max_field: f64,
+    /// (max - min) / (max + min) of the cell averages
+    pub ripple: f64,
+    /// Share of the non-constant spectral energy in modes with n != 0, zero
+    /// for a quasi-axisymmetric surface
+    pub symmetry_breaking: f64,
+    /// Largest modes other than (0, 0), relative to the mean field
+    pub modes: Vec<FieldMode>,
+}
+
+/// Cell averages of |B| over a (theta, phi) grid, `None` for unvisited cells
+pub struct SurfaceGrid {
+    pub theta_bins: usize,
+    pub phi_bins: usize,
+    pub cells: Vec<Option<f64>>,
+}
+
+impl SurfaceGrid {
+    /// Bins |B| samples taken at `points` of a field line by poloidal angle
+    /// around the circle of `major_radius` and toroidal angle
+    pub fn from_samples(
+        points: &[Point],
+        fields: &[f64],
+        theta_bins: usize,
+        phi_bins: usize,
+        major_radius: f64,
+    ) -> Self {
+        let mut sums = vec![(0.0, 0usize); theta_bins * phi_bins];
+        for (point, field) in points.iter().zip(fields) {
+            let phi = point.y.atan2(point.x).rem_euclid(2.0 * PI);
+            let r = point.x.hypot(point.y);
+            let theta = point.z.atan2(r - major_radius).rem_euclid(2.0 * PI);
+            let i = ((theta / (2.0 * PI) * theta_bins as f64) as usize).min(theta_bins - 1);
+            let j = ((phi / (2.0 * PI) * phi_bins as f64) as usize).min(phi_bins - 1);
+            let cell = &mut sums[i * phi_bins + j];
+            cell.0 += field;
+            cell.1 += 1;
+        }
+        SurfaceGrid {
+            theta_bins,
+            phi_bins,
+            cells: sums
+                .into_iter()
+                .map(|(sum, count)| (count > 0).then(|| sum / count as f64))
+                .collect(),
+        }
+    }
+
+    /// Ripple metrics and the `num_modes` largest Fourier modes of the
+    /// visited cells
+    pub fn ripple_report(&self, num_modes: usize) -> RippleReport {
+        let visited: Vec<(usize, usize, f64)> = self
+            .cells
+            .iter()
+            .enumerate()
+            .filter_map(|(index, cell)| {
+                cell.map(|field| (index / self.phi_bins, index % self.phi_bins, field))
+            })
+            .collect();
+        let count = visited.len().max(1) as f64;
+        let mean_field = visited.iter().map(|cell| cell.2).sum::<f64>() / count;
+        let min_field = visited
+            .iter()
+            .map(|cell| cell.2)
+            .fold(f64::INFINITY, f64::min);
+        let max_field = visited.iter().map(|cell| cell.2).fold(0.0, f64::max);
+
+        let mut modes = Vec::new();
+        let max_m = (self.theta_bins / 2) as i64;
+        let max_n = (self.phi_bins / 2) as i64;
+        for m in 0..=max_m {
+            for n in -max_n..=max_n {
+                if m == 0 && n <= 0 {
+                    continue;
+                }
+                let (mut re, mut im) = (0.0, 0.0);
+                for (i, j, field) in &visited {
+                    let theta = 2.0 * PI * (*i as f64 + 0.5) / self.theta_bins as f64;
+                    let phi = 2.0 * PI * (*j as f64 + 0.5) / self.phi_bins as f64;
+                    let angle = m as f64 * theta - n as f64 * phi;
+                    re += (field - mean_field) * angle.cos();
+                    im += (field - mean_field) * angle.sin();
+                }
+                let amplitude = 2.0 * re.hypot(im) / count;
+                modes.push(FieldMode { m, n, amplitude });
+            }
+        }
+        let energy = |modes: &mut dyn Iterator<Item = &FieldMode>| {
+            modes.map(|mode| mode.amplitude.powi(2)).sum::<f64>()
+        };
+        let total = energy(&mut modes.iter());
+        let breaking = energy(&mut modes.iter().filter(|mode| mode.n != 0));
+        modes.sort_by(|a, b| b.amplitude.total_cmp(&a.amplitude));
+        modes.truncate(num_modes);
+        for mode in &mut modes {
+            mode.amplitude /= mean_field;
+        }
+        RippleReport {
+            theta_bins: self.theta_bins,
+            phi_bins: self.phi_bins,
+            coverage: visited.len() as f64 / self.cells.len() as f64,
+            mean_field,
+            min_field,
+            max_field,
+            ripple: (max_field - min_field) / (max_field + min_field),
+            symmetry_breaking: if total > 0.0 { breaking / total } else { 0.0 },
+            modes,
+        }
+    }
+}
+
+/// Loss fraction and mean rotational transform of a set of field lines
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct ConfinementSummary {
+    /// Fraction of field lines that left the minor radius
+    pub loss_fraction: f64,
+    /// Mean rotational transform of the confined field lines with at least
+    /// two Poincaré punctures, NaN if there are none
+    pub iota: f64,
+}
+
+/// Traces every starting point for `steps` steps and summarises confinement
+pub fn confinement_summary(
+    starts: &[Point],
+    coils: &CoilSet,
+    steps: u32,
+    step_size: f64,
+) -> ConfinementSummary {
+    let lines: Vec<Option<f64>> = starts
+        .par_iter()
+        .map(|start| {
+            let mut line = *start;
+            let mut punctures = Vec::new();
+            for _ in 0..steps {
+                let next = simulate_step(&line, coils, step_size)?;
+                punctures.extend(poincare_crossing(&line, &next));
+                line = next;
+            }
+            if punctures.len() >= 2 {
+                Some(rotational_transform(&punctures, coils.physics.major_radius))
+            } else {
+                Some(f64::NAN)
+            }
+        })
+        .collect();
+    let lost = lines.iter().filter(|line| line.is_none()).count();
+    let iotas: Vec<f64> = lines
+        .iter()
+        .flatten()
+        .copied()
+        .filter(|iota| !iota.is_nan())
+        .collect();
+    ConfinementSummary {
+        loss_fraction: lost as f64 / starts.len().max(1) as f64,
+        iota: iotas.iter().sum::<f64>() / iotas.len() as f64,
+    }
+}
+
+/// Name of the integrator drift diagnostics inside an output directory
+pub const DRIFT_FILE: &str = "drift.csv";
+
+/// How far the particles advanced in one step stray from an exact
+/// integration, summed over particles. A normalised field line step moves
+/// a particle by the step size, an orbit step by its speed times the step
+/// duration, and a field line on a flux surface drifts neither towards nor
+/// away from the magnetic axis on average.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DriftSums {
+    /// Particles that took the step and stayed confined
+    pub particles: usize,
+    /// Particles whose step ended at a NaN or infinite position
+    pub non_finite: usize,
+    /// Sum of the relative errors of the displacements against the
+    /// expected step length
+    pub length_error: f64,
+    pub length_error_squared: f64,
+    /// Largest magnitude of the relative error
+    pub max_length_error: f64,
+    /// Sum of the changes in distance to the magnetic axis
+    pub radial_drift: f64,
+    pub radial_drift_squared: f64,
+}
+
+impl DriftSums {
+    /// One particle moving from `from` to `to` in a step that should have
+    /// covered `expected_length`
+    pub fn moved(from: &Point, to: &Point, expected_length: f64, physics: &PhysicsParams) -> Self {
+        let length_error =
+            (to.get_displacement(from).get_norm() - expected_length) / expected_length;
+        let radial_drift = distance_to_axis(to, physics) - distance_to_axis(from, physics);
+        DriftSums {
+            particles: 1,
+            non_finite: 0,
+            length_error,
+            length_error_squared: length_error * length_error,
+            max_length_error: length_error.abs(),
+            radial_drift,
+            radial_drift_squared: radial_drift * radial_drift,
+        }
+    }
+
+    /// One particle whose step ended at a NaN or infinite position
+    pub fn non_finite() -> Self {
+        DriftSums {
+            non_finite: 1,
+            ..DriftSums::default()
+        }
+    }
+
+    pub fn merge(self, other: DriftSums) -> Self {
+        DriftSums {
+            particles: self.particles + other.particles,
+            non_finite: self.non_finite + other.non_finite,
+            length_error: self.length_error + other.length_error,
+            length_error_squared: self.length_error_squared + other.length_error_squared,
+            max_length_error: self.max_length_error.max(other.max_length_error),
+            radial_drift: self.radial_drift + other.radial_drift,
+            radial_drift_squared: self.radial_drift_squared + other.radial_drift_squared,
+        }
+    }
+
+    /// The sums that add up over ranks, in a fixed order
+    fn additive(&self) -> [f64; 6] {
+        [
+            self.particles as f64,
+            self.non_finite as f64,
+            self.length_error,
+            self.length_error_squared,
+            self.radial_drift,
+            self.radial_drift_squared,
+        ]
+    }
+
+    fn from_reduced(additive: &[f64], max_length_error: f64) -> Self {
+        DriftSums {
+            particles: additive[0] as usize,
+            non_finite: additive[1] as usize,
+            length_error: additive[2],
+            length_error_squared: additive[3],
+            max_length_error,
+            radial_drift: additive[4],
+            radial_drift_squared: additive[5],
+        }
+    }
+}
+
+/// Drift statistics of one step over the particles of every rank, one row
+/// of the drift diagnostics file. Means are NaN when no particle took the step.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DriftRecord {
+    pub step: u32,
+    pub particles: usize,
+    pub non_finite: usize,
+    pub mean_length_error: f64,
+    pub rms_length_error: f64,
+    pub max_length_error: f64,
+    /// Mean change in distance to the magnetic axis, in metres
+    pub mean_radial_drift: f64,
+    pub rms_radial_drift: f64,
+}
+
+impl DriftRecord {
+    pub fn new(step: u32, sums: &DriftSums) -> Self {
+        let count = sums.particles as f64;
+        DriftRecord {
+            step,
+            particles: sums.particles,
+            non_finite: sums.non_finite,
+            mean_length_error: sums.length_error / count,
+            rms_length_error: (sums.length_error_squared / count).sqrt(),
+            max_length_error: sums.max_length_error,
+            mean_radial_drift: sums.radial_drift / count,
+            rms_radial_drift: (sums.radial_drift_squared / count).sqrt(),
+        }
+    }
+}
+
+/// Collects the drift sums of every step of a rank and periodically
+/// reduces them over the ranks, rank 0 appending them to `drift.csv`
+#[derive(Debug, Clone)]
+pub struct DriftLog {
+    path: PathBuf,
+    steps: Vec<(u32, DriftSums)>,
+    /// Whether the next write starts the file over, false once it is
+    /// written or when a resumed run continues it
+    truncate: bool,
+}
+
+impl DriftLog {
+    /// Drift log of `output_dir`, continuing an existing one when the run
+    /// starts after step 0
+    pub fn new(output_dir: &Path, first_step: u32) -> Self {
+        let path = output_dir.join(DRIFT_FILE);
+        DriftLog {
+            truncate: first_step == 0 || !path.exists(),
+            path,
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, step: u32, sums: DriftSums) {
+        self.steps.push((step, sums));
+    }
+
+    /// Reduces the steps recorded since the last flush over the ranks of
+    /// `comm` and writes them on rank 0. Collective, every rank must have
+    /// recorded the same steps.
+    pub fn flush(&mut self, comm: &impl Collectives) -> Result<(), Box<dyn Error>> {
+        let additive: Vec<f64> = self
+            .steps
+            .iter()
+            .flat_map(|(_, sums)| sums.additive())
+            .collect();
+        let maxima: Vec<f64> = self
+            .steps
+            .iter()
+            .map(|(_, sums)| sums.max_length_error)
+            .collect();
+        let steps = std::mem::take(&mut self.steps);
+        let (Some(additive), Some(maxima)) = (comm.sum_values(&additive), comm.max_values(&maxima))
+        else {
+            return Ok(());
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(!self.truncate)
+            .truncate(self.truncate)
+            .open(&self.path)?;
+        let mut wtr = csv::WriterBuilder::new()
+            .has_headers(self.truncate)
+            .from_writer(file);
+        for (((step, _), additive), max_length_error) in
+            steps.iter().zip(additive.chunks(6)).zip(maxima)
+        {
+            let sums = DriftSums::from_reduced(additive, max_length_error);
+            wtr.serialize(DriftRecord::new(*step, &sums))?;
+        }
+        wtr.flush()?;
+        self.truncate = false;
+        Ok(())
+    }
+}
+
+/// Name of the file of the Poincaré punctures a rank recorded
+pub fn punctures_file_name(rank: i32) -> String {
+    format!("punctures_{}.csv", rank)
+}
+
+/// One Poincaré puncture, one row of a punctures file
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Puncture {
+    /// Global index
+    pub particle: u64,
+    /// Step whose segment crossed the plane
+    pub step: u32,
+    pub r: f64,
+    pub z: f64,
+}
+
+/// Collects the Poincaré punctures of the particles a rank advances, by
+/// interpolating within each integration step, and appends them to its
+/// punctures file whenever a snapshot is written
+#[derive(Debug, Clone)]
+pub struct PunctureLog {
+    path: PathBuf,
+    punctures: Vec<Puncture>,
+    /// Whether the next write starts the file over, false once it is
+    /// written or when a resumed run continues it
+    truncate: bool,
+}
+
+impl PunctureLog {
+    /// Puncture log of `rank` in `output_dir`, continuing an existing one
+    /// when the run starts after step 0
+    pub fn new(output_dir: &Path, rank: i32, first_step: u32) -> Self {
+        let path = output_dir.join(punctures_file_name(rank));
+        PunctureLog {
+            truncate: first_step == 0 || !path.exists(),
+            path,
+            punctures: Vec::new(),
+        }
+    }
+
+    /// Records the punctures `states` made in `step`
+    pub fn record<'a>(&mut self, step: u32, states: impl Iterator<Item = &'a IntegrationState>) {
+        self.punctures.extend(states.filter_map(|state| {
+            state.puncture.map(|puncture| Puncture {
+                particle: state.index,
+                step,
+                r: puncture.r,
+                z: puncture.z,
+            })
+        }));
+    }
+
+    /// Appends the punctures recorded since the last flush
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(!self.truncate)
+            .truncate(self.truncate)
+            .open(&self.path)?;
+        let mut wtr = csv::WriterBuilder::new()
+            .has_headers(self.truncate)
+            .from_writer(file);
+        for puncture in self.punctures.drain(..) {
+            wtr.serialize(puncture)?;
+        }
+        wtr.flush()?;
+        self.truncate = false;
+        Ok(())
+    }
+}
+
+/// Poincaré punctures of each of `num_particles` particles, in the order
+/// they were made, from the punctures files every rank of a run in
+/// `run_dir` wrote. Punctures recorded again by runs restarted from an
+/// earlier step are kept once.
+pub fn read_punctures(
+    run_dir: &Path,
+    num_particles: usize,
+) -> Result<Vec<Vec<PoloidalPoint>>, Box<dyn Error>> {
+    let mut punctures: Vec<Puncture> = Vec::new();
+    let mut files = 0;
+    for entry in fs::read_dir(run_dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|name| name.to_str());
+        let is_punctures = name.is_some_and(|name| {
+            name.strip_prefix("punctures_")
+                .and_then(|name| name.strip_suffix(".csv"))
+                .is_some_and(|rank| rank.parse::<i32>().is_ok())
+        });
+        if !is_punctures {
+            continue;
+        }
+        files += 1;
+        for puncture in csv::Reader::from_path(&path)?.deserialize() {
+            let puncture: Puncture = puncture?;
+            if puncture.particle as usize >= num_particles {
+                return Err(format!(
+                    "{} holds particle {} of {}",
+                    path.display(),
+                    puncture.particle,
+                    num_particles
+                )
+                .into());
+            }
+            punctures.push(puncture);
+        }
+    }
+    if files == 0 {
+        return Err(format!(
+            "{} holds no punctures, run the simulation with --record-punctures",
+            run_dir.display()
+        )
+        .into());
+    }
+    punctures.sort_by_key(|puncture| (puncture.particle, puncture.step));
+    punctures.dedup_by_key(|puncture| (puncture.particle, puncture.step));
+    let mut lines = vec![Vec::new(); num_particles];
+    for puncture in punctures {
+        lines[puncture.particle as usize].push(PoloidalPoint {
+            r: puncture.r,
+            z: puncture.z,
+        });
+    }
+    Ok(lines)
+}
+
+/// Name of the rotational transform of every field line inside an output directory
+pub const IOTA_FILE: &str = "iota.csv";
+
+/// Rotational transform of the field line of one particle over the steps of
+/// a run, one row of the rotational transform file
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RotationalTransform {
+    /// Global index
+    pub particle: usize,
+    /// Distance to the circle of major radius at the first step
+    pub start_radius: f64,
+    pub toroidal_turns: f64,
+    pub poloidal_turns: f64,
+    pub iota: f64,
+    /// Whether the particle was lost, so its turns end at the loss
+    pub lost: bool,
+}
+
+/// Rotational transforms of the particles of every rank of `comm` in global
+/// order on rank 0, `None` on the others. `start_radii` are the distances
+/// to the axis the particles this rank was given started the run at, which
+/// may since have been rebalanced onto other ranks, and `states` those the
+/// rank holds now. Collective.
+pub fn gather_rotational_transforms(
+    start_radii: &[f64],
+    states: &[IntegrationState],
+    comm: &impl Collectives,
+) -> Result<Option<Vec<RotationalTransform>>, Box<dyn Error>> {
+    // Indices and the lost flag travel as floats, exact far beyond any count
+    let local: Vec<f64> = states
+        .iter()
+        .flat_map(|state| {
+            let lost = if state.status.is_active() { 0.0 } else { 1.0 };
+            [
+                state.index as f64,
+                state.angles.toroidal,
+                state.angles.poloidal,
+                lost,
+            ]
+        })
+        .collect();
+    let start_radii = comm.gather_values(start_radii)?;
+    let gathered = comm.gather_values(&local)?;
+    let (Some(start_radii), Some(gathered)) = (start_radii, gathered) else {
+        return Ok(None);
+    };
+    let turns = |angle: f64| angle / (2.0 * PI);
+    let mut transforms: Vec<RotationalTransform> = gathered
+        .chunks_exact(4)
+        .map(|values| {
+            let particle = values[0] as usize;
+            let angles = SweptAngles {
+                toroidal: values[1],
+                poloidal: values[2],
+            };
+            RotationalTransform {
+                particle,
+                start_radius: start_radii[particle],
+                toroidal_turns: turns(angles.toroidal),
+                poloidal_turns: turns(angles.poloidal),
+                iota: angles.rotational_transform(),
+                lost: values[3] != 0.0,
+            }
+        })
+        .collect();
+    transforms.sort_by_key(|transform| transform.particle);
+    Ok(Some(transforms))
+}
+
+pub fn write_rotational_transforms(
+    path: &Path,
+    transforms: &[RotationalTransform],
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_path(path)?;
+    for transform in transforms {
+        wtr.serialize(transform)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+pub fn read_rotational_transforms(path: &Path) -> Result<Vec<RotationalTransform>, Box<dyn Error>> {
+    let mut rdr = csv::Reader::from_path(path)?;
+    let mut transforms = Vec::new();
+    for result in rdr.deserialize() {
+        transforms.push(result?);
+    }
+    Ok(transforms)
+}
+
+/// Reads the drift diagnostics written to `path`
+pub fn read_drift(path: &Path) -> Result<Vec<DriftRecord>, Box<dyn Error>> {
+    let mut rdr = csv::Reader::from_path(path)?;
+    let mut records = Vec::new();
+    for result in rdr.deserialize() {
+        records.push(result?);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{collectives::SingleProcess, constants::MAJOR_RADIUS, particle::ParticleState};
+
+    #[test]
+    fn detects_three_island_chain() {
+        // A field line circling slowly around the O points of three islands
+        // at minor radius 0.05, advancing one island per toroidal turn
+        for major_radius in [MAJOR_RADIUS, 0.5] {
+            let punctures: Vec<PoloidalPoint> = (0..60)
+                .map(|turn| {
+                    let island = (turn % 3) as f64 * 2.0 * PI / 3.0 + 0.3;
+                    let around = turn as f64 * 0.1;
+                    PoloidalPoint {
+                        r: major_radius + 0.05 * island.cos() + 0.004 * around.cos(),
+                        z: 0.05 * island.sin() + 0.004 * around.sin(),
+                    }
+                })
+                .collect();
+            let chain = detect_island_chain(&punctures, 10, major_radius).unwrap();
+            assert_eq!(chain.poloidal_mode, 3);
+            assert_eq!(chain.toroidal_mode, 1);
+            assert_eq!(chain.o_points.len(), 3);
+            assert_eq!(chain.x_points.len(), 3);
+            assert!((chain.width - 0.008).abs() < 1e-3);
+            for point in chain.o_points.iter().chain(&chain.x_points) {
+                assert!((point.minor_radius(major_radius) - 0.05).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn toroidal_ripple_shows_up_as_n_mode() {
+        let mut points = Vec::new();
+        let mut fields = Vec::new();
+        for i in 0..64 {
+            for j in 0..64 {
+                let theta = 2.0 * PI * (i as f64 + 0.5) / 64.0;
+                let phi = 2.0 * PI * (j as f64 + 0.5) / 64.0;
+                let r = MAJOR_RADIUS + 0.05 * theta.cos();
+                points.push(Point {
+                    x: r * phi.cos(),
+                    y: r * phi.sin(),
+                    z: 0.05 * theta.sin(),
+                });
+                fields.push(1.0 + 0.01 * (3.0 * phi).cos());
+            }
+        }
+        let report =
+            SurfaceGrid::from_samples(&points, &fields, 16, 32, MAJOR_RADIUS).ripple_report(3);
+        assert_eq!(report.coverage, 1.0);
+        assert_eq!((report.modes[0].m, report.modes[0].n), (0, 3));
+        assert!((report.modes[0].amplitude - 0.01).abs() < 1e-3);
+        assert!(report.symmetry_breaking > 0.99);
+    }
+
+    #[test]
+    fn crossing_interpolates_onto_the_plane() {
+        let from = Point {
+            x: 0.2,
+            y: -0.01,
+            z: 0.0,
+        };
+        let to = Point {
+            x: 0.2,
+            y: 0.01,
+            z: 0.02,
+        };
+        let crossing = poincare_crossing(&from, &to).unwrap();
+        assert!((crossing.z - 0.01).abs() < 1e-12);
+        assert!(poincare_crossing(&from, &from).is_none());
+    }
+
+    #[test]
+    fn recorded_punctures_are_read_back_per_particle_in_order() {
+        let dir = std::env::temp_dir().join("bs_solctra_punctures_test");
+        fs::create_dir_all(&dir).unwrap();
+        let crossing = |index: u64, r: f64| IntegrationState {
+            index,
+            puncture: Some(PoloidalPoint { r, z: 0.0 }),
+            ..IntegrationState::new(0.001)
+        };
+        let mut first = PunctureLog::new(&dir, 0, 0);
+        first.record(3, [crossing(1, 0.21), IntegrationState::new(0.001)].iter());
+        first.record(9, [crossing(1, 0.23)].iter());
+        first.flush().unwrap();
+        // A run restarted from step 5 on another rank records step 9 again
+        let mut second = PunctureLog::new(&dir, 1, 0);
+        second.record(9, [crossing(1, 0.23)].iter());
+        second.record(7, [crossing(0, 0.25), crossing(1, 0.22)].iter());
+        second.flush().unwrap();
+        let mut resumed = PunctureLog::new(&dir, 1, 10);
+        resumed.record(12, [crossing(0, 0.24)].iter());
+        resumed.flush().unwrap();
+
+        let radii =
+            |line: &Vec<PoloidalPoint>| line.iter().map(|point| point.r).collect::<Vec<_>>();
+        let punctures = read_punctures(&dir, 2).unwrap();
+        assert_eq!(radii(&punctures[0]), [0.25, 0.24]);
+        assert_eq!(radii(&punctures[1]), [0.21, 0.22, 0.23]);
+        assert!(read_punctures(&dir, 1).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+        fs::create_dir_all(&dir).unwrap();
+        assert!(read_punctures(&dir, 2).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn swept_angles_give_the_transform_of_a_helix() {
+        // Two toroidal turns of a line on a circular surface of minor radius
+        // 0.05 advancing 0.3 poloidal turns per toroidal turn
+        let iota = 0.3;
+        let on_surface = |phi: f64| {
+            let theta = iota * phi + 0.5;
+            let r = MAJOR_RADIUS + 0.05 * theta.cos();
+            Point {
+                x: r * phi.cos(),
+                y: r * phi.sin(),
+                z: 0.05 * theta.sin(),
+            }
+        };
+        let steps = 400;
+        let mut state = IntegrationState::new(0.001);
+        for step in 0..steps {
+            let from = on_surface(4.0 * PI * step as f64 / steps as f64);
+            let to = on_surface(4.0 * PI * (step + 1) as f64 / steps as f64);
+            state.angles.advance(&from, &to, MAJOR_RADIUS);
+        }
+        assert!((state.angles.toroidal - 4.0 * PI).abs() < 1e-9);
+        assert!((state.angles.rotational_transform() - iota).abs() < 1e-9);
+        assert!(SweptAngles::default().rotational_transform().is_nan());
+
+        // Rebalanced particles come back in global order
+        let lost = IntegrationState {
+            status: ParticleState::Lost { step: 3 },
+            index: 0,
+            ..state
+        };
+        let confined = IntegrationState { index: 1, ..state };
+        let transforms =
+            gather_rotational_transforms(&[0.01, 0.05], &[confined, lost], &SingleProcess)
+                .unwrap()
+                .unwrap();
+        assert_eq!(transforms.len(), 2);
+        assert_eq!(transforms[0].particle, 0);
+        assert!(transforms[0].lost);
+        assert_eq!(transforms[1].start_radius, 0.05);
+        assert!((transforms[1].toroidal_turns - 2.0).abs() < 1e-9);
+        assert!((transforms[1].poloidal_turns - 0.6).abs() < 1e-9);
+        assert!(!transforms[1].lost);
+    }
+}
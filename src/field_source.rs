@@ -0,0 +1,72 @@
+use crate::point::Point;
+
+/// Anything that contributes to the magnetic field particles are traced
+/// through. The field at a point is the sum over the sources of a run.
+pub trait FieldSource: Sync {
+    /// Field in teslas at `point`
+    fn field_at(&self, point: &Point) -> Point;
+}
+
+/// Analytic field added to that of the coils, selected with
+/// `--background-field`
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum BackgroundField {
+    /// The same field everywhere, such as the vertical field of a pair of
+    /// large poloidal field coils
+    Uniform { bx: f64, by: f64, bz: f64 },
+    /// Field along the toroidal direction falling off as 1/R, `b0` at the
+    /// major radius `r0`, as of an ideal set of toroidal field coils
+    Toroidal { b0: f64, r0: f64 },
+}
+
+impl FieldSource for BackgroundField {
+    fn field_at(&self, point: &Point) -> Point {
+        match *self {
+            BackgroundField::Uniform { bx, by, bz } => Point {
+                x: bx,
+                y: by,
+                z: bz,
+            },
+            BackgroundField::Toroidal { b0, r0 } => {
+                let r_squared = point.x * point.x + point.y * point.y;
+                if r_squared == 0.0 {
+                    return Point::default();
+                }
+                // B0 R0 / R along (-y, x) / R
+                let factor = b0 * r0 / r_squared;
+                Point {
+                    x: -factor * point.y,
+                    y: factor * point.x,
+                    z: 0.0,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toroidal_field_falls_off_with_the_major_radius() {
+        let field = BackgroundField::Toroidal { b0: 0.5, r0: 0.25 };
+        let at_axis = field.field_at(&Point {
+            x: 0.0,
+            y: 0.25,
+            z: 0.1,
+        });
+        assert!((at_axis.x + 0.5).abs() < 1e-12);
+        assert!(at_axis.y.abs() < 1e-12 && at_axis.z == 0.0);
+        let point = Point {
+            x: 0.3,
+            y: 0.4,
+            z: 0.0,
+        };
+        let outside = field.field_at(&point);
+        assert!((outside.get_norm() - 0.25).abs() < 1e-12);
+        assert!(outside.dot(&point).abs() < 1e-12);
+        assert_eq!(field.field_at(&Point::default()), Point::default());
+    }
+}
@@ -0,0 +1,365 @@
+use crate::{
+    coils::CoilGroup,
+    collectives::Collectives,
+    partition::{particle_counts, particle_offsets},
+    point::Point,
+    simulation::{CoilSet, compute_magnetic_field},
+    vtk::{Scalars, Vectors, write_vts_grid},
+};
+use clap::ValueEnum;
+use log::info;
+use rayon::prelude::*;
+use std::{error::Error, path::Path};
+
+/// Coordinates the axes of a field map grid run along
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GridCoordinates {
+    /// x, y and z in metres
+    #[default]
+    Cartesian,
+    /// Major radius R in metres, toroidal angle φ in radians and height Z
+    /// in metres, in that order. Field components are B_R, B_φ and B_Z.
+    Cylindrical,
+}
+
+impl GridCoordinates {
+    fn axis_names(&self) -> [&'static str; 3] {
+        match self {
+            GridCoordinates::Cartesian => ["x", "y", "z"],
+            GridCoordinates::Cylindrical => ["r", "phi", "z"],
+        }
+    }
+}
+
+/// File formats of field maps
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FieldMapFormat {
+    /// One CSV row per grid point, the last axis varying fastest
+    #[default]
+    Csv,
+    /// XML StructuredGrid for ParaView, with Cartesian points and vectors in
+    /// the grid coordinates
+    Vts,
+    /// netCDF with one dimension and coordinate variable per axis, requires
+    /// building with the `netcdf` feature
+    Netcdf,
+}
+
+/// Regular grid of `shape` points per axis from `min` to `max`, both
+/// included, in Cartesian or cylindrical coordinates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Grid {
+    pub coordinates: GridCoordinates,
+    pub min: Point,
+    pub max: Point,
+    pub shape: [usize; 3],
+}
+
+impl Grid {
+    pub fn len(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Coordinates of the points along `axis`
+    pub fn axis(&self, axis: usize) -> Vec<f64> {
+        let (min, max) = match axis {
+            0 => (self.min.x, self.max.x),
+            1 => (self.min.y, self.max.y),
+            _ => (self.min.z, self.max.z),
+        };
+        let count = self.shape[axis];
+        (0..count)
+            .map(|index| {
+                if count > 1 {
+                    min + (max - min) * index as f64 / (count - 1) as f64
+                } else {
+                    min
+                }
+            })
+            .collect()
+    }
+
+    /// Grid coordinates of the point at `index` in grid order, the last
+    /// axis varying fastest
+    fn coordinates_at(&self, index: usize, axes: &[Vec<f64>; 3]) -> [f64; 3] {
+        let [_, ny, nz] = self.shape;
+        [
+            axes[0][index / (ny * nz)],
+            axes[1][index / nz % ny],
+            axes[2][index % nz],
+        ]
+    }
+
+    /// Cartesian position of a point given in grid coordinates
+    fn position(&self, [a, b, c]: [f64; 3]) -> Point {
+        match self.coordinates {
+            GridCoordinates::Cartesian => Point { x: a, y: b, z: c },
+            GridCoordinates::Cylindrical => Point {
+                x: a * b.cos(),
+                y: a * b.sin(),
+                z: c,
+            },
+        }
+    }
+
+    /// Components of the Cartesian vector `v` at a point of the grid along
+    /// the grid coordinates
+    fn components(&self, [_, phi, _]: [f64; 3], v: &Point) -> [f64; 3] {
+        match self.coordinates {
+            GridCoordinates::Cartesian => [v.x, v.y, v.z],
+            GridCoordinates::Cylindrical => [
+                v.x * phi.cos() + v.y * phi.sin(),
+                -v.x * phi.sin() + v.y * phi.cos(),
+                v.z,
+            ],
+        }
+    }
+
+    /// Cartesian positions of every point in grid order
+    pub fn points(&self) -> Vec<Point> {
+        let axes = [self.axis(0), self.axis(1), self.axis(2)];
+        (0..self.len())
+            .map(|index| self.position(self.coordinates_at(index, &axes)))
+            .collect()
+    }
+}
+
+/// The field on a grid: for every point in grid order the components of B
+/// along the grid coordinates and |B|, then the components of dB/dI of
+/// every coil group
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldMap {
+    pub grid: Grid,
+    pub groups: usize,
+    pub values: Vec<f64>,
+}
+
+impl FieldMap {
+    /// Values per grid point
+    pub fn columns(&self) -> usize {
+        4 + 3 * self.groups
+    }
+
+    /// Values of the point at `index` in grid order
+    pub fn row(&self, index: usize) -> &[f64] {
+        &self.values[index * self.columns()..(index + 1) * self.columns()]
+    }
+
+    fn component_names(&self) -> Vec<String> {
+        let axes = self.grid.coordinates.axis_names();
+        let mut names: Vec<String> = axes.iter().map(|axis| format!("b{}", axis)).collect();
+        names.push("b".to_string());
+        for group in 0..self.groups {
+            names.extend(axes.iter().map(|axis| format!("db{}_di_{}", axis, group)));
+        }
+        names
+    }
+}
+
+/// Evaluates the field of `coils`, and of every coil group per ampere, at
+/// the share of the grid points of this rank and gathers the map on rank
+/// 0, `None` on the others. Fails on every rank for maps of more values
+/// than an MPI count.
+pub fn evaluate(
+    grid: &Grid,
+    coils: &CoilSet,
+    groups: &[CoilGroup],
+    comm: &impl Collectives,
+) -> Result<Option<FieldMap>, Box<dyn Error>> {
+    let counts = particle_counts(grid.len(), comm.ranks());
+    let rank = comm.local_rank();
+    let first = particle_offsets(&counts)[rank];
+    let axes = [grid.axis(0), grid.axis(1), grid.axis(2)];
+    let local: Vec<f64> = (first..first + counts[rank])
+        .into_par_iter()
+        .flat_map_iter(|index| {
+            let coordinates = grid.coordinates_at(index, &axes);
+            let point = grid.position(coordinates);
+            let b = compute_magnetic_field(&point, coils);
+            let mut row = grid.components(coordinates, &b).to_vec();
+            row.push(b.get_norm());
+            for group in groups {
+                row.extend(grid.components(coordinates, &group.field_sensitivity(&point)));
+            }
+            row
+        })
+        .collect();
+    let Some(values) = comm.gather_values(&local)? else {
+        return Ok(None);
+    };
+    Ok(Some(FieldMap {
+        grid: *grid,
+        groups: groups.len(),
+        values,
+    }))
+}
+
+/// Writes `map` to `path` in `format`
+pub fn write_field_map(
+    path: &Path,
+    map: &FieldMap,
+    format: FieldMapFormat,
+) -> Result<(), Box<dyn Error>> {
+    match format {
+        FieldMapFormat::Csv => write_csv(path, map)?,
+        FieldMapFormat::Vts => write_vts(path, map)?,
+        FieldMapFormat::Netcdf => write_netcdf(path, map)?,
+    }
+    info!("Wrote {}", path.display());
+    Ok(())
+}
+
+fn write_csv(path: &Path, map: &FieldMap) -> Result<(), Box<dyn Error>> {
+    let grid = &map.grid;
+    let axes = [grid.axis(0), grid.axis(1), grid.axis(2)];
+    let mut wtr = csv::Writer::from_path(path)?;
+    let mut header: Vec<String> = grid
+        .coordinates
+        .axis_names()
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+    header.extend(map.component_names());
+    wtr.write_record(&header)?;
+    for index in 0..grid.len() {
+        let coordinates = grid.coordinates_at(index, &axes);
+        wtr.write_record(
+            coordinates
+                .iter()
+                .chain(map.row(index))
+                .map(|value| value.to_string()),
+        )?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+fn write_vts(path: &Path, map: &FieldMap) -> Result<(), Box<dyn Error>> {
+    let grid = &map.grid;
+    let [nx, ny, nz] = grid.shape;
+    // VTK runs the first axis fastest, the grid order the last one
+    let vtk_order: Vec<usize> = (0..nz)
+        .flat_map(|k| (0..ny).flat_map(move |j| (0..nx).map(move |i| (i * ny + j) * nz + k)))
+        .collect();
+    let positions = grid.points();
+    let points: Vec<Point> = vtk_order.iter().map(|&index| positions[index]).collect();
+    let vector = |column: usize| -> Vec<Point> {
+        vtk_order
+            .iter()
+            .map(|&index| {
+                let row = map.row(index);
+                Point {
+                    x: row[column],
+                    y: row[column + 1],
+                    z: row[column + 2],
+                }
+            })
+            .collect()
+    };
+    let names: Vec<String> = (0..map.groups)
+        .map(|group| format!("dB_dI_{}", group))
+        .collect();
+    let mut fields = vec![vector(0)];
+    fields.extend((0..map.groups).map(|group| vector(4 + 3 * group)));
+    let mut vectors = vec![Vectors {
+        name: "B",
+        values: &fields[0],
+    }];
+    for (name, values) in names.iter().zip(&fields[1..]) {
+        vectors.push(Vectors { name, values });
+    }
+    let magnitude: Vec<f64> = vtk_order.iter().map(|&index| map.row(index)[3]).collect();
+    let scalars = [Scalars {
+        name: "|B|",
+        values: &magnitude,
+    }];
+    write_vts_grid(path, grid.shape, &points, &vectors, &scalars)
+}
+
+#[cfg(feature = "netcdf")]
+fn write_netcdf(path: &Path, map: &FieldMap) -> Result<(), Box<dyn Error>> {
+    let grid = &map.grid;
+    let names = grid.coordinates.axis_names();
+    let mut file = netcdf::create(path)?;
+    for (axis, name) in names.iter().enumerate() {
+        file.add_dimension(name, grid.shape[axis])?;
+    }
+    for (axis, name) in names.iter().enumerate() {
+        let mut variable = file.add_variable::<f64>(name, &[name])?;
+        variable.put_attribute("units", if *name == "phi" { "rad" } else { "m" })?;
+        variable.put_values(&grid.axis(axis), ..)?;
+    }
+    for (column, name) in map.component_names().iter().enumerate() {
+        let values: Vec<f64> = map
+            .values
+            .iter()
+            .skip(column)
+            .step_by(map.columns())
+            .copied()
+            .collect();
+        let mut variable = file.add_variable::<f64>(name, &names)?;
+        variable.put_attribute("units", if column < 4 { "T" } else { "T/A" })?;
+        variable.put_values(&values, ..)?;
+    }
+    let coordinates = format!("{:?}", grid.coordinates).to_lowercase();
+    file.add_attribute("title", "BS-Solctra magnetic field map")?;
+    file.add_attribute(
+        "source",
+        concat!("bs-solctra-rs ", env!("CARGO_PKG_VERSION")),
+    )?;
+    file.add_attribute("coordinates", coordinates)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "netcdf"))]
+fn write_netcdf(_path: &Path, _map: &FieldMap) -> Result<(), Box<dyn Error>> {
+    Err("netCDF output requires building with the `netcdf` feature".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{collectives::SingleProcess, simulation::read_coil_data_directory};
+
+    #[test]
+    fn cylindrical_maps_rotate_the_cartesian_field() {
+        let coils = CoilSet::new(
+            &read_coil_data_directory(Path::new("tests/test-resources/resources")).unwrap(),
+        );
+        let cylindrical = Grid {
+            coordinates: GridCoordinates::Cylindrical,
+            min: Point {
+                x: 0.2,
+                y: 0.0,
+                z: -0.02,
+            },
+            max: Point {
+                x: 0.26,
+                y: 1.5,
+                z: 0.02,
+            },
+            shape: [3, 4, 2],
+        };
+        let map = evaluate(&cylindrical, &coils, &[], &SingleProcess)
+            .unwrap()
+            .unwrap();
+        assert_eq!(map.values.len(), cylindrical.len() * map.columns());
+        for (index, point) in cylindrical.points().iter().enumerate() {
+            let b = compute_magnetic_field(point, &coils);
+            let phi = point.y.atan2(point.x);
+            let row = map.row(index);
+            assert!((row[0] - (b.x * phi.cos() + b.y * phi.sin())).abs() < 1e-12);
+            assert!((row[1] - (b.y * phi.cos() - b.x * phi.sin())).abs() < 1e-12);
+            assert_eq!(row[2], b.z);
+            assert!((row[3] - b.get_norm()).abs() < 1e-12);
+        }
+        // Grid order runs the last axis fastest
+        let points = cylindrical.points();
+        assert_eq!([points[0].z, points[1].z], [-0.02, 0.02]);
+        assert!((points[2].y - 0.2 * 0.5f64.sin()).abs() < 1e-15);
+    }
+}
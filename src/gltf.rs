@@ -0,0 +1,280 @@
+use crate::{point::Point, utils::base64_encode};
+use serde::Serialize;
+use std::{error::Error, fs::File, io::BufWriter, path::Path};
+
+const FLOAT: u32 = 5126;
+const ARRAY_BUFFER: u32 = 34962;
+const LINE_STRIP: u32 = 3;
+
+/// Polylines sharing a name and color, exported as one mesh
+pub struct LineSet {
+    pub name: String,
+    pub color: [f32; 4],
+    pub lines: Vec<Vec<Point>>,
+}
+
+#[derive(Serialize)]
+struct Asset {
+    version: &'static str,
+    generator: &'static str,
+}
+
+#[derive(Serialize)]
+struct Scene {
+    nodes: Vec<usize>,
+}
+
+#[derive(Serialize)]
+struct Node {
+    name: String,
+    mesh: usize,
+}
+
+#[derive(Serialize)]
+struct Attributes {
+    #[serde(rename = "POSITION")]
+    position: usize,
+}
+
+#[derive(Serialize)]
+struct Primitive {
+    attributes: Attributes,
+    material: usize,
+    mode: u32,
+}
+
+#[derive(Serialize)]
+struct Mesh {
+    name: String,
+    primitives: Vec<Primitive>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PbrMetallicRoughness {
+    base_color_factor: [f32; 4],
+    metallic_factor: f32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Material {
+    name: String,
+    pbr_metallic_roughness: PbrMetallicRoughness,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Accessor {
+    buffer_view: usize,
+    component_type: u32,
+    count: usize,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    min: [f32; 3],
+    max: [f32; 3],
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BufferView {
+    buffer: usize,
+    byte_offset: usize,
+    byte_length: usize,
+    target: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Buffer {
+    byte_length: usize,
+    uri: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Gltf {
+    asset: Asset,
+    scene: usize,
+    scenes: Vec<Scene>,
+    nodes: Vec<Node>,
+    meshes: Vec<Mesh>,
+    materials: Vec<Material>,
+    accessors: Vec<Accessor>,
+    buffer_views: Vec<BufferView>,
+    buffers: Vec<Buffer>,
+}
+
+/// Writes line sets as a self-contained glTF 2.0 scene with an embedded
+/// buffer, viewable in three.js, Blender or any browser based glTF viewer
+pub fn write_gltf_scene(path: &Path, line_sets: &[LineSet]) -> Result<(), Box<dyn Error>> {
+    let mut data = Vec::<u8>::new();
+    let mut meshes = Vec::new();
+    let mut materials = Vec::new();
+    let mut accessors = Vec::new();
+    let mut buffer_views = Vec::new();
+    let mut nodes = Vec::new();
+
+    for line_set in line_sets {
+        let mut primitives = Vec::new();
+        for line in line_set.lines.iter().filter(|line| line.len() > 1) {
+            let mut min = [f32::MAX; 3];
+            let mut max = [f32::MIN; 3];
+            let byte_offset = data.len();
+            for point in line {
+                let coordinates = [point.x as f32, point.y as f32, point.z as f32];
+                for (axis, value) in coordinates.iter().enumerate() {
+                    min[axis] = min[axis].min(*value);
+                    max[axis] = max[axis].max(*value);
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            buffer_views.push(BufferView {
+                buffer: 0,
+                byte_offset,
+                byte_length: data.len() - byte_offset,
+                target: ARRAY_BUFFER,
+            });
+            accessors.push(Accessor {
+                buffer_view: buffer_views.len() - 1,
+                component_type: FLOAT,
+                count: line.len(),
+                kind: "VEC3",
+                min,
+                max,
+            });
+            primitives.push(Primitive {
+                attributes: Attributes {
+                    position: accessors.len() - 1,
+                },
+                material: materials.len(),
+                mode: LINE_STRIP,
+            });
+        }
+        // Meshes without primitives are invalid glTF
+        if primitives.is_empty() {
+            continue;
+        }
+        nodes.push(Node {
+            name: line_set.name.clone(),
+            mesh: meshes.len(),
+        });
+        meshes.push(Mesh {
+            name: line_set.name.clone(),
+            primitives,
+        });
+        materials.push(Material {
+            name: line_set.name.clone(),
+            pbr_metallic_roughness: PbrMetallicRoughness {
+                base_color_factor: line_set.color,
+                metallic_factor: 0.0,
+            },
+        });
+    }
+
+    let gltf = Gltf {
+        asset: Asset {
+            version: "2.0",
+            generator: "bs-solctra-rs",
+        },
+        scene: 0,
+        scenes: vec![Scene {
+            nodes: (0..nodes.len()).collect(),
+        }],
+        nodes,
+        meshes,
+        materials,
+        accessors,
+        buffer_views,
+        buffers: vec![Buffer {
+            byte_length: data.len(),
+            uri: format!(
+                "data:application/octet-stream;base64,{}",
+                base64_encode(&data)
+            ),
+        }],
+    };
+    serde_json::to_writer(BufWriter::new(File::create(path)?), &gltf)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct WrittenAccessor {
+        count: usize,
+        min: [f32; 3],
+        max: [f32; 3],
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct WrittenBuffer {
+        byte_length: usize,
+        uri: String,
+    }
+
+    #[derive(Deserialize)]
+    struct WrittenMesh {
+        name: String,
+    }
+
+    #[derive(Deserialize)]
+    struct Written {
+        meshes: Vec<WrittenMesh>,
+        accessors: Vec<WrittenAccessor>,
+        buffers: Vec<WrittenBuffer>,
+    }
+
+    #[test]
+    fn scenes_embed_every_line_longer_than_a_point() {
+        let point = |x: f64, y: f64, z: f64| Point { x, y, z };
+        let line_sets = [
+            LineSet {
+                name: "coils".to_string(),
+                color: [0.8, 0.5, 0.2, 1.0],
+                lines: vec![
+                    vec![
+                        point(0.0, 0.0, 0.0),
+                        point(1.0, -2.0, 0.5),
+                        point(0.5, 1.0, 3.0),
+                    ],
+                    vec![point(4.0, 4.0, 4.0)],
+                ],
+            },
+            LineSet {
+                name: "trajectories".to_string(),
+                color: [0.2, 0.4, 0.9, 1.0],
+                lines: Vec::new(),
+            },
+        ];
+        let path = std::env::temp_dir().join("bs_solctra_gltf_test.gltf");
+        write_gltf_scene(&path, &line_sets).unwrap();
+        let written: Result<Written, _> =
+            serde_json::from_reader(std::io::BufReader::new(File::open(&path).unwrap()));
+        std::fs::remove_file(&path).unwrap();
+        let written = written.unwrap();
+
+        // The single point line and the empty set have nothing to draw
+        let names: Vec<&str> = written
+            .meshes
+            .iter()
+            .map(|mesh| mesh.name.as_str())
+            .collect();
+        assert_eq!(names, ["coils"]);
+        assert_eq!(written.accessors.len(), 1);
+        assert_eq!(written.accessors[0].count, 3);
+        assert_eq!(written.accessors[0].min, [0.0, -2.0, 0.0]);
+        assert_eq!(written.accessors[0].max, [1.0, 1.0, 3.0]);
+        let buffer = &written.buffers[0];
+        assert_eq!(buffer.byte_length, 3 * 3 * 4);
+        let data = buffer
+            .uri
+            .strip_prefix("data:application/octet-stream;base64,")
+            .unwrap();
+        assert_eq!(data.len(), 4 * buffer.byte_length / 3);
+    }
+}
@@ -0,0 +1,290 @@
+use crate::{
+    constants::PI,
+    field_source::FieldSource,
+    integrator::{IntegrationState, Integrator, IntegratorKind, Rk4},
+    point::Point,
+    simulation::{CoilSet, Precision, Summation},
+};
+use cust::{
+    context::{Context, CurrentContext},
+    launch,
+    memory::{CopyDestination, DeviceBuffer},
+    module::Module,
+    stream::{Stream, StreamFlags},
+};
+use std::{error::Error, sync::Mutex};
+
+/// `biot_savart.cu` as compiled by build.rs
+static PTX: &str = include_str!(concat!(env!("OUT_DIR"), "/biot_savart.ptx"));
+
+/// Threads per block of the kernel, one per point
+const BLOCK_SIZE: u32 = 128;
+
+/// Coil segments of a `CoilSet` on the device, in the layout of its buffers
+struct CoilBuffers {
+    x: DeviceBuffer<f64>,
+    y: DeviceBuffer<f64>,
+    z: DeviceBuffer<f64>,
+    e_x: DeviceBuffer<f64>,
+    e_y: DeviceBuffer<f64>,
+    e_z: DeviceBuffer<f64>,
+    lengths: DeviceBuffer<f64>,
+    /// Field multiplier of the coil of each segment, 0 where no segment starts
+    multipliers: DeviceBuffer<f64>,
+    num_segments: u32,
+}
+
+/// Kernel and coils on the device. The context is declared last so that it
+/// is destroyed after everything that lives in it.
+struct Device {
+    coils: CoilBuffers,
+    module: Module,
+    stream: Stream,
+    context: Context,
+}
+
+/// Biot–Savart field of the stored coils of a set evaluated on a CUDA
+/// device, one kernel launch per batch of points
+pub struct GpuField {
+    device: Mutex<Device>,
+}
+
+impl GpuField {
+    /// Uploads the coils of `coils` to the first device this process sees.
+    /// Fails without a device or for sums the kernel does not do, which are
+    /// compensated or single precision ones.
+    pub fn new(coils: &CoilSet) -> Result<Self, Box<dyn Error>> {
+        if coils.summation != Summation::Naive || coils.precision != Precision::F64 {
+            return Err("the GPU kernel only sums in double precision without compensation".into());
+        }
+        let context = cust::quick_init()?;
+        let module = Module::from_ptx(PTX, &[])?;
+        let stream = Stream::new(StreamFlags::NON_BLOCKING, None)?;
+        let mut multipliers = vec![0.0; coils.num_points().saturating_sub(1)];
+        for coil in 0..coils.len() {
+            for j in coils.segments(coil) {
+                multipliers[j] = coils.field_multiplier(coil);
+            }
+        }
+        let coils = CoilBuffers {
+            x: DeviceBuffer::from_slice(&coils.x)?,
+            y: DeviceBuffer::from_slice(&coils.y)?,
+            z: DeviceBuffer::from_slice(&coils.z)?,
+            e_x: DeviceBuffer::from_slice(&coils.e_x)?,
+            e_y: DeviceBuffer::from_slice(&coils.e_y)?,
+            e_z: DeviceBuffer::from_slice(&coils.e_z)?,
+            lengths: DeviceBuffer::from_slice(&coils.lengths)?,
+            num_segments: multipliers.len() as u32,
+            multipliers: DeviceBuffer::from_slice(&multipliers)?,
+        };
+        Ok(GpuField {
+            device: Mutex::new(Device {
+                coils,
+                module,
+                stream,
+                context,
+            }),
+        })
+    }
+
+    /// Field at every point of `points`, as `compute_magnetic_field` gives it
+    /// for `coils`, the set the field was created from. The points of every
+    /// field period go to the device in one launch, the background fields
+    /// are added on the host.
+    pub fn fields(&self, points: &[Point], coils: &CoilSet) -> Result<Vec<Point>, Box<dyn Error>> {
+        let periods = coils.field_periods.max(1);
+        let rotations: Vec<(f64, f64)> = (0..periods)
+            .map(|period| (2.0 * PI * period as f64 / periods as f64).sin_cos())
+            .collect();
+        // As in `coils_field`, the field of each period is that of the stored
+        // one at the point rotated back
+        let mut rotated = Vec::with_capacity(3 * periods * points.len());
+        for (sin, cos) in &rotations {
+            for point in points {
+                rotated.extend([
+                    cos * point.x + sin * point.y,
+                    cos * point.y - sin * point.x,
+                    point.z,
+                ]);
+            }
+        }
+        let stored = self.stored_fields(&rotated)?;
+        let mut fields = vec![Point::default(); points.len()];
+        for (period, (sin, cos)) in rotations.iter().enumerate() {
+            let period_fields = stored[3 * period * points.len()..].chunks_exact(3);
+            for (b, field) in fields.iter_mut().zip(period_fields) {
+                b.x += cos * field[0] - sin * field[1];
+                b.y += sin * field[0] + cos * field[1];
+                b.z += field[2];
+            }
+        }
+        for (b, point) in fields.iter_mut().zip(points) {
+            for source in &coils.background {
+                let field = source.field_at(point);
+                b.x += field.x;
+                b.y += field.y;
+                b.z += field.z;
+            }
+        }
+        Ok(fields)
+    }
+
+    /// Field of the stored coils at interleaved x, y, z `points`, interleaved
+    fn stored_fields(&self, points: &[f64]) -> Result<Vec<f64>, Box<dyn Error>> {
+        let mut values = vec![0.0; points.len()];
+        if points.is_empty() {
+            return Ok(values);
+        }
+        let device = self
+            .device
+            .lock()
+            .map_err(|_| "the GPU device lock is poisoned")?;
+        // Steps may run on another thread than the one that created the context
+        CurrentContext::set_current(&device.context)?;
+        let num_points = (points.len() / 3) as u32;
+        let points = DeviceBuffer::from_slice(points)?;
+        let fields = DeviceBuffer::from_slice(&values)?;
+        let coils = &device.coils;
+        let stream = &device.stream;
+        let kernel = device.module.get_function("biot_savart")?;
+        // Safety: the buffers hold `num_points` points and fields and the
+        // `num_segments + 1` coil points the kernel reads
+        unsafe {
+            launch!(kernel<<<num_points.div_ceil(BLOCK_SIZE), BLOCK_SIZE, 0, stream>>>(
+                points.as_device_ptr(),
+                num_points,
+                coils.x.as_device_ptr(),
+                coils.y.as_device_ptr(),
+                coils.z.as_device_ptr(),
+                coils.e_x.as_device_ptr(),
+                coils.e_y.as_device_ptr(),
+                coils.e_z.as_device_ptr(),
+                coils.lengths.as_device_ptr(),
+                coils.multipliers.as_device_ptr(),
+                coils.num_segments,
+                fields.as_device_ptr()
+            ))?;
+        }
+        stream.synchronize()?;
+        fields.copy_to(&mut values[..])?;
+        Ok(values)
+    }
+}
+
+/// Classic fourth order Runge–Kutta, as `Rk4`, whose stages are evaluated
+/// for every particle of a step at once on the device
+pub struct GpuRk4 {
+    pub step_size: f64,
+    field: GpuField,
+}
+
+impl GpuRk4 {
+    /// Fails unless `kind` is the fixed step `Rk4`, the only scheme batched
+    /// on the device, or as `GpuField::new` does
+    pub fn new(
+        kind: IntegratorKind,
+        step_size: f64,
+        coils: &CoilSet,
+    ) -> Result<Self, Box<dyn Error>> {
+        if kind != IntegratorKind::Rk4 {
+            return Err(format!("the GPU backend only batches rk4 steps, not {:?}", kind).into());
+        }
+        Ok(GpuRk4 {
+            step_size,
+            field: GpuField::new(coils)?,
+        })
+    }
+
+    /// Normalised `fields` scaled to the step size, the `k` of a stage
+    fn stage(&self, fields: &[Point]) -> Vec<Point> {
+        fields
+            .iter()
+            .map(|field| {
+                let norm = field.get_norm();
+                Point {
+                    x: (field.x / norm) * self.step_size,
+                    y: (field.y / norm) * self.step_size,
+                    z: (field.z / norm) * self.step_size,
+                }
+            })
+            .collect()
+    }
+}
+
+/// `starts` moved by `fraction` of the `k` of a stage
+fn displaced(starts: &[Point], k: &[Point], fraction: f64) -> Vec<Point> {
+    starts
+        .iter()
+        .zip(k)
+        .map(|(start, k)| Point {
+            x: k.x * fraction + start.x,
+            y: k.y * fraction + start.y,
+            z: k.z * fraction + start.z,
+        })
+        .collect()
+}
+
+impl Integrator for GpuRk4 {
+    fn step_size(&self) -> f64 {
+        self.step_size
+    }
+
+    fn prepare(
+        &self,
+        particles: &[Point],
+        coils: &CoilSet,
+        states: &mut [IntegrationState],
+        lent: &[bool],
+    ) -> Result<(), Box<dyn Error>> {
+        let active: Vec<usize> = (0..particles.len())
+            .filter(|&index| {
+                states[index].status.is_active() && !lent.get(index).copied().unwrap_or(false)
+            })
+            .collect();
+        let starts: Vec<Point> = active.iter().map(|&index| particles[index]).collect();
+        // Fields kept from the end of the last step start this one, as on the CPU
+        let missing: Vec<Point> = active
+            .iter()
+            .filter(|&&index| states[index].field.is_none())
+            .map(|&index| particles[index])
+            .collect();
+        let mut computed = self.field.fields(&missing, coils)?.into_iter();
+        let fields: Vec<Point> = active
+            .iter()
+            .map(|&index| states[index].field.take().or_else(|| computed.next()))
+            .collect::<Option<_>>()
+            .ok_or("the device returned fewer fields than points")?;
+        let k1 = self.stage(&fields);
+        let k2 = self.stage(&self.field.fields(&displaced(&starts, &k1, 0.5), coils)?);
+        let k3 = self.stage(&self.field.fields(&displaced(&starts, &k2, 0.5), coils)?);
+        let k4 = self.stage(&self.field.fields(&displaced(&starts, &k3, 1.0), coils)?);
+        for (n, &index) in active.iter().enumerate() {
+            states[index].next = Some(Point {
+                x: starts[n].x + (k1[n].x + 2.0 * k2[n].x + 2.0 * k3[n].x + k4[n].x) / 6.0,
+                y: starts[n].y + (k1[n].y + 2.0 * k2[n].y + 2.0 * k3[n].y + k4[n].y) / 6.0,
+                z: starts[n].z + (k1[n].z + 2.0 * k2[n].z + 2.0 * k3[n].z + k4[n].z) / 6.0,
+            });
+        }
+        Ok(())
+    }
+
+    /// Takes the step `prepare` computed, or computes it on the CPU for
+    /// particles it did not see
+    fn advance(
+        &self,
+        particle: &Point,
+        coils: &CoilSet,
+        state: &mut IntegrationState,
+    ) -> Option<Point> {
+        match state.next.take() {
+            Some(next) => {
+                state.counts.accepted += 1;
+                Some(next)
+            }
+            None => Rk4 {
+                step_size: self.step_size,
+            }
+            .advance(particle, coils, state),
+        }
+    }
+}
@@ -0,0 +1,665 @@
+use crate::{
+    diagnostics::{PoloidalPoint, SweptAngles},
+    particle::{OrbitSettings, ParticleState, Species},
+    point::Point,
+    simulation::{CoilSet, compute_magnetic_field, confine},
+};
+use clap::ValueEnum;
+use std::error::Error;
+
+/// Scheme advancing particles along their field lines, selected by `--integrator`
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum IntegratorKind {
+    Euler,
+    /// Midpoint method
+    Rk2,
+    #[default]
+    Rk4,
+    /// Dormand–Prince 5(4) substeps with per-particle step size control
+    Rk45,
+    /// Full orbit Lorentz force pusher, steps are in seconds
+    Boris,
+}
+
+impl IntegratorKind {
+    /// The integrator of this kind, `tolerances` only apply to adaptive ones
+    /// and `orbit` only to orbit pushers
+    pub fn integrator(
+        &self,
+        step_size: f64,
+        tolerances: Tolerances,
+        orbit: OrbitSettings,
+    ) -> Box<dyn Integrator> {
+        match self {
+            IntegratorKind::Euler => Box::new(Euler { step_size }),
+            IntegratorKind::Rk2 => Box::new(Rk2 { step_size }),
+            IntegratorKind::Rk4 => Box::new(Rk4 { step_size }),
+            IntegratorKind::Rk45 => Box::new(DormandPrince {
+                step_size,
+                tolerances,
+            }),
+            IntegratorKind::Boris => Box::new(Boris {
+                step_size,
+                species: orbit.species,
+            }),
+        }
+    }
+
+    pub fn is_adaptive(&self) -> bool {
+        *self == IntegratorKind::Rk45
+    }
+
+    /// Whether particles carry a velocity instead of following field lines
+    pub fn is_orbit(&self) -> bool {
+        *self == IntegratorKind::Boris
+    }
+}
+
+/// Error tolerances of the adaptive integrator, per coordinate
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Tolerances {
+    pub absolute: f64,
+    pub relative: f64,
+}
+
+/// Integration substeps taken, fixed step schemes accept exactly one per step
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StepCounts {
+    pub accepted: u64,
+    pub rejected: u64,
+}
+
+/// Per-particle state carried from one step to the next
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IntegrationState {
+    pub status: ParticleState,
+    /// Substep size adaptive schemes try first
+    pub substep: f64,
+    /// Velocity in m/s of particles pushed by the Lorentz force, `None`
+    /// while following field lines
+    pub velocity: Option<Point>,
+    pub counts: StepCounts,
+    /// Arc length travelled while confined, the connection length once the
+    /// particle is lost
+    pub length: f64,
+    /// Magnetic field at the particle, kept from the end of a step when
+    /// fields are written or collisions need it so that the next step
+    /// starts from it
+    pub field: Option<Point>,
+    /// Global index of the particle, which keys its random collisions
+    pub index: u64,
+    /// Angles swept around the torus while confined
+    pub angles: SweptAngles,
+    /// Where the last step crossed the Poincaré plane, see `poincare_crossing`
+    pub puncture: Option<PoloidalPoint>,
+    /// End of the coming step, computed ahead by integrators that batch the
+    /// particles of a step
+    pub next: Option<Point>,
+}
+
+impl IntegrationState {
+    pub fn new(step_size: f64) -> Self {
+        IntegrationState {
+            status: ParticleState::Active,
+            substep: step_size,
+            velocity: None,
+            counts: StepCounts::default(),
+            length: 0.0,
+            field: None,
+            index: 0,
+            angles: SweptAngles::default(),
+            puncture: None,
+            next: None,
+        }
+    }
+
+    pub fn with_velocity(self, velocity: Point) -> Self {
+        IntegrationState {
+            velocity: Some(velocity),
+            ..self
+        }
+    }
+}
+
+/// Advances a particle by one step. Implementations may stop early with
+/// `None` when a substep leaves the loss boundary, the caller checks the
+/// final position itself.
+pub trait Integrator: Sync {
+    /// Field line length of one step, or its duration in seconds for orbit pushers
+    fn step_size(&self) -> f64;
+
+    /// Computes the coming step of every active particle not flagged `lent`
+    /// at once, for `advance` to take from their states. Integrators that
+    /// advance particles one at a time have nothing to prepare.
+    fn prepare(
+        &self,
+        _particles: &[Point],
+        _coils: &CoilSet,
+        _states: &mut [IntegrationState],
+        _lent: &[bool],
+    ) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn advance(
+        &self,
+        particle: &Point,
+        coils: &CoilSet,
+        state: &mut IntegrationState,
+    ) -> Option<Point>;
+}
+
+/// Substeps shorter than this fraction of the step size are never tried
+const MIN_SUBSTEP_FRACTION: f64 = 1e-12;
+
+fn field_direction(point: &Point, coils: &CoilSet) -> Point {
+    compute_magnetic_field(point, coils).get_unit_vector()
+}
+
+/// Field at the start of a step, computed unless the state holds it already
+fn start_field(particle: &Point, coils: &CoilSet, state: &mut IntegrationState) -> Point {
+    state
+        .field
+        .take()
+        .unwrap_or_else(|| compute_magnetic_field(particle, coils))
+}
+
+fn offset(point: &Point, terms: &[(f64, &Point)], h: f64) -> Point {
+    let mut result = *point;
+    for (coefficient, k) in terms {
+        result.x += h * coefficient * k.x;
+        result.y += h * coefficient * k.y;
+        result.z += h * coefficient * k.z;
+    }
+    result
+}
+
+pub struct Euler {
+    pub step_size: f64,
+}
+
+impl Integrator for Euler {
+    fn step_size(&self) -> f64 {
+        self.step_size
+    }
+
+    fn advance(
+        &self,
+        particle: &Point,
+        coils: &CoilSet,
+        state: &mut IntegrationState,
+    ) -> Option<Point> {
+        let step_size = self.step_size;
+        state.counts.accepted += 1;
+        let k1 = start_field(particle, coils, state).get_unit_vector();
+        Some(offset(particle, &[(1.0, &k1)], step_size))
+    }
+}
+
+/// Midpoint method
+pub struct Rk2 {
+    pub step_size: f64,
+}
+
+impl Integrator for Rk2 {
+    fn step_size(&self) -> f64 {
+        self.step_size
+    }
+
+    fn advance(
+        &self,
+        particle: &Point,
+        coils: &CoilSet,
+        state: &mut IntegrationState,
+    ) -> Option<Point> {
+        let step_size = self.step_size;
+        state.counts.accepted += 1;
+        let k1 = start_field(particle, coils, state).get_unit_vector();
+        let k2 = field_direction(&offset(particle, &[(0.5, &k1)], step_size), coils);
+        Some(offset(particle, &[(1.0, &k2)], step_size))
+    }
+}
+
+/// Classic fourth order Runge–Kutta
+pub struct Rk4 {
+    pub step_size: f64,
+}
+
+impl Rk4 {
+    /// One step of `step_size` along the normalised field
+    pub fn step(particle: &Point, coils: &CoilSet, step_size: f64) -> Point {
+        Rk4::step_from(
+            particle,
+            compute_magnetic_field(particle, coils),
+            coils,
+            step_size,
+        )
+    }
+
+    /// `step` from the already known `field` at `particle`
+    pub fn step_from(particle: &Point, field: Point, coils: &CoilSet, step_size: f64) -> Point {
+        let mut k1 = field;
+        let k1norm = k1.get_norm();
+        k1.x = (k1.x / k1norm) * step_size;
+        k1.y = (k1.y / k1norm) * step_size;
+        k1.z = (k1.z / k1norm) * step_size;
+        let p1 = Point {
+            x: k1.x / 2.0 + particle.x,
+            y: k1.y / 2.0 + particle.y,
+            z: k1.z / 2.0 + particle.z,
+        };
+
+        let mut k2 = compute_magnetic_field(&p1, coils);
+        let k2norm = k2.get_norm();
+        k2.x = (k2.x / k2norm) * step_size;
+        k2.y = (k2.y / k2norm) * step_size;
+        k2.z = (k2.z / k2norm) * step_size;
+        let p2 = Point {
+            x: k2.x / 2.0 + particle.x,
+            y: k2.y / 2.0 + particle.y,
+            z: k2.z / 2.0 + particle.z,
+        };
+
+        let mut k3 = compute_magnetic_field(&p2, coils);
+        let k3norm = k3.get_norm();
+        k3.x = (k3.x / k3norm) * step_size;
+        k3.y = (k3.y / k3norm) * step_size;
+        k3.z = (k3.z / k3norm) * step_size;
+        let p3 = Point {
+            x: k3.x + particle.x,
+            y: k3.y + particle.y,
+            z: k3.z + particle.z,
+        };
+        let mut k4 = compute_magnetic_field(&p3, coils);
+        let k4norm = k4.get_norm();
+        k4.x = (k4.x / k4norm) * step_size;
+        k4.y = (k4.y / k4norm) * step_size;
+        k4.z = (k4.z / k4norm) * step_size;
+        Point {
+            x: particle.x + (k1.x + 2.0 * k2.x + 2.0 * k3.x + k4.x) / 6.0,
+            y: particle.y + (k1.y + 2.0 * k2.y + 2.0 * k3.y + k4.y) / 6.0,
+            z: particle.z + (k1.z + 2.0 * k2.z + 2.0 * k3.z + k4.z) / 6.0,
+        }
+    }
+}
+
+impl Integrator for Rk4 {
+    fn step_size(&self) -> f64 {
+        self.step_size
+    }
+
+    fn advance(
+        &self,
+        particle: &Point,
+        coils: &CoilSet,
+        state: &mut IntegrationState,
+    ) -> Option<Point> {
+        let step_size = self.step_size;
+        state.counts.accepted += 1;
+        let field = start_field(particle, coils, state);
+        Some(Rk4::step_from(particle, field, coils, step_size))
+    }
+}
+
+/// Dormand–Prince 5(4) with per-particle step size control
+pub struct DormandPrince {
+    pub step_size: f64,
+    pub tolerances: Tolerances,
+}
+
+impl Integrator for DormandPrince {
+    fn step_size(&self) -> f64 {
+        self.step_size
+    }
+
+    fn advance(
+        &self,
+        particle: &Point,
+        coils: &CoilSet,
+        state: &mut IntegrationState,
+    ) -> Option<Point> {
+        let step_size = self.step_size;
+        simulate_adaptive_step(
+            particle,
+            coils,
+            step_size,
+            &self.tolerances,
+            &mut state.substep,
+            &mut state.counts,
+            state.field.take(),
+        )
+    }
+}
+
+/// Boris pusher for the Lorentz force of the static coil field on a charged
+/// particle, the velocity is kept in `IntegrationState::velocity`
+pub struct Boris {
+    pub step_size: f64,
+    pub species: Species,
+}
+
+impl Integrator for Boris {
+    fn step_size(&self) -> f64 {
+        self.step_size
+    }
+
+    fn advance(
+        &self,
+        particle: &Point,
+        coils: &CoilSet,
+        state: &mut IntegrationState,
+    ) -> Option<Point> {
+        state.counts.accepted += 1;
+        let velocity = state.velocity.unwrap_or_default();
+        let b = start_field(particle, coils, state);
+        // Without an electric field the push is a pure rotation of the
+        // velocity about B by the gyration angle of one step
+        let rotation = self.species.charge_over_mass() * self.step_size / 2.0;
+        let t = Point {
+            x: b.x * rotation,
+            y: b.y * rotation,
+            z: b.z * rotation,
+        };
+        let scale = 2.0 / (1.0 + t.dot(&t));
+        let s = Point {
+            x: t.x * scale,
+            y: t.y * scale,
+            z: t.z * scale,
+        };
+        let half = velocity.cross(&t);
+        let v_prime = Point {
+            x: velocity.x + half.x,
+            y: velocity.y + half.y,
+            z: velocity.z + half.z,
+        };
+        let turn = v_prime.cross(&s);
+        let pushed = Point {
+            x: velocity.x + turn.x,
+            y: velocity.y + turn.y,
+            z: velocity.z + turn.z,
+        };
+        state.velocity = Some(pushed);
+        Some(offset(particle, &[(1.0, &pushed)], self.step_size))
+    }
+}
+
+/// One Dormand–Prince step of length `h` along the field line, returns the
+/// fifth order solution and its difference to the embedded fourth order one
+pub fn dormand_prince_step(particle: &Point, coils: &CoilSet, h: f64) -> (Point, Point) {
+    let (next, error, _) =
+        dormand_prince_step_from(particle, &field_direction(particle, coils), coils, h);
+    (next, error)
+}
+
+/// `dormand_prince_step` from the already known field direction `k1` at
+/// `particle`, also returning the direction at the fifth order solution,
+/// which is the first of a step from there
+pub fn dormand_prince_step_from(
+    particle: &Point,
+    k1: &Point,
+    coils: &CoilSet,
+    h: f64,
+) -> (Point, Point, Point) {
+    let k2 = field_direction(&offset(particle, &[(1.0 / 5.0, k1)], h), coils);
+    let k3 = field_direction(
+        &offset(particle, &[(3.0 / 40.0, k1), (9.0 / 40.0, &k2)], h),
+        coils,
+    );
+    let k4 = field_direction(
+        &offset(
+            particle,
+            &[(44.0 / 45.0, k1), (-56.0 / 15.0, &k2), (32.0 / 9.0, &k3)],
+            h,
+        ),
+        coils,
+    );
+    let k5 = field_direction(
+        &offset(
+            particle,
+            &[
+                (19372.0 / 6561.0, k1),
+                (-25360.0 / 2187.0, &k2),
+                (64448.0 / 6561.0, &k3),
+                (-212.0 / 729.0, &k4),
+            ],
+            h,
+        ),
+        coils,
+    );
+    let k6 = field_direction(
+        &offset(
+            particle,
+            &[
+                (9017.0 / 3168.0, k1),
+                (-355.0 / 33.0, &k2),
+                (46732.0 / 5247.0, &k3),
+                (49.0 / 176.0, &k4),
+                (-5103.0 / 18656.0, &k5),
+            ],
+            h,
+        ),
+        coils,
+    );
+    let next = offset(
+        particle,
+        &[
+            (35.0 / 384.0, k1),
+            (500.0 / 1113.0, &k3),
+            (125.0 / 192.0, &k4),
+            (-2187.0 / 6784.0, &k5),
+            (11.0 / 84.0, &k6),
+        ],
+        h,
+    );
+    let k7 = field_direction(&next, coils);
+    let error = offset(
+        &Point::default(),
+        &[
+            (71.0 / 57600.0, k1),
+            (-71.0 / 16695.0, &k3),
+            (71.0 / 1920.0, &k4),
+            (-17253.0 / 339200.0, &k5),
+            (22.0 / 525.0, &k6),
+            (-1.0 / 40.0, &k7),
+        ],
+        h,
+    );
+    (next, error, k7)
+}
+
+fn error_norm(error: &Point, from: &Point, to: &Point, tolerances: &Tolerances) -> f64 {
+    let scaled = |error: f64, from: f64, to: f64| {
+        error / (tolerances.absolute + tolerances.relative * from.abs().max(to.abs()))
+    };
+    let x = scaled(error.x, from.x, to.x);
+    let y = scaled(error.y, from.y, to.y);
+    let z = scaled(error.z, from.z, to.z);
+    ((x * x + y * y + z * z) / 3.0).sqrt()
+}
+
+/// Advances a particle by `step_size` in Dormand–Prince substeps, starting
+/// from and updating the particle's substep size `h`. The first substep
+/// starts from `field` when the field at `particle` is known, and every
+/// later one from the direction the previous substep ended with. `None`
+/// when a substep leaves the loss boundary or the substeps become too short.
+pub fn simulate_adaptive_step(
+    particle: &Point,
+    coils: &CoilSet,
+    step_size: f64,
+    tolerances: &Tolerances,
+    h: &mut f64,
+    counts: &mut StepCounts,
+    field: Option<Point>,
+) -> Option<Point> {
+    let mut position = *particle;
+    let mut direction = field.map_or_else(
+        || field_direction(particle, coils),
+        |field| field.get_unit_vector(),
+    );
+    let mut remaining = step_size;
+    while remaining > step_size * MIN_SUBSTEP_FRACTION {
+        let trial = h.min(remaining);
+        if trial < step_size * MIN_SUBSTEP_FRACTION {
+            return None;
+        }
+        let (next, error, next_direction) =
+            dormand_prince_step_from(&position, &direction, coils, trial);
+        let norm = error_norm(&error, &position, &next, tolerances);
+        let factor = if norm.is_finite() {
+            (0.9 * norm.powf(-0.2)).clamp(0.2, 5.0)
+        } else {
+            0.2
+        };
+        if norm <= 1.0 {
+            counts.accepted += 1;
+            position = next;
+            direction = next_direction;
+            remaining -= trial;
+            confine(position, coils)?;
+            // A substep cut short to land on the step boundary says nothing
+            // about the size the next one can have
+            if trial == *h || factor < 1.0 {
+                *h = trial * factor;
+            }
+        } else {
+            counts.rejected += 1;
+            *h = trial * factor;
+        }
+    }
+    Some(position)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        particle::{ELEMENTARY_CHARGE, PROTON_MASS},
+        simulation::read_coil_data_directory,
+    };
+    use std::path::Path;
+
+    #[test]
+    fn adaptive_steps_match_fine_rk4() {
+        let coils = CoilSet::new(
+            &read_coil_data_directory(Path::new("tests/test-resources/resources")).unwrap(),
+        );
+        let start = Point {
+            x: 0.2256,
+            y: 0.0,
+            z: 0.0,
+        };
+        let mut reference = start;
+        for _ in 0..500 {
+            reference = Rk4::step(&reference, &coils, 0.0002);
+        }
+        let tolerances = Tolerances {
+            absolute: 1e-10,
+            relative: 1e-10,
+        };
+        let mut h = 0.1;
+        let mut counts = StepCounts::default();
+        let adaptive =
+            simulate_adaptive_step(&start, &coils, 0.1, &tolerances, &mut h, &mut counts, None)
+                .unwrap();
+        assert!(adaptive.get_distance(&reference) < 1e-7);
+        assert!(counts.accepted < 500);
+        assert!(counts.rejected > 0);
+
+        // A kept field seeds the first substep without changing it
+        let mut kept = IntegrationState {
+            field: Some(compute_magnetic_field(&start, &coils)),
+            ..IntegrationState::new(0.1)
+        };
+        let dormand_prince = DormandPrince {
+            step_size: 0.1,
+            tolerances,
+        };
+        let mut fresh = IntegrationState::new(0.1);
+        assert_eq!(
+            dormand_prince.advance(&start, &coils, &mut kept),
+            dormand_prince.advance(&start, &coils, &mut fresh)
+        );
+        assert_eq!(kept.field, None);
+        assert_eq!(kept.counts, fresh.counts);
+    }
+
+    #[test]
+    fn boris_conserves_energy_and_magnetic_moment() {
+        let coils = CoilSet::new(
+            &read_coil_data_directory(Path::new("tests/test-resources/resources")).unwrap(),
+        );
+        let orbit = OrbitSettings {
+            species: Species {
+                mass: PROTON_MASS,
+                charge: ELEMENTARY_CHARGE,
+            },
+            energy: 1.0,
+            pitch: 0.5,
+            collisions: None,
+        };
+        let start = Point {
+            x: 0.2256,
+            y: 0.0,
+            z: 0.0,
+        };
+        let b = compute_magnetic_field(&start, &coils);
+        let boris = Boris {
+            step_size: 1e-9,
+            species: orbit.species,
+        };
+        let mut state = IntegrationState::new(boris.step_size).with_velocity(orbit.velocity(&b));
+        let moment = |position: &Point, velocity: &Point| {
+            let b = compute_magnetic_field(position, &coils);
+            let parallel = velocity.dot(&b) / b.get_norm();
+            (velocity.dot(velocity) - parallel * parallel) / b.get_norm()
+        };
+        let initial_speed = state.velocity.unwrap().get_norm();
+        let initial_moment = moment(&start, &state.velocity.unwrap());
+        let mut position = start;
+        for _ in 0..2000 {
+            position = boris.advance(&position, &coils, &mut state).unwrap();
+        }
+        let velocity = state.velocity.unwrap();
+        assert!((velocity.get_norm() - initial_speed).abs() < 1e-9 * initial_speed);
+        assert!((moment(&position, &velocity) - initial_moment).abs() < 0.05 * initial_moment);
+        assert!(position.get_distance(&start) > 1e-3);
+    }
+
+    #[test]
+    fn schemes_converge_with_their_order() {
+        let coils = CoilSet::new(
+            &read_coil_data_directory(Path::new("tests/test-resources/resources")).unwrap(),
+        );
+        let start = Point {
+            x: 0.2256,
+            y: 0.0,
+            z: 0.0,
+        };
+        let trace = |integrator: &dyn Integrator, steps: u32| {
+            let mut state = IntegrationState::new(integrator.step_size());
+            (0..steps).fold(start, |point, _| {
+                integrator.advance(&point, &coils, &mut state).unwrap()
+            })
+        };
+        let length = 0.02;
+        let reference = trace(
+            &Rk4 {
+                step_size: length / 200.0,
+            },
+            200,
+        );
+        let error = |integrator: &dyn Integrator| {
+            let steps = (length / integrator.step_size()).round() as u32;
+            trace(integrator, steps).get_distance(&reference)
+        };
+        let coarse = length / 10.0;
+        let fine = length / 20.0;
+        // Halving the step size divides the error by about 2^order
+        assert!(error(&Euler { step_size: coarse }) / error(&Euler { step_size: fine }) > 1.8);
+        assert!(error(&Rk2 { step_size: coarse }) / error(&Rk2 { step_size: fine }) > 3.5);
+        assert!(error(&Rk4 { step_size: coarse }) < error(&Rk2 { step_size: coarse }));
+    }
+}
@@ -1,5 +1,44 @@
+#[cfg(feature = "mpi")]
+pub mod aggregator;
 pub mod args;
+pub mod balance;
+pub mod boundary;
+pub mod coil_format;
+pub mod coil_spline;
+pub mod coil_transform;
+pub mod coils;
+pub mod collectives;
+pub mod collisions;
+pub mod commands;
+pub mod compression;
+pub mod config;
 pub mod constants;
+pub mod diagnostics;
+pub mod field_source;
+pub mod fieldmap;
+pub mod gltf;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+pub mod integrator;
+pub mod logging;
+pub mod manifest;
+pub mod output;
+pub mod particle;
+pub mod particle_file;
+pub mod partition;
 pub mod point;
+pub mod profile;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod restart;
+pub mod seeding;
+pub mod shutdown;
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+pub mod simd;
 pub mod simulation;
+pub mod stream;
+pub mod summary;
+pub mod tracer;
 pub mod utils;
+pub mod validation;
+pub mod vtk;
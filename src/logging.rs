@@ -0,0 +1,186 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicI32, Ordering},
+    },
+    time::Instant,
+};
+
+/// Most verbose level written to the rank log files whatever RUST_LOG says
+const FILE_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// Rank before `set_rank`, when every process still logs to stderr
+const NO_RANK: i32 = -1;
+
+static LOGGER: OnceLock<RankLogger> = OnceLock::new();
+
+/// Name of the JSON lines log of world rank `rank` in an output directory
+pub fn log_file_name(rank: i32) -> String {
+    format!("log_{}.jsonl", rank)
+}
+
+/// Logs to stderr as filtered by RUST_LOG, only from rank 0 once the rank
+/// is known except for errors, and to the log file of the rank once opened
+struct RankLogger {
+    stderr: env_logger::Logger,
+    started: Instant,
+    rank: AtomicI32,
+    file: Mutex<Option<BufWriter<File>>>,
+}
+
+#[derive(serde::Serialize)]
+struct MessageRecord<'a> {
+    time: f64,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+}
+
+#[derive(serde::Serialize)]
+struct TimingRecord<'a> {
+    time: f64,
+    timing: &'a PhaseTimes,
+}
+
+impl RankLogger {
+    fn write_line(&self, record: &impl serde::Serialize) {
+        if let Ok(Some(file)) = self.file.lock().as_deref_mut() {
+            // A log line that cannot be written is not worth failing the run
+            if serde_json::to_writer(&mut *file, record).is_ok() {
+                let _ = writeln!(file);
+            }
+        }
+    }
+}
+
+impl Log for RankLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILE_LEVEL || self.stderr.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let rank = self.rank.load(Ordering::Relaxed);
+        if self.stderr.matches(record) && (rank <= 0 || record.level() == Level::Error) {
+            self.stderr.log(record);
+        }
+        if record.level() <= FILE_LEVEL || self.stderr.matches(record) {
+            self.write_line(&MessageRecord {
+                time: self.started.elapsed().as_secs_f64(),
+                level: record.level().as_str(),
+                target: record.target(),
+                message: record.args().to_string(),
+            });
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+        if let Ok(Some(file)) = self.file.lock().as_deref_mut() {
+            let _ = file.flush();
+        }
+    }
+}
+
+/// Installs the logger, configured like env_logger through RUST_LOG
+pub fn init() {
+    let logger = LOGGER.get_or_init(|| RankLogger {
+        stderr: env_logger::Builder::from_default_env().build(),
+        started: Instant::now(),
+        rank: AtomicI32::new(NO_RANK),
+        file: Mutex::new(None),
+    });
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(logger.stderr.filter().max(FILE_LEVEL));
+    }
+}
+
+/// Silences this process on stderr, except for errors, unless `rank` is 0
+pub fn set_rank(rank: i32) {
+    if let Some(logger) = LOGGER.get() {
+        logger.rank.store(rank, Ordering::Relaxed);
+    }
+}
+
+/// Also writes every message and timing record of this process as JSON
+/// lines into `log_<rank>.jsonl` in `output_dir`, which must exist
+pub fn open_rank_file(output_dir: &Path, rank: i32) -> io::Result<()> {
+    let Some(logger) = LOGGER.get() else {
+        return Ok(());
+    };
+    let file = File::create(output_dir.join(log_file_name(rank)))?;
+    if let Ok(mut slot) = logger.file.lock() {
+        *slot = Some(BufWriter::new(file));
+    }
+    Ok(())
+}
+
+/// Wall time in seconds spent in each phase of the step loop by one rank,
+/// over the `steps` steps up to `step`
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PhaseTimes {
+    pub step: u32,
+    pub steps: u32,
+    /// Advancing the particles
+    pub integrate: f64,
+    /// Lending particles to other ranks and settling the loans
+    pub balance: f64,
+    /// Deciding whether to write, then gathering, streaming and writing snapshots
+    pub output: f64,
+}
+
+impl PhaseTimes {
+    /// Times of `self` followed by `later`, up to the step of `later`
+    pub fn merge(self, later: PhaseTimes) -> Self {
+        PhaseTimes {
+            step: later.step,
+            steps: self.steps + later.steps,
+            integrate: self.integrate + later.integrate,
+            balance: self.balance + later.balance,
+            output: self.output + later.output,
+        }
+    }
+}
+
+/// Writes `times` into the rank log file, if one is open
+pub fn log_phase_times(times: &PhaseTimes) {
+    if let Some(logger) = LOGGER.get() {
+        logger.write_line(&TimingRecord {
+            time: logger.started.elapsed().as_secs_f64(),
+            timing: times,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timing_records_are_one_json_object() {
+        let times = PhaseTimes {
+            step: 40,
+            steps: 10,
+            integrate: 1.5,
+            balance: 0.25,
+            output: 0.125,
+        };
+        let line = serde_json::to_string(&TimingRecord {
+            time: 2.0,
+            timing: &times,
+        })
+        .unwrap();
+        assert!(!line.contains('\n'));
+        #[derive(serde::Deserialize)]
+        struct Line {
+            time: f64,
+            timing: PhaseTimes,
+        }
+        let parsed: Line = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed.time, 2.0);
+        assert_eq!(parsed.timing, times);
+    }
+}
@@ -1,63 +1,351 @@
-use clap::Parser;
-use log::{debug, info, trace};
-use mpi::traits::{Communicator, CommunicatorCollectives, Root};
+use clap::{CommandFactory, Parser, error::ErrorKind};
+use log::{debug, error, info, trace, warn};
+use mpi::{
+    Rank,
+    collective::SystemOperation,
+    datatype::{Equivalence, Partition},
+    environment::Universe,
+    topology::{Color, SimpleCommunicator},
+    traits::{Communicator, CommunicatorCollectives, Root},
+};
 use std::{
+    env,
+    error::Error,
     fs::{self},
     path::Path,
+    thread,
+    time::Instant,
 };
 
-use bs_solctra_rs::{args, point, simulation, utils};
+use bs_solctra_rs::{
+    aggregator, args, coil_format, coil_spline, coil_transform, coils,
+    collectives::{self, Collectives},
+    commands, config, constants, diagnostics, fieldmap, integrator, logging, manifest, output,
+    particle, particle_file, partition, point, profile, restart, seeding, shutdown, simulation,
+    stream, tracer, utils,
+};
 
 fn main() {
-    env_logger::init();
+    logging::init();
+    let cli = args::Cli::parse();
+    let args = match cli.command {
+        Some(args::Command::Simulate(args)) => *args,
+        Some(args::Command::Fieldmap(args)) => {
+            run_on_mpi(|universe| write_field_map(universe, &args).map(|_| true));
+            return;
+        }
+        Some(command) => {
+            run_command(command);
+            return;
+        }
+        None => match cli.run {
+            Some(args) => args,
+            None => args::Cli::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "simulation arguments are required without a subcommand",
+                )
+                .exit(),
+        },
+    };
+    // A conflict with one value of an option, which clap cannot declare
+    if args.rebalance_every.is_some() && args.decimation == args::DecimationMode::Curvature {
+        args::Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--rebalance-every cannot be used with --decimation curvature",
+            )
+            .exit();
+    }
+    run_on_mpi(|universe| run(universe, &args));
+}
+
+/// Initializes MPI and runs `run` on this rank. Exits with status 1 if it
+/// returns false, aborts every rank if it fails.
+fn run_on_mpi(run: impl FnOnce(&Universe) -> Result<bool, Box<dyn Error>>) {
     let universe = mpi::initialize().unwrap();
+    let full_world = universe.world();
+    logging::set_rank(full_world.rank());
+    let result = run(&universe);
+    log::logger().flush();
+    match result {
+        Ok(true) => {}
+        Ok(false) => {
+            // Finalize MPI first, exiting skips the destructor of the universe
+            drop(universe);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            // Other ranks may be waiting in a collective for this one, take
+            // them down with it instead of leaving them to the job timeout
+            error!("Rank {}: {}", full_world.rank(), err);
+            log::logger().flush();
+            full_world.abort(1);
+        }
+    }
+}
+
+/// Evaluates this rank's share of a field map, which rank 0 writes
+fn write_field_map(universe: &Universe, args: &args::FieldmapArgs) -> Result<(), Box<dyn Error>> {
     let world = universe.world();
+    let grid = args.grid();
+    let mut coils = Vec::new();
+    let mut currents = Vec::new();
+    if world.rank() == 0 {
+        coils = coil_format::read_coils(&args.resource_path, args.coil_format)
+            .map_err(|err| format!("reading the coils: {}", err))?;
+        if let Some(path) = &args.coil_transforms {
+            let transforms = coil_transform::read_coil_transforms(path)
+                .map_err(|err| format!("reading coil transforms: {}", err))?;
+            coil_transform::apply_coil_transforms(&mut coils, &transforms)?;
+        }
+        if let Some(segments) = args.coil_segments {
+            coil_spline::resample_coils(&mut coils, segments as usize)
+                .map_err(|err| format!("resampling the coils: {}", err))?;
+        }
+        if let Some(path) = &args.currents {
+            let coil_files = simulation::list_coil_files(&args.resource_path)?;
+            currents = coil_format::read_currents(path, &coil_files, coils.len(), args.current)
+                .map_err(|err| format!("reading coil currents: {}", err))?;
+        }
+    }
+    let coils = broadcast_coils(&world, &coils);
+    let currents = match &args.currents {
+        Some(_) => broadcast_values(&world, &currents),
+        None => Vec::new(),
+    };
+    let physics = constants::PhysicsParams {
+        current: args.current,
+        ..constants::PhysicsParams::default()
+    };
+    let coils = tracer::Simulation::builder()
+        .coils(coils)
+        .currents(currents)
+        .field_periods(args.field_periods.unwrap_or(1) as usize)
+        .physics(physics)
+        .background(args.background_fields.clone())
+        .coil_set()?;
+    let groups = match args.coil_groups() {
+        Some(groups) => coils::coil_groups(&coils, groups)?,
+        None => Vec::new(),
+    };
+    if world.rank() == 0 {
+        info!(
+            "Evaluating the field at {} points for {} coil groups on {} ranks",
+            grid.len(),
+            groups.len(),
+            world.size()
+        );
+    }
+    let t_start = mpi::time();
+    let map = fieldmap::evaluate(&grid, &coils, &groups, &world)?;
+    if let Some(map) = map {
+        info!("Field map time: {}", mpi::time() - t_start);
+        fieldmap::write_field_map(&args.output, &map, args.format)
+            .map_err(|err| format!("writing {}: {}", args.output.display(), err))?;
+    }
+    Ok(())
+}
+
+/// Runs the simulation on this rank of the world, returns false if its
+/// snapshots diverge from the `--validate` reference
+fn run(universe: &Universe, args: &args::Args) -> Result<bool, Box<dyn Error>> {
+    let started = Instant::now();
+    // Writer ranks too, so that they keep serving the final snapshot
+    shutdown::install_signal_handler()
+        .map_err(|err| format!("installing the SIGTERM handler: {}", err))?;
+    let full_world = universe.world();
+    let output_dir = Path::new(&args.output);
+    if full_world.rank() == 0 {
+        if fs::exists(output_dir)? {
+            info!("Output path: {} already exists", args.output);
+        } else {
+            utils::create_directory(output_dir)
+                .map_err(|err| format!("creating {}: {}", args.output, err))?;
+        }
+    }
+    full_world.barrier();
+    logging::open_rank_file(output_dir, full_world.rank())
+        .map_err(|err| format!("opening the log file: {}", err))?;
+    let writer_ranks = aggregator::WriterRanks {
+        world_size: full_world.size(),
+        writers: args.writer_ranks.unwrap_or(0) as Rank,
+    };
+    if writer_ranks.compute_size() < 1 {
+        return Err(format!(
+            "{} writer ranks leave none of the {} ranks to compute",
+            writer_ranks.writers, writer_ranks.world_size
+        )
+        .into());
+    }
+    let is_writer = writer_ranks.is_writer(full_world.rank());
+    let world = full_world
+        .split_by_color(Color::with_value(is_writer as i32))
+        .ok_or("could not split off the writer ranks")?;
+    if is_writer {
+        serve_snapshots(&full_world, writer_ranks, args)?;
+        return Ok(true);
+    }
     let world_size = world.size();
     let rank = world.rank();
-    let processor = mpi::environment::processor_name().unwrap();
+    let processor = mpi::environment::processor_name()?;
 
     if rank == 0 {
         info!("Starting BS-Solctra");
         info!("Total ranks: {}", world_size);
+        if writer_ranks.writers > 0 {
+            info!("Writer ranks: {}", writer_ranks.writers);
+        }
     }
     trace!("Rank: {}, processor: {}", rank, processor);
-    let args = args::Args::parse();
     if rank == 0 {
         trace!("{:?}", args);
     }
-    let output_dir = Path::new(&args.output);
+    configure_threads(&world, args.threads_per_rank)?;
 
-    if rank == 0 {
-        match fs::exists(output_dir) {
-            Ok(true) => info!("Output path: {} already exists", args.output),
-            Ok(false) => utils::create_directory(output_dir),
-            Err(err) => panic!("Error querying path: {}", err),
-        }
-        info!("Reading particles from file {}", args.particles_file);
-    }
-    let max_particles = args.num_particles;
-    let particles_per_rank = max_particles / world_size as usize;
-    let mut local_particles = vec![
-        point::Point {
-            x: 0.0,
-            y: 0.0,
-            z: 0.0
-        };
-        particles_per_rank
-    ];
-    if rank == 0 {
-        let particles = match point::read_from_file(Path::new(&args.particles_file), max_particles)
-        {
-            Ok(particles) => particles,
-            Err(err) => panic!("Error: {}", err),
-        };
-        world
-            .process_at_rank(0)
-            .scatter_into_root(particles.as_slice(), local_particles.as_mut_slice());
+    let mut restart_point = None;
+    let mut resume_point = None;
+    let mut local_velocities = None;
+    let mut local_restored = None;
+    let mut local_loss_steps = None;
+    let (particle_counts, local_particles) = if args.mmap_particles {
+        if rank == 0 {
+            info!("Mapping particles file {:?}", args.particles_file);
+        }
+        let path = args.particles_file.as_deref().unwrap_or_default();
+        let file = particle_file::ParticleFile::open(Path::new(path))?;
+        // Each rank counts the records of its byte range of the file only
+        let local_records = file.slice_len(rank as usize, world_size as usize);
+        let offset = world.exclusive_sum_count(local_records);
+        let local_count = local_records.min(args.num_particles.saturating_sub(offset));
+        let particle_counts = world.all_gather_count(local_count);
+        let local_particles =
+            file.read_slice(rank as usize, world_size as usize, offset, local_count)?;
+        (particle_counts, local_particles)
     } else {
-        world
-            .process_at_rank(0)
-            .scatter_into(local_particles.as_mut_slice());
+        let mut particles = Vec::new();
+        let mut file_velocities = None;
+        let mut restored = RestoredParticles::default();
+        if rank == 0 {
+            particles = match &args.restart_from {
+                None if args.resume => {
+                    if args.integrator.is_orbit() {
+                        return Err(format!(
+                            "resuming {:?} runs is not supported",
+                            args.integrator
+                        )
+                        .into());
+                    }
+                    let resume = restart::RestartPoint::load(
+                        output_dir,
+                        None,
+                        &restart::ParticleFilter::All,
+                    )?;
+                    check_manifest(output_dir, &resume.config)?;
+                    if resume.config.world_size != world_size {
+                        info!(
+                            "Repartitioning the particles of {} ranks over {}",
+                            resume.config.world_size, world_size
+                        );
+                    }
+                    info!(
+                        "Resuming {} particles from step {}",
+                        resume.particles.len(),
+                        resume.source.step
+                    );
+                    restored = restore_particles(output_dir, &resume)?;
+                    let particles = resume.particles.clone();
+                    resume_point = Some(resume);
+                    particles
+                }
+                Some(run_dir) => {
+                    info!("Restarting particles from run {}", run_dir);
+                    let mut restart = restart::RestartPoint::load(
+                        Path::new(run_dir),
+                        args.restart_step,
+                        &args.restart_particles,
+                    )?;
+                    check_manifest(Path::new(run_dir), &restart.config)?;
+                    restart.truncate(args.num_particles);
+                    info!(
+                        "Restarting {} particles from step {}",
+                        restart.particles.len(),
+                        restart.source.step
+                    );
+                    // Particles lost in the earlier run start this one lost
+                    restored.loss_steps = restart
+                        .statuses
+                        .iter()
+                        .map(|status| match status {
+                            particle::ParticleState::Active => status.to_value(),
+                            particle::ParticleState::Lost { .. } => {
+                                particle::ParticleState::Lost { step: 0 }.to_value()
+                            }
+                        })
+                        .collect();
+                    let particles = restart.particles.clone();
+                    restart_point = Some(restart);
+                    particles
+                }
+                None => match (&args.particles_file, args.seeding()) {
+                    (_, Some(seeding)) => {
+                        info!(
+                            "Seeding {} particles on a {:?}",
+                            args.num_particles, seeding.mode
+                        );
+                        seeding.generate(args.num_particles, &args.physics())
+                    }
+                    (Some(particles_file), None) => {
+                        info!("Reading particles from file {}", particles_file);
+                        let path = Path::new(particles_file);
+                        if args.integrator.is_orbit() {
+                            file_velocities = particle::read_velocities(path, args.num_particles)?;
+                        }
+                        point::read_from_file(path, args.num_particles)?
+                    }
+                    (None, None) => unreachable!("clap requires a particles file or a seed mode"),
+                },
+            };
+        }
+        let mut num_particles = particles.len();
+        world.process_at_rank(0).broadcast_into(&mut num_particles);
+        let particle_counts = partition::particle_counts(num_particles, world_size as usize);
+        let local_particles = scatter_values(&world, &particles, &particle_counts);
+        let mut has_velocities = file_velocities.is_some();
+        world.process_at_rank(0).broadcast_into(&mut has_velocities);
+        if has_velocities {
+            local_velocities = Some(scatter_values(
+                &world,
+                file_velocities.as_deref().unwrap_or_default(),
+                &particle_counts,
+            ));
+        }
+        if args.resume {
+            local_restored = Some(RestoredParticles {
+                loss_steps: scatter_values(&world, &restored.loss_steps, &particle_counts),
+                lengths: scatter_values(&world, &restored.lengths, &particle_counts),
+                toroidal: scatter_values(&world, &restored.toroidal, &particle_counts),
+                poloidal: scatter_values(&world, &restored.poloidal, &particle_counts),
+                start_radii: scatter_values(&world, &restored.start_radii, &particle_counts),
+            });
+        } else if args.restart_from.is_some() {
+            local_loss_steps = Some(scatter_values(
+                &world,
+                &restored.loss_steps,
+                &particle_counts,
+            ));
+        }
+        (particle_counts, local_particles)
+    };
+
+    let mut first_step = resume_point.as_ref().map_or(0, |resume| resume.source.step);
+    world.process_at_rank(0).broadcast_into(&mut first_step);
+    if first_step > args.steps {
+        return Err(format!(
+            "cannot resume from step {}, past the {} steps of --steps",
+            first_step, args.steps
+        )
+        .into());
     }
 
     world.barrier();
@@ -70,45 +358,908 @@ fn main() {
 
     trace!("Rank {}, {:?}", rank, local_particles);
 
+    // Only rank 0 touches the coil files, the others receive the coils
+    let coil_loading_started = Instant::now();
+    let mut coils = Vec::new();
+    let mut coil_transforms = Vec::new();
+    let mut coil_files = Vec::new();
+    let mut currents = Vec::new();
     if rank == 0 {
-        info!("Reading coil data from directory: {}", &args.resource_path);
+        info!("Reading coil data from: {}", &args.resource_path);
+        coils = coil_format::read_coils(Path::new(&args.resource_path), args.coil_format)
+            .map_err(|err| format!("reading coils from {}: {}", args.resource_path, err))?;
+        if let Some(path) = &args.coil_transforms {
+            coil_transforms = coil_transform::read_coil_transforms(Path::new(path))
+                .map_err(|err| format!("reading coil transforms: {}", err))?;
+        }
+        coil_transform::apply_coil_transforms(&mut coils, &coil_transforms)?;
+        if let Some(segments) = args.coil_segments {
+            coil_spline::resample_coils(&mut coils, segments as usize)
+                .map_err(|err| format!("resampling the coils: {}", err))?;
+        }
+        coil_files = simulation::list_coil_files(Path::new(&args.resource_path))?;
+        if let Some(path) = &args.currents {
+            currents =
+                coil_format::read_currents(Path::new(path), &coil_files, coils.len(), args.current)
+                    .map_err(|err| format!("reading coil currents: {}", err))?;
+        }
     }
-    let coils = match simulation::read_coil_data_directory(Path::new(&args.resource_path)) {
-        Ok(coils) => coils,
-        Err(err) => panic!("Error: {}", err),
-    };
+    let coils = broadcast_coils(&world, &coils);
+    let currents = args
+        .currents
+        .as_ref()
+        .map(|_| broadcast_values(&world, &currents));
+    let coil_loading = coil_loading_started.elapsed().as_secs_f64();
+    let boundary = args
+        .loss_boundary()
+        .map_err(|err| format!("reading the loss boundary: {}", err))?;
+    let mut run_manifest = None;
     if rank == 0 {
-        info!("Computing displacements");
+        let run_config = config::RunConfig::new(args, &coil_files, particle_counts.clone())
+            .with_currents(currents.clone())
+            .with_coil_transforms(coil_transforms)
+            .with_boundary(boundary.clone())
+            .with_input_checksums()
+            .map_err(|err| format!("computing input checksums: {}", err))?;
+        let run_config = match restart_point {
+            Some(restart) => {
+                restart.config.check_restart_compatibility(&run_config)?;
+                run_config.with_restart(restart.source)
+            }
+            None => run_config,
+        };
+        let run_config = match resume_point {
+            Some(resume) => {
+                resume.config.check_restart_compatibility(&run_config)?;
+                // Ranks beyond this run would leave their lost particles behind
+                for stale in world_size..resume.config.world_size {
+                    for name in [
+                        particle::lost_particles_file_name(stale),
+                        particle::resume_states_file_name(stale),
+                    ] {
+                        let path = output_dir.join(name);
+                        if path.exists() {
+                            fs::remove_file(&path)
+                                .map_err(|err| format!("removing {}: {}", path.display(), err))?;
+                        }
+                    }
+                }
+                run_config.with_resume(resume.config, first_step)
+            }
+            None => run_config,
+        };
+        run_config
+            .write(output_dir)
+            .map_err(|err| format!("writing run configuration: {}", err))?;
+        debug!("Wrote run configuration to {:?}", output_dir);
+        let started_manifest = manifest::Manifest::new(run_config, full_world.size() as usize);
+        started_manifest
+            .write(output_dir)
+            .map_err(|err| format!("writing the manifest: {}", err))?;
+        run_manifest = Some(started_manifest);
+        let currents = currents.as_deref().unwrap_or_default();
+        for (index, stats) in coils::coil_set_stats(&coils, &args.physics(), currents)
+            .iter()
+            .enumerate()
+        {
+            info!("Coil {}: {}", index, stats);
+        }
+        info!("Computing coil segments");
     }
-    let displacements = simulation::compute_all_displacements(&coils);
-    if rank == 0 {
-        info!("Computing e_roof");
+
+    let written_steps: Vec<u32> = if first_step > 0 {
+        output::list_snapshots(output_dir)?
+            .into_keys()
+            .filter(|&step| step <= first_step)
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let offset = partition::particle_offsets(&particle_counts)[rank as usize];
+    let mut writer =
+        output::SnapshotWriter::new(output_dir, rank, args.text_format(), args.decimation())
+            .with_retention(args.retention())
+            .with_written_steps(&written_steps)
+            .with_single_file(args.writes_single_files())
+            .with_fields(args.write_fields)
+            .with_layout(args.output_layout)
+            .with_binary_precision(args.binary_precision)
+            .with_compression(args.compress);
+    if args.reproducible || args.output_layout == output::OutputLayout::Trajectories {
+        writer = writer.with_particle_ids(offset);
     }
-    let e_roof = simulation::compute_all_e_roof(&displacements);
+    if args.drift_diagnostics {
+        writer = writer.with_drift(diagnostics::DriftLog::new(output_dir, first_step));
+    }
+    if args.record_punctures {
+        writer = writer.with_punctures(diagnostics::PunctureLog::new(output_dir, rank, first_step));
+    }
+    let mut writer = writer.with_output_format(args.output_format)?;
+    if args.output_format == output::OutputFormat::Netcdf {
+        if writer_ranks.writers > 0 || args.resume {
+            return Err(
+                "netCDF output is written by rank 0 of runs without writer ranks and \
+                        cannot be resumed"
+                    .into(),
+            );
+        }
+        // Rank 0 appends the gathered snapshots of every step to one file
+        let netcdf = match &run_manifest {
+            Some(manifest) => {
+                let particles: Vec<usize> = (0..particle_counts.iter().sum()).collect();
+                let path = output_dir.join(output::NETCDF_FILE);
+                let file = output::NetcdfTrajectories::create(&path, &manifest.config, &particles)
+                    .map_err(|err| format!("creating {}: {}", path.display(), err))?;
+                Some(file)
+            }
+            None => None,
+        };
+        writer = writer.with_netcdf(netcdf);
+    }
+    if let Some(endpoint) = &args.stream {
+        let publisher = if rank == 0 {
+            let publisher = stream::Publisher::bind(endpoint)
+                .map_err(|err| format!("streaming on {}: {}", endpoint, err))?;
+            Some(publisher)
+        } else {
+            None
+        };
+        writer = writer.with_stream(publisher);
+    }
+    if writer_ranks.writers > 0 {
+        writer = writer.with_forwarder(aggregator::Forwarder::new(
+            universe.world(),
+            writer_ranks,
+            rank,
+        ));
+    }
+    let mut builder = tracer::Simulation::builder()
+        .coils(coils)
+        .currents(currents.unwrap_or_default())
+        .field_periods(args.field_periods.unwrap_or(1) as usize)
+        .summation(args.summation)
+        .precision(args.precision)
+        .backend(args.backend)
+        .physics(args.physics())
+        .boundary(boundary)
+        .background(args.background_fields.clone())
+        .integrator(args.integrator, args.step_size)
+        .tolerances(args.tolerances())
+        .orbit(args.orbit())
+        .add_particles(&local_particles)
+        .writer(writer)
+        .first_step(first_step)
+        .first_index(offset)
+        .rebalance_every(args.rebalance_every)
+        .progress_every(args.progress_interval)
+        .shutdown(shutdown::Shutdown {
+            deadline: args.max_walltime.map(|budget| started + budget),
+            ..shutdown::Shutdown::default()
+        });
+    if let Some(velocities) = local_velocities {
+        builder = builder.velocities(velocities);
+    }
+    let start_radii: Vec<f64> = match &local_restored {
+        Some(restored) => restored.start_radii.clone(),
+        None => local_particles
+            .iter()
+            .map(|particle| simulation::distance_to_axis(particle, &args.physics()))
+            .collect(),
+    };
+    if let Some(loss_steps) = local_loss_steps {
+        builder = builder.statuses(
+            loss_steps
+                .into_iter()
+                .map(particle::ParticleState::from_value)
+                .collect(),
+        );
+    }
+    if let Some(restored) = local_restored {
+        let statuses = restored
+            .loss_steps
+            .into_iter()
+            .map(particle::ParticleState::from_value)
+            .collect();
+        let angles = restored
+            .toroidal
+            .into_iter()
+            .zip(restored.poloidal)
+            .map(|(toroidal, poloidal)| diagnostics::SweptAngles { toroidal, poloidal })
+            .collect();
+        builder = builder
+            .statuses(statuses)
+            .lengths(restored.lengths)
+            .angles(angles);
+    }
+    let mut simulation = builder.build()?;
     if rank == 0 {
-        debug!("Total e_roof: {}", e_roof.len());
-        trace!("{:?}", e_roof);
+        debug!(
+            "Total coil points: {} in {} coils",
+            simulation.coils().num_points(),
+            simulation.coils().len()
+        );
+        if let Some(field_periods) = args.field_periods {
+            info!(
+                "Storing the coils of one of {} field periods",
+                field_periods
+            );
+        }
+        trace!("{:?}", simulation.coils());
 
         info!("Computing simulation")
     }
-
     world.barrier();
     let t_start = mpi::time();
-    simulation::simulate_particles(
-        local_particles.as_mut_slice(),
-        args.steps,
-        args.step_size,
-        &coils,
-        &displacements,
-        &e_roof,
-        output_dir,
-        args.write_frequency,
-        rank,
-    );
-    world.barrier();
+    simulation.run_on(args.steps - first_step, &world)?;
+    let stopped = simulation.step() < args.steps;
+    simulation.writer().finish();
+    let profile = profile::Profile {
+        coil_loading,
+        precompute: simulation.precompute_time(),
+        steps: simulation.phase_times(),
+        total: started.elapsed().as_secs_f64(),
+    };
+    // Writer ranks are done with every snapshot once the whole world is here
+    full_world.barrier();
     let t_end = mpi::time();
     if rank == 0 {
-        info!("Finished simulation");
+        if stopped {
+            warn!(
+                "Stopped at step {} of {}, continue with --resume",
+                simulation.step(),
+                args.steps
+            );
+        } else {
+            info!("Finished simulation");
+        }
         info!("Simulation time: {}", t_end - t_start);
     }
+    if let Some(report) = args.profile.then(|| profile.reduce(&world)).flatten() {
+        info!(
+            "Profile over {} ranks and {} steps:",
+            report.ranks, report.steps
+        );
+        for line in report.table() {
+            info!("{}", line);
+        }
+        report
+            .write(output_dir)
+            .map_err(|err| format!("writing profiling report: {}", err))?;
+        debug!("Wrote profiling report to {:?}", output_dir);
+    }
+
+    if args.rotational_transform {
+        write_rotational_transforms(&world, output_dir, &start_radii, &simulation)
+            .map_err(|err| format!("writing rotational transforms: {}", err))?;
+    }
+
+    let statuses = simulation.statuses();
+    let lengths = simulation.lengths();
+    if args.reproducible {
+        let gathered =
+            particle::gather_particle_states(&world, simulation.particles(), &statuses, &lengths)?;
+        if let Some((particles, statuses, lengths)) = gathered {
+            write_lost_particles(
+                output_dir,
+                output::ALL_RANKS,
+                0,
+                &particles,
+                &statuses,
+                &lengths,
+                args,
+            )?;
+        }
+    } else {
+        write_lost_particles(
+            output_dir,
+            rank,
+            offset,
+            simulation.particles(),
+            &statuses,
+            &lengths,
+            args,
+        )?;
+    }
+    let resume_path = output_dir.join(particle::resume_states_file_name(rank));
+    let resume_states: Vec<particle::ResumeState> = simulation
+        .states()
+        .iter()
+        .zip(&start_radii)
+        .map(|(state, start_radius)| particle::ResumeState {
+            particle: state.index as usize,
+            step: simulation.step(),
+            length: state.length,
+            toroidal: state.angles.toroidal,
+            poloidal: state.angles.poloidal,
+            start_radius: *start_radius,
+        })
+        .collect();
+    particle::write_resume_states(&resume_path, &resume_states, args.delimiter)
+        .map_err(|err| format!("writing {}: {}", resume_path.display(), err))?;
+    let local_losses = particle::loss_counts(&statuses, args.steps);
+    let mut losses = vec![0u64; local_losses.len()];
+    world.all_reduce_into(&local_losses[..], &mut losses[..], SystemOperation::sum());
+
+    let local_summary = simulation.summary(t_end - t_start);
+    let mut max_length = 0.0;
+    world.all_reduce_into(
+        &lengths.iter().copied().fold(0.0, f64::max),
+        &mut max_length,
+        SystemOperation::max(),
+    );
+    let local_summary = local_summary.with_connection_lengths(&statuses, &lengths, max_length);
+    let local_counts = local_summary.counts();
+    let mut counts = vec![0u64; local_counts.len()];
+    world.all_reduce_into(&local_counts[..], &mut counts[..], SystemOperation::sum());
+    if rank == 0 {
+        let run_summary = local_summary.with_counts(&counts);
+        info!(
+            "Lost {} of {} particles",
+            run_summary.lost, run_summary.num_particles
+        );
+        info!(
+            "Integration substeps: {} accepted, {} rejected",
+            run_summary.accepted_steps, run_summary.rejected_steps
+        );
+        run_summary
+            .write(output_dir)
+            .map_err(|err| format!("writing run summary: {}", err))?;
+        debug!("Wrote run summary to {:?}", output_dir);
+        // Stopped runs keep the manifest of a run without a wall time
+        if let Some(run_manifest) = run_manifest.filter(|_| !stopped) {
+            run_manifest
+                .with_wall_time(started.elapsed().as_secs_f64())
+                .write(output_dir)
+                .map_err(|err| format!("writing the manifest: {}", err))?;
+            debug!("Wrote manifest to {:?}", output_dir);
+        }
+        particle::write_loss_counts(&output_dir.join(particle::LOSSES_FILE), &losses)
+            .map_err(|err| format!("writing loss counts: {}", err))?;
+        debug!("Wrote loss counts to {:?}", output_dir);
+        if args.output_format == output::OutputFormat::Vtk {
+            output::write_snapshot_collection(output_dir, args.step_size)
+                .map_err(|err| format!("writing snapshot collection: {}", err))?;
+            debug!("Wrote snapshot collection to {:?}", output_dir);
+        }
+        if let Some(reference_dir) = args.validate.as_ref().filter(|_| !stopped) {
+            return validate_run(output_dir, Path::new(reference_dir), args);
+        }
+    }
+    Ok(true)
+}
+
+/// Writes the lost particles of `rank`, or of every rank for `ALL_RANKS`,
+/// whose first particle has global index `offset`
+fn write_lost_particles(
+    output_dir: &Path,
+    rank: Rank,
+    offset: usize,
+    particles: &[point::Point],
+    statuses: &[particle::ParticleState],
+    lengths: &[f64],
+    args: &args::Args,
+) -> Result<(), Box<dyn Error>> {
+    let lost_path = output_dir.join(particle::lost_particles_file_name(rank));
+    particle::write_lost_particles(
+        &lost_path,
+        offset,
+        particles,
+        statuses,
+        lengths,
+        &args.text_format(),
+    )
+    .map_err(|err| format!("writing lost particles: {}", err).into())
+}
+
+/// What the particles of a resumed run carry over from the run it resumes,
+/// one value per particle in global order
+#[derive(Default)]
+struct RestoredParticles {
+    /// `ParticleState` values
+    loss_steps: Vec<f64>,
+    lengths: Vec<f64>,
+    /// Swept angles
+    toroidal: Vec<f64>,
+    poloidal: Vec<f64>,
+    start_radii: Vec<f64>,
+}
+
+/// Reads back what the particles resumed from `resume` carried up to the
+/// resumed step, from the resume states of the run it resumes and the
+/// losses `resume` read. Fails unless that run ended at the resumed step, as
+/// runs that crashed do not.
+fn restore_particles(
+    output_dir: &Path,
+    resume: &restart::RestartPoint,
+) -> Result<RestoredParticles, Box<dyn Error>> {
+    let delimiter = resume.config.text_format().delimiter;
+    let step = resume.source.step;
+    let num_particles = resume.particles.len();
+    let mut restored = RestoredParticles {
+        loss_steps: resume
+            .statuses
+            .iter()
+            .map(|status| status.to_value())
+            .collect(),
+        lengths: vec![f64::NAN; num_particles],
+        toroidal: vec![0.0; num_particles],
+        poloidal: vec![0.0; num_particles],
+        start_radii: vec![0.0; num_particles],
+    };
+    for rank in 0..resume.config.world_size {
+        let path = output_dir.join(particle::resume_states_file_name(rank));
+        let states = particle::read_resume_states(&path, delimiter).map_err(|err| {
+            format!(
+                "reading {}: {}, a run that did not finish or stop cannot be resumed, restart it with --restart-from",
+                path.display(),
+                err
+            )
+        })?;
+        for state in states {
+            if state.step != step {
+                return Err(format!(
+                    "{} is of step {}, not of the last snapshot {}, restart the run with --restart-from",
+                    path.display(),
+                    state.step,
+                    step
+                )
+                .into());
+            }
+            if state.particle >= num_particles {
+                return Err(
+                    format!("{} has no particle {}", path.display(), state.particle).into(),
+                );
+            }
+            restored.lengths[state.particle] = state.length;
+            restored.toroidal[state.particle] = state.toroidal;
+            restored.poloidal[state.particle] = state.poloidal;
+            restored.start_radii[state.particle] = state.start_radius;
+        }
+    }
+    if let Some(missing) = restored.lengths.iter().position(|length| length.is_nan()) {
+        return Err(format!("no resume state of particle {}", missing).into());
+    }
+    Ok(restored)
+}
+
+/// Gathers the rotational transform of every field line on rank 0, which
+/// writes them and logs those of the innermost and outermost confined lines.
+/// `start_radii` are the distances to the axis the particles of this rank
+/// started at.
+fn write_rotational_transforms(
+    world: &impl collectives::Collectives,
+    output_dir: &Path,
+    start_radii: &[f64],
+    simulation: &tracer::Simulation,
+) -> Result<(), Box<dyn Error>> {
+    let Some(transforms) =
+        diagnostics::gather_rotational_transforms(start_radii, simulation.states(), world)?
+    else {
+        return Ok(());
+    };
+    diagnostics::write_rotational_transforms(
+        &output_dir.join(diagnostics::IOTA_FILE),
+        &transforms,
+    )?;
+    let mut confined: Vec<&diagnostics::RotationalTransform> = transforms
+        .iter()
+        .filter(|transform| !transform.lost && transform.iota.is_finite())
+        .collect();
+    confined.sort_by(|a, b| a.start_radius.total_cmp(&b.start_radius));
+    let (Some(innermost), Some(outermost)) = (confined.first(), confined.last()) else {
+        info!("No confined field line to take the rotational transform of");
+        return Ok(());
+    };
+    info!(
+        "Rotational transform {:.4} at r = {:.4} m to {:.4} at r = {:.4} m",
+        innermost.iota, innermost.start_radius, outermost.iota, outermost.start_radius
+    );
+    Ok(())
+}
+
+/// Fails if `run_dir` has a manifest that does not record `config`, its
+/// `run.json`, and warns when another version of the code wrote it
+fn check_manifest(run_dir: &Path, config: &config::RunConfig) -> Result<(), Box<dyn Error>> {
+    let Some(run_manifest) = manifest::Manifest::read_if_present(run_dir)? else {
+        return Ok(());
+    };
+    run_manifest
+        .check_config(config)
+        .map_err(|err| format!("{}: {}", run_dir.display(), err))?;
+    if run_manifest.version != manifest::VERSION {
+        warn!(
+            "{} was written by version {}, this is version {}",
+            run_dir.display(),
+            run_manifest.version,
+            manifest::VERSION
+        );
+    }
+    Ok(())
+}
+
+/// Logs the error of every reference step, returns whether none diverged
+fn validate_run(
+    output_dir: &Path,
+    reference_dir: &Path,
+    args: &args::Args,
+) -> Result<bool, Box<dyn Error>> {
+    commands::validate(output_dir, reference_dir, args.validation_tolerances())
+        .map_err(|err| format!("validating against {:?}: {}", reference_dir, err).into())
+}
+
+/// Scatters `values` of rank 0 so that every rank receives its count of them
+fn scatter_values<T: Equivalence + Default + Clone>(
+    world: &impl Communicator,
+    values: &[T],
+    counts: &[usize],
+) -> Vec<T> {
+    let mut local_values = vec![T::default(); counts[world.rank() as usize]];
+    if world.rank() == 0 {
+        let mpi_counts = partition::to_mpi_counts(counts);
+        let displs = partition::to_mpi_counts(&partition::particle_offsets(counts));
+        let partition = Partition::new(values, mpi_counts, displs);
+        world
+            .process_at_rank(0)
+            .scatter_varcount_into_root(&partition, local_values.as_mut_slice());
+    } else {
+        world
+            .process_at_rank(0)
+            .scatter_varcount_into(local_values.as_mut_slice());
+    }
+    local_values
+}
+
+/// `values` of rank 0 on every rank, whatever the others pass
+fn broadcast_values<T: Equivalence + Default + Clone>(
+    world: &impl Communicator,
+    values: &[T],
+) -> Vec<T> {
+    let root = world.process_at_rank(0);
+    let mut len = values.len() as u64;
+    root.broadcast_into(&mut len);
+    let mut received = vec![T::default(); len as usize];
+    if world.rank() == 0 {
+        received.clone_from_slice(values);
+    }
+    root.broadcast_into(&mut received[..]);
+    received
+}
+
+/// `coils` of rank 0 on every rank, sent as the length of every coil and
+/// then all of their points at once
+fn broadcast_coils(
+    world: &impl Communicator,
+    coils: &[Vec<point::Point>],
+) -> Vec<Vec<point::Point>> {
+    let lengths: Vec<u64> = coils.iter().map(|coil| coil.len() as u64).collect();
+    let lengths = broadcast_values(world, &lengths);
+    let points = broadcast_values(world, &coils.concat());
+    let mut rest = &points[..];
+    lengths
+        .iter()
+        .map(|&length| {
+            let (coil, tail) = rest.split_at(length as usize);
+            rest = tail;
+            coil.to_vec()
+        })
+        .collect()
+}
+
+/// Sizes the global rayon pool before anything runs on it: `threads` if
+/// given, otherwise the cores of the node divided among the ranks sharing it
+fn configure_threads(
+    world: &SimpleCommunicator,
+    threads: Option<u32>,
+) -> Result<(), Box<dyn Error>> {
+    let node = world.split_shared(world.rank());
+    let threads = match threads {
+        Some(threads) => threads as usize,
+        None if env::var_os("RAYON_NUM_THREADS").is_some() => return Ok(()),
+        None => {
+            let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+            (cores / node.size() as usize).max(1)
+        }
+    };
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+        .map_err(|err| format!("configuring threads: {}", err))?;
+    if world.rank() == 0 {
+        info!(
+            "Threads per rank: {}, ranks on the first node: {}",
+            threads,
+            node.size()
+        );
+    }
+    Ok(())
+}
+
+/// Runs a writer rank: writes the snapshots its compute ranks send until all
+/// of them finished, then meets them at the final barrier
+fn serve_snapshots(
+    world: &SimpleCommunicator,
+    ranks: aggregator::WriterRanks,
+    args: &args::Args,
+) -> Result<(), Box<dyn Error>> {
+    let output_dir = Path::new(&args.output);
+    // A resume continues from the latest snapshot, so every one on disk
+    // precedes it
+    let written_steps: Vec<u32> = if args.resume {
+        output::list_snapshots(output_dir)?.into_keys().collect()
+    } else {
+        Vec::new()
+    };
+    let writer_for = |rank| {
+        output::SnapshotWriter::new(output_dir, rank, args.text_format(), args.decimation())
+            .with_retention(args.retention())
+            .with_written_steps(&written_steps)
+            .with_binary_precision(args.binary_precision)
+            .with_compression(args.compress)
+            .with_output_format(args.output_format)
+            .map_err(Into::into)
+    };
+    aggregator::serve(world, ranks, writer_for)
+        .map_err(|err| format!("writing snapshots: {}", err))?;
+    world.barrier();
+    Ok(())
+}
+
+fn run_command(command: args::Command) {
+    match command {
+        args::Command::Simulate(_) | args::Command::Fieldmap(_) => {
+            unreachable!("MPI commands run in main")
+        }
+        args::Command::Seed {
+            mode,
+            count,
+            radius,
+            phi,
+            seed,
+            major_radius,
+            output,
+        } => {
+            let seeding = seeding::Seeding {
+                mode,
+                radius,
+                phi,
+                seed,
+            };
+            let physics = constants::PhysicsParams {
+                major_radius,
+                ..constants::PhysicsParams::default()
+            };
+            if let Err(err) = commands::seed(&seeding, count, &physics, &output) {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        args::Command::Validate {
+            run_dir,
+            reference,
+            abs_tol,
+            rel_tol,
+        } => {
+            let tolerances = integrator::Tolerances {
+                absolute: abs_tol,
+                relative: rel_tol,
+            };
+            match commands::validate(&run_dir, &reference, tolerances) {
+                Ok(true) => {}
+                Ok(false) => std::process::exit(1),
+                Err(err) => {
+                    error!("{}", err);
+                    std::process::exit(1)
+                }
+            }
+        }
+        args::Command::Poincare { run_dir, output } => {
+            if let Err(err) = commands::poincare(&run_dir, &output) {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        args::Command::ConfigDiff { left, right } => match commands::config_diff(&left, &right) {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(1),
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1)
+            }
+        },
+        args::Command::Merge {
+            run_dir,
+            output,
+            hdf5,
+        } => {
+            if let Err(err) = commands::merge(&run_dir, output.as_deref(), hdf5.as_deref()) {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        args::Command::Inspect { run_dir } => match commands::inspect(&run_dir) {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(1),
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1)
+            }
+        },
+        args::Command::Animate {
+            run_dir,
+            output,
+            color_by,
+            particle_stride,
+        } => {
+            if let Err(err) =
+                commands::animate(&run_dir, &output, color_by, particle_stride as usize)
+            {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        args::Command::Scene {
+            run_dir,
+            output,
+            step_stride,
+            particle_stride,
+        } => {
+            let stride = commands::Stride {
+                steps: step_stride as usize,
+                particles: particle_stride as usize,
+            };
+            if let Err(err) = commands::export_scene(&run_dir, &output, stride) {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        args::Command::TraceOne {
+            resource_path,
+            start,
+            steps,
+            step_size,
+        } => {
+            if let Err(err) = commands::trace_one(&resource_path, start, steps, step_size) {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        args::Command::FieldJacobian {
+            resource_path,
+            points,
+        } => {
+            if let Err(err) = commands::field_jacobian(&resource_path, &points) {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        args::Command::Lyapunov {
+            resource_path,
+            particles_file,
+            num_particles,
+            output,
+            steps,
+            step_size,
+            separation,
+            renormalize_every,
+        } => {
+            let settings = diagnostics::LyapunovSettings {
+                steps,
+                step_size,
+                separation,
+                renormalize_every,
+            };
+            if let Err(err) = commands::lyapunov(
+                &resource_path,
+                &particles_file,
+                num_particles,
+                &output,
+                &settings,
+            ) {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        args::Command::Islands {
+            run_dir,
+            output,
+            max_mode,
+        } => {
+            if let Err(err) = commands::islands(&run_dir, output.as_deref(), max_mode) {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        args::Command::Ripple {
+            resource_path,
+            start,
+            steps,
+            step_size,
+            theta_bins,
+            phi_bins,
+            modes,
+            major_radius,
+            output,
+        } => {
+            let settings = commands::RippleSettings {
+                steps,
+                step_size,
+                theta_bins: theta_bins as usize,
+                phi_bins: phi_bins as usize,
+                num_modes: modes,
+                major_radius,
+            };
+            if let Err(err) = commands::ripple(&resource_path, start, &settings, output.as_deref())
+            {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        args::Command::Sensitivity {
+            resource_path,
+            particles_file,
+            num_particles,
+            steps,
+            step_size,
+            delta,
+            coil_groups,
+            output,
+        } => {
+            let starts = match point::read_from_file(&particles_file, num_particles) {
+                Ok(starts) => starts,
+                Err(err) => {
+                    error!("{}", err);
+                    std::process::exit(1)
+                }
+            };
+            if let Err(err) = commands::sensitivity(
+                &resource_path,
+                &starts,
+                steps,
+                step_size,
+                delta,
+                &coil_groups,
+                output.as_deref(),
+            ) {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        args::Command::Aggregate {
+            campaign_dir,
+            output,
+            poincare,
+        } => {
+            if let Err(err) =
+                commands::aggregate(&campaign_dir, output.as_deref(), poincare.as_deref())
+            {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        args::Command::Convert {
+            run_dir,
+            output,
+            to,
+            binary_precision,
+            step_stride,
+            particle_stride,
+            text,
+        } => {
+            let stride = commands::Stride {
+                steps: step_stride as usize,
+                particles: particle_stride as usize,
+            };
+            if let Err(err) = commands::convert(
+                &run_dir,
+                &output,
+                to,
+                binary_precision,
+                &text.text_format(),
+                stride,
+            ) {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
 }
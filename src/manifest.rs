@@ -0,0 +1,154 @@
+use crate::config::{ConfigDifference, DifferenceKind, RUN_CONFIG_FILE, RunConfig};
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+/// Name of the manifest rank 0 writes into every output directory
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Version of the code writing manifests
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// What produced an output directory, for post-processing: the resolved
+/// configuration, which holds the coil checksums, the particle count and
+/// the steps, with the code version, the ranks and the wall time. Written
+/// when a run starts and again once it finishes.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    /// Every rank of the run, writer ranks included
+    pub ranks: usize,
+    /// Seconds from the start of the run to its end, `None` while it runs
+    /// or when it was interrupted
+    pub wall_time: Option<f64>,
+    pub config: RunConfig,
+}
+
+impl Manifest {
+    pub fn new(config: RunConfig, ranks: usize) -> Self {
+        Manifest {
+            version: VERSION.to_string(),
+            ranks,
+            wall_time: None,
+            config,
+        }
+    }
+
+    pub fn with_wall_time(self, wall_time: f64) -> Self {
+        Manifest {
+            wall_time: Some(wall_time),
+            ..self
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = if path.is_dir() {
+            path.join(MANIFEST_FILE)
+        } else {
+            path.to_path_buf()
+        };
+        let reader = BufReader::new(File::open(&path)?);
+        let manifest = serde_json::from_reader(reader)
+            .map_err(|err| format!("Error parsing {}: {}", path.display(), err))?;
+        Ok(manifest)
+    }
+
+    /// Manifest of `run_dir`, `None` for runs from before manifests
+    pub fn read_if_present(run_dir: &Path) -> Result<Option<Self>, Box<dyn Error>> {
+        if run_dir.join(MANIFEST_FILE).exists() {
+            Manifest::read(run_dir).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn write(&self, output_dir: &Path) -> Result<(), Box<dyn Error>> {
+        let writer = BufWriter::new(File::create(output_dir.join(MANIFEST_FILE))?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    /// Fails unless `config`, read from the `run.json` next to the
+    /// manifest, is the configuration the manifest records
+    pub fn check_config(&self, config: &RunConfig) -> Result<(), Box<dyn Error>> {
+        let differences: Vec<String> = self
+            .config
+            .diff(config)
+            .iter()
+            .map(|difference| difference.to_string())
+            .collect();
+        if differences.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} does not match {}: {}",
+                RUN_CONFIG_FILE,
+                MANIFEST_FILE,
+                differences.join(", ")
+            )
+            .into())
+        }
+    }
+
+    /// Differences in the physics and integration of two runs, which make
+    /// their trajectories differ. Runs of different lengths share the steps
+    /// of the shorter, so the step counts are left out.
+    pub fn trajectory_differences(&self, other: &Manifest) -> Vec<ConfigDifference> {
+        self.config
+            .diff(&other.config)
+            .into_iter()
+            .filter(|difference| match difference.kind {
+                DifferenceKind::Physics => true,
+                DifferenceKind::Integration => difference.field != "steps",
+                _ => false,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifests_check_their_configuration() {
+        let config = RunConfig {
+            steps: 100,
+            step_size: 0.001,
+            num_particles: 8,
+            ..RunConfig::default()
+        };
+        let manifest = Manifest::new(config.clone(), 4).with_wall_time(2.5);
+        assert_eq!(manifest.version, VERSION);
+        assert!(manifest.check_config(&config).is_ok());
+        let err = manifest
+            .check_config(&RunConfig {
+                num_particles: 9,
+                ..config.clone()
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("num_particles"), "{}", err);
+
+        let longer = Manifest::new(
+            RunConfig {
+                steps: 200,
+                ..config.clone()
+            },
+            4,
+        );
+        assert!(manifest.trajectory_differences(&longer).is_empty());
+        let finer = Manifest::new(
+            RunConfig {
+                step_size: 0.0005,
+                ..config
+            },
+            4,
+        );
+        let differences = manifest.trajectory_differences(&finer);
+        assert_eq!(differences.len(), 1);
+        assert_eq!(differences[0].field, "step_size");
+    }
+}
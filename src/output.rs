@@ -0,0 +1,1384 @@
+use crate::{
+    collectives::Collectives,
+    compression::{
+        Compression, FileWriter, compressed_path, create_csv, finish_csv, uncompressed_name,
+    },
+    config::RunConfig,
+    diagnostics::{DriftLog, PunctureLog},
+    particle::{write_fields, write_velocities},
+    point::{
+        BINARY_POINTS_HEADER_LEN, BINARY_POINTS_MAGIC, Point, read_binary_points,
+        read_from_file_with_delimiter,
+    },
+    stream::Publisher,
+    vtk::{DataSet, Scalars, read_vtp_points, write_pvd, write_vtp_points},
+};
+use clap::ValueEnum;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Name of the single-file HDF5 output inside an output directory
+pub const HDF5_FILE: &str = "trajectories.h5";
+
+/// Name of the single-file netCDF output inside an output directory
+pub const NETCDF_FILE: &str = "trajectories.nc";
+
+/// Name of the single-file Parquet output inside an output directory
+pub const PARQUET_FILE: &str = "trajectories.parquet";
+
+/// Snapshots of a run with their steps, in step order
+pub type Snapshots = Vec<(u32, Vec<Point>)>;
+
+/// Snapshots read back from a single-file output, with the global indices
+/// of their particles in snapshot order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trajectories {
+    pub particles: Vec<usize>,
+    pub snapshots: Snapshots,
+}
+
+/// Per-rank snapshot files of an output directory, keyed by step and then rank
+pub type SnapshotIndex = BTreeMap<u32, BTreeMap<i32, PathBuf>>;
+
+/// Rank of the snapshots that hold the particles of every rank, in global order
+pub const ALL_RANKS: i32 = -1;
+
+/// Notation used for floating point values in text outputs
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Notation {
+    #[default]
+    Fixed,
+    Scientific,
+}
+
+/// Name of the ParaView collection of the snapshots of a VTK output directory
+pub const VTK_COLLECTION_FILE: &str = "snapshots.pvd";
+
+/// Formats outputs can be written in
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// One delimited text file per rank and step
+    #[default]
+    Text,
+    /// One XML PolyData (`.vtp`) file per rank and step, collected in `snapshots.pvd`
+    Vtk,
+    /// A single HDF5 file holding every step
+    Hdf5,
+    /// A single netCDF file holding every step, with the run configuration
+    /// as attributes
+    Netcdf,
+    /// One raw `.bin` file of `f64` or `f32` coordinates per rank and step
+    Binary,
+    /// A single Parquet table of step, particle, x, y and z columns with a
+    /// row per particle and step
+    Parquet,
+}
+
+impl OutputFormat {
+    /// Extension of the per-rank snapshot files, `None` for single-file formats
+    pub fn snapshot_extension(&self) -> Option<&'static str> {
+        match self {
+            OutputFormat::Text => Some("csv"),
+            OutputFormat::Vtk => Some("vtp"),
+            OutputFormat::Hdf5 | OutputFormat::Netcdf | OutputFormat::Parquet => None,
+            OutputFormat::Binary => Some("bin"),
+        }
+    }
+}
+
+/// How the positions written during a run are grouped into files
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputLayout {
+    /// One file per rank and written step, see `OutputFormat`
+    #[default]
+    Snapshots,
+    /// One CSV file per particle in `trajectories/`, named by its global
+    /// index, with a row appended every written step while it is confined
+    Trajectories,
+}
+
+/// Directory of the per-particle trajectory files inside an output directory
+pub const TRAJECTORY_DIR: &str = "trajectories";
+
+/// Name of the trajectory file of the particle with global index `id`
+pub fn trajectory_file_name(id: usize) -> String {
+    format!("particle_{}.csv", id)
+}
+
+/// Width of the coordinates of binary outputs
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum BinaryPrecision {
+    /// Half the size, about 7 significant digits
+    F32,
+    #[default]
+    F64,
+}
+
+/// Numeric formatting applied by the text/CSV writers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextFormat {
+    /// Digits after the decimal point, `None` writes the shortest exact representation
+    pub precision: Option<usize>,
+    pub notation: Notation,
+    pub delimiter: u8,
+}
+
+impl Default for TextFormat {
+    fn default() -> Self {
+        TextFormat {
+            precision: None,
+            notation: Notation::Fixed,
+            delimiter: b',',
+        }
+    }
+}
+
+impl TextFormat {
+    pub fn format_value(&self, value: f64) -> String {
+        match (self.notation, self.precision) {
+            (Notation::Fixed, Some(precision)) => format!("{:.*}", precision, value),
+            (Notation::Fixed, None) => format!("{}", value),
+            (Notation::Scientific, Some(precision)) => format!("{:.*e}", precision, value),
+            (Notation::Scientific, None) => format!("{:e}", value),
+        }
+    }
+}
+
+/// Decides which integration steps are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decimation {
+    /// Every k-th step
+    Every(u32),
+    /// Whenever some particle turned by more than `max_angle` radians since
+    /// the last written step, plus the first and last step
+    Curvature { max_angle: f64 },
+    /// No step, for simulations that are only queried in memory
+    Never,
+}
+
+/// Which written snapshots stay on disk while a run progresses
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    /// Number of most recent snapshots to keep, `None` keeps all of them
+    pub keep_last: Option<u32>,
+    /// Snapshots of steps that are multiples of this are never deleted
+    pub checkpoint_every: Option<u32>,
+}
+
+impl Retention {
+    pub fn is_checkpoint(&self, step: u32) -> bool {
+        self.checkpoint_every
+            .is_some_and(|every| step.is_multiple_of(every))
+    }
+
+    /// Steps of `written` (in write order) that remain once the run has finished
+    pub fn retained(&self, written: &[u32]) -> Vec<u32> {
+        let recent = self.keep_last.map_or(0, |keep_last| {
+            written.len().saturating_sub(keep_last as usize)
+        });
+        written
+            .iter()
+            .enumerate()
+            .filter(|(index, step)| *index >= recent || self.is_checkpoint(**step))
+            .map(|(_, step)| *step)
+            .collect()
+    }
+}
+
+/// Writes the per-rank snapshots of a run, thinning them according to a
+/// `Decimation` and deleting old ones according to a `Retention`
+pub struct SnapshotWriter {
+    pub output_dir: PathBuf,
+    pub rank: i32,
+    pub format: TextFormat,
+    pub output_format: OutputFormat,
+    pub layout: OutputLayout,
+    pub binary_precision: BinaryPrecision,
+    /// Compression of the snapshot, velocity and field files
+    pub compression: Option<Compression>,
+    pub decimation: Decimation,
+    pub retention: Retention,
+    /// Gather every snapshot on rank 0 and write it as one `ALL_RANKS` file
+    pub single_file: bool,
+    /// Also write the magnetic field at every particle
+    pub fields: bool,
+    /// Global index of the first particle of this rank. When set, text and
+    /// VTK snapshots carry the global index of every particle.
+    pub particle_ids: Option<usize>,
+    /// Gather every snapshot on rank 0 to stream it through `publisher`
+    pub streaming: bool,
+    publisher: Option<Publisher>,
+    /// Drift statistics of every step, written whenever a snapshot is
+    pub drift: Option<DriftLog>,
+    /// Poincaré punctures of every step, written whenever a snapshot is
+    pub punctures: Option<PunctureLog>,
+    /// Sends the snapshots to a writer rank instead of writing them here
+    #[cfg(feature = "mpi")]
+    forwarder: Option<crate::aggregator::Forwarder>,
+    /// File the gathered snapshots of `OutputFormat::Netcdf` runs are
+    /// appended to, only on rank 0
+    netcdf: Option<NetcdfTrajectories>,
+    reference_directions: Vec<Point>,
+    recent_steps: VecDeque<u32>,
+}
+
+impl SnapshotWriter {
+    pub fn new(output_dir: &Path, rank: i32, format: TextFormat, decimation: Decimation) -> Self {
+        SnapshotWriter {
+            output_dir: output_dir.to_path_buf(),
+            rank,
+            format,
+            output_format: OutputFormat::Text,
+            layout: OutputLayout::Snapshots,
+            binary_precision: BinaryPrecision::default(),
+            compression: None,
+            decimation,
+            retention: Retention::default(),
+            single_file: false,
+            fields: false,
+            particle_ids: None,
+            streaming: false,
+            publisher: None,
+            drift: None,
+            punctures: None,
+            #[cfg(feature = "mpi")]
+            forwarder: None,
+            netcdf: None,
+            reference_directions: Vec::new(),
+            recent_steps: VecDeque::new(),
+        }
+    }
+
+    pub fn with_retention(self, retention: Retention) -> Self {
+        SnapshotWriter { retention, ..self }
+    }
+
+    /// Counts snapshots already on disk, from before a resumed run, against
+    /// the retention window
+    pub fn with_written_steps(mut self, steps: &[u32]) -> Self {
+        self.recent_steps.extend(steps);
+        self
+    }
+
+    pub fn with_single_file(self, single_file: bool) -> Self {
+        SnapshotWriter {
+            single_file,
+            ..self
+        }
+    }
+
+    pub fn with_fields(self, fields: bool) -> Self {
+        SnapshotWriter { fields, ..self }
+    }
+
+    /// Writes the global index of every particle with the snapshots, those
+    /// of this rank starting at `first_id`
+    pub fn with_particle_ids(self, first_id: usize) -> Self {
+        SnapshotWriter {
+            particle_ids: Some(first_id),
+            ..self
+        }
+    }
+
+    /// Groups the written positions by particle instead of by step. The
+    /// particles of this rank are numbered from the first id of
+    /// `with_particle_ids`, 0 by default.
+    pub fn with_layout(self, layout: OutputLayout) -> Self {
+        SnapshotWriter { layout, ..self }
+    }
+
+    pub fn with_binary_precision(self, binary_precision: BinaryPrecision) -> Self {
+        SnapshotWriter {
+            binary_precision,
+            ..self
+        }
+    }
+
+    pub fn with_compression(self, compression: Option<Compression>) -> Self {
+        SnapshotWriter {
+            compression,
+            ..self
+        }
+    }
+
+    /// Also streams every snapshot to live consumers. Every rank takes part
+    /// in gathering the particles, `publisher` is only needed on rank 0.
+    pub fn with_stream(self, publisher: Option<Publisher>) -> Self {
+        SnapshotWriter {
+            streaming: true,
+            publisher,
+            ..self
+        }
+    }
+
+    /// Also records how far each step strays from an exact integration,
+    /// see `DriftSums`
+    pub fn with_drift(self, drift: DriftLog) -> Self {
+        SnapshotWriter {
+            drift: Some(drift),
+            ..self
+        }
+    }
+
+    /// Also records where the particles cross the Poincaré plane
+    pub fn with_punctures(self, punctures: PunctureLog) -> Self {
+        SnapshotWriter {
+            punctures: Some(punctures),
+            ..self
+        }
+    }
+
+    /// Leaves writing the snapshots, and their retention, to a writer rank
+    #[cfg(feature = "mpi")]
+    pub fn with_forwarder(self, forwarder: crate::aggregator::Forwarder) -> Self {
+        SnapshotWriter {
+            forwarder: Some(forwarder),
+            ..self
+        }
+    }
+
+    /// Fails for single-file formats other than netCDF, for netCDF
+    /// trajectory layouts, for compressed VTK and netCDF snapshots, which
+    /// ParaView and netCDF readers could not open, and for compressions this
+    /// build does not have
+    pub fn with_output_format(self, output_format: OutputFormat) -> Result<Self, String> {
+        if output_format.snapshot_extension().is_none() && output_format != OutputFormat::Netcdf {
+            return Err(format!(
+                "{:?} output is only written by the convert subcommand",
+                output_format
+            ));
+        }
+        if output_format == OutputFormat::Netcdf && self.layout == OutputLayout::Trajectories {
+            return Err("netCDF output holds snapshots, not per-particle trajectories".to_string());
+        }
+        if let (OutputFormat::Vtk | OutputFormat::Netcdf, Some(compression)) =
+            (output_format, self.compression)
+        {
+            return Err(format!(
+                "{:?} snapshots cannot be compressed with {:?}",
+                output_format, compression
+            ));
+        }
+        if let Some(compression) = self.compression {
+            compression.check_available()?;
+        }
+        Ok(SnapshotWriter {
+            output_format,
+            ..self
+        })
+    }
+
+    /// Appends the gathered snapshots of `OutputFormat::Netcdf` runs to
+    /// `netcdf`, which only rank 0 passes
+    pub fn with_netcdf(self, netcdf: Option<NetcdfTrajectories>) -> Self {
+        SnapshotWriter { netcdf, ..self }
+    }
+
+    /// Writes the snapshot of `step`, where `lost` flags the particles whose
+    /// points are `DIVERGENT_PARTICLE`, along with the particle velocities of
+    /// full orbit runs and the magnetic fields if given, then deletes the oldest snapshot that fell out of the
+    /// retention window unless it is a checkpoint. Collective when writing
+    /// single files, netCDF or streaming.
+    pub fn write(
+        &mut self,
+        points: &[Point],
+        lost: &[bool],
+        velocities: Option<&[Point]>,
+        fields: Option<&[Point]>,
+        step: u32,
+        comm: &impl Collectives,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.decimation == Decimation::Never {
+            return Ok(());
+        }
+        let mut gathered = None;
+        if self.streaming {
+            gathered = comm.gather_points(points)?;
+            if let (Some(publisher), Some(points)) = (&mut self.publisher, &gathered) {
+                publisher.publish(step, points)?;
+            }
+        }
+        #[cfg(feature = "mpi")]
+        if let Some(forwarder) = &self.forwarder {
+            forwarder.send(points, velocities, fields, step);
+            return Ok(());
+        }
+        if self.layout == OutputLayout::Trajectories {
+            return self.append_trajectories(points, lost, velocities, fields, step);
+        }
+        // netCDF files only hold the positions, gathered on rank 0
+        let positions_only = self.output_format == OutputFormat::Netcdf;
+        if !self.single_file && !positions_only {
+            return self.write_files(points, velocities, fields, step);
+        }
+        let gather = |values: Option<&[Point]>| {
+            if !positions_only && comm.any(values.is_some()) {
+                comm.gather_points(values.unwrap_or_default())
+            } else {
+                Ok(None)
+            }
+        };
+        let velocities = gather(velocities)?;
+        let fields = gather(fields)?;
+        if !self.streaming {
+            gathered = comm.gather_points(points)?;
+        }
+        match (gathered, &mut self.netcdf) {
+            (Some(points), Some(netcdf)) => netcdf.append(step, &points),
+            (Some(points), None) => {
+                self.write_files(&points, velocities.as_deref(), fields.as_deref(), step)
+            }
+            (None, _) => Ok(()),
+        }
+    }
+
+    /// Tells the writer rank, if any, that the run wrote its last snapshot
+    pub fn finish(&self) {
+        #[cfg(feature = "mpi")]
+        if let Some(forwarder) = &self.forwarder {
+            forwarder.finish();
+        }
+    }
+
+    pub(crate) fn write_files(
+        &mut self,
+        points: &[Point],
+        velocities: Option<&[Point]>,
+        fields: Option<&[Point]>,
+        step: u32,
+    ) -> Result<(), Box<dyn Error>> {
+        // Gathered snapshots hold every particle from the first one
+        let first_id = self
+            .particle_ids
+            .map(|first_id| if self.single_file { 0 } else { first_id });
+        match self.output_format {
+            OutputFormat::Vtk => {
+                let mut components: Vec<(&str, Vec<f64>)> = Vec::new();
+                if let Some(first_id) = first_id {
+                    let ids = (first_id..first_id + points.len()).map(|id| id as f64);
+                    components.push(("particle", ids.collect()));
+                }
+                if let Some(velocities) = velocities {
+                    components.push(("vx", velocities.iter().map(|v| v.x).collect()));
+                    components.push(("vy", velocities.iter().map(|v| v.y).collect()));
+                    components.push(("vz", velocities.iter().map(|v| v.z).collect()));
+                }
+                if let Some(fields) = fields {
+                    components.push(("bx", fields.iter().map(|b| b.x).collect()));
+                    components.push(("by", fields.iter().map(|b| b.y).collect()));
+                    components.push(("bz", fields.iter().map(|b| b.z).collect()));
+                    components.push(("b", fields.iter().map(|b| b.get_norm()).collect()));
+                }
+                let scalars: Vec<Scalars> = components
+                    .iter()
+                    .map(|(name, values)| Scalars { name, values })
+                    .collect();
+                write_vtp_points(&self.snapshot_path(step), points, &scalars)?
+            }
+            OutputFormat::Binary => {
+                write_binary_points(&self.snapshot_path(step), points, self.binary_precision)?;
+                if let Some(velocities) = velocities {
+                    write_binary_points(
+                        &self.velocity_path(step),
+                        velocities,
+                        self.binary_precision,
+                    )?;
+                }
+                if let Some(fields) = fields {
+                    write_binary_points(&self.field_path(step), fields, self.binary_precision)?;
+                }
+            }
+            _ => {
+                match first_id {
+                    Some(first_id) => write_indexed_points(
+                        &self.snapshot_path(step),
+                        first_id,
+                        points,
+                        &self.format,
+                    )?,
+                    None => write_points(&self.snapshot_path(step), points, &self.format)?,
+                }
+                if let Some(velocities) = velocities {
+                    write_velocities(&self.velocity_path(step), velocities, &self.format)?;
+                }
+                if let Some(fields) = fields {
+                    write_fields(&self.field_path(step), fields, &self.format)?;
+                }
+            }
+        }
+        let Some(keep_last) = self.retention.keep_last else {
+            return Ok(());
+        };
+        self.recent_steps.push_back(step);
+        while self.recent_steps.len() > keep_last as usize {
+            let expired = self.recent_steps.pop_front();
+            if let Some(expired) = expired.filter(|step| !self.retention.is_checkpoint(*step)) {
+                // Snapshots from before a resume may be gone already
+                for path in [
+                    self.snapshot_path(expired),
+                    self.velocity_path(expired),
+                    self.field_path(expired),
+                ] {
+                    if path.exists() {
+                        fs::remove_file(path)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends the row of `step` to the trajectory file of every particle
+    /// not flagged `lost`, starting the file over at step 0
+    fn append_trajectories(
+        &self,
+        points: &[Point],
+        lost: &[bool],
+        velocities: Option<&[Point]>,
+        fields: Option<&[Point]>,
+        step: u32,
+    ) -> Result<(), Box<dyn Error>> {
+        let dir = self.output_dir.join(TRAJECTORY_DIR);
+        fs::create_dir_all(&dir)?;
+        let mut header = vec!["step", "x", "y", "z"];
+        if velocities.is_some() {
+            header.extend(["vx", "vy", "vz"]);
+        }
+        if fields.is_some() {
+            header.extend(["bx", "by", "bz", "b"]);
+        }
+        let first_id = self.particle_ids.unwrap_or(0);
+        for (index, point) in points.iter().enumerate().filter(|(index, _)| !lost[*index]) {
+            let path = dir.join(trajectory_file_name(first_id + index));
+            let new = step == 0 || !path.exists();
+            let file = if new {
+                File::create(&path)?
+            } else {
+                OpenOptions::new().append(true).open(&path)?
+            };
+            let mut wtr = csv::WriterBuilder::new()
+                .delimiter(self.format.delimiter)
+                .from_writer(file);
+            if new {
+                wtr.write_record(&header)?;
+            }
+            let mut values = vec![*point];
+            values.extend(velocities.map(|velocities| velocities[index]));
+            let mut record = vec![step.to_string()];
+            for value in &values {
+                record.extend([value.x, value.y, value.z].map(|v| self.format.format_value(v)));
+            }
+            if let Some(fields) = fields {
+                let b = fields[index];
+                record.extend([b.x, b.y, b.z, b.get_norm()].map(|v| self.format.format_value(v)));
+            }
+            wtr.write_record(&record)?;
+            wtr.flush()?;
+        }
+        Ok(())
+    }
+
+    fn velocity_path(&self, step: u32) -> PathBuf {
+        let path = self
+            .output_dir
+            .join(velocity_file_name(self.file_rank(), step));
+        let path = match self.output_format {
+            OutputFormat::Binary => path.with_extension("bin"),
+            _ => path,
+        };
+        compressed_path(path, self.compression)
+    }
+
+    fn field_path(&self, step: u32) -> PathBuf {
+        let path = self
+            .output_dir
+            .join(field_file_name(self.file_rank(), step));
+        let path = match self.output_format {
+            OutputFormat::Binary => path.with_extension("bin"),
+            _ => path,
+        };
+        compressed_path(path, self.compression)
+    }
+
+    fn file_rank(&self) -> i32 {
+        if self.single_file {
+            ALL_RANKS
+        } else {
+            self.rank
+        }
+    }
+
+    fn snapshot_path(&self, step: u32) -> PathBuf {
+        let name = match self.output_format {
+            OutputFormat::Vtk => vtk_snapshot_file_name(self.file_rank(), step),
+            OutputFormat::Binary => binary_snapshot_file_name(self.file_rank(), step),
+            _ => snapshot_file_name(self.file_rank(), step),
+        };
+        compressed_path(self.output_dir.join(name), self.compression)
+    }
+
+    /// Whether `step` must be written, given the direction each particle moved
+    /// in during that step. Collective in curvature mode.
+    pub fn is_due(
+        &mut self,
+        step: u32,
+        total_steps: u32,
+        directions: &[Point],
+        comm: &impl Collectives,
+    ) -> bool {
+        match self.decimation {
+            Decimation::Every(k) => step.is_multiple_of(k),
+            Decimation::Never => false,
+            Decimation::Curvature { max_angle } => {
+                if self.reference_directions.len() != directions.len() {
+                    self.reference_directions = directions.to_vec();
+                }
+                let turned = directions
+                    .iter()
+                    .zip(&self.reference_directions)
+                    .any(|(direction, reference)| turning_angle(direction, reference) > max_angle);
+                let due = comm.any(turned) || step == total_steps;
+                if due {
+                    self.reference_directions.copy_from_slice(directions);
+                }
+                due
+            }
+        }
+    }
+}
+
+fn turning_angle(direction: &Point, reference: &Point) -> f64 {
+    let norms = direction.get_norm() * reference.get_norm();
+    if norms == 0.0 {
+        return 0.0;
+    }
+    (direction.dot(reference) / norms).clamp(-1.0, 1.0).acos()
+}
+
+pub fn snapshot_file_name(rank: i32, step: u32) -> String {
+    format!("out_{}_{}.csv", rank_label(rank), step)
+}
+
+pub fn vtk_snapshot_file_name(rank: i32, step: u32) -> String {
+    format!("out_{}_{}.vtp", rank_label(rank), step)
+}
+
+pub fn binary_snapshot_file_name(rank: i32, step: u32) -> String {
+    format!("out_{}_{}.bin", rank_label(rank), step)
+}
+
+/// Velocities of the particles of a text snapshot of a full orbit run
+pub fn velocity_file_name(rank: i32, step: u32) -> String {
+    format!("vel_{}_{}.csv", rank_label(rank), step)
+}
+
+/// Magnetic field at the particles of a text snapshot, written with
+/// `--write-fields`
+pub fn field_file_name(rank: i32, step: u32) -> String {
+    format!("field_{}_{}.csv", rank_label(rank), step)
+}
+
+pub(crate) fn rank_label(rank: i32) -> String {
+    if rank == ALL_RANKS {
+        "all".to_string()
+    } else {
+        rank.to_string()
+    }
+}
+
+/// Inverse of the snapshot file names of every per-rank format, compressed
+/// or not, returns the rank and step
+pub fn parse_snapshot_file_name(name: &str) -> Option<(i32, u32)> {
+    let stem = uncompressed_name(name).strip_prefix("out_")?;
+    let stem = stem
+        .strip_suffix(".csv")
+        .or_else(|| stem.strip_suffix(".vtp"))
+        .or_else(|| stem.strip_suffix(".bin"))?;
+    let (rank, step) = stem.split_once('_')?;
+    let rank = match rank {
+        "all" => ALL_RANKS,
+        rank => rank.parse().ok()?,
+    };
+    Some((rank, step.parse().ok()?))
+}
+
+pub fn merged_file_name(step: u32) -> String {
+    format!("merged_{}.csv", step)
+}
+
+pub fn list_snapshots(output_dir: &Path) -> Result<SnapshotIndex, io::Error> {
+    let mut index = SnapshotIndex::new();
+    for entry in fs::read_dir(output_dir)? {
+        let path = entry?.path();
+        let parsed = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(parse_snapshot_file_name);
+        if let Some((rank, step)) = parsed {
+            index.entry(step).or_default().insert(rank, path);
+        }
+    }
+    Ok(index)
+}
+
+/// Reads a snapshot written in either per-rank format, telling them apart
+/// by extension, decompressing text and binary snapshots
+pub fn read_snapshot(path: &Path, delimiter: u8) -> Result<Vec<Point>, Box<dyn Error>> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    match Path::new(uncompressed_name(name))
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        Some("vtp") => read_vtp_points(path),
+        Some("bin") => read_binary_points(path),
+        _ => read_from_file_with_delimiter(path, usize::MAX, delimiter),
+    }
+}
+
+/// Writes `snapshots.pvd` referencing the VTK snapshots of every rank, one
+/// part per rank, so ParaView opens the whole run as one time series
+pub fn write_snapshot_collection(output_dir: &Path, step_size: f64) -> Result<(), Box<dyn Error>> {
+    let mut data_sets = Vec::new();
+    for (step, rank_files) in list_snapshots(output_dir)? {
+        for (rank, path) in rank_files {
+            let Some(file) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .filter(|name| name.ends_with(".vtp"))
+            else {
+                continue;
+            };
+            data_sets.push(DataSet {
+                time: step as f64 * step_size,
+                part: rank.max(0) as usize,
+                file: file.to_string(),
+            });
+        }
+    }
+    write_pvd(&output_dir.join(VTK_COLLECTION_FILE), &data_sets)
+}
+
+pub fn write_points(
+    path: &Path,
+    points: &[Point],
+    format: &TextFormat,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = create_csv(path, format.delimiter)?;
+    wtr.write_record(["x", "y", "z"])?;
+    for point in points {
+        wtr.write_record([
+            format.format_value(point.x),
+            format.format_value(point.y),
+            format.format_value(point.z),
+        ])?;
+    }
+    finish_csv(wtr)
+}
+
+/// Writes points after a `particle` column of their global index, the
+/// first one `first_id`. Readers of `x y z` files skip the extra column.
+pub fn write_indexed_points(
+    path: &Path,
+    first_id: usize,
+    points: &[Point],
+    format: &TextFormat,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = create_csv(path, format.delimiter)?;
+    wtr.write_record(["particle", "x", "y", "z"])?;
+    for (index, point) in points.iter().enumerate() {
+        wtr.write_record([
+            (first_id + index).to_string(),
+            format.format_value(point.x),
+            format.format_value(point.y),
+            format.format_value(point.z),
+        ])?;
+    }
+    finish_csv(wtr)
+}
+
+/// Writes points in the binary layout read by `read_binary_points`
+pub fn write_binary_points(
+    path: &Path,
+    points: &[Point],
+    precision: BinaryPrecision,
+) -> Result<(), Box<dyn Error>> {
+    let width = match precision {
+        BinaryPrecision::F32 => 4,
+        BinaryPrecision::F64 => 8,
+    };
+    let mut writer = BufWriter::new(FileWriter::create(path)?);
+    let mut header = Vec::with_capacity(BINARY_POINTS_HEADER_LEN);
+    header.extend(BINARY_POINTS_MAGIC);
+    header.extend((points.len() as u64).to_le_bytes());
+    header.extend((width as u64).to_le_bytes());
+    writer.write_all(&header)?;
+    for point in points {
+        for value in [point.x, point.y, point.z] {
+            match precision {
+                BinaryPrecision::F32 => writer.write_all(&(value as f32).to_le_bytes())?,
+                BinaryPrecision::F64 => writer.write_all(&value.to_le_bytes())?,
+            }
+        }
+    }
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())?
+        .finish()?;
+    Ok(())
+}
+
+/// Writes snapshots of every step into one HDF5 file with `steps`,
+/// `particles` and `positions` (step, particle, xyz) datasets. `particles`
+/// are the global indices of the particles of the snapshots.
+#[cfg(feature = "hdf5")]
+pub fn write_hdf5_trajectories(
+    path: &Path,
+    steps: &[u32],
+    particles: &[usize],
+    snapshots: &[Vec<Point>],
+) -> Result<(), Box<dyn Error>> {
+    let num_particles = snapshots.first().map_or(0, |points| points.len());
+    let positions: Vec<f64> = snapshots
+        .iter()
+        .flatten()
+        .flat_map(|point| [point.x, point.y, point.z])
+        .collect();
+    let file = hdf5::File::create(path)?;
+    file.new_dataset::<u32>()
+        .shape(steps.len())
+        .create("steps")?
+        .write_raw(steps)?;
+    let indices: Vec<u64> = particles.iter().map(|&index| index as u64).collect();
+    file.new_dataset::<u64>()
+        .shape(particles.len())
+        .create("particles")?
+        .write_raw(&indices)?;
+    file.new_dataset::<f64>()
+        .shape((steps.len(), num_particles, 3))
+        .create("positions")?
+        .write_raw(&positions)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "hdf5"))]
+pub fn write_hdf5_trajectories(
+    _path: &Path,
+    _steps: &[u32],
+    _particles: &[usize],
+    _snapshots: &[Vec<Point>],
+) -> Result<(), Box<dyn Error>> {
+    Err("HDF5 output requires building with the `hdf5` feature".into())
+}
+
+/// Writes snapshots of every step into one netCDF file, see
+/// `NetcdfTrajectories`
+pub fn write_netcdf_trajectories(
+    path: &Path,
+    config: &RunConfig,
+    steps: &[u32],
+    particles: &[usize],
+    snapshots: &[Vec<Point>],
+) -> Result<(), Box<dyn Error>> {
+    let mut file = NetcdfTrajectories::create(path, config, particles)?;
+    for (&step, snapshot) in steps.iter().zip(snapshots) {
+        file.append(step, snapshot)?;
+    }
+    Ok(())
+}
+
+/// netCDF file of snapshots, appended to one step at a time along its
+/// unlimited `step` dimension. A `position` (step, particle, xyz) variable
+/// holds the coordinates. The `step`, `time` and `particle` coordinates hold
+/// the step numbers, the field line length or, for orbit runs, the time at
+/// each step, and the global particle indices. The run configuration is
+/// stored in the global attributes.
+#[cfg(feature = "netcdf")]
+pub struct NetcdfTrajectories {
+    file: netcdf::FileMut,
+    particles: usize,
+    step_size: f64,
+    steps: usize,
+}
+
+#[cfg(not(feature = "netcdf"))]
+pub struct NetcdfTrajectories(std::convert::Infallible);
+
+#[cfg(feature = "netcdf")]
+impl NetcdfTrajectories {
+    /// Creates the file at `path` for snapshots of `particles`, the global
+    /// indices of the particles in snapshot order
+    pub fn create(
+        path: &Path,
+        config: &RunConfig,
+        particles: &[usize],
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut file = netcdf::create(path)?;
+        file.add_unlimited_dimension("step")?;
+        file.add_dimension("particle", particles.len())?;
+        file.add_dimension("xyz", 3)?;
+
+        let mut step = file.add_variable::<u32>("step", &["step"])?;
+        step.put_attribute("long_name", "integration step")?;
+        let mut time = file.add_variable::<f64>("time", &["step"])?;
+        if config.orbit.is_some() {
+            time.put_attribute("long_name", "time")?;
+            time.put_attribute("units", "s")?;
+        } else {
+            time.put_attribute("long_name", "field line length")?;
+            time.put_attribute("units", "m")?;
+        }
+        let mut particle = file.add_variable::<u64>("particle", &["particle"])?;
+        particle.put_attribute("long_name", "global particle index")?;
+        let indices: Vec<u64> = particles.iter().map(|&index| index as u64).collect();
+        particle.put_values(&indices, ..)?;
+        let mut position = file.add_variable::<f64>("position", &["step", "particle", "xyz"])?;
+        position.put_attribute("long_name", "particle position")?;
+        position.put_attribute("units", "m")?;
+
+        file.add_attribute("title", "BS-Solctra particle trajectories")?;
+        file.add_attribute(
+            "source",
+            concat!("bs-solctra-rs ", env!("CARGO_PKG_VERSION")),
+        )?;
+        file.add_attribute("integrator", format!("{:?}", config.integrator))?;
+        file.add_attribute("step_size", config.step_size)?;
+        file.add_attribute("steps", config.steps)?;
+        file.add_attribute("current", config.current)?;
+        file.add_attribute("major_radius", config.major_radius)?;
+        file.add_attribute("minor_radius", config.minor_radius)?;
+        file.add_attribute("coil_files", config.coil_files.join(","))?;
+        file.add_attribute("run_config", serde_json::to_string(config)?)?;
+        Ok(NetcdfTrajectories {
+            file,
+            particles: particles.len(),
+            step_size: config.step_size,
+            steps: 0,
+        })
+    }
+
+    /// Appends the snapshot of `step`, holding every particle in order
+    pub fn append(&mut self, step: u32, points: &[Point]) -> Result<(), Box<dyn Error>> {
+        if points.len() != self.particles {
+            return Err(format!(
+                "snapshot of step {} holds {} particles, not {}",
+                step,
+                points.len(),
+                self.particles
+            )
+            .into());
+        }
+        let index = self.steps;
+        self.variable("step")?
+            .put_values(&[step], index..index + 1)?;
+        let time = step as f64 * self.step_size;
+        self.variable("time")?
+            .put_values(&[time], index..index + 1)?;
+        let positions: Vec<f64> = points
+            .iter()
+            .flat_map(|point| [point.x, point.y, point.z])
+            .collect();
+        self.variable("position")?
+            .put_values(&positions, (index..index + 1, .., ..))?;
+        self.steps += 1;
+        Ok(())
+    }
+
+    fn variable(&mut self, name: &str) -> Result<netcdf::VariableMut<'_>, String> {
+        self.file
+            .variable_mut(name)
+            .ok_or_else(|| format!("no {} variable", name))
+    }
+}
+
+#[cfg(not(feature = "netcdf"))]
+impl NetcdfTrajectories {
+    pub fn create(
+        _path: &Path,
+        _config: &RunConfig,
+        _particles: &[usize],
+    ) -> Result<Self, Box<dyn Error>> {
+        Err("netCDF output requires building with the `netcdf` feature".into())
+    }
+
+    pub fn append(&mut self, _step: u32, _points: &[Point]) -> Result<(), Box<dyn Error>> {
+        match self.0 {}
+    }
+}
+
+/// Reads back the snapshots of the single-file output of `format` in
+/// `output_dir`
+pub fn read_trajectories(
+    output_dir: &Path,
+    format: OutputFormat,
+) -> Result<Trajectories, Box<dyn Error>> {
+    match format {
+        OutputFormat::Hdf5 => read_hdf5_trajectories(&output_dir.join(HDF5_FILE)),
+        OutputFormat::Netcdf => read_netcdf_trajectories(&output_dir.join(NETCDF_FILE)),
+        OutputFormat::Parquet => read_parquet_trajectories(&output_dir.join(PARQUET_FILE)),
+        OutputFormat::Text | OutputFormat::Vtk | OutputFormat::Binary => {
+            Err(format!("{:?} output is written one file per rank and step", format).into())
+        }
+    }
+}
+
+/// Splits `positions`, xyz triples of every particle at every step, into
+/// the snapshots of `steps`, each holding as many particles as `particles`
+#[cfg(any(feature = "hdf5", feature = "netcdf"))]
+fn split_snapshots(
+    steps: Vec<u32>,
+    particles: Vec<u64>,
+    positions: &[f64],
+) -> Result<Trajectories, Box<dyn Error>> {
+    let values = 3 * particles.len();
+    if values * steps.len() != positions.len() {
+        return Err(format!(
+            "{} coordinates do not split into {} snapshots of {} particles",
+            positions.len(),
+            steps.len(),
+            particles.len()
+        )
+        .into());
+    }
+    let snapshots = steps
+        .into_iter()
+        .zip(positions.chunks(values.max(1)))
+        .map(|(step, values)| {
+            let points = values
+                .chunks_exact(3)
+                .map(|xyz| Point {
+                    x: xyz[0],
+                    y: xyz[1],
+                    z: xyz[2],
+                })
+                .collect();
+            (step, points)
+        })
+        .collect();
+    Ok(Trajectories {
+        particles: particles.into_iter().map(|index| index as usize).collect(),
+        snapshots,
+    })
+}
+
+/// Reads a file written by `write_hdf5_trajectories`
+#[cfg(feature = "hdf5")]
+pub fn read_hdf5_trajectories(path: &Path) -> Result<Trajectories, Box<dyn Error>> {
+    let file = hdf5::File::open(path)?;
+    let steps: Vec<u32> = file.dataset("steps")?.read_raw()?;
+    let particles: Vec<u64> = file.dataset("particles")?.read_raw()?;
+    let positions: Vec<f64> = file.dataset("positions")?.read_raw()?;
+    split_snapshots(steps, particles, &positions)
+}
+
+#[cfg(not(feature = "hdf5"))]
+pub fn read_hdf5_trajectories(_path: &Path) -> Result<Trajectories, Box<dyn Error>> {
+    Err("HDF5 input requires building with the `hdf5` feature".into())
+}
+
+/// Reads the `step`, `particle` and `position` variables of a file written by
+/// `write_netcdf_trajectories`
+#[cfg(feature = "netcdf")]
+pub fn read_netcdf_trajectories(path: &Path) -> Result<Trajectories, Box<dyn Error>> {
+    let file = netcdf::open(path)?;
+    let variable = |name: &str| {
+        file.variable(name)
+            .ok_or_else(|| format!("{} has no {} variable", path.display(), name))
+    };
+    let steps: Vec<u32> = variable("step")?.get_values(..)?;
+    let particles: Vec<u64> = variable("particle")?.get_values(..)?;
+    let positions: Vec<f64> = variable("position")?.get_values(..)?;
+    split_snapshots(steps, particles, &positions)
+}
+
+#[cfg(not(feature = "netcdf"))]
+pub fn read_netcdf_trajectories(_path: &Path) -> Result<Trajectories, Box<dyn Error>> {
+    Err("netCDF input requires building with the `netcdf` feature".into())
+}
+
+/// Writes snapshots of every step into one Parquet file, a row group of
+/// step, particle, x, y and z columns with a row per particle and step.
+/// `particles` are the global indices of the particles of the snapshots.
+#[cfg(feature = "parquet")]
+pub fn write_parquet_trajectories(
+    path: &Path,
+    steps: &[u32],
+    particles: &[usize],
+    snapshots: &[Vec<Point>],
+) -> Result<(), Box<dyn Error>> {
+    use parquet::{
+        data_type::{DoubleType, Int64Type},
+        file::{properties::WriterProperties, writer::SerializedFileWriter},
+        schema::parser::parse_message_type,
+    };
+    use std::sync::Arc;
+
+    let schema = parse_message_type(
+        "message trajectories {
+            REQUIRED INT64 step;
+            REQUIRED INT64 particle;
+            REQUIRED DOUBLE x;
+            REQUIRED DOUBLE y;
+            REQUIRED DOUBLE z;
+        }",
+    )?;
+    let rows = || {
+        steps.iter().zip(snapshots).flat_map(|(&step, points)| {
+            particles
+                .iter()
+                .zip(points)
+                .map(move |(&particle, point)| (step, particle, point))
+        })
+    };
+    let step_column: Vec<i64> = rows().map(|(step, _, _)| step as i64).collect();
+    let particle_column: Vec<i64> = rows().map(|(_, particle, _)| particle as i64).collect();
+    let coordinates: [Vec<f64>; 3] = [
+        rows().map(|(_, _, point)| point.x).collect(),
+        rows().map(|(_, _, point)| point.y).collect(),
+        rows().map(|(_, _, point)| point.z).collect(),
+    ];
+    let mut writer = SerializedFileWriter::new(
+        File::create(path)?,
+        Arc::new(schema),
+        Arc::new(WriterProperties::builder().build()),
+    )?;
+    let mut row_group = writer.next_row_group()?;
+    let mut column_index = 0;
+    while let Some(mut column) = row_group.next_column()? {
+        match column_index {
+            0 => column
+                .typed::<Int64Type>()
+                .write_batch(&step_column, None, None)?,
+            1 => column
+                .typed::<Int64Type>()
+                .write_batch(&particle_column, None, None)?,
+            axis => column
+                .typed::<DoubleType>()
+                .write_batch(&coordinates[axis - 2], None, None)?,
+        };
+        column.close()?;
+        column_index += 1;
+    }
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "parquet"))]
+pub fn write_parquet_trajectories(
+    _path: &Path,
+    _steps: &[u32],
+    _particles: &[usize],
+    _snapshots: &[Vec<Point>],
+) -> Result<(), Box<dyn Error>> {
+    Err("Parquet output requires building with the `parquet` feature".into())
+}
+
+/// Reads a file written by `write_parquet_trajectories`, whose rows hold
+/// the same particles of each step in order
+#[cfg(feature = "parquet")]
+pub fn read_parquet_trajectories(path: &Path) -> Result<Trajectories, Box<dyn Error>> {
+    use parquet::{
+        file::reader::{FileReader, SerializedFileReader},
+        record::RowAccessor,
+    };
+
+    let reader = SerializedFileReader::new(File::open(path)?)?;
+    let mut particles = Vec::new();
+    let mut snapshots: Snapshots = Vec::new();
+    for row in reader.get_row_iter(None)? {
+        let row = row?;
+        let step = u32::try_from(row.get_long(0)?)?;
+        let particle = usize::try_from(row.get_long(1)?)?;
+        let point = Point {
+            x: row.get_double(2)?,
+            y: row.get_double(3)?,
+            z: row.get_double(4)?,
+        };
+        match snapshots.last_mut() {
+            Some((last, points)) if *last == step => points.push(point),
+            _ => snapshots.push((step, vec![point])),
+        }
+        let (_, points) = &snapshots[snapshots.len() - 1];
+        if snapshots.len() == 1 {
+            particles.push(particle);
+        } else if particles.get(points.len() - 1) != Some(&particle) {
+            return Err(format!(
+                "row {} of step {} holds particle {}, not that of the first step",
+                points.len() - 1,
+                step,
+                particle
+            )
+            .into());
+        }
+    }
+    Ok(Trajectories {
+        particles,
+        snapshots,
+    })
+}
+
+#[cfg(not(feature = "parquet"))]
+pub fn read_parquet_trajectories(_path: &Path) -> Result<Trajectories, Box<dyn Error>> {
+    Err("Parquet input requires building with the `parquet` feature".into())
+}
+
+pub fn write_points_to_file(
+    points: &[Point],
+    output_dir: &Path,
+    step: u32,
+    rank: i32,
+    format: &TextFormat,
+) -> Result<(), Box<dyn Error>> {
+    let mut path = PathBuf::new();
+    path.push(output_dir);
+    path.push(snapshot_file_name(rank, step));
+    write_points(&path, points, format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_format_is_lossless() {
+        let format = TextFormat::default();
+        let value = 0.1455416056924451;
+        assert_eq!(format.format_value(value).parse::<f64>().unwrap(), value);
+    }
+
+    #[test]
+    fn fixed_and_scientific_notation() {
+        let fixed = TextFormat {
+            precision: Some(3),
+            ..Default::default()
+        };
+        let scientific = TextFormat {
+            precision: Some(2),
+            notation: Notation::Scientific,
+            ..Default::default()
+        };
+        assert_eq!(fixed.format_value(0.0031465), "0.003");
+        assert_eq!(scientific.format_value(0.0031465), "3.15e-3");
+    }
+
+    #[test]
+    fn retention_keeps_recent_steps_and_checkpoints() {
+        let retention = Retention {
+            keep_last: Some(2),
+            checkpoint_every: Some(40),
+        };
+        let written: Vec<u32> = (0..=100).step_by(10).collect();
+        assert_eq!(retention.retained(&written), vec![0, 40, 80, 90, 100]);
+        assert_eq!(Retention::default().retained(&written), written);
+    }
+
+    #[test]
+    fn curvature_decimation_skips_straight_steps() {
+        let mut writer = SnapshotWriter::new(
+            Path::new("."),
+            0,
+            TextFormat::default(),
+            Decimation::Curvature { max_angle: 0.1 },
+        );
+        let straight = [Point {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        }];
+        let turned = [Point {
+            x: 1.0,
+            y: 1.0,
+            z: 0.0,
+        }];
+        let comm = crate::collectives::SingleProcess;
+        assert!(!writer.is_due(1, 10, &straight, &comm));
+        assert!(!writer.is_due(2, 10, &straight, &comm));
+        assert!(writer.is_due(3, 10, &turned, &comm));
+        assert!(!writer.is_due(4, 10, &turned, &comm));
+        assert!(writer.is_due(10, 10, &turned, &comm));
+    }
+
+    #[test]
+    fn runs_write_netcdf_but_no_other_single_file_format() {
+        let writer = || {
+            SnapshotWriter::new(
+                Path::new("."),
+                0,
+                TextFormat::default(),
+                Decimation::Every(1),
+            )
+        };
+        assert!(writer().with_output_format(OutputFormat::Netcdf).is_ok());
+        assert!(writer().with_output_format(OutputFormat::Hdf5).is_err());
+        assert!(writer().with_output_format(OutputFormat::Parquet).is_err());
+        let trajectories = writer().with_layout(OutputLayout::Trajectories);
+        assert!(
+            trajectories
+                .with_output_format(OutputFormat::Netcdf)
+                .is_err()
+        );
+        let compressed = writer().with_compression(Some(Compression::Gzip));
+        assert!(compressed.with_output_format(OutputFormat::Netcdf).is_err());
+    }
+
+    #[test]
+    fn snapshot_file_names_round_trip() {
+        let name = snapshot_file_name(3, 120);
+        assert_eq!(parse_snapshot_file_name(&name), Some((3, 120)));
+        let name = vtk_snapshot_file_name(3, 120);
+        assert_eq!(parse_snapshot_file_name(&name), Some((3, 120)));
+        let name = binary_snapshot_file_name(3, 120);
+        assert_eq!(parse_snapshot_file_name(&name), Some((3, 120)));
+        let name = snapshot_file_name(ALL_RANKS, 120);
+        assert_eq!(parse_snapshot_file_name(&name), Some((ALL_RANKS, 120)));
+        assert_eq!(parse_snapshot_file_name(&merged_file_name(120)), None);
+        let name = format!("{}.zst", binary_snapshot_file_name(3, 120));
+        assert_eq!(parse_snapshot_file_name(&name), Some((3, 120)));
+        let name = format!("{}.gz", snapshot_file_name(3, 120));
+        assert_eq!(parse_snapshot_file_name(&name), Some((3, 120)));
+    }
+
+    #[test]
+    fn binary_points_round_trip_at_either_precision() {
+        let points = [
+            Point {
+                x: 0.1455416056924451,
+                y: -0.009491670745324678,
+                z: 3.0,
+            },
+            Point::default(),
+        ];
+        let path = std::env::temp_dir().join("bs_solctra_binary_points_test.bin");
+        write_binary_points(&path, &points, BinaryPrecision::F64).unwrap();
+        assert_eq!(read_snapshot(&path, b',').unwrap(), points);
+        write_binary_points(&path, &points, BinaryPrecision::F32).unwrap();
+        let single = read_binary_points(&path).unwrap();
+        assert_eq!(single.len(), 2);
+        assert!((single[0].x - points[0].x).abs() < 1e-7);
+        assert_eq!(single[0].x, points[0].x as f32 as f64);
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(read_binary_points(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,516 @@
+use crate::{
+    collectives::Collectives,
+    collisions::Collisions,
+    compression::{self, create_csv, finish_csv},
+    config::RunConfig,
+    output::{ALL_RANKS, TextFormat, rank_label},
+    point::Point,
+    simulation::{CoilSet, compute_magnetic_field},
+};
+use log::debug;
+use std::{error::Error, path::Path};
+
+/// Number of particles lost in each step of a run, summed over all ranks
+pub const LOSSES_FILE: &str = "losses.csv";
+
+/// Proton mass in kilograms
+pub const PROTON_MASS: f64 = 1.67262192595e-27;
+
+/// Elementary charge in coulombs
+pub const ELEMENTARY_CHARGE: f64 = 1.602176634e-19;
+
+/// Mass and charge shared by the particles of a full orbit run
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Species {
+    /// Kilograms
+    pub mass: f64,
+    /// Coulombs
+    pub charge: f64,
+}
+
+impl Species {
+    pub fn charge_over_mass(&self) -> f64 {
+        self.charge / self.mass
+    }
+}
+
+/// Species and initial velocities of a full orbit run
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct OrbitSettings {
+    pub species: Species,
+    /// Kinetic energy in eV of particles without a velocity in the particles file
+    pub energy: f64,
+    /// Cosine of the angle between their velocity and the field
+    pub pitch: f64,
+    /// Pitch-angle scattering after every push, `None` without collisions
+    #[serde(default)]
+    pub collisions: Option<Collisions>,
+}
+
+impl Default for OrbitSettings {
+    /// Protons of 100 eV at a pitch of 0.5, as on the command line
+    fn default() -> Self {
+        OrbitSettings {
+            species: Species {
+                mass: PROTON_MASS,
+                charge: ELEMENTARY_CHARGE,
+            },
+            energy: 100.0,
+            pitch: 0.5,
+            collisions: None,
+        }
+    }
+}
+
+impl OrbitSettings {
+    /// Velocity with the configured energy and pitch relative to the field `b`.
+    /// The perpendicular part points along `perpendicular_to` the field.
+    pub fn velocity(&self, b: &Point) -> Point {
+        let speed = (2.0 * self.energy * ELEMENTARY_CHARGE / self.species.mass).sqrt();
+        let along = b.get_unit_vector();
+        let perpendicular = perpendicular_to(&along);
+        let parallel_speed = speed * self.pitch;
+        let perpendicular_speed = speed * (1.0 - self.pitch * self.pitch).max(0.0).sqrt();
+        Point {
+            x: parallel_speed * along.x + perpendicular_speed * perpendicular.x,
+            y: parallel_speed * along.y + perpendicular_speed * perpendicular.y,
+            z: parallel_speed * along.z + perpendicular_speed * perpendicular.z,
+        }
+    }
+
+    /// Velocities of particles started at `positions` with the configured
+    /// energy and pitch
+    pub fn initial_velocities(&self, positions: &[Point], coils: &CoilSet) -> Vec<Point> {
+        positions
+            .iter()
+            .map(|position| self.velocity(&compute_magnetic_field(position, coils)))
+            .collect()
+    }
+}
+
+/// Unit vector perpendicular to the unit vector `along`, along `along` × z,
+/// or `along` × x where `along` is vertical
+pub(crate) fn perpendicular_to(along: &Point) -> Point {
+    let mut perpendicular = along.cross(&Point {
+        x: 0.0,
+        y: 0.0,
+        z: 1.0,
+    });
+    if perpendicular.get_norm() < 1e-12 {
+        perpendicular = along.cross(&Point {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        });
+    }
+    perpendicular.get_unit_vector()
+}
+
+/// Whether a particle is still integrated, or the step in which it left the
+/// loss boundary
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ParticleState {
+    #[default]
+    Active,
+    Lost {
+        step: u32,
+    },
+}
+
+impl ParticleState {
+    pub fn is_active(&self) -> bool {
+        *self == ParticleState::Active
+    }
+
+    /// Loss step as a float for collectives, exact far beyond any step
+    /// count, negative while active
+    pub fn to_value(self) -> f64 {
+        match self {
+            ParticleState::Active => -1.0,
+            ParticleState::Lost { step } => step as f64,
+        }
+    }
+
+    pub fn from_value(value: f64) -> Self {
+        if value < 0.0 {
+            ParticleState::Active
+        } else {
+            ParticleState::Lost { step: value as u32 }
+        }
+    }
+}
+
+/// Particles lost in each step from 0 to `total_steps`, to be summed over ranks
+pub fn loss_counts(states: &[ParticleState], total_steps: u32) -> Vec<u64> {
+    let mut counts = vec![0; total_steps as usize + 1];
+    for state in states {
+        if let ParticleState::Lost { step } = state {
+            counts[*step as usize] += 1;
+        }
+    }
+    counts
+}
+
+/// Writes the steps in which particles were lost, with the number lost in each
+pub fn write_loss_counts(path: &Path, counts: &[u64]) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_path(path)?;
+    wtr.write_record(["step", "lost"])?;
+    for (step, count) in counts.iter().enumerate().filter(|(_, count)| **count > 0) {
+        wtr.write_record([step.to_string(), count.to_string()])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+pub fn lost_particles_file_name(rank: i32) -> String {
+    format!("lost_{}.csv", rank_label(rank))
+}
+
+/// Name of the file of what the particles of a rank carried up to the step
+/// the run ended at, which `--resume` reads back
+pub fn resume_states_file_name(rank: i32) -> String {
+    format!("resume_{}.csv", rank_label(rank))
+}
+
+/// What a particle carried up to the step a run ended at, active or lost
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ResumeState {
+    /// Global index
+    pub particle: usize,
+    pub step: u32,
+    /// Arc length travelled while confined
+    pub length: f64,
+    /// Angles swept around the torus while confined, in radians
+    pub toroidal: f64,
+    pub poloidal: f64,
+    /// Distance to the magnetic axis the particle started at
+    pub start_radius: f64,
+}
+
+/// Writes `states` with every digit of their values, for runs to resume
+/// from them exactly
+pub fn write_resume_states(
+    path: &Path,
+    states: &[ResumeState],
+    delimiter: u8,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_path(path)?;
+    for state in states {
+        wtr.serialize(state)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+pub fn read_resume_states(path: &Path, delimiter: u8) -> Result<Vec<ResumeState>, Box<dyn Error>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_path(path)?;
+    let mut states = Vec::new();
+    for result in rdr.deserialize() {
+        states.push(result?);
+    }
+    Ok(states)
+}
+
+/// Positions, states and connection lengths of particles
+pub type ParticleRecords = (Vec<Point>, Vec<ParticleState>, Vec<f64>);
+
+/// Final positions, states and connection lengths of the particles of every
+/// rank on rank 0, in global particle order, `None` on the other ranks
+pub fn gather_particle_states(
+    comm: &impl Collectives,
+    particles: &[Point],
+    states: &[ParticleState],
+    lengths: &[f64],
+) -> Result<Option<ParticleRecords>, Box<dyn Error>> {
+    let steps: Vec<f64> = states.iter().map(|state| state.to_value()).collect();
+    let steps = comm.gather_values(&steps)?;
+    let lengths = comm.gather_values(lengths)?;
+    let particles = comm.gather_points(particles)?;
+    let (Some(steps), Some(lengths), Some(particles)) = (steps, lengths, particles) else {
+        return Ok(None);
+    };
+    let states = steps.into_iter().map(ParticleState::from_value).collect();
+    Ok(Some((particles, states, lengths)))
+}
+
+/// Writes the global index, loss step, last confined position and
+/// connection length of the lost particles of a rank, whose first particle
+/// has index `offset`
+pub fn write_lost_particles(
+    path: &Path,
+    offset: usize,
+    particles: &[Point],
+    states: &[ParticleState],
+    lengths: &[f64],
+    format: &TextFormat,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(format.delimiter)
+        .from_path(path)?;
+    wtr.write_record(["particle", "step", "x", "y", "z", "length"])?;
+    for (index, ((particle, state), length)) in
+        particles.iter().zip(states).zip(lengths).enumerate()
+    {
+        if let ParticleState::Lost { step } = state {
+            wtr.write_record([
+                (offset + index).to_string(),
+                step.to_string(),
+                format.format_value(particle.x),
+                format.format_value(particle.y),
+                format.format_value(particle.z),
+                format.format_value(*length),
+            ])?;
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+struct LostRecord {
+    particle: usize,
+    step: u32,
+    x: f64,
+    y: f64,
+    z: f64,
+    length: f64,
+}
+
+/// Particle lost in an earlier run, as its lost particles file records it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LostParticle {
+    /// Global index
+    pub particle: usize,
+    pub step: u32,
+    /// Last confined position
+    pub position: Point,
+    pub length: f64,
+}
+
+/// Reads back a file written by `write_lost_particles`
+pub fn read_lost_particles(
+    path: &Path,
+    delimiter: u8,
+) -> Result<Vec<LostParticle>, Box<dyn Error>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_path(path)?;
+    let mut lost = Vec::new();
+    for result in rdr.deserialize() {
+        let record: LostRecord = result?;
+        lost.push(LostParticle {
+            particle: record.particle,
+            step: record.step,
+            position: Point {
+                x: record.x,
+                y: record.y,
+                z: record.z,
+            },
+            length: record.length,
+        });
+    }
+    Ok(lost)
+}
+
+/// Lost particles of the run in `run_dir` by global index, `None` for those
+/// that were never lost. Fails unless the run finished or stopped, as only
+/// those write their lost particles files.
+pub fn read_run_losses(
+    run_dir: &Path,
+    config: &RunConfig,
+) -> Result<Vec<Option<LostParticle>>, Box<dyn Error>> {
+    let ranks: Vec<i32> = if config.reproducible {
+        vec![ALL_RANKS]
+    } else {
+        (0..config.world_size).collect()
+    };
+    let mut losses = vec![None; config.num_particles];
+    for rank in ranks {
+        let path = run_dir.join(lost_particles_file_name(rank));
+        let lost = read_lost_particles(&path, config.text_format().delimiter).map_err(|err| {
+            format!(
+                "reading {}: {}, runs that did not finish or stop record no losses",
+                path.display(),
+                err
+            )
+        })?;
+        for lost in lost {
+            let loss = losses
+                .get_mut(lost.particle)
+                .ok_or_else(|| format!("{} has no particle {}", path.display(), lost.particle))?;
+            *loss = Some(lost);
+        }
+    }
+    Ok(losses)
+}
+
+#[derive(serde::Deserialize)]
+struct VelocityRecord {
+    vx: f64,
+    vy: f64,
+    vz: f64,
+}
+
+/// Reads the vx, vy and vz columns of a particles file, `None` if it has none
+pub fn read_velocities(
+    path: &Path,
+    max_items: usize,
+) -> Result<Option<Vec<Point>>, Box<dyn Error>> {
+    let mut rdr = csv::Reader::from_reader(compression::open(path)?);
+    if !rdr.headers()?.iter().any(|header| header == "vx") {
+        return Ok(None);
+    }
+    let mut velocities = Vec::new();
+    for result in rdr.deserialize().take(max_items) {
+        let record: VelocityRecord = result?;
+        velocities.push(Point {
+            x: record.vx,
+            y: record.vy,
+            z: record.vz,
+        });
+    }
+    debug!("Read {} velocities from file {:?}", velocities.len(), path);
+    Ok(Some(velocities))
+}
+
+pub fn write_velocities(
+    path: &Path,
+    velocities: &[Point],
+    format: &TextFormat,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = create_csv(path, format.delimiter)?;
+    wtr.write_record(["vx", "vy", "vz"])?;
+    for velocity in velocities {
+        wtr.write_record([
+            format.format_value(velocity.x),
+            format.format_value(velocity.y),
+            format.format_value(velocity.z),
+        ])?;
+    }
+    finish_csv(wtr)
+}
+
+/// Writes the field components and magnitude at every particle
+pub fn write_fields(
+    path: &Path,
+    fields: &[Point],
+    format: &TextFormat,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = create_csv(path, format.delimiter)?;
+    wtr.write_record(["bx", "by", "bz", "b"])?;
+    for field in fields {
+        wtr.write_record([
+            format.format_value(field.x),
+            format.format_value(field.y),
+            format.format_value(field.z),
+            format.format_value(field.get_norm()),
+        ])?;
+    }
+    finish_csv(wtr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn velocity_has_energy_and_pitch() {
+        let settings = OrbitSettings {
+            species: Species {
+                mass: PROTON_MASS,
+                charge: ELEMENTARY_CHARGE,
+            },
+            energy: 100.0,
+            pitch: 0.6,
+            collisions: None,
+        };
+        let b = Point {
+            x: 0.0,
+            y: 0.3,
+            z: 0.4,
+        };
+        let velocity = settings.velocity(&b);
+        let energy = 0.5 * PROTON_MASS * velocity.dot(&velocity) / ELEMENTARY_CHARGE;
+        assert!((energy - 100.0).abs() < 1e-9);
+        let pitch = velocity.dot(&b) / (velocity.get_norm() * b.get_norm());
+        assert!((pitch - 0.6).abs() < 1e-12);
+    }
+
+    #[test]
+    fn losses_are_counted_per_step() {
+        let states = [
+            ParticleState::Active,
+            ParticleState::Lost { step: 2 },
+            ParticleState::Lost { step: 2 },
+            ParticleState::Lost { step: 4 },
+        ];
+        assert_eq!(loss_counts(&states, 4), vec![0, 0, 2, 0, 1]);
+    }
+
+    #[test]
+    fn lost_particles_are_read_back() {
+        let path = std::env::temp_dir().join("bs_solctra_lost_particles.csv");
+        let format = TextFormat {
+            delimiter: b'\t',
+            ..TextFormat::default()
+        };
+        let particles = [
+            Point {
+                x: 1.0,
+                y: 2.0,
+                z: 3.0,
+            },
+            Point {
+                x: 0.5,
+                y: -0.25,
+                z: 0.125,
+            },
+        ];
+        let states = [ParticleState::Active, ParticleState::Lost { step: 7 }];
+        write_lost_particles(&path, 10, &particles, &states, &[1.0, 2.5], &format).unwrap();
+        let lost = read_lost_particles(&path, format.delimiter).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            lost,
+            vec![LostParticle {
+                particle: 11,
+                step: 7,
+                position: particles[1],
+                length: 2.5,
+            }]
+        );
+        assert_eq!(ParticleState::from_value(states[1].to_value()), states[1]);
+        assert_eq!(ParticleState::from_value(states[0].to_value()), states[0]);
+    }
+
+    #[test]
+    fn resume_states_keep_every_digit() {
+        let path = std::env::temp_dir().join("bs_solctra_resume_states.csv");
+        let states = [
+            ResumeState {
+                particle: 3,
+                step: 40,
+                length: 0.1 + 0.2,
+                toroidal: -12.5,
+                poloidal: 0.7,
+                start_radius: 0.02,
+            },
+            ResumeState {
+                particle: 4,
+                step: 40,
+                length: 1.0 / 3.0,
+                toroidal: 1e-300,
+                poloidal: f64::MIN_POSITIVE,
+                start_radius: 0.0,
+            },
+        ];
+        write_resume_states(&path, &states, b';').unwrap();
+        let read = read_resume_states(&path, b';').unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read, states);
+    }
+}
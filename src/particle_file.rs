@@ -0,0 +1,205 @@
+use crate::point::Point;
+use log::debug;
+use std::{error::Error, fs::File, path::Path};
+
+/// Read-only view of a particles file that each rank parses only its own
+/// slice of. The file is memory-mapped on unix, so ranks never hold more
+/// than their records plus the pages the kernel keeps cached.
+pub struct ParticleFile {
+    data: FileBytes,
+    /// Byte offset of the first record, after the header line
+    records_start: usize,
+}
+
+enum FileBytes {
+    #[cfg(unix)]
+    Mapped {
+        address: *mut libc::c_void,
+        len: usize,
+    },
+    Owned(Vec<u8>),
+}
+
+impl FileBytes {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            #[cfg(unix)]
+            FileBytes::Mapped { address, len } => unsafe {
+                std::slice::from_raw_parts(*address as *const u8, *len)
+            },
+            FileBytes::Owned(bytes) => bytes,
+        }
+    }
+}
+
+impl Drop for FileBytes {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let FileBytes::Mapped { address, len } = self {
+            unsafe {
+                libc::munmap(*address, *len);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn map_file(file: &File) -> Result<FileBytes, Box<dyn Error>> {
+    use std::os::unix::io::AsRawFd;
+    let len = file.metadata()?.len() as usize;
+    if len == 0 {
+        return Ok(FileBytes::Owned(Vec::new()));
+    }
+    let address = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if address == libc::MAP_FAILED {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(FileBytes::Mapped { address, len })
+}
+
+#[cfg(not(unix))]
+fn map_file(mut file: &File) -> Result<FileBytes, Box<dyn Error>> {
+    use std::io::Read;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    Ok(FileBytes::Owned(bytes))
+}
+
+impl ParticleFile {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        debug!("Mapping particles file {:?}", path);
+        let data = map_file(&File::open(path)?)?;
+        let bytes = data.as_slice();
+        let records_start = memchr::memchr(b'\n', bytes).map_or(bytes.len(), |end| end + 1);
+        Ok(ParticleFile {
+            data,
+            records_start,
+        })
+    }
+
+    /// Offset of the first record starting at or after byte `offset`
+    fn record_start_from(&self, offset: usize) -> usize {
+        let bytes = self.data.as_slice();
+        if offset <= self.records_start {
+            return self.records_start;
+        }
+        memchr::memchr(b'\n', &bytes[offset - 1..]).map_or(bytes.len(), |end| offset + end)
+    }
+
+    /// Bytes of the records starting in the `rank`-th of `ranks` byte
+    /// ranges of about the same length, without reading the rest of the file
+    fn slice(&self, rank: usize, ranks: usize) -> &[u8] {
+        let body = self.data.as_slice().len() - self.records_start;
+        let bound = |rank: usize| self.record_start_from(self.records_start + rank * body / ranks);
+        &self.data.as_slice()[bound(rank)..bound(rank + 1)]
+    }
+
+    /// Number of records, without parsing them
+    pub fn len(&self) -> usize {
+        records(self.slice(0, 1)).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        records(self.slice(0, 1)).next().is_none()
+    }
+
+    /// Number of records in the slice of `rank` out of `ranks`, without
+    /// parsing them
+    pub fn slice_len(&self, rank: usize, ranks: usize) -> usize {
+        records(self.slice(rank, ranks)).count()
+    }
+
+    /// Parses the first `count` records of the slice of `rank` out of
+    /// `ranks`, the first of them being record `first_index` of the file
+    pub fn read_slice(
+        &self,
+        rank: usize,
+        ranks: usize,
+        first_index: usize,
+        count: usize,
+    ) -> Result<Vec<Point>, Box<dyn Error>> {
+        records(self.slice(rank, ranks))
+            .take(count)
+            .enumerate()
+            .map(|(index, line)| {
+                parse_record(line).ok_or_else(|| {
+                    format!("Invalid particle record {}", first_index + index).into()
+                })
+            })
+            .collect()
+    }
+}
+
+/// Lines of `bytes` that are not blank
+fn records(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut line_start = 0;
+    memchr::memchr_iter(b'\n', bytes)
+        .chain(std::iter::once(bytes.len()))
+        .map(move |line_end| {
+            let line = &bytes[line_start.min(line_end)..line_end];
+            line_start = line_end + 1;
+            line
+        })
+        .filter(|line| !line.iter().all(|byte| byte.is_ascii_whitespace()))
+}
+
+fn parse_record(line: &[u8]) -> Option<Point> {
+    let mut fields = std::str::from_utf8(line)
+        .ok()?
+        .split(',')
+        .map(|field| field.trim().parse::<f64>());
+    let mut next = || fields.next()?.ok();
+    Some(Point {
+        x: next()?,
+        y: next()?,
+        z: next()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point::read_from_file;
+
+    #[test]
+    fn slices_match_full_read() {
+        let path = Path::new("tests/test-resources/input_1000.csv");
+        let file = ParticleFile::open(path).unwrap();
+        let all = read_from_file(path, usize::MAX).unwrap();
+        assert_eq!(file.len(), all.len());
+        for ranks in [1, 3, 7, 64] {
+            let mut read = Vec::new();
+            for rank in 0..ranks {
+                let count = file.slice_len(rank, ranks);
+                read.extend(file.read_slice(rank, ranks, read.len(), count).unwrap());
+            }
+            assert_eq!(read, all);
+        }
+        assert_eq!(
+            file.read_slice(1, 3, 0, 5).unwrap(),
+            file.read_slice(1, 3, 0, 9).unwrap()[..5]
+        );
+    }
+
+    #[test]
+    fn slices_start_at_whole_records() {
+        let path = std::env::temp_dir().join("bs_solctra_particle_slices.csv");
+        std::fs::write(&path, "x,y,z\n1,2,3\n\n4,5,6\r\n7,8,9").unwrap();
+        let file = ParticleFile::open(&path).unwrap();
+        let counts: Vec<usize> = (0..5).map(|rank| file.slice_len(rank, 5)).collect();
+        let last = file.read_slice(4, 5, 2, 1);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(counts.iter().sum::<usize>(), 3);
+        assert_eq!(file.len(), 3);
+        assert!(last.is_ok());
+    }
+}
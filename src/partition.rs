@@ -0,0 +1,80 @@
+#[cfg(feature = "mpi")]
+use mpi::Count;
+
+/// Particles held by each of `ranks` ranks when distributing `total`
+/// particles, the remainder goes to the first ranks one particle each
+pub fn particle_counts(total: usize, ranks: usize) -> Vec<usize> {
+    let base = total / ranks;
+    let remainder = total % ranks;
+    (0..ranks)
+        .map(|rank| base + usize::from(rank < remainder))
+        .collect()
+}
+
+/// Global index of the first particle of each rank
+pub fn particle_offsets(counts: &[usize]) -> Vec<usize> {
+    counts
+        .iter()
+        .scan(0, |offset, count| {
+            let current = *offset;
+            *offset += count;
+            Some(current)
+        })
+        .collect()
+}
+
+/// Counts or offsets as MPI counts for the varcount collectives
+#[cfg(feature = "mpi")]
+pub fn to_mpi_counts(values: &[usize]) -> Vec<Count> {
+    values.iter().map(|&value| value as Count).collect()
+}
+
+/// Active particles each rank lends to each other rank, indexed by lender
+/// and then borrower, so every rank ends up integrating the share
+/// `particle_counts` gives it of the active particles
+pub fn loan_plan(active: &[usize]) -> Vec<Vec<usize>> {
+    let ranks = active.len();
+    let targets = particle_counts(active.iter().sum(), ranks);
+    let mut plan = vec![vec![0; ranks]; ranks];
+    let mut surplus: Vec<usize> = (0..ranks)
+        .map(|rank| active[rank].saturating_sub(targets[rank]))
+        .collect();
+    let mut deficit: Vec<usize> = (0..ranks)
+        .map(|rank| targets[rank].saturating_sub(active[rank]))
+        .collect();
+    let mut borrower = 0;
+    for lender in 0..ranks {
+        while surplus[lender] > 0 {
+            while deficit[borrower] == 0 {
+                borrower += 1;
+            }
+            let amount = surplus[lender].min(deficit[borrower]);
+            plan[lender][borrower] += amount;
+            surplus[lender] -= amount;
+            deficit[borrower] -= amount;
+        }
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remainder_goes_to_first_ranks() {
+        let counts = particle_counts(10, 4);
+        assert_eq!(counts, vec![3, 3, 2, 2]);
+        assert_eq!(particle_offsets(&counts), vec![0, 3, 6, 8]);
+        assert_eq!(particle_counts(3, 5), vec![1, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn loans_even_out_active_particles() {
+        let active = [10, 0, 4, 2];
+        let plan = loan_plan(&active);
+        assert_eq!(plan[0], vec![0, 4, 0, 2]);
+        assert!(plan[1..].iter().flatten().all(|&amount| amount == 0));
+        assert_eq!(loan_plan(&[3, 3, 2]), vec![vec![0; 3]; 3]);
+    }
+}
@@ -1,8 +1,15 @@
+use crate::compression;
 use core::fmt;
 use csv;
 use log::debug;
+#[cfg(feature = "mpi")]
 use mpi::{datatype::UserDatatype, traits::Equivalence};
-use std::{error::Error, path::{Path, PathBuf}};
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
 
 #[derive(Debug, Default, PartialEq, Clone, Copy, serde::Deserialize, serde::Serialize)]
 pub struct Point {
@@ -16,6 +23,18 @@ impl Point {
         (self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
     }
 
+    pub(crate) fn dot(&self, other: &Point) -> f64 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub(crate) fn cross(&self, other: &Point) -> Point {
+        Point {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
+    }
+
     pub(crate) fn get_distance(&self, other: &Point) -> f64 {
         let x = self.x - other.x;
         let y = self.y - other.y;
@@ -39,9 +58,10 @@ impl Point {
     }
 }
 
+#[cfg(feature = "mpi")]
 unsafe impl Equivalence for Point {
     type Out = UserDatatype;
-    
+
     fn equivalent_datatype() -> Self::Out {
         mpi::datatype::UncommittedUserDatatype::contiguous(3, &f64::equivalent_datatype()).commit()
     }
@@ -54,8 +74,18 @@ impl fmt::Display for Point {
 }
 
 pub fn read_from_file(path: &Path, max_items: usize) -> Result<Vec<Point>, Box<dyn Error>> {
+    read_from_file_with_delimiter(path, max_items, b',')
+}
+
+pub fn read_from_file_with_delimiter(
+    path: &Path,
+    max_items: usize,
+    delimiter: u8,
+) -> Result<Vec<Point>, Box<dyn Error>> {
     debug!("Reading data from file {:?}", path);
-    let mut rdr = csv::Reader::from_path(path)?;
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_reader(compression::open(path)?);
     let mut points = Vec::<Point>::new();
     for result in rdr.deserialize().take(max_items) {
         let point: Point = result?;
@@ -65,20 +95,87 @@ pub fn read_from_file(path: &Path, max_items: usize) -> Result<Vec<Point>, Box<d
     return Ok(points);
 }
 
-pub fn write_points_to_file(
-    points: &[Point],
-    output_dir: &Path,
-    step: u32,
-    rank: i32
-) -> Result<(), Box<dyn Error>> {
-    let mut path = PathBuf::new();
-    path.push(output_dir);
-    path.push(format!("out_{}_{}.csv", rank, step));
-    let mut wtr = csv::Writer::from_path(path)?;
-    for point in points {
-        wtr.serialize(point)?;
-    }
-    Ok(())
+/// First bytes of a binary points file. The point count and the bytes of
+/// each coordinate (4 or 8) follow as little-endian `u64`s, then the `x y z`
+/// coordinates of every point as little-endian floats.
+pub const BINARY_POINTS_MAGIC: &[u8; 8] = b"BSPOINTS";
+
+/// Bytes of the header of a binary points file
+pub const BINARY_POINTS_HEADER_LEN: usize = 24;
+
+/// Reads a binary points file of `f32` or `f64` coordinates
+pub fn read_binary_points(path: &Path) -> Result<Vec<Point>, Box<dyn Error>> {
+    let mut bytes = Vec::new();
+    compression::open(path)?.read_to_end(&mut bytes)?;
+    if bytes.len() < BINARY_POINTS_HEADER_LEN || &bytes[..8] != BINARY_POINTS_MAGIC {
+        return Err(format!("{:?} is not a binary points file", path).into());
+    }
+    let header = |index: usize| u64::from_le_bytes(bytes[index..index + 8].try_into().unwrap());
+    let (count, width) = (header(8), header(16));
+    let (Ok(count), Ok(width)) = (usize::try_from(count), usize::try_from(width)) else {
+        return Err(format!("{:?}: {} points do not fit in memory", path, count).into());
+    };
+    if width != 4 && width != 8 {
+        return Err(format!("{:?}: unsupported coordinate size {}", path, width).into());
+    }
+    let body = &bytes[BINARY_POINTS_HEADER_LEN..];
+    // The count comes from the file, a corrupt one must not overflow
+    let expected = count.checked_mul(3 * width);
+    if expected != Some(body.len()) {
+        return Err(format!(
+            "{:?}: expected {} points of {} bytes, found {} bytes",
+            path,
+            count,
+            3 * width,
+            body.len()
+        )
+        .into());
+    }
+    let values: Vec<f64> = body
+        .chunks_exact(width)
+        .map(|value| match width {
+            4 => f32::from_le_bytes(value.try_into().unwrap()) as f64,
+            _ => f64::from_le_bytes(value.try_into().unwrap()),
+        })
+        .collect();
+    let points: Vec<Point> = values
+        .chunks_exact(3)
+        .map(|c| Point {
+            x: c[0],
+            y: c[1],
+            z: c[2],
+        })
+        .collect();
+    debug!("Read {} points from file {:?}", points.len(), path);
+    Ok(points)
+}
+
+/// Buffer size used when reading large point files
+const READ_BUFFER_SIZE: usize = 1 << 20;
+
+/// Reads every record of an x,y,z file through a large buffer, parsing the
+/// fields directly rather than through serde. Returns a `Send` error so that
+/// files can be read in parallel.
+pub fn read_points_fast(path: &Path) -> Result<Vec<Point>, Box<dyn Error + Send + Sync>> {
+    let reader = BufReader::with_capacity(READ_BUFFER_SIZE, File::open(path)?);
+    let mut rdr = csv::ReaderBuilder::new().from_reader(reader);
+    let mut record = csv::ByteRecord::new();
+    let mut points = Vec::<Point>::new();
+    while rdr.read_byte_record(&mut record)? {
+        let field = |index: usize| -> Result<f64, Box<dyn Error + Send + Sync>> {
+            let bytes = record
+                .get(index)
+                .ok_or_else(|| format!("{:?}: record {} has too few fields", path, points.len()))?;
+            Ok(std::str::from_utf8(bytes)?.trim().parse::<f64>()?)
+        };
+        points.push(Point {
+            x: field(0)?,
+            y: field(1)?,
+            z: field(2)?,
+        });
+    }
+    debug!("Read {} points from file {:?}", points.len(), path);
+    Ok(points)
 }
 
 #[cfg(test)]
@@ -106,4 +203,24 @@ mod tests {
         let result = point.to_string();
         assert_eq!(result, "3.3,4.4,5.5")
     }
+
+    #[test]
+    fn binary_headers_with_overflowing_counts_are_rejected() {
+        let path = std::env::temp_dir().join("bs_solctra_overflowing_points.bin");
+        let mut bytes = BINARY_POINTS_MAGIC.to_vec();
+        bytes.extend_from_slice(&(u64::MAX / 4).to_le_bytes());
+        bytes.extend_from_slice(&8u64.to_le_bytes());
+        bytes.extend_from_slice(&[0; 24]);
+        std::fs::write(&path, bytes).unwrap();
+        let result = read_binary_points(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn fast_reader_matches_serde_reader() {
+        let path = Path::new("tests/test-resources/resources/Bobina00m.csv");
+        let fast = read_points_fast(path).unwrap();
+        assert_eq!(fast, read_from_file(path, usize::MAX).unwrap());
+    }
 }
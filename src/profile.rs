@@ -0,0 +1,145 @@
+use crate::{collectives::Collectives, logging::PhaseTimes};
+use std::{error::Error, fs::File, io::BufWriter, path::Path};
+
+/// Name of the profiling report written by runs with `--profile`
+pub const PROFILE_FILE: &str = "profile.json";
+
+/// Wall time in seconds one rank spent in each phase of a run
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Profile {
+    /// Reading the coils and currents and transforming the coils on rank 0,
+    /// then broadcasting them
+    pub coil_loading: f64,
+    /// Computing the coil segments and their unit vectors
+    pub precompute: f64,
+    /// Phases of the step loop
+    pub steps: PhaseTimes,
+    /// From the start of the run to the end of the step loop
+    pub total: f64,
+}
+
+/// Minimum, maximum and mean over the ranks of the time of one phase
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PhaseStats {
+    pub phase: String,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+/// Times of every phase across the ranks of a run, as recorded in `profile.json`
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ProfileReport {
+    pub ranks: usize,
+    pub steps: u32,
+    pub phases: Vec<PhaseStats>,
+}
+
+impl Profile {
+    fn phases(&self) -> [(&'static str, f64); 6] {
+        [
+            ("coil loading", self.coil_loading),
+            ("precompute", self.precompute),
+            ("field evaluation", self.steps.integrate),
+            ("balance", self.steps.balance),
+            ("output", self.steps.output),
+            ("total", self.total),
+        ]
+    }
+
+    /// Report of the profiles of every rank of `comm` on rank 0, `None` on
+    /// the others
+    pub fn reduce(&self, comm: &impl Collectives) -> Option<ProfileReport> {
+        let times: Vec<f64> = self.phases().iter().map(|&(_, time)| time).collect();
+        let min = comm.min_values(&times);
+        let max = comm.max_values(&times);
+        let sum = comm.sum_values(&times);
+        let (Some(min), Some(max), Some(sum)) = (min, max, sum) else {
+            return None;
+        };
+        let ranks = comm.ranks();
+        let phases = self
+            .phases()
+            .iter()
+            .enumerate()
+            .map(|(index, &(phase, _))| PhaseStats {
+                phase: phase.to_string(),
+                min: min[index],
+                max: max[index],
+                mean: sum[index] / ranks as f64,
+            })
+            .collect();
+        Some(ProfileReport {
+            ranks,
+            steps: self.steps.steps,
+            phases,
+        })
+    }
+}
+
+impl ProfileReport {
+    /// One line per phase with its times and the imbalance, the maximum
+    /// over the mean, under a header line
+    pub fn table(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{:<16} {:>10} {:>10} {:>10} {:>9}",
+            "phase", "min [s]", "mean [s]", "max [s]", "imbalance"
+        )];
+        for stats in &self.phases {
+            let imbalance = if stats.mean > 0.0 {
+                format!("{:.2}", stats.max / stats.mean)
+            } else {
+                "-".to_string()
+            };
+            lines.push(format!(
+                "{:<16} {:>10.3} {:>10.3} {:>10.3} {:>9}",
+                stats.phase, stats.min, stats.mean, stats.max, imbalance
+            ));
+        }
+        lines
+    }
+
+    pub fn write(&self, output_dir: &Path) -> Result<(), Box<dyn Error>> {
+        let writer = BufWriter::new(File::create(output_dir.join(PROFILE_FILE))?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collectives::SingleProcess;
+
+    #[test]
+    fn single_rank_reports_its_own_times() {
+        let profile = Profile {
+            coil_loading: 0.5,
+            precompute: 0.25,
+            steps: PhaseTimes {
+                step: 20,
+                steps: 20,
+                integrate: 4.0,
+                balance: 0.0,
+                output: 1.0,
+            },
+            total: 6.0,
+        };
+        let report = profile.reduce(&SingleProcess).unwrap();
+        assert_eq!(report.ranks, 1);
+        assert_eq!(report.steps, 20);
+        assert_eq!(report.phases.len(), 6);
+        let integrate = &report.phases[2];
+        assert_eq!(integrate.phase, "field evaluation");
+        assert_eq!(
+            (integrate.min, integrate.mean, integrate.max),
+            (4.0, 4.0, 4.0)
+        );
+
+        let table = report.table();
+        assert_eq!(table.len(), 7);
+        assert!(table[3].starts_with("field evaluation"));
+        assert!(table[3].ends_with("1.00"));
+        assert!(table[4].ends_with('-'));
+    }
+}
@@ -0,0 +1,175 @@
+use crate::{
+    coil_format::{CoilFormat, read_coils},
+    coil_spline::resample_coils,
+    constants::PhysicsParams,
+    integrator::IntegratorKind,
+    point::Point,
+    simulation::{self, CoilSet},
+    tracer::Simulation,
+};
+use clap::ValueEnum;
+use numpy::{IntoPyArray, PyArray2, PyReadonlyArray2, ndarray::Array2};
+use pyo3::{exceptions::PyValueError, prelude::*};
+use rayon::prelude::*;
+use std::path::Path;
+
+/// Coils as loaded by `load_coils`, with their segments precomputed for the
+/// field evaluations
+#[pyclass(name = "Coils", module = "bs_solctra", frozen)]
+pub struct PyCoils {
+    coils: Vec<Vec<Point>>,
+    physics: PhysicsParams,
+    set: CoilSet,
+}
+
+#[pymethods]
+impl PyCoils {
+    fn __len__(&self) -> usize {
+        self.coils.len()
+    }
+
+    /// Points of coil `index` as an (n, 3) array in meters
+    fn points<'py>(&self, py: Python<'py>, index: usize) -> PyResult<Bound<'py, PyArray2<f64>>> {
+        let coil = self.coils.get(index).ok_or_else(|| {
+            PyValueError::new_err(format!("no coil {} of {}", index, self.coils.len()))
+        })?;
+        Ok(to_array(py, coil))
+    }
+
+    #[getter]
+    fn current(&self) -> f64 {
+        self.physics.current
+    }
+}
+
+/// Reads the coils at `resource_path` as the tracer does, each carrying
+/// `current` amperes, optionally resampled to `coil_segments` segments
+#[pyfunction]
+#[pyo3(signature = (resource_path, coil_format = "auto", current = None, coil_segments = None))]
+fn load_coils(
+    resource_path: &str,
+    coil_format: &str,
+    current: Option<f64>,
+    coil_segments: Option<usize>,
+) -> PyResult<PyCoils> {
+    let format = parse_value::<CoilFormat>("coil format", coil_format)?;
+    let mut coils = read_coils(Path::new(resource_path), format)
+        .map_err(|err| PyValueError::new_err(err.to_string()))?;
+    if let Some(segments) = coil_segments {
+        resample_coils(&mut coils, segments).map_err(PyValueError::new_err)?;
+    }
+    let defaults = PhysicsParams::default();
+    let physics = PhysicsParams {
+        current: current.unwrap_or(defaults.current),
+        ..defaults
+    };
+    let set = CoilSet::new(&coils).with_physics(physics);
+    Ok(PyCoils {
+        coils,
+        physics,
+        set,
+    })
+}
+
+/// Magnetic field in teslas of `coils` at each row of `points`, an (n, 3)
+/// array in meters, evaluated in parallel
+#[pyfunction]
+fn compute_magnetic_field<'py>(
+    py: Python<'py>,
+    coils: &PyCoils,
+    points: PyReadonlyArray2<'py, f64>,
+) -> PyResult<Bound<'py, PyArray2<f64>>> {
+    let points = to_points(&points)?;
+    let set = &coils.set;
+    let fields: Vec<Point> = py.allow_threads(|| {
+        points
+            .par_iter()
+            .map(|point| simulation::compute_magnetic_field(point, set))
+            .collect()
+    });
+    Ok(to_array(py, &fields))
+}
+
+/// Field line through `start` followed for `steps` steps, as a (k, 3) array
+/// starting at `start`. Lines leaving the torus end at their last confined
+/// point, so k is at most `steps + 1`.
+#[pyfunction]
+#[pyo3(signature = (coils, start, steps, step_size = 0.001, integrator = "rk4"))]
+fn trace_field_line<'py>(
+    py: Python<'py>,
+    coils: &PyCoils,
+    start: [f64; 3],
+    steps: u32,
+    step_size: f64,
+    integrator: &str,
+) -> PyResult<Bound<'py, PyArray2<f64>>> {
+    let kind = parse_value::<IntegratorKind>("integrator", integrator)?;
+    if kind.is_orbit() {
+        return Err(PyValueError::new_err(format!(
+            "{} follows orbits, not field lines",
+            integrator
+        )));
+    }
+    let [x, y, z] = start;
+    let track = py.allow_threads(|| {
+        let mut simulation = Simulation::builder()
+            .coils(coils.coils.clone())
+            .physics(coils.physics)
+            .integrator(kind, step_size)
+            .add_particles(&[Point { x, y, z }])
+            .build()
+            .map_err(|err| err.to_string())?;
+        simulation
+            .trajectories(steps)
+            .map(|mut tracks| tracks.remove(0))
+            .map_err(|err| err.to_string())
+    });
+    let track = track.map_err(PyValueError::new_err)?;
+    Ok(to_array(py, &track))
+}
+
+/// Variant of a command line enum by its name on the command line
+fn parse_value<T: ValueEnum>(what: &str, value: &str) -> PyResult<T> {
+    T::from_str(value, true)
+        .map_err(|_| PyValueError::new_err(format!("unknown {} {:?}", what, value)))
+}
+
+fn to_points(points: &PyReadonlyArray2<'_, f64>) -> PyResult<Vec<Point>> {
+    let points = points.as_array();
+    if points.ncols() != 3 {
+        return Err(PyValueError::new_err(format!(
+            "points need 3 columns, not {}",
+            points.ncols()
+        )));
+    }
+    Ok(points
+        .rows()
+        .into_iter()
+        .map(|row| Point {
+            x: row[0],
+            y: row[1],
+            z: row[2],
+        })
+        .collect())
+}
+
+fn to_array<'py>(py: Python<'py>, points: &[Point]) -> Bound<'py, PyArray2<f64>> {
+    let values = points
+        .iter()
+        .flat_map(|point| [point.x, point.y, point.z])
+        .collect();
+    Array2::from_shape_vec((points.len(), 3), values)
+        .expect("three values per point")
+        .into_pyarray(py)
+}
+
+/// Field evaluation and field line tracing from Python, on one process
+#[pymodule]
+#[pyo3(name = "bs_solctra")]
+fn module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyCoils>()?;
+    m.add_function(wrap_pyfunction!(load_coils, m)?)?;
+    m.add_function(wrap_pyfunction!(compute_magnetic_field, m)?)?;
+    m.add_function(wrap_pyfunction!(trace_field_line, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use crate::{
+    commands::read_global_snapshot,
+    config::RunConfig,
+    output::list_snapshots,
+    particle::{ParticleState, read_run_losses},
+    point::Point,
+    utils::parse_id_ranges,
+};
+use std::{error::Error, ops::RangeInclusive, path::Path, str::FromStr};
+
+/// Snapshot of an earlier run that a run started from
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RestartSource {
+    pub run_dir: String,
+    pub step: u32,
+    /// Global ids in the earlier run of the restarted particles, in their new order
+    pub particle_ids: Vec<usize>,
+}
+
+/// Which particles of a snapshot to restart
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParticleFilter {
+    All,
+    /// Particles that had not been lost by the snapshot
+    Confined,
+    /// Particles with the given global ids
+    Ids(Vec<RangeInclusive<usize>>),
+}
+
+impl ParticleFilter {
+    pub fn selects(&self, id: usize, status: &ParticleState) -> bool {
+        match self {
+            ParticleFilter::All => true,
+            ParticleFilter::Confined => status.is_active(),
+            ParticleFilter::Ids(ranges) => ranges.iter().any(|range| range.contains(&id)),
+        }
+    }
+}
+
+/// Parses "all", "confined" or comma separated ids and ranges such as "0-99,150"
+impl FromStr for ParticleFilter {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "all" => Ok(ParticleFilter::All),
+            "confined" => Ok(ParticleFilter::Confined),
+            _ => parse_id_ranges(value).map(ParticleFilter::Ids),
+        }
+    }
+}
+
+/// Particles selected from a snapshot of an earlier run
+pub struct RestartPoint {
+    /// Configuration of the earlier run
+    pub config: RunConfig,
+    pub source: RestartSource,
+    /// Lost particles are at their last confined position
+    pub particles: Vec<Point>,
+    /// Whether each particle was lost by the snapshot, and in which step
+    pub statuses: Vec<ParticleState>,
+}
+
+impl RestartPoint {
+    /// Reads the snapshot of `step` (default: the last one) from `run_dir`
+    /// and keeps the particles selected by `filter`, with the losses the lost
+    /// particles files of the earlier run record up to that step. Fails if
+    /// the inputs of the earlier run changed since it was started.
+    pub fn load(
+        run_dir: &Path,
+        step: Option<u32>,
+        filter: &ParticleFilter,
+    ) -> Result<Self, Box<dyn Error>> {
+        let config = RunConfig::read(run_dir)?;
+        config.verify_input_checksums()?;
+        let snapshots = list_snapshots(run_dir)?;
+        let (step, rank_files) = match step {
+            Some(step) => snapshots
+                .get_key_value(&step)
+                .ok_or_else(|| format!("No snapshot of step {} in {}", step, run_dir.display()))?,
+            None => snapshots
+                .last_key_value()
+                .ok_or_else(|| format!("No snapshots in {}", run_dir.display()))?,
+        };
+        let losses = read_run_losses(run_dir, &config)?;
+        let mut particle_ids = Vec::new();
+        let mut particles = Vec::new();
+        let mut statuses = Vec::new();
+        for (id, (point, loss)) in read_global_snapshot(&config, *step, rank_files)?
+            .into_iter()
+            .zip(losses)
+            .enumerate()
+        {
+            let (point, status) = match loss.filter(|loss| loss.step <= *step) {
+                Some(loss) => (loss.position, ParticleState::Lost { step: loss.step }),
+                None => (point, ParticleState::Active),
+            };
+            if filter.selects(id, &status) {
+                particle_ids.push(id);
+                particles.push(point);
+                statuses.push(status);
+            }
+        }
+        Ok(RestartPoint {
+            source: RestartSource {
+                run_dir: run_dir.to_string_lossy().into_owned(),
+                step: *step,
+                particle_ids,
+            },
+            config,
+            particles,
+            statuses,
+        })
+    }
+
+    /// Drops particles beyond the first `count`
+    pub fn truncate(&mut self, count: usize) {
+        self.particles.truncate(count);
+        self.statuses.truncate(count);
+        self.source.particle_ids.truncate(count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_id_ranges() {
+        let filter: ParticleFilter = "0-2,7".parse().unwrap();
+        assert_eq!(filter, ParticleFilter::Ids(vec![0..=2, 7..=7]));
+        assert!(filter.selects(2, &ParticleState::Active));
+        assert!(!filter.selects(3, &ParticleState::Active));
+        assert!("1-x".parse::<ParticleFilter>().is_err());
+        assert!(!ParticleFilter::Confined.selects(0, &ParticleState::Lost { step: 4 }));
+    }
+}
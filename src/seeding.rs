@@ -0,0 +1,144 @@
+use crate::{
+    constants::{PI, PhysicsParams},
+    point::Point,
+};
+use clap::ValueEnum;
+
+/// Generators of starting particles, used instead of a particles file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeedMode {
+    /// Square grid over the disk of the seed radius around the magnetic axis,
+    /// in the poloidal cross-section at the seed angle
+    Grid,
+    /// Uniformly random over the area of every poloidal cross-section of the
+    /// torus of the seed radius around the magnetic axis, at random angles
+    Torus,
+    /// Evenly spaced from the magnetic axis outwards to the seed radius, in
+    /// the midplane at the seed angle
+    Line,
+}
+
+/// Generator and parameters the starting particles of a run came from
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Seeding {
+    pub mode: SeedMode,
+    /// Distance to the magnetic axis particles start within
+    pub radius: f64,
+    /// Toroidal angle in radians of the `grid` and `line` cross-section
+    pub phi: f64,
+    /// Seed of the random number generator of `torus`
+    pub seed: u64,
+}
+
+impl Seeding {
+    /// `count` starting particles around the magnetic axis of `physics`
+    pub fn generate(&self, count: usize, physics: &PhysicsParams) -> Vec<Point> {
+        let at = |phi: f64, r: f64, theta: f64| {
+            let major = physics.major_radius + r * theta.cos();
+            Point {
+                x: major * phi.cos(),
+                y: major * phi.sin(),
+                z: r * theta.sin(),
+            }
+        };
+        match self.mode {
+            SeedMode::Grid => {
+                // Smallest grid with at least `count` points on the disk,
+                // thinned out evenly to `count`
+                let mut side = (count as f64 * 4.0 / PI).sqrt() as usize;
+                let grid = loop {
+                    side += 1;
+                    let spacing = 2.0 * self.radius / side as f64;
+                    let grid: Vec<(f64, f64)> = (0..side * side)
+                        .map(|index| {
+                            let u = (index % side) as f64 * spacing + spacing / 2.0;
+                            let v = (index / side) as f64 * spacing + spacing / 2.0;
+                            (u - self.radius, v - self.radius)
+                        })
+                        .filter(|(u, v)| u.hypot(*v) < self.radius)
+                        .collect();
+                    if grid.len() >= count {
+                        break grid;
+                    }
+                };
+                (0..count)
+                    .map(|index| grid[index * grid.len() / count])
+                    .map(|(u, v)| at(self.phi, u.hypot(v), v.atan2(u)))
+                    .collect()
+            }
+            SeedMode::Torus => {
+                let mut rng = SplitMix64(self.seed);
+                (0..count)
+                    .map(|_| {
+                        let phi = 2.0 * PI * rng.next_f64();
+                        let r = self.radius * rng.next_f64().sqrt();
+                        let theta = 2.0 * PI * rng.next_f64();
+                        at(phi, r, theta)
+                    })
+                    .collect()
+            }
+            SeedMode::Line => (0..count)
+                .map(|index| at(self.phi, self.radius * index as f64 / count as f64, 0.0))
+                .collect(),
+        }
+    }
+}
+
+/// SplitMix64 generator, small and fully determined by its seed on every
+/// platform
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::distance_to_axis;
+
+    #[test]
+    fn seeds_start_within_the_radius() {
+        let physics = PhysicsParams::default();
+        for mode in [SeedMode::Grid, SeedMode::Torus, SeedMode::Line] {
+            let seeding = Seeding {
+                mode,
+                radius: 0.05,
+                phi: 0.3,
+                seed: 7,
+            };
+            let particles = seeding.generate(100, &physics);
+            assert_eq!(particles.len(), 100, "{:?}", mode);
+            assert!(
+                particles
+                    .iter()
+                    .all(|particle| distance_to_axis(particle, &physics) < 0.05 + 1e-12),
+                "{:?}",
+                mode
+            );
+            assert_eq!(particles, seeding.generate(100, &physics));
+        }
+        let torus = |seed| {
+            Seeding {
+                mode: SeedMode::Torus,
+                radius: 0.05,
+                phi: 0.0,
+                seed,
+            }
+            .generate(10, &physics)
+        };
+        assert_ne!(torus(1), torus(2));
+    }
+}
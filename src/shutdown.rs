@@ -0,0 +1,91 @@
+use std::{
+    error::Error,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+
+/// Set by the SIGTERM handler, read by the step loop
+static TERMINATED: AtomicBool = AtomicBool::new(false);
+
+/// Steps between the checks for a shutdown, each a collective over the ranks
+pub const CHECK_EVERY: u32 = 50;
+
+/// When a run stops before its last step: on SIGTERM, as sent by schedulers
+/// such as SLURM shortly before the walltime, or past a deadline of its own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shutdown {
+    /// Stop once this instant is past, `None` for no walltime budget
+    pub deadline: Option<Instant>,
+    /// Ranks agree on whether to stop every this many steps, so a run may
+    /// go on for up to this many steps once a shutdown is requested
+    pub check_every: u32,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            deadline: None,
+            check_every: CHECK_EVERY,
+        }
+    }
+}
+
+impl Shutdown {
+    /// Whether the ranks check for a shutdown after `step`
+    pub fn is_checked_at(&self, step: u32) -> bool {
+        step.is_multiple_of(self.check_every.max(1))
+    }
+
+    /// Whether this rank was asked to stop. Ranks may disagree, as signals
+    /// and clocks reach them at different times.
+    pub fn is_requested(&self) -> bool {
+        TERMINATED.load(Ordering::Relaxed)
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+/// Catches SIGTERM so that runs stop within `Shutdown::check_every` steps
+/// instead of being killed. Only sets a flag, which is all a signal handler
+/// may safely do.
+#[cfg(unix)]
+pub fn install_signal_handler() -> Result<(), Box<dyn Error>> {
+    extern "C" fn on_terminate(_signal: libc::c_int) {
+        TERMINATED.store(true, Ordering::Relaxed);
+    }
+    let handler = on_terminate as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    let previous = unsafe { libc::signal(libc::SIGTERM, handler) };
+    if previous == libc::SIG_ERR {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn install_signal_handler() -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn deadlines_request_a_shutdown_once_past() {
+        let now = Instant::now();
+        let past = Shutdown {
+            deadline: Some(now - Duration::from_millis(1)),
+            ..Shutdown::default()
+        };
+        assert!(past.is_requested());
+        let future = Shutdown {
+            deadline: Some(now + Duration::from_secs(3600)),
+            ..Shutdown::default()
+        };
+        assert!(!future.is_requested());
+        assert!(past.is_checked_at(2 * CHECK_EVERY));
+        assert!(!past.is_checked_at(CHECK_EVERY + 1));
+    }
+}
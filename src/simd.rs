@@ -0,0 +1,336 @@
+use crate::{
+    point::Point,
+    simulation::{CoilSet, add_segment_fields, add_single_segment_fields},
+};
+use std::{arch::x86_64::*, iter::Sum, ops::Range};
+
+/// AVX vector of coil segment values in one precision, the operations the
+/// field kernel needs. Every method needs a CPU supporting AVX.
+trait Lanes: Copy {
+    type Scalar: Copy + From<f32> + Sum;
+    /// Segments processed per iteration of the kernel
+    const LANES: usize;
+
+    unsafe fn splat(value: Self::Scalar) -> Self;
+    /// Values from `index` on, which must be followed by `LANES - 1` more
+    unsafe fn load(values: &[Self::Scalar], index: usize) -> Self;
+    unsafe fn add(self, other: Self) -> Self;
+    unsafe fn sub(self, other: Self) -> Self;
+    unsafe fn mul(self, other: Self) -> Self;
+    unsafe fn div(self, other: Self) -> Self;
+    unsafe fn sqrt(self) -> Self;
+    unsafe fn sum(self) -> Self::Scalar;
+}
+
+/// Implements `Lanes` for a vector type with its intrinsics, taken in the
+/// order of the trait methods
+macro_rules! impl_lanes {
+    ($vector:ty, $scalar:ty, $lanes:expr, $splat:ident, $load:ident, $add:ident, $sub:ident,
+     $mul:ident, $div:ident, $sqrt:ident, $store:ident) => {
+        impl Lanes for $vector {
+            type Scalar = $scalar;
+            const LANES: usize = $lanes;
+
+            #[inline]
+            #[target_feature(enable = "avx")]
+            unsafe fn splat(value: $scalar) -> Self {
+                $splat(value)
+            }
+
+            #[inline]
+            #[target_feature(enable = "avx")]
+            unsafe fn load(values: &[$scalar], index: usize) -> Self {
+                unsafe { $load(values.as_ptr().add(index)) }
+            }
+
+            #[inline]
+            #[target_feature(enable = "avx")]
+            unsafe fn add(self, other: Self) -> Self {
+                $add(self, other)
+            }
+
+            #[inline]
+            #[target_feature(enable = "avx")]
+            unsafe fn sub(self, other: Self) -> Self {
+                $sub(self, other)
+            }
+
+            #[inline]
+            #[target_feature(enable = "avx")]
+            unsafe fn mul(self, other: Self) -> Self {
+                $mul(self, other)
+            }
+
+            #[inline]
+            #[target_feature(enable = "avx")]
+            unsafe fn div(self, other: Self) -> Self {
+                $div(self, other)
+            }
+
+            #[inline]
+            #[target_feature(enable = "avx")]
+            unsafe fn sqrt(self) -> Self {
+                $sqrt(self)
+            }
+
+            #[inline]
+            #[target_feature(enable = "avx")]
+            unsafe fn sum(self) -> $scalar {
+                let mut values = [0.0; $lanes];
+                // Safety: `values` holds exactly one vector
+                unsafe { $store(values.as_mut_ptr(), self) };
+                values.iter().sum()
+            }
+        }
+    };
+}
+
+impl_lanes!(
+    __m256d,
+    f64,
+    4,
+    _mm256_set1_pd,
+    _mm256_loadu_pd,
+    _mm256_add_pd,
+    _mm256_sub_pd,
+    _mm256_mul_pd,
+    _mm256_div_pd,
+    _mm256_sqrt_pd,
+    _mm256_storeu_pd
+);
+impl_lanes!(
+    __m256,
+    f32,
+    8,
+    _mm256_set1_ps,
+    _mm256_loadu_ps,
+    _mm256_add_ps,
+    _mm256_sub_ps,
+    _mm256_mul_ps,
+    _mm256_div_ps,
+    _mm256_sqrt_ps,
+    _mm256_storeu_ps
+);
+
+/// Points, unit vectors and lengths of the segments of a coil set in one
+/// precision
+struct Segments<'a, T> {
+    x: &'a [T],
+    y: &'a [T],
+    z: &'a [T],
+    e_x: &'a [T],
+    e_y: &'a [T],
+    e_z: &'a [T],
+    lengths: &'a [T],
+}
+
+/// Field at `position` of the segments `range` of one coil, `LANES` at a
+/// time, with the lanes summed at the end. `range` must hold a multiple of
+/// `LANES` segments.
+///
+/// # Safety
+///
+/// The CPU must support AVX.
+#[target_feature(enable = "avx")]
+unsafe fn vector_field<L: Lanes>(
+    position: [L::Scalar; 3],
+    segments: &Segments<'_, L::Scalar>,
+    range: Range<usize>,
+    multiplier: L::Scalar,
+) -> [L::Scalar; 3] {
+    // Safety: the caller checked for AVX, and j + LANES is at most the
+    // last point of the coil
+    unsafe {
+        let two = L::splat(2.0.into());
+        let one = L::splat(1.0.into());
+        let [px, py, pz] = position.map(|value| L::splat(value));
+        let multiplier = L::splat(multiplier);
+        let zero = L::splat(0.0.into());
+        let (mut bx, mut by, mut bz) = (zero, zero, zero);
+        for j in range.step_by(L::LANES) {
+            let rmi_x = px.sub(L::load(segments.x, j));
+            let rmi_y = py.sub(L::load(segments.y, j));
+            let rmi_z = pz.sub(L::load(segments.z, j));
+            let rmf_x = px.sub(L::load(segments.x, j + 1));
+            let rmf_y = py.sub(L::load(segments.y, j + 1));
+            let rmf_z = pz.sub(L::load(segments.z, j + 1));
+            let ux = multiplier.mul(L::load(segments.e_x, j));
+            let uy = multiplier.mul(L::load(segments.e_y, j));
+            let uz = multiplier.mul(L::load(segments.e_z, j));
+            let length = L::load(segments.lengths, j);
+
+            let norm = |x: L, y: L, z: L| x.mul(x).add(y.mul(y)).add(z.mul(z)).sqrt();
+            let rmi_norm = norm(rmi_x, rmi_y, rmi_z);
+            let rmf_norm = norm(rmf_x, rmf_y, rmf_z);
+            let norm_sum = rmi_norm.add(rmf_norm);
+            let c = two
+                .mul(length)
+                .mul(norm_sum)
+                .div(rmi_norm.mul(rmf_norm))
+                .mul(one.div(norm_sum.mul(norm_sum).sub(length.mul(length))));
+            let (vx, vy, vz) = (rmi_x.mul(c), rmi_y.mul(c), rmi_z.mul(c));
+
+            bx = bx.add(uy.mul(vz).sub(uz.mul(vy)));
+            by = by.sub(ux.mul(vz).sub(uz.mul(vx)));
+            bz = bz.add(ux.mul(vy).sub(uy.mul(vx)));
+        }
+        [bx.sum(), by.sum(), bz.sum()]
+    }
+}
+
+/// End of the segments of `range` the vector kernel takes, the rest are
+/// left to the scalar path
+fn whole_lanes_end(range: &Range<usize>, lanes: usize) -> usize {
+    range.start + range.len() / lanes * lanes
+}
+
+/// `compute_magnetic_field` evaluating four coil segments at a time with AVX.
+/// Lanes are summed at the end of each coil, so results differ from the
+/// scalar path by rounding only.
+///
+/// # Safety
+///
+/// The CPU must support AVX.
+#[target_feature(enable = "avx")]
+pub unsafe fn compute_magnetic_field_avx(particle: &Point, coils: &CoilSet) -> Point {
+    let arrays = Segments {
+        x: &coils.x,
+        y: &coils.y,
+        z: &coils.z,
+        e_x: &coils.e_x,
+        e_y: &coils.e_y,
+        e_z: &coils.e_z,
+        lengths: &coils.lengths,
+    };
+    let position = [particle.x, particle.y, particle.z];
+    let mut b = Point::default();
+    for coil in 0..coils.len() {
+        let segments = coils.segments(coil);
+        let multiplier = coils.field_multiplier(coil);
+        let vector_end = whole_lanes_end(&segments, __m256d::LANES);
+        // Safety: the caller checked for AVX
+        let [x, y, z] = unsafe {
+            vector_field::<__m256d>(position, &arrays, segments.start..vector_end, multiplier)
+        };
+        b.x += x;
+        b.y += y;
+        b.z += z;
+        add_segment_fields(
+            particle,
+            coils,
+            vector_end..segments.end,
+            multiplier,
+            &mut b,
+        );
+    }
+    b
+}
+
+/// `compute_magnetic_field_avx` with the segment sums of each coil in
+/// single precision, eight segments at a time, for `Precision::F32`
+///
+/// # Safety
+///
+/// The CPU must support AVX.
+#[target_feature(enable = "avx")]
+pub unsafe fn compute_magnetic_field_avx_f32(particle: &Point, coils: &CoilSet) -> Point {
+    let single = &coils.single;
+    let arrays = Segments {
+        x: &single.x,
+        y: &single.y,
+        z: &single.z,
+        e_x: &single.e_x,
+        e_y: &single.e_y,
+        e_z: &single.e_z,
+        lengths: &single.lengths,
+    };
+    let position = [particle.x as f32, particle.y as f32, particle.z as f32];
+    let mut b = Point::default();
+    for coil in 0..coils.len() {
+        let segments = coils.segments(coil);
+        let multiplier = coils.field_multiplier(coil) as f32;
+        let vector_end = whole_lanes_end(&segments, __m256::LANES);
+        // Safety: the caller checked for AVX
+        let mut sum = unsafe {
+            vector_field::<__m256>(position, &arrays, segments.start..vector_end, multiplier)
+        };
+        add_single_segment_fields(
+            position,
+            single,
+            vector_end..segments.end,
+            multiplier,
+            &mut sum,
+        );
+        b.x += sum[0] as f64;
+        b.y += sum[1] as f64;
+        b.z += sum[2] as f64;
+    }
+    b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::{Precision, read_coil_data_directory};
+    use std::path::Path;
+
+    #[test]
+    fn vector_kernel_matches_scalar_path() {
+        if !is_x86_feature_detected!("avx") {
+            return;
+        }
+        let coils = CoilSet::new(
+            &read_coil_data_directory(Path::new("tests/test-resources/resources")).unwrap(),
+        );
+        for point in [
+            Point {
+                x: 0.2,
+                y: 0.03,
+                z: 0.01,
+            },
+            Point {
+                x: -0.1,
+                y: 0.21,
+                z: -0.04,
+            },
+        ] {
+            let mut scalar = Point::default();
+            for coil in 0..coils.len() {
+                add_segment_fields(
+                    &point,
+                    &coils,
+                    coils.segments(coil),
+                    coils.field_multiplier(coil),
+                    &mut scalar,
+                );
+            }
+            let vector = unsafe { compute_magnetic_field_avx(&point, &coils) };
+            let tolerance = 1e-12 * scalar.get_norm();
+            assert!((vector.x - scalar.x).abs() < tolerance);
+            assert!((vector.y - scalar.y).abs() < tolerance);
+            assert!((vector.z - scalar.z).abs() < tolerance);
+
+            let single = coils.clone().with_precision(Precision::F32);
+            let position = [point.x as f32, point.y as f32, point.z as f32];
+            let mut scalar = Point::default();
+            for coil in 0..single.len() {
+                let mut sum = [0.0; 3];
+                add_single_segment_fields(
+                    position,
+                    &single.single,
+                    single.segments(coil),
+                    single.field_multiplier(coil) as f32,
+                    &mut sum,
+                );
+                scalar.x += sum[0] as f64;
+                scalar.y += sum[1] as f64;
+                scalar.z += sum[2] as f64;
+            }
+            let vector = unsafe { compute_magnetic_field_avx_f32(&point, &single) };
+            let tolerance = 1e-5 * scalar.get_norm();
+            assert!((vector.x - scalar.x).abs() < tolerance);
+            assert!((vector.y - scalar.y).abs() < tolerance);
+            assert!((vector.z - scalar.z).abs() < tolerance);
+        }
+    }
+}
@@ -1,188 +1,993 @@
 use crate::{
-    constants::{I, MAJOR_RADIUS, MINOR_RADIUS, MIU, PI},
-    point::{Point, read_from_file, write_points_to_file},
+    balance::Loans,
+    boundary::LossBoundary,
+    coil_format::{CoilFormat, read_coils},
+    collectives::Collectives,
+    collisions::Collisions,
+    constants::{MINOR_RADIUS, PI, PhysicsParams},
+    diagnostics::{DriftSums, poincare_crossing},
+    field_source::{BackgroundField, FieldSource},
+    integrator::{IntegrationState, Integrator, Rk4, StepCounts},
+    logging::{PhaseTimes, log_phase_times},
+    output::SnapshotWriter,
+    particle::ParticleState,
+    point::Point,
+    shutdown::Shutdown,
+    utils::format_duration,
 };
-use clap::error::Result;
-use log::debug;
+use clap::{ValueEnum, error::Result};
+use log::{debug, info, trace};
 use rayon::prelude::*;
-use std::{error::Error, fs, io, path::Path, usize};
+use std::{error::Error, iter::repeat_n, ops::Range, path::Path, time::Instant, usize};
 
-pub fn compute_magnetic_field(
-    particle: &Point,
-    coils: &Vec<Vec<Point>>,
-    displacements: &Vec<Vec<Point>>,
-    e_roof: &Vec<Vec<Point>>,
-) -> Point {
-    let multiplier = (MIU * I) / (4.0 * PI);
+/// How the contributions of the coil segments to the field are added up
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Summation {
+    /// In order, vectorised with the `simd` feature
+    #[default]
+    Naive,
+    /// Neumaier's compensated summation, which carries the rounding error of
+    /// every addition along so the sum is as if computed in twice the
+    /// precision. Always scalar.
+    Compensated,
+}
+
+/// Floating point width the Biot–Savart sums are evaluated in
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    /// Double precision
+    #[default]
+    F64,
+    /// Single precision sums over the segments of each coil, twice as many
+    /// lanes per vector with the `simd` feature. Particle positions, the
+    /// integrator and the sums over coils and field periods stay in double
+    /// precision.
+    F32,
+}
+
+/// Device the field of the particles of a rank is evaluated on
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// Rayon over the particles of the rank
+    #[default]
+    Cpu,
+    /// CUDA kernel of the `gpu` feature, evaluating the field of every
+    /// particle of the rank per Runge–Kutta stage on the first device the
+    /// rank sees. Falls back to the CPU without a usable device.
+    Gpu,
+}
+
+/// Running sum with Neumaier compensation
+#[derive(Debug, Default, Clone, Copy)]
+struct CompensatedSum {
+    sum: f64,
+    compensation: f64,
+}
+
+impl CompensatedSum {
+    fn add(&mut self, value: f64) {
+        let sum = self.sum + value;
+        self.compensation += if self.sum.abs() >= value.abs() {
+            (self.sum - sum) + value
+        } else {
+            (value - sum) + self.sum
+        };
+        self.sum = sum;
+    }
+
+    fn value(&self) -> f64 {
+        self.sum + self.compensation
+    }
+}
+
+/// Position snapshots record for particles that left the loss boundary
+pub const DIVERGENT_PARTICLE: Point = Point {
+    x: MINOR_RADIUS,
+    y: MINOR_RADIUS,
+    z: MINOR_RADIUS,
+};
+
+/// Coil geometry in flat structure-of-arrays buffers. Coil `c` owns points
+/// `offsets[c]..offsets[c + 1]`, and the segment from point `j` to `j + 1`
+/// stores its unit vector and length at index `j`, so the last slot of every
+/// coil in the segment buffers is unused.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CoilSet {
+    /// Current and device geometry the field and confinement are computed with
+    pub physics: PhysicsParams,
+    pub x: Vec<f64>,
+    pub y: Vec<f64>,
+    pub z: Vec<f64>,
+    pub e_x: Vec<f64>,
+    pub e_y: Vec<f64>,
+    pub e_z: Vec<f64>,
+    pub lengths: Vec<f64>,
+    pub offsets: Vec<usize>,
+    /// Current of each coil in amperes, empty when every coil carries
+    /// `physics.current`
+    pub currents: Vec<f64>,
+    /// Field periods of the device when the coils are those of one period,
+    /// the field then sums copies of them rotated about the z axis by
+    /// multiples of 2π / `field_periods`. 0 or 1 when they are all coils.
+    pub field_periods: usize,
+    pub summation: Summation,
+    /// Wall of the vessel, particles leaving it are lost
+    pub boundary: LossBoundary,
+    pub precision: Precision,
+    /// Geometry rounded to single precision, empty unless `precision` is
+    /// `Precision::F32`
+    pub single: SingleCoils,
+    /// Analytic fields added to that of the coils
+    pub background: Vec<BackgroundField>,
+}
+
+/// Points, unit vectors and lengths of the segments of a `CoilSet` in
+/// single precision, laid out like those of the set
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SingleCoils {
+    pub x: Vec<f32>,
+    pub y: Vec<f32>,
+    pub z: Vec<f32>,
+    pub e_x: Vec<f32>,
+    pub e_y: Vec<f32>,
+    pub e_z: Vec<f32>,
+    pub lengths: Vec<f32>,
+}
+
+impl SingleCoils {
+    fn new(coils: &CoilSet) -> Self {
+        let round = |values: &[f64]| values.iter().map(|&value| value as f32).collect();
+        SingleCoils {
+            x: round(&coils.x),
+            y: round(&coils.y),
+            z: round(&coils.z),
+            e_x: round(&coils.e_x),
+            e_y: round(&coils.e_y),
+            e_z: round(&coils.e_z),
+            lengths: round(&coils.lengths),
+        }
+    }
+}
+
+impl CoilSet {
+    pub fn new(coils: &[Vec<Point>]) -> Self {
+        let mut set = CoilSet {
+            offsets: vec![0],
+            ..Default::default()
+        };
+        for coil in coils {
+            let displacements = compute_displacements(coil);
+            let e_roof = compute_e_roof(&displacements);
+            set.x.extend(coil.iter().map(|point| point.x));
+            set.y.extend(coil.iter().map(|point| point.y));
+            set.z.extend(coil.iter().map(|point| point.z));
+            let unused = coil.len() - displacements.len();
+            set.e_x
+                .extend(e_roof.iter().map(|e| e.x).chain(repeat_n(0.0, unused)));
+            set.e_y
+                .extend(e_roof.iter().map(|e| e.y).chain(repeat_n(0.0, unused)));
+            set.e_z
+                .extend(e_roof.iter().map(|e| e.z).chain(repeat_n(0.0, unused)));
+            set.lengths.extend(
+                displacements
+                    .iter()
+                    .map(|displacement| displacement.get_norm())
+                    .chain(repeat_n(0.0, unused)),
+            );
+            set.offsets.push(set.x.len());
+        }
+        set
+    }
+
+    pub fn with_physics(self, physics: PhysicsParams) -> Self {
+        CoilSet { physics, ..self }
+    }
+
+    /// Currents of the coils in order, signed to flip their field
+    pub fn with_currents(self, currents: Vec<f64>) -> Self {
+        CoilSet { currents, ..self }
+    }
+
+    /// Coils of one of `field_periods` periods instead of all coils
+    pub fn with_field_periods(self, field_periods: usize) -> Self {
+        CoilSet {
+            field_periods,
+            ..self
+        }
+    }
+
+    pub fn with_summation(self, summation: Summation) -> Self {
+        CoilSet { summation, ..self }
+    }
+
+    pub fn with_boundary(self, boundary: LossBoundary) -> Self {
+        CoilSet { boundary, ..self }
+    }
+
+    pub fn with_background(self, background: Vec<BackgroundField>) -> Self {
+        CoilSet { background, ..self }
+    }
+
+    /// Evaluates the field in `precision`, rounding the geometry for `F32`
+    pub fn with_precision(self, precision: Precision) -> Self {
+        let single = match precision {
+            Precision::F64 => SingleCoils::default(),
+            Precision::F32 => SingleCoils::new(&self),
+        };
+        CoilSet {
+            precision,
+            single,
+            ..self
+        }
+    }
+
+    /// Factor of the Biot-Savart law applied to every segment of coil `index`
+    pub fn field_multiplier(&self, index: usize) -> f64 {
+        match self.currents.get(index) {
+            Some(&current) => PhysicsParams {
+                current,
+                ..self.physics
+            }
+            .field_multiplier(),
+            None => self.physics.field_multiplier(),
+        }
+    }
+
+    /// Number of coils
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn num_points(&self) -> usize {
+        self.x.len()
+    }
+
+    /// Indices of the starting points of the segments of coil `index`
+    pub fn segments(&self, index: usize) -> Range<usize> {
+        let (start, end) = (self.offsets[index], self.offsets[index + 1]);
+        start..end.saturating_sub(1).max(start)
+    }
+
+    pub fn coil(&self, index: usize) -> Vec<Point> {
+        (self.offsets[index]..self.offsets[index + 1])
+            .map(|j| Point {
+                x: self.x[j],
+                y: self.y[j],
+                z: self.z[j],
+            })
+            .collect()
+    }
+
+    pub fn coils(&self) -> Vec<Vec<Point>> {
+        (0..self.len()).map(|index| self.coil(index)).collect()
+    }
+
+    /// Coils of the given indices, in that order, without the background
+    /// fields so that their field is linear in their currents
+    pub fn select(&self, indices: &[usize]) -> CoilSet {
+        let mut set = CoilSet {
+            physics: self.physics,
+            field_periods: self.field_periods,
+            summation: self.summation,
+            boundary: self.boundary.clone(),
+            offsets: vec![0],
+            ..Default::default()
+        };
+        for &index in indices {
+            let points = self.offsets[index]..self.offsets[index + 1];
+            set.x.extend_from_slice(&self.x[points.clone()]);
+            set.y.extend_from_slice(&self.y[points.clone()]);
+            set.z.extend_from_slice(&self.z[points.clone()]);
+            set.e_x.extend_from_slice(&self.e_x[points.clone()]);
+            set.e_y.extend_from_slice(&self.e_y[points.clone()]);
+            set.e_z.extend_from_slice(&self.e_z[points.clone()]);
+            set.lengths.extend_from_slice(&self.lengths[points]);
+            set.offsets.push(set.x.len());
+            set.currents.extend(self.currents.get(index));
+        }
+        set.with_precision(self.precision)
+    }
+}
+
+/// Field at `particle` of the coils and of every background field of the set
+pub fn compute_magnetic_field(particle: &Point, coils: &CoilSet) -> Point {
+    let sources = coils
+        .background
+        .iter()
+        .map(|field| field as &dyn FieldSource);
+    std::iter::once(coils as &dyn FieldSource)
+        .chain(sources)
+        .fold(Point::default(), |b, source| {
+            let field = source.field_at(particle);
+            Point {
+                x: b.x + field.x,
+                y: b.y + field.y,
+                z: b.z + field.z,
+            }
+        })
+}
+
+/// Biot-Savart field of the coils, the stored ones and those of the other
+/// field periods
+impl FieldSource for CoilSet {
+    fn field_at(&self, point: &Point) -> Point {
+        coils_field(point, self)
+    }
+}
+
+fn coils_field(particle: &Point, coils: &CoilSet) -> Point {
+    let periods = coils.field_periods.max(1);
+    if periods == 1 {
+        return stored_coils_field(particle, coils);
+    }
+    // The field of the period rotated by phi at the particle is the field of
+    // the stored period at the particle rotated by -phi, rotated by phi
+    let mut b = Point::default();
+    for period in 0..periods {
+        let (sin, cos) = (2.0 * PI * period as f64 / periods as f64).sin_cos();
+        let rotated = Point {
+            x: cos * particle.x + sin * particle.y,
+            y: cos * particle.y - sin * particle.x,
+            z: particle.z,
+        };
+        let field = stored_coils_field(&rotated, coils);
+        b.x += cos * field.x - sin * field.y;
+        b.y += sin * field.x + cos * field.y;
+        b.z += field.z;
+    }
+    b
+}
+
+/// Field of the coils of the set as stored, without other field periods
+fn stored_coils_field(particle: &Point, coils: &CoilSet) -> Point {
+    if coils.summation == Summation::Compensated {
+        return compensated_field(particle, coils);
+    }
+    if coils.precision == Precision::F32 {
+        return single_precision_field(particle, coils);
+    }
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if is_x86_feature_detected!("avx") {
+        // Safety: the CPU supports AVX, checked above
+        return unsafe { crate::simd::compute_magnetic_field_avx(particle, coils) };
+    }
     let mut b = Point {
         x: 0.0,
         y: 0.0,
         z: 0.0,
     };
+    for coil in 0..coils.len() {
+        add_segment_fields(
+            particle,
+            coils,
+            coils.segments(coil),
+            coils.field_multiplier(coil),
+            &mut b,
+        );
+    }
+    b
+}
 
-    for ((coil, e_roof_slice), displacement_slice) in
-        coils.iter().zip(e_roof.iter()).zip(displacements.iter())
-    {
-        for (points, (e, displacement)) in coil
-            .windows(2)
-            .zip(e_roof_slice.iter().zip(displacement_slice.iter()))
-        {
-            let rmi_a = unsafe { particle.get_displacement(&points.get_unchecked(0)) };
-            let rmf_a = unsafe { particle.get_displacement(&points.get_unchecked(1)) };
-            let u = Point {
-                x: multiplier * e.x,
-                y: multiplier * e.y,
-                z: multiplier * e.z,
-            };
-            let displacement_norm = displacement.get_norm();
-            let rmi_a_norm = rmi_a.get_norm();
-            let rmf_a_norm = rmf_a.get_norm();
-            let c = ((2.0 * displacement_norm * (rmi_a_norm + rmf_a_norm))
-                / (rmi_a_norm * rmf_a_norm))
-                * (1.0 / ((rmi_a_norm + rmf_a_norm).powi(2) - displacement_norm.powi(2)));
-
-            // Compute vector v
-            let v = Point {
-                x: rmi_a.x * c,
-                y: rmi_a.y * c,
-                z: rmi_a.z * c,
-            };
+/// Adds the field of the segments starting at the points of `segments`,
+/// which carry the current of `multiplier`, to `b`
+pub(crate) fn add_segment_fields(
+    particle: &Point,
+    coils: &CoilSet,
+    segments: Range<usize>,
+    multiplier: f64,
+    b: &mut Point,
+) {
+    for j in segments {
+        let field = segment_field(particle, coils, j, multiplier);
+        b.x += field.x;
+        b.y += field.y;
+        b.z += field.z;
+    }
+}
 
-            // Update b using the cross product of u and v
-            b.x += (u.y * v.z) - (u.z * v.y);
-            b.y -= (u.x * v.z) - (u.z * v.x);
-            b.z += (u.x * v.y) - (u.y * v.x);
-        }
+/// `stored_coils_field` summing the segments of each coil in single precision
+fn single_precision_field(particle: &Point, coils: &CoilSet) -> Point {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if is_x86_feature_detected!("avx") {
+        // Safety: the CPU supports AVX, checked above
+        return unsafe { crate::simd::compute_magnetic_field_avx_f32(particle, coils) };
+    }
+    let position = [particle.x as f32, particle.y as f32, particle.z as f32];
+    let mut b = Point::default();
+    for coil in 0..coils.len() {
+        let mut sum = [0.0; 3];
+        add_single_segment_fields(
+            position,
+            &coils.single,
+            coils.segments(coil),
+            coils.field_multiplier(coil) as f32,
+            &mut sum,
+        );
+        b.x += sum[0] as f64;
+        b.y += sum[1] as f64;
+        b.z += sum[2] as f64;
     }
     b
 }
 
-pub fn simulate_step(
-    particle: &Point,
-    coils: &Vec<Vec<Point>>,
-    displacements: &Vec<Vec<Point>>,
-    e_roof: &Vec<Vec<Point>>,
-    step_size: f64,
-) -> Point {
-    let mut k1 = compute_magnetic_field(particle, coils, displacements, e_roof);
-    let k1norm = k1.get_norm();
-    k1.x = (k1.x / k1norm) * step_size;
-    k1.y = (k1.y / k1norm) * step_size;
-    k1.z = (k1.z / k1norm) * step_size;
-    let p1 = Point {
-        x: k1.x / 2.0 + particle.x,
-        y: k1.y / 2.0 + particle.y,
-        z: k1.z / 2.0 + particle.z,
-    };
+/// `add_segment_fields` in single precision
+pub(crate) fn add_single_segment_fields(
+    [px, py, pz]: [f32; 3],
+    coils: &SingleCoils,
+    segments: Range<usize>,
+    multiplier: f32,
+    b: &mut [f32; 3],
+) {
+    for j in segments {
+        let rmi = [px - coils.x[j], py - coils.y[j], pz - coils.z[j]];
+        let rmf = [
+            px - coils.x[j + 1],
+            py - coils.y[j + 1],
+            pz - coils.z[j + 1],
+        ];
+        let u = [
+            multiplier * coils.e_x[j],
+            multiplier * coils.e_y[j],
+            multiplier * coils.e_z[j],
+        ];
+        let length = coils.lengths[j];
+        let rmi_norm = (rmi[0] * rmi[0] + rmi[1] * rmi[1] + rmi[2] * rmi[2]).sqrt();
+        let rmf_norm = (rmf[0] * rmf[0] + rmf[1] * rmf[1] + rmf[2] * rmf[2]).sqrt();
+        let norm_sum = rmi_norm + rmf_norm;
+        let c = ((2.0 * length * norm_sum) / (rmi_norm * rmf_norm))
+            * (1.0 / (norm_sum * norm_sum - length * length));
+        let v = [rmi[0] * c, rmi[1] * c, rmi[2] * c];
+        b[0] += u[1] * v[2] - u[2] * v[1];
+        b[1] -= u[0] * v[2] - u[2] * v[0];
+        b[2] += u[0] * v[1] - u[1] * v[0];
+    }
+}
 
-    let mut k2 = compute_magnetic_field(&p1, coils, displacements, e_roof);
-    let k2norm = k2.get_norm();
-    k2.x = (k2.x / k2norm) * step_size;
-    k2.y = (k2.y / k2norm) * step_size;
-    k2.z = (k2.z / k2norm) * step_size;
-    let p2 = Point {
-        x: k2.x / 2.0 + particle.x,
-        y: k2.y / 2.0 + particle.y,
-        z: k2.z / 2.0 + particle.z,
+/// `stored_coils_field` with compensated sums
+fn compensated_field(particle: &Point, coils: &CoilSet) -> Point {
+    let mut sums = [CompensatedSum::default(); 3];
+    for coil in 0..coils.len() {
+        let multiplier = coils.field_multiplier(coil);
+        for j in coils.segments(coil) {
+            let field = segment_field(particle, coils, j, multiplier);
+            sums[0].add(field.x);
+            sums[1].add(field.y);
+            sums[2].add(field.z);
+        }
+    }
+    Point {
+        x: sums[0].value(),
+        y: sums[1].value(),
+        z: sums[2].value(),
+    }
+}
+
+/// Field at `particle` of the segment starting at point `j`
+#[inline(always)]
+fn segment_field(particle: &Point, coils: &CoilSet, j: usize, multiplier: f64) -> Point {
+    let rmi_a = Point {
+        x: particle.x - coils.x[j],
+        y: particle.y - coils.y[j],
+        z: particle.z - coils.z[j],
+    };
+    let rmf_a = Point {
+        x: particle.x - coils.x[j + 1],
+        y: particle.y - coils.y[j + 1],
+        z: particle.z - coils.z[j + 1],
     };
+    let u = Point {
+        x: multiplier * coils.e_x[j],
+        y: multiplier * coils.e_y[j],
+        z: multiplier * coils.e_z[j],
+    };
+    let displacement_norm = coils.lengths[j];
+    let rmi_a_norm = rmi_a.get_norm();
+    let rmf_a_norm = rmf_a.get_norm();
+    let c = ((2.0 * displacement_norm * (rmi_a_norm + rmf_a_norm)) / (rmi_a_norm * rmf_a_norm))
+        * (1.0 / ((rmi_a_norm + rmf_a_norm).powi(2) - displacement_norm.powi(2)));
 
-    let mut k3 = compute_magnetic_field(&p2, coils, displacements, e_roof);
-    let k3norm = k3.get_norm();
-    k3.x = (k3.x / k3norm) * step_size;
-    k3.y = (k3.y / k3norm) * step_size;
-    k3.z = (k3.z / k3norm) * step_size;
-    let p3 = Point {
-        x: k3.x + particle.x,
-        y: k3.y + particle.y,
-        z: k3.z + particle.z,
+    // Compute vector v
+    let v = Point {
+        x: rmi_a.x * c,
+        y: rmi_a.y * c,
+        z: rmi_a.z * c,
     };
-    let mut k4 = compute_magnetic_field(&p3, coils, displacements, e_roof);
-    let k4norm = k4.get_norm();
-    k4.x = (k4.x / k4norm) * step_size;
-    k4.y = (k4.y / k4norm) * step_size;
-    k4.z = (k4.z / k4norm) * step_size;
-    let mut result = Point {
-        x: particle.x + (k1.x + 2.0 * k2.x + 2.0 * k3.x + k4.x) / 6.0,
-        y: particle.y + (k1.y + 2.0 * k2.y + 2.0 * k3.y + k4.y) / 6.0,
-        z: particle.z + (k1.z + 2.0 * k2.z + 2.0 * k3.z + k4.z) / 6.0,
+
+    // Cross product of u and v
+    Point {
+        x: (u.y * v.z) - (u.z * v.y),
+        y: -((u.x * v.z) - (u.z * v.x)),
+        z: (u.x * v.y) - (u.y * v.x),
+    }
+}
+
+/// Spacing of the finite difference stencil used for field derivatives
+pub const JACOBIAN_STEP: f64 = 1e-5;
+
+/// Jacobian of the magnetic field, `jacobian[i][j]` is ∂B_i/∂x_j, from fourth
+/// order central differences with spacing `JACOBIAN_STEP`
+pub fn compute_field_jacobian(particle: &Point, coils: &CoilSet) -> [[f64; 3]; 3] {
+    let field_at = |axis: usize, offset: f64| {
+        let mut point = *particle;
+        match axis {
+            0 => point.x += offset,
+            1 => point.y += offset,
+            _ => point.z += offset,
+        }
+        let b = compute_magnetic_field(&point, coils);
+        [b.x, b.y, b.z]
     };
+    let h = JACOBIAN_STEP;
+    let mut jacobian = [[0.0; 3]; 3];
+    for axis in 0..3 {
+        let (plus2, plus1) = (field_at(axis, 2.0 * h), field_at(axis, h));
+        let (minus1, minus2) = (field_at(axis, -h), field_at(axis, -2.0 * h));
+        for (component, row) in jacobian.iter_mut().enumerate() {
+            row[axis] = (-plus2[component] + 8.0 * plus1[component] - 8.0 * minus1[component]
+                + minus2[component])
+                / (12.0 * h);
+        }
+    }
+    jacobian
+}
+
+/// One RK4 step, `None` for particles that leave the loss boundary
+pub fn simulate_step(particle: &Point, coils: &CoilSet, step_size: f64) -> Option<Point> {
+    confine(Rk4::step(particle, coils, step_size), coils)
+}
+
+/// `point` if it is inside the loss boundary of the coils, `None` otherwise
+pub fn confine(point: Point, coils: &CoilSet) -> Option<Point> {
+    Some(point).filter(|point| coils.boundary.contains(point, &coils.physics))
+}
 
+/// Distance of a point to the circle of major radius in the z = 0 plane
+pub fn distance_to_axis(point: &Point, physics: &PhysicsParams) -> f64 {
     let p = Point {
-        x: result.x,
-        y: result.y,
+        x: point.x,
+        y: point.y,
         z: 0.0,
     };
     let origin = Point {
-        x: MAJOR_RADIUS * p.x / p.get_norm(),
-        y: MAJOR_RADIUS * p.y / p.get_norm(),
+        x: physics.major_radius * p.x / p.get_norm(),
+        y: physics.major_radius * p.y / p.get_norm(),
         z: 0.0,
     };
+    point.get_distance(&origin)
+}
 
-    let distance = result.get_distance(&origin);
-    if distance > MINOR_RADIUS {
-        result.x = MINOR_RADIUS;
-        result.y = MINOR_RADIUS;
-        result.z = MINOR_RADIUS;
-    }
+/// Steps of a run, how often active particles are rebalanced over ranks
+/// and the collisions between steps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Schedule {
+    /// Step the particles are at, later than 0 when resuming a run
+    pub first_step: u32,
+    pub total_steps: u32,
+    /// Lend active particles to ranks with fewer of them at least this often,
+    /// `None` keeps every particle on the rank it was scattered to
+    pub rebalance_every: Option<u32>,
+    /// Log progress on rank 0 every this many steps
+    pub progress_every: Option<u32>,
+    /// Scattering of the velocity of orbit particles after every push
+    pub collisions: Option<Collisions>,
+    /// Stop early with a snapshot of the step reached when a shutdown is
+    /// requested on any rank, `None` runs every step
+    pub shutdown: Option<Shutdown>,
+}
 
-    result
+impl Schedule {
+    pub fn new(total_steps: u32) -> Self {
+        Schedule {
+            first_step: 0,
+            total_steps,
+            rebalance_every: None,
+            progress_every: None,
+            collisions: None,
+            shutdown: None,
+        }
+    }
 }
 
+/// Integrates every particle over the steps of `schedule` from its
+/// integration state in `states`, writing snapshots through `writer`, and
+/// returns the substeps taken with the time this rank spent in each phase
+/// of the step loop, up to the step the loop ended at. A requested
+/// shutdown ends it early on every rank after writing the step all ranks
+/// agreed to stop at, checked with a collective every
+/// `Shutdown::check_every` steps. Loans of rebalancing are settled before
+/// every written step, so curvature decimation would miss the turns of
+/// lent particles. Fails as soon as a snapshot cannot be written, leaving
+/// the other ranks to be aborted by the caller.
 pub fn simulate_particles(
     particles: &mut [Point],
-    total_steps: u32,
-    step_size: f64,
-    coils: &Vec<Vec<Point>>,
-    displacements: &Vec<Vec<Point>>,
-    e_roof: &Vec<Vec<Point>>,
-    output_dir: &Path,
-    write_frequency: u32,
-    rank: i32,
-) {
+    states: &mut [IntegrationState],
+    schedule: Schedule,
+    integrator: &dyn Integrator,
+    coils: &CoilSet,
+    writer: &mut SnapshotWriter,
+    comm: &impl Collectives,
+) -> Result<(StepCounts, PhaseTimes), Box<dyn Error>> {
     let length = particles.len();
-    let divergent_particle = Point {
-        x: MINOR_RADIUS,
-        y: MINOR_RADIUS,
-        z: MINOR_RADIUS,
+    let total_steps = schedule.total_steps;
+    let mut directions = vec![Point::default(); length];
+    let mut loans: Option<Loans> = None;
+    let started = Instant::now();
+    let fields = writer.fields;
+    let tracks_drift = writer.drift.is_some();
+    let collisions = schedule.collisions.as_ref();
+    let mut times = PhaseTimes {
+        step: schedule.first_step,
+        ..PhaseTimes::default()
     };
+    let mut total_times = times;
 
     debug!("Total particles: {}", length);
 
-    match write_points_to_file(&particles, output_dir, 0, rank) {
-        Ok(_) => debug!("Wrote points to {:?}", output_dir),
-        Err(error) => panic!("Error writing points to file. {}", error),
-    };
-    for step in 1..total_steps + 1 {
-        particles.par_iter_mut().for_each(|particle| {
-            if *particle != divergent_particle {
-                *particle = simulate_step(particle, coils, displacements, e_roof, step_size);
+    // Restarted and resumed runs pass in the losses of the run they start
+    // from, their other particles may still be outside a changed boundary
+    for (particle, state) in particles.iter().zip(states.iter_mut()) {
+        if state.status.is_active() && confine(*particle, coils).is_none() {
+            state.status = ParticleState::Lost {
+                step: schedule.first_step,
+            };
+        }
+    }
+    if fields {
+        particles
+            .par_iter()
+            .zip(states.par_iter_mut())
+            .filter(|(_, state)| state.status.is_active())
+            .for_each(|(particle, state)| {
+                state.field = Some(compute_magnetic_field(particle, coils));
+            });
+    }
+    if schedule.first_step == 0 {
+        writer
+            .write(
+                &snapshot_points(particles, states),
+                &lost_flags(states),
+                velocities(states).as_deref(),
+                magnetic_fields(states, writer).as_deref(),
+                0,
+                comm,
+            )
+            .map_err(|error| format!("writing the snapshot of step 0: {}", error))?;
+        debug!("Wrote points to {:?}", writer.output_dir);
+    }
+    for step in schedule.first_step + 1..total_steps + 1 {
+        let mut timer = Instant::now();
+        if schedule.rebalance_every.is_some() && loans.is_none() {
+            let lent = Loans::lend(particles, states, comm);
+            trace!(
+                "Step {}: lent {} particles, borrowed {}",
+                step,
+                lent.lent.iter().filter(|&&lent| lent).count(),
+                lent.borrowed.len()
+            );
+            loans = Some(lent);
+        }
+        times.balance += lap(&mut timer);
+        if let Some(loans) = &mut loans {
+            integrator
+                .prepare(&loans.borrowed, coils, &mut loans.borrowed_states, &[])
+                .map_err(|error| format!("preparing step {}: {}", step, error))?;
+        }
+        let lent = loans.as_ref().map_or(&[][..], |loans| &loans.lent);
+        integrator
+            .prepare(particles, coils, states, lent)
+            .map_err(|error| format!("preparing step {}: {}", step, error))?;
+        let drift_of = |outcome: StepOutcome, particle: &Point, state: &IntegrationState| {
+            if tracks_drift {
+                outcome.drift(particle, state, integrator, coils)
+            } else {
+                DriftSums::default()
             }
+        };
+        let mut drift = particles
+            .par_iter_mut()
+            .zip(directions.par_iter_mut())
+            .zip(states.par_iter_mut())
+            .enumerate()
+            .filter(|(index, _)| !lent.get(*index).copied().unwrap_or(false))
+            .map(|(_, ((particle, direction), state))| {
+                let outcome =
+                    advance_particle(particle, state, integrator, coils, step, fields, collisions);
+                *direction = outcome.direction();
+                drift_of(outcome, particle, state)
+            })
+            .reduce(DriftSums::default, DriftSums::merge);
+        if let Some(loans) = &mut loans {
+            let borrowed_drift = loans
+                .borrowed
+                .par_iter_mut()
+                .zip(loans.borrowed_states.par_iter_mut())
+                .map(|(particle, state)| {
+                    let outcome = advance_particle(
+                        particle, state, integrator, coils, step, fields, collisions,
+                    );
+                    drift_of(outcome, particle, state)
+                })
+                .reduce(DriftSums::default, DriftSums::merge);
+            drift = drift.merge(borrowed_drift);
+        }
+        if let Some(log) = &mut writer.punctures {
+            // Each rank records the particles it advanced, borrowed ones included
+            let lent = loans.as_ref().map_or(&[][..], |loans| &loans.lent);
+            let borrowed = loans
+                .as_ref()
+                .map_or(&[][..], |loans| &loans.borrowed_states);
+            let advanced = states
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| !lent.get(*index).copied().unwrap_or(false))
+                .map(|(_, state)| state);
+            log.record(step, advanced.chain(borrowed));
+        }
+        times.integrate += lap(&mut timer);
+        let stopping = schedule.shutdown.is_some_and(|shutdown| {
+            shutdown.is_checked_at(step) && comm.any(shutdown.is_requested())
         });
-        if step % write_frequency == 0 {
-            match write_points_to_file(&particles, output_dir, step, rank) {
-                Ok(_) => debug!("Wrote points to {:?}", output_dir),
-                Err(error) => panic!("Error writing points to file. {}", error),
+        let due = writer.is_due(step, total_steps, &directions, comm) || stopping;
+        times.output += lap(&mut timer);
+        let rebalance = schedule
+            .rebalance_every
+            .is_some_and(|every| step.is_multiple_of(every));
+        let settle = due || rebalance || step == total_steps;
+        if let Some(loans) = loans.take_if(|_| settle) {
+            loans.settle(particles, states, comm);
+        }
+        times.balance += lap(&mut timer);
+        if due {
+            writer
+                .write(
+                    &snapshot_points(particles, states),
+                    &lost_flags(states),
+                    velocities(states).as_deref(),
+                    magnetic_fields(states, writer).as_deref(),
+                    step,
+                    comm,
+                )
+                .map_err(|error| format!("writing the snapshot of step {}: {}", step, error))?;
+            debug!("Wrote points to {:?}", writer.output_dir);
+        }
+        let flushes = due || step == total_steps;
+        if let Some(log) = writer.punctures.as_mut().filter(|_| flushes) {
+            log.flush()
+                .map_err(|error| format!("writing the punctures of step {}: {}", step, error))?;
+        }
+        if let Some(log) = &mut writer.drift {
+            log.record(step, drift);
+            if due || step == total_steps {
+                log.flush(comm).map_err(|error| {
+                    format!("writing the drift diagnostics of step {}: {}", step, error)
+                })?;
+            }
+        }
+        times.output += lap(&mut timer);
+        if due || step == total_steps {
+            times.steps = step - times.step;
+            times.step = step;
+            log_phase_times(&times);
+            total_times = total_times.merge(times);
+            times = PhaseTimes {
+                step,
+                ..PhaseTimes::default()
             };
         }
+        if schedule
+            .progress_every
+            .is_some_and(|every| step.is_multiple_of(every))
+        {
+            let active = comm.sum_count(active_count(states, loans.as_ref()));
+            if let Some(active) = active {
+                report_progress(step, &schedule, active, started.elapsed().as_secs_f64());
+            }
+        }
+        if stopping && step < total_steps {
+            if comm.local_rank() == 0 {
+                info!("Shutdown requested, stopped at step {}", step);
+            }
+            break;
+        }
     }
+    Ok((total_step_counts(states), total_times))
 }
 
-pub fn read_coil_data_directory(path: &Path) -> Result<Vec<Vec<Point>>, Box<dyn Error>> {
-    let mut coil_files = fs::read_dir(path)
-        .expect("Error reading file")
-        .map(|res| res.map(|e| e.path()))
-        .collect::<Result<Vec<_>, io::Error>>()
-        .unwrap();
-    coil_files.sort();
+/// Seconds since `timer` was started or last lapped, restarting it
+fn lap(timer: &mut Instant) -> f64 {
+    let now = Instant::now();
+    let seconds = now.duration_since(*timer).as_secs_f64();
+    *timer = now;
+    seconds
+}
+
+/// Substeps taken over all of `states`
+pub fn total_step_counts(states: &[IntegrationState]) -> StepCounts {
+    states
+        .iter()
+        .fold(StepCounts::default(), |total, state| StepCounts {
+            accepted: total.accepted + state.counts.accepted,
+            rejected: total.rejected + state.counts.rejected,
+        })
+}
 
-    let mut coils = Vec::<Vec<Point>>::new();
+/// Active particles advanced by this rank, counting borrowed ones instead
+/// of lent ones
+fn active_count(states: &[IntegrationState], loans: Option<&Loans>) -> usize {
+    let lent = loans.map_or(&[][..], |loans| &loans.lent);
+    let borrowed = loans.map_or(&[][..], |loans| &loans.borrowed_states);
+    states
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !lent.get(*index).copied().unwrap_or(false))
+        .map(|(_, state)| state)
+        .chain(borrowed)
+        .filter(|state| state.status.is_active())
+        .count()
+}
+
+fn report_progress(step: u32, schedule: &Schedule, active: usize, elapsed: f64) {
+    let done = step - schedule.first_step;
+    let steps_per_second = done as f64 / elapsed;
+    let remaining = (schedule.total_steps - step) as f64 / steps_per_second;
+    info!(
+        "Step {}/{} ({:.1}%), {} active particles, {:.2} steps/s, ETA {}",
+        step,
+        schedule.total_steps,
+        100.0 * step as f64 / schedule.total_steps as f64,
+        active,
+        steps_per_second,
+        format_duration(remaining)
+    );
+}
+
+/// What one step did to a particle
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StepOutcome {
+    /// The particle was lost before the step
+    Inactive,
+    /// The particle moved by this displacement and stayed confined
+    Moved(Point),
+    /// The particle left the loss boundary, at a NaN or infinite position
+    /// if `non_finite`
+    Lost { non_finite: bool },
+}
 
-    for coil_file in coil_files {
-        let data = read_from_file(&coil_file.as_path(), usize::MAX)?;
-        coils.push(data);
+impl StepOutcome {
+    /// Direction the particle moved in, zero unless it stayed confined
+    fn direction(&self) -> Point {
+        match self {
+            StepOutcome::Moved(displacement) => *displacement,
+            _ => Point::default(),
+        }
     }
-    debug!("Read {} coils", coils.len());
-    return Ok(coils);
+
+    /// Drift of the step of a particle that is now at `particle`
+    fn drift(
+        &self,
+        particle: &Point,
+        state: &IntegrationState,
+        integrator: &dyn Integrator,
+        coils: &CoilSet,
+    ) -> DriftSums {
+        match self {
+            StepOutcome::Moved(displacement) => {
+                let from = particle.get_displacement(displacement);
+                // Orbit steps are durations, the Lorentz force keeps the speed
+                let speed = state.velocity.map_or(1.0, |velocity| velocity.get_norm());
+                DriftSums::moved(
+                    &from,
+                    particle,
+                    integrator.step_size() * speed,
+                    &coils.physics,
+                )
+            }
+            StepOutcome::Lost { non_finite: true } => DriftSums::non_finite(),
+            _ => DriftSums::default(),
+        }
+    }
+}
+
+/// Advances an active particle by one step. Particles leaving the loss
+/// boundary are marked lost in `step` and keep their last confined position.
+fn advance_particle(
+    particle: &mut Point,
+    state: &mut IntegrationState,
+    integrator: &dyn Integrator,
+    coils: &CoilSet,
+    step: u32,
+    fields: bool,
+    collisions: Option<&Collisions>,
+) -> StepOutcome {
+    state.puncture = None;
+    if !state.status.is_active() {
+        return StepOutcome::Inactive;
+    }
+    let moved = integrator.advance(particle, coils, state);
+    let Some(next) = moved.and_then(|next| confine(next, coils)) else {
+        state.status = ParticleState::Lost { step };
+        state.field = None;
+        let non_finite = moved
+            .is_some_and(|next| !(next.x.is_finite() && next.y.is_finite() && next.z.is_finite()));
+        return StepOutcome::Lost { non_finite };
+    };
+    let direction = next.get_displacement(particle);
+    state.length += direction.get_norm();
+    state
+        .angles
+        .advance(particle, &next, coils.physics.major_radius);
+    state.puncture = poincare_crossing(particle, &next);
+    *particle = next;
+    if fields {
+        // Written with this step and reused by the next one
+        state.field = Some(compute_magnetic_field(particle, coils));
+    }
+    if let (Some(collisions), Some(velocity)) = (collisions, state.velocity) {
+        // Kept for the next push, which would compute it again otherwise
+        let b = *state
+            .field
+            .get_or_insert_with(|| compute_magnetic_field(particle, coils));
+        state.velocity =
+            Some(collisions.scatter(&velocity, &b, integrator.step_size(), state.index, step));
+    }
+    StepOutcome::Moved(direction)
+}
+
+/// Positions as written to snapshots, where lost particles are still
+/// marked with `DIVERGENT_PARTICLE`
+fn snapshot_points(particles: &[Point], states: &[IntegrationState]) -> Vec<Point> {
+    particles
+        .iter()
+        .zip(states)
+        .map(|(particle, state)| {
+            if state.status.is_active() {
+                *particle
+            } else {
+                DIVERGENT_PARTICLE
+            }
+        })
+        .collect()
+}
+
+/// Whether each particle was lost, which snapshots do not record
+fn lost_flags(states: &[IntegrationState]) -> Vec<bool> {
+    states
+        .iter()
+        .map(|state| !state.status.is_active())
+        .collect()
+}
+
+/// Velocities of the particles, `None` unless they are pushed along full orbits
+fn velocities(states: &[IntegrationState]) -> Option<Vec<Point>> {
+    states.first()?.velocity?;
+    states.iter().map(|state| state.velocity).collect()
+}
+
+/// Magnetic field at the particles, zero at lost ones, `None` unless the
+/// writer writes fields
+fn magnetic_fields(states: &[IntegrationState], writer: &SnapshotWriter) -> Option<Vec<Point>> {
+    writer.fields.then(|| {
+        states
+            .iter()
+            .map(|state| state.field.unwrap_or_default())
+            .collect()
+    })
+}
+
+pub use crate::coil_format::list_coil_files;
+
+pub fn read_coil_data_directory(path: &Path) -> Result<Vec<Vec<Point>>, Box<dyn Error>> {
+    read_coils(path, CoilFormat::Auto)
 }
 
 pub fn compute_displacements(coil: &[Point]) -> Vec<Point> {
@@ -208,3 +1013,205 @@ pub fn compute_all_e_roof(all_displacements: &Vec<Vec<Point>>) -> Vec<Vec<Point>
         .map(|disps| compute_e_roof(disps))
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coil_set_keeps_coils_and_segments() {
+        let point = |x: f64, y: f64| Point { x, y, z: 0.0 };
+        let coils = vec![
+            vec![point(0.0, 0.0), point(3.0, 4.0), point(3.0, 5.0)],
+            vec![point(1.0, 1.0), point(1.0, 3.0)],
+        ];
+        let set = CoilSet::new(&coils);
+        assert_eq!(set.len(), 2);
+        assert_eq!(set.coils(), coils);
+        assert_eq!(set.segments(0), 0..2);
+        assert_eq!(set.segments(1), 3..4);
+        assert_eq!(set.lengths[..2], [5.0, 1.0]);
+        assert_eq!((set.e_x[0], set.e_y[0]), (0.6, 0.8));
+        let selected = set.select(&[1]);
+        assert_eq!(selected.coils(), vec![coils[1].clone()]);
+        assert_eq!(selected.lengths[0], 2.0);
+    }
+
+    #[test]
+    fn physics_sets_field_and_confinement() {
+        let coils = CoilSet::new(
+            &read_coil_data_directory(Path::new("tests/test-resources/resources")).unwrap(),
+        );
+        let physics = PhysicsParams {
+            current: 2.0 * coils.physics.current,
+            minor_radius: 0.01,
+            ..coils.physics
+        };
+        let doubled = coils.clone().with_physics(physics);
+        let particle = Point {
+            x: 0.25,
+            y: 0.0,
+            z: 0.0,
+        };
+        let b = compute_magnetic_field(&particle, &coils);
+        let b_doubled = compute_magnetic_field(&particle, &doubled);
+        assert!((b_doubled.get_norm() - 2.0 * b.get_norm()).abs() < 1e-12 * b.get_norm());
+        assert_eq!(confine(particle, &coils), Some(particle));
+        assert_eq!(confine(particle, &doubled), None);
+    }
+
+    #[test]
+    fn background_fields_add_to_the_coils_but_not_to_their_groups() {
+        let coils = CoilSet::new(
+            &read_coil_data_directory(Path::new("tests/test-resources/resources")).unwrap(),
+        );
+        let particle = Point {
+            x: 0.0,
+            y: 0.25,
+            z: 0.01,
+        };
+        let with_background = coils.clone().with_background(vec![
+            BackgroundField::Uniform {
+                bx: 0.0,
+                by: 0.0,
+                bz: 0.01,
+            },
+            BackgroundField::Toroidal { b0: 0.5, r0: 0.25 },
+        ]);
+        let b = compute_magnetic_field(&particle, &coils);
+        let total = compute_magnetic_field(&particle, &with_background);
+        assert!((total.x - (b.x - 0.5)).abs() < 1e-12);
+        assert!((total.y - b.y).abs() < 1e-12);
+        assert!((total.z - (b.z + 0.01)).abs() < 1e-12);
+        let selected = with_background.select(&[0, 1]);
+        assert_eq!(
+            compute_magnetic_field(&particle, &selected),
+            compute_magnetic_field(&particle, &coils.select(&[0, 1]))
+        );
+    }
+
+    #[test]
+    fn coil_currents_scale_and_flip_their_fields() {
+        let coils = CoilSet::new(
+            &read_coil_data_directory(Path::new("tests/test-resources/resources")).unwrap(),
+        );
+        let current = coils.physics.current;
+        let mut currents = vec![current; coils.len()];
+        currents[0] = 3.0 * current;
+        currents[1] = -current;
+        let varied = coils.clone().with_currents(currents);
+        let particle = Point {
+            x: 0.22,
+            y: 0.02,
+            z: 0.01,
+        };
+        let field_of = |set: &CoilSet| compute_magnetic_field(&particle, set);
+        let (b, b_varied) = (field_of(&coils), field_of(&varied));
+        let (b0, b1) = (field_of(&coils.select(&[0])), field_of(&coils.select(&[1])));
+        let expected = Point {
+            x: b.x + 2.0 * b0.x - 2.0 * b1.x,
+            y: b.y + 2.0 * b0.y - 2.0 * b1.y,
+            z: b.z + 2.0 * b0.z - 2.0 * b1.z,
+        };
+        assert!(b_varied.get_distance(&expected) < 1e-12 * b.get_norm());
+        let flipped = field_of(&varied.select(&[1]));
+        assert_eq!((flipped.x, flipped.y, flipped.z), (-b1.x, -b1.y, -b1.z));
+    }
+
+    /// Exact sum of `values` rounded once, by adding them as integer
+    /// multiples of the smallest unit in the last place among them
+    fn exact_sum(values: &[f64]) -> f64 {
+        let parts: Vec<(i128, i32)> = values
+            .iter()
+            .map(|value| {
+                let bits = value.to_bits();
+                let exponent = ((bits >> 52) & 0x7ff) as i32;
+                let hidden = if exponent > 0 { 1 << 52 } else { 0 };
+                let mantissa = ((bits & ((1 << 52) - 1)) | hidden) as i128;
+                let sign = if *value < 0.0 { -1 } else { 1 };
+                (sign * mantissa, exponent.max(1) - 1075)
+            })
+            .collect();
+        let lowest = parts
+            .iter()
+            .map(|(_, exponent)| *exponent)
+            .min()
+            .unwrap_or(0);
+        let total: i128 = parts
+            .iter()
+            .map(|(mantissa, exponent)| mantissa << (exponent - lowest))
+            .sum();
+        total as f64 * 2f64.powi(lowest)
+    }
+
+    #[test]
+    fn compensated_summation_rounds_the_field_sum_once() {
+        let coils = CoilSet::new(
+            &read_coil_data_directory(Path::new("tests/test-resources/resources")).unwrap(),
+        );
+        let (mut naive_error, mut compensated_error) = (0.0, 0.0);
+        for index in 0..20 {
+            let angle = index as f64 * 0.3;
+            let particle = Point {
+                x: 0.24 * angle.cos(),
+                y: 0.24 * angle.sin(),
+                z: 0.01 * (index % 5) as f64,
+            };
+            let mut terms = [Vec::new(), Vec::new(), Vec::new()];
+            let mut naive = Point::default();
+            for coil in 0..coils.len() {
+                let multiplier = coils.field_multiplier(coil);
+                for j in coils.segments(coil) {
+                    let field = segment_field(&particle, &coils, j, multiplier);
+                    terms[0].push(field.x);
+                    terms[1].push(field.y);
+                    terms[2].push(field.z);
+                }
+                add_segment_fields(
+                    &particle,
+                    &coils,
+                    coils.segments(coil),
+                    multiplier,
+                    &mut naive,
+                );
+            }
+            let exact = Point {
+                x: exact_sum(&terms[0]),
+                y: exact_sum(&terms[1]),
+                z: exact_sum(&terms[2]),
+            };
+            let compensated = compensated_field(
+                &particle,
+                &coils.clone().with_summation(Summation::Compensated),
+            );
+            let norm = exact.get_norm();
+            naive_error += naive.get_distance(&exact) / norm;
+            compensated_error += compensated.get_distance(&exact) / norm;
+            assert!(compensated.get_distance(&exact) <= 2.0 * f64::EPSILON * norm);
+        }
+        assert!(compensated_error * 4.0 < naive_error);
+    }
+
+    #[test]
+    fn single_precision_fields_round_the_double_precision_ones() {
+        let coils = CoilSet::new(
+            &read_coil_data_directory(Path::new("tests/test-resources/resources")).unwrap(),
+        );
+        let single = coils.clone().with_precision(Precision::F32);
+        assert_eq!(single.single.x.len(), coils.num_points());
+        for index in 0..20 {
+            let angle = index as f64 * 0.3;
+            let particle = Point {
+                x: 0.24 * angle.cos(),
+                y: 0.24 * angle.sin(),
+                z: 0.01 * (index % 5) as f64,
+            };
+            let double = compute_magnetic_field(&particle, &coils);
+            let rounded = compute_magnetic_field(&particle, &single);
+            assert_ne!(rounded, double);
+            assert!(rounded.get_distance(&double) < 1e-5 * double.get_norm());
+        }
+        assert!(!single.select(&[1, 0]).single.x.is_empty());
+        assert!(coils.select(&[1, 0]).single.x.is_empty());
+    }
+}
@@ -0,0 +1,135 @@
+use crate::point::Point;
+use log::{debug, info, warn};
+use std::{
+    error::Error,
+    io::{self, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    time::Duration,
+};
+
+/// Longest a slow consumer may hold up a step before it is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Particles of one step as streamed, one JSON object per line
+#[derive(Debug, PartialEq, serde::Deserialize)]
+pub struct Batch {
+    pub step: u32,
+    pub positions: Vec<Point>,
+}
+
+#[derive(serde::Serialize)]
+struct BatchRef<'a> {
+    step: u32,
+    positions: &'a [Point],
+}
+
+/// Publishes the particles of every snapshot to the TCP consumers connected
+/// at the time. Consumers may connect and disconnect at any point of a run,
+/// the simulation never waits for one to appear.
+pub struct Publisher {
+    listener: TcpListener,
+    consumers: Vec<TcpStream>,
+}
+
+impl Publisher {
+    /// Listens on `endpoint`, a `host:port` address
+    pub fn bind(endpoint: &str) -> Result<Self, Box<dyn Error>> {
+        let listener = TcpListener::bind(endpoint)?;
+        listener.set_nonblocking(true)?;
+        info!("Streaming particles on {}", listener.local_addr()?);
+        Ok(Publisher {
+            listener,
+            consumers: Vec::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Number of consumers the last batch reached
+    pub fn consumers(&self) -> usize {
+        self.consumers.len()
+    }
+
+    /// Sends the particles of `step` to every consumer, dropping those that
+    /// disconnected or fall behind
+    pub fn publish(&mut self, step: u32, positions: &[Point]) -> Result<(), Box<dyn Error>> {
+        self.accept()?;
+        if self.consumers.is_empty() {
+            return Ok(());
+        }
+        let mut line = serde_json::to_vec(&BatchRef { step, positions })?;
+        line.push(b'\n');
+        self.consumers
+            .retain_mut(|consumer| match consumer.write_all(&line) {
+                Ok(()) => true,
+                Err(err) => {
+                    warn!(
+                        "Dropping stream consumer {:?}: {}",
+                        consumer.peer_addr(),
+                        err
+                    );
+                    false
+                }
+            });
+        Ok(())
+    }
+
+    fn accept(&mut self) -> io::Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((consumer, address)) => {
+                    consumer.set_nonblocking(false)?;
+                    consumer.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                    debug!("Stream consumer connected from {}", address);
+                    self.consumers.push(consumer);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn consumers_receive_every_batch_after_connecting() {
+        let mut publisher = Publisher::bind("127.0.0.1:0").unwrap();
+        let positions = vec![
+            Point {
+                x: 0.2,
+                y: 0.0,
+                z: 0.01,
+            },
+            Point {
+                x: 0.0,
+                y: -0.25,
+                z: 0.0,
+            },
+        ];
+        publisher.publish(0, &positions).unwrap();
+        assert_eq!(publisher.consumers(), 0);
+
+        let consumer = TcpStream::connect(publisher.local_addr().unwrap()).unwrap();
+        publisher.publish(10, &positions).unwrap();
+        publisher.publish(20, &positions[1..]).unwrap();
+        assert_eq!(publisher.consumers(), 1);
+        let mut lines = BufReader::new(consumer).lines();
+        let batch: Batch = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(
+            batch,
+            Batch {
+                step: 10,
+                positions: positions.clone()
+            }
+        );
+        let batch: Batch = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(batch.step, 20);
+        assert_eq!(batch.positions, positions[1..]);
+    }
+}
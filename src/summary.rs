@@ -0,0 +1,272 @@
+use crate::{
+    constants::PhysicsParams, integrator::StepCounts, particle::ParticleState, point::Point,
+    simulation::distance_to_axis,
+};
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+/// Name of the end-of-run summary written into every output directory
+pub const SUMMARY_FILE: &str = "summary.json";
+
+/// Bins of the final minor radius histogram
+pub const RADIUS_BINS: usize = 20;
+
+/// Bins of the connection length histogram
+pub const LENGTH_BINS: usize = 20;
+
+/// Fixed-range histogram that can be summed across ranks and runs
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Histogram {
+    pub min: f64,
+    pub max: f64,
+    pub counts: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn new(min: f64, max: f64, bins: usize) -> Self {
+        Histogram {
+            min,
+            max,
+            counts: vec![0; bins],
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        let bins = self.counts.len();
+        let bin = ((value - self.min) / (self.max - self.min) * bins as f64).floor();
+        if bin >= 0.0 {
+            self.counts[(bin as usize).min(bins - 1)] += 1;
+        }
+    }
+
+    /// Adds the counts of a histogram with the same range and bins
+    pub fn merge(&mut self, other: &Histogram) -> Result<(), String> {
+        if self.min != other.min || self.max != other.max || self.counts.len() != other.counts.len()
+        {
+            return Err(format!(
+                "cannot merge histograms over [{}, {}] with {} bins and [{}, {}] with {} bins",
+                self.min,
+                self.max,
+                self.counts.len(),
+                other.min,
+                other.max,
+                other.counts.len()
+            ));
+        }
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        Ok(())
+    }
+}
+
+/// Mean, sample standard deviation and normal approximation 95% confidence
+/// interval of the mean of a set of samples
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct Statistic {
+    pub samples: usize,
+    pub mean: f64,
+    pub std_dev: f64,
+    pub confidence_95: [f64; 2],
+}
+
+impl Statistic {
+    pub fn from_samples(samples: &[f64]) -> Self {
+        let count = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / count;
+        let std_dev = if samples.len() > 1 {
+            (samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (count - 1.0)).sqrt()
+        } else {
+            0.0
+        };
+        let half_width = 1.96 * std_dev / count.sqrt();
+        Statistic {
+            samples: samples.len(),
+            mean,
+            std_dev,
+            confidence_95: [mean - half_width, mean + half_width],
+        }
+    }
+}
+
+/// Outcome of a finished run, as recorded in `summary.json`
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RunSummary {
+    pub num_particles: u64,
+    pub lost: u64,
+    pub loss_fraction: f64,
+    /// Integration substeps accepted and rejected over all particles
+    #[serde(default)]
+    pub accepted_steps: u64,
+    #[serde(default)]
+    pub rejected_steps: u64,
+    /// Wall time of the integration in seconds
+    pub simulation_time: f64,
+    /// Distance of the confined particles to the magnetic axis at the end
+    pub radius_histogram: Histogram,
+    /// Arc length the lost particles travelled before leaving the minor
+    /// radius, up to the longest length of any particle
+    #[serde(default)]
+    pub connection_length_histogram: Option<Histogram>,
+}
+
+impl RunSummary {
+    /// Lost particles and radius histogram of the particles of one rank, to
+    /// be summed over all ranks through `counts` and `with_counts`
+    pub fn local(
+        particles: &[Point],
+        states: &[ParticleState],
+        physics: &PhysicsParams,
+        steps: StepCounts,
+        simulation_time: f64,
+    ) -> Self {
+        let mut radius_histogram = Histogram::new(0.0, physics.minor_radius, RADIUS_BINS);
+        let mut lost = 0;
+        for (particle, state) in particles.iter().zip(states) {
+            if state.is_active() {
+                radius_histogram.add(distance_to_axis(particle, physics));
+            } else {
+                lost += 1;
+            }
+        }
+        RunSummary {
+            num_particles: particles.len() as u64,
+            lost,
+            loss_fraction: lost as f64 / particles.len().max(1) as f64,
+            accepted_steps: steps.accepted,
+            rejected_steps: steps.rejected,
+            simulation_time,
+            radius_histogram,
+            connection_length_histogram: None,
+        }
+    }
+
+    /// Adds the histogram of the connection lengths of the lost particles
+    /// over `[0, max_length]`, the same range on every rank
+    pub fn with_connection_lengths(
+        self,
+        states: &[ParticleState],
+        lengths: &[f64],
+        max_length: f64,
+    ) -> Self {
+        let mut histogram = Histogram::new(0.0, max_length, LENGTH_BINS);
+        for (state, length) in states.iter().zip(lengths) {
+            if !state.is_active() {
+                histogram.add(*length);
+            }
+        }
+        RunSummary {
+            connection_length_histogram: Some(histogram),
+            ..self
+        }
+    }
+
+    /// Particle, loss, step and histogram counts in one buffer for a sum reduction
+    pub fn counts(&self) -> Vec<u64> {
+        [
+            self.num_particles,
+            self.lost,
+            self.accepted_steps,
+            self.rejected_steps,
+        ]
+        .into_iter()
+        .chain(self.radius_histogram.counts.iter().copied())
+        .chain(
+            self.connection_length_histogram
+                .iter()
+                .flat_map(|histogram| histogram.counts.iter().copied()),
+        )
+        .collect()
+    }
+
+    /// Replaces the counts with reduced ones laid out as by `counts`
+    pub fn with_counts(self, counts: &[u64]) -> Self {
+        let (num_particles, lost) = (counts[0], counts[1]);
+        let (radius_counts, length_counts) =
+            counts[4..].split_at(self.radius_histogram.counts.len());
+        RunSummary {
+            num_particles,
+            lost,
+            loss_fraction: lost as f64 / num_particles.max(1) as f64,
+            accepted_steps: counts[2],
+            rejected_steps: counts[3],
+            radius_histogram: Histogram {
+                counts: radius_counts.to_vec(),
+                ..self.radius_histogram
+            },
+            connection_length_histogram: self.connection_length_histogram.map(|histogram| {
+                Histogram {
+                    counts: length_counts.to_vec(),
+                    ..histogram
+                }
+            }),
+            ..self
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = if path.is_dir() {
+            path.join(SUMMARY_FILE)
+        } else {
+            path.to_path_buf()
+        };
+        let reader = BufReader::new(File::open(&path)?);
+        let summary = serde_json::from_reader(reader)
+            .map_err(|err| format!("Error parsing {}: {}", path.display(), err))?;
+        Ok(summary)
+    }
+
+    pub fn write(&self, output_dir: &Path) -> Result<(), Box<dyn Error>> {
+        let writer = BufWriter::new(File::create(output_dir.join(SUMMARY_FILE))?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_merge_only_with_matching_bins() {
+        let mut left = Histogram::new(0.0, 1.0, 4);
+        left.add(0.1);
+        left.add(1.0);
+        let mut right = Histogram::new(0.0, 1.0, 4);
+        right.add(0.3);
+        left.merge(&right).unwrap();
+        assert_eq!(left.counts, vec![1, 1, 0, 1]);
+        assert!(left.merge(&Histogram::new(0.0, 2.0, 4)).is_err());
+    }
+
+    #[test]
+    fn connection_lengths_count_lost_particles_across_ranks() {
+        let particles = [Point::default(); 3];
+        let states = [
+            ParticleState::Active,
+            ParticleState::Lost { step: 2 },
+            ParticleState::Lost { step: 5 },
+        ];
+        let lengths = [4.0, 0.5, 3.9];
+        let local = RunSummary::local(
+            &particles,
+            &states,
+            &PhysicsParams::default(),
+            StepCounts::default(),
+            1.0,
+        )
+        .with_connection_lengths(&states, &lengths, 4.0);
+        let counts: Vec<u64> = local.counts().iter().map(|count| count * 2).collect();
+        let total = local.with_counts(&counts);
+        let histogram = total.connection_length_histogram.unwrap();
+        assert_eq!(total.lost, 4);
+        assert_eq!(histogram.counts.iter().sum::<u64>(), 4);
+        assert_eq!(histogram.counts[2], 2);
+        assert_eq!(histogram.counts[LENGTH_BINS - 1], 2);
+        assert_eq!(total.radius_histogram.counts.len(), RADIUS_BINS);
+    }
+}
@@ -0,0 +1,560 @@
+use crate::{
+    boundary::LossBoundary,
+    coil_format::{CoilFormat, read_coils},
+    coils::fold_field_periods,
+    collectives::{Collectives, SingleProcess},
+    collisions::Collisions,
+    constants::PhysicsParams,
+    diagnostics::SweptAngles,
+    field_source::BackgroundField,
+    integrator::{IntegrationState, Integrator, IntegratorKind, StepCounts, Tolerances},
+    logging::PhaseTimes,
+    output::{Decimation, SnapshotWriter, TextFormat},
+    particle::{OrbitSettings, ParticleState},
+    point::Point,
+    shutdown::Shutdown,
+    simulation::{
+        Backend, CoilSet, Precision, Schedule, Summation, compute_magnetic_field,
+        simulate_particles, total_step_counts,
+    },
+    summary::RunSummary,
+};
+use log::warn;
+use std::{error::Error, path::Path, time::Instant};
+
+/// Configures a `Simulation`: coils and their physics, the integrator and
+/// the starting particles, optionally a writer for snapshots
+pub struct SimulationBuilder {
+    coils: Vec<Vec<Point>>,
+    currents: Vec<f64>,
+    field_periods: usize,
+    summation: Summation,
+    precision: Precision,
+    physics: PhysicsParams,
+    boundary: LossBoundary,
+    background: Vec<BackgroundField>,
+    kind: IntegratorKind,
+    step_size: f64,
+    tolerances: Tolerances,
+    orbit: OrbitSettings,
+    backend: Backend,
+    particles: Vec<Point>,
+    velocities: Option<Vec<Point>>,
+    statuses: Option<Vec<ParticleState>>,
+    lengths: Option<Vec<f64>>,
+    angles: Option<Vec<SweptAngles>>,
+    writer: Option<SnapshotWriter>,
+    first_step: u32,
+    first_index: usize,
+    rebalance_every: Option<u32>,
+    progress_every: Option<u32>,
+    shutdown: Option<Shutdown>,
+}
+
+impl Default for SimulationBuilder {
+    fn default() -> Self {
+        SimulationBuilder {
+            coils: Vec::new(),
+            currents: Vec::new(),
+            field_periods: 1,
+            summation: Summation::Naive,
+            precision: Precision::F64,
+            physics: PhysicsParams::default(),
+            boundary: LossBoundary::Torus,
+            background: Vec::new(),
+            kind: IntegratorKind::Rk4,
+            step_size: 0.001,
+            tolerances: Tolerances {
+                absolute: 1e-9,
+                relative: 1e-6,
+            },
+            orbit: OrbitSettings::default(),
+            backend: Backend::Cpu,
+            particles: Vec::new(),
+            velocities: None,
+            statuses: None,
+            lengths: None,
+            angles: None,
+            writer: None,
+            first_step: 0,
+            first_index: 0,
+            rebalance_every: None,
+            progress_every: None,
+            shutdown: None,
+        }
+    }
+}
+
+impl SimulationBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn coils(self, coils: Vec<Vec<Point>>) -> Self {
+        SimulationBuilder { coils, ..self }
+    }
+
+    /// Reads the coils from a directory or coil file in `format`
+    pub fn load_coils(self, path: &Path, format: CoilFormat) -> Result<Self, Box<dyn Error>> {
+        Ok(self.coils(read_coils(path, format)?))
+    }
+
+    /// Current of each coil, by default every coil carries the current of
+    /// the physics parameters
+    pub fn currents(self, currents: Vec<f64>) -> Self {
+        SimulationBuilder { currents, ..self }
+    }
+
+    /// Keeps only the coils of the first of this many field periods and
+    /// computes the field of the others by rotation, see `fold_field_periods`
+    pub fn field_periods(self, field_periods: usize) -> Self {
+        SimulationBuilder {
+            field_periods,
+            ..self
+        }
+    }
+
+    pub fn summation(self, summation: Summation) -> Self {
+        SimulationBuilder { summation, ..self }
+    }
+
+    pub fn precision(self, precision: Precision) -> Self {
+        SimulationBuilder { precision, ..self }
+    }
+
+    pub fn physics(self, physics: PhysicsParams) -> Self {
+        SimulationBuilder { physics, ..self }
+    }
+
+    /// Wall particles are lost at, the minor radius torus by default
+    pub fn boundary(self, boundary: LossBoundary) -> Self {
+        SimulationBuilder { boundary, ..self }
+    }
+
+    /// Analytic fields added to that of the coils
+    pub fn background(self, background: Vec<BackgroundField>) -> Self {
+        SimulationBuilder { background, ..self }
+    }
+
+    /// Integration scheme and the length, or for orbit pushers the
+    /// duration in seconds, of one step
+    pub fn integrator(self, kind: IntegratorKind, step_size: f64) -> Self {
+        SimulationBuilder {
+            kind,
+            step_size,
+            ..self
+        }
+    }
+
+    pub fn tolerances(self, tolerances: Tolerances) -> Self {
+        SimulationBuilder { tolerances, ..self }
+    }
+
+    /// Species and initial velocities of orbit pushers, 100 eV protons by default
+    pub fn orbit(self, orbit: OrbitSettings) -> Self {
+        SimulationBuilder { orbit, ..self }
+    }
+
+    /// Device the field is evaluated on, the CPU by default. Runs fall back
+    /// to the CPU, with a warning, when the GPU cannot integrate them.
+    pub fn backend(self, backend: Backend) -> Self {
+        SimulationBuilder { backend, ..self }
+    }
+
+    pub fn add_particles(mut self, particles: &[Point]) -> Self {
+        self.particles.extend_from_slice(particles);
+        self
+    }
+
+    /// Initial velocities of orbit pushers, one per particle, instead of
+    /// those given by the orbit settings
+    pub fn velocities(self, velocities: Vec<Point>) -> Self {
+        SimulationBuilder {
+            velocities: Some(velocities),
+            ..self
+        }
+    }
+
+    /// Whether each particle is active or the step it was lost in, when
+    /// resuming a run that lost some of them, all active by default
+    pub fn statuses(self, statuses: Vec<ParticleState>) -> Self {
+        SimulationBuilder {
+            statuses: Some(statuses),
+            ..self
+        }
+    }
+
+    /// Arc lengths the particles travelled before a resumed run, one per
+    /// particle, 0 by default
+    pub fn lengths(self, lengths: Vec<f64>) -> Self {
+        SimulationBuilder {
+            lengths: Some(lengths),
+            ..self
+        }
+    }
+
+    /// Angles the particles swept before a resumed run, one per particle,
+    /// none by default
+    pub fn angles(self, angles: Vec<SweptAngles>) -> Self {
+        SimulationBuilder {
+            angles: Some(angles),
+            ..self
+        }
+    }
+
+    /// Writes snapshots through `writer`, by default nothing is written
+    pub fn writer(self, writer: SnapshotWriter) -> Self {
+        SimulationBuilder {
+            writer: Some(writer),
+            ..self
+        }
+    }
+
+    /// Step the particles are at, later than 0 when resuming a run
+    /// Global index of the first particle when the particles are the share
+    /// of one rank, 0 by default
+    pub fn first_index(self, first_index: usize) -> Self {
+        SimulationBuilder {
+            first_index,
+            ..self
+        }
+    }
+
+    pub fn first_step(self, first_step: u32) -> Self {
+        SimulationBuilder { first_step, ..self }
+    }
+
+    /// Lends active particles to ranks with fewer of them at least this often
+    pub fn rebalance_every(self, rebalance_every: Option<u32>) -> Self {
+        SimulationBuilder {
+            rebalance_every,
+            ..self
+        }
+    }
+
+    /// Logs progress on rank 0 every this many steps
+    pub fn progress_every(self, progress_every: Option<u32>) -> Self {
+        SimulationBuilder {
+            progress_every,
+            ..self
+        }
+    }
+
+    /// Stops runs early when `shutdown` is requested on any rank
+    pub fn shutdown(self, shutdown: Shutdown) -> Self {
+        SimulationBuilder {
+            shutdown: Some(shutdown),
+            ..self
+        }
+    }
+
+    /// Coil set the simulation would integrate in, for evaluating the same
+    /// field without particles. Fails without coils, with currents that are
+    /// not one per coil, with coils that do not repeat over the field
+    /// periods or with compensated sums in single precision.
+    pub fn coil_set(&self) -> Result<CoilSet, Box<dyn Error>> {
+        if self.coils.is_empty() {
+            return Err("a simulation needs coils".into());
+        }
+        if self.precision == Precision::F32 && self.summation == Summation::Compensated {
+            return Err("compensated summation needs double precision".into());
+        }
+        if !self.currents.is_empty() && self.currents.len() != self.coils.len() {
+            return Err(format!(
+                "{} currents for {} coils",
+                self.currents.len(),
+                self.coils.len()
+            )
+            .into());
+        }
+        let coils = if self.field_periods > 1 {
+            let (coils, currents) =
+                fold_field_periods(&self.coils, &self.currents, self.field_periods)?;
+            CoilSet::new(&coils)
+                .with_currents(currents)
+                .with_field_periods(self.field_periods)
+        } else {
+            CoilSet::new(&self.coils).with_currents(self.currents.clone())
+        };
+        Ok(coils
+            .with_physics(self.physics)
+            .with_summation(self.summation)
+            .with_boundary(self.boundary.clone())
+            .with_background(self.background.clone())
+            .with_precision(self.precision))
+    }
+
+    /// Fails for the coils as `coil_set` does, or when the velocities of an
+    /// orbit pusher or the statuses, lengths and angles are not one per
+    /// particle
+    pub fn build(self) -> Result<Simulation, Box<dyn Error>> {
+        let precompute_started = Instant::now();
+        let coils = self.coil_set()?;
+        let precompute_time = precompute_started.elapsed().as_secs_f64();
+        let collisions = self.orbit.collisions;
+        if let Some(collisions) = &collisions {
+            if !self.kind.is_orbit() {
+                return Err("collisions need an orbit integrator".into());
+            }
+            collisions.check(self.step_size)?;
+        }
+        let mut states: Vec<IntegrationState> = (0..self.particles.len())
+            .map(|index| IntegrationState {
+                index: (self.first_index + index) as u64,
+                ..IntegrationState::new(self.step_size)
+            })
+            .collect();
+        if let Some(statuses) = self.statuses {
+            if statuses.len() != self.particles.len() {
+                return Err(format!(
+                    "{} statuses for {} particles",
+                    statuses.len(),
+                    self.particles.len()
+                )
+                .into());
+            }
+            for (state, status) in states.iter_mut().zip(statuses) {
+                state.status = status;
+            }
+        }
+        if let Some(lengths) = self.lengths {
+            if lengths.len() != self.particles.len() {
+                return Err(format!(
+                    "{} lengths for {} particles",
+                    lengths.len(),
+                    self.particles.len()
+                )
+                .into());
+            }
+            for (state, length) in states.iter_mut().zip(lengths) {
+                state.length = length;
+            }
+        }
+        if let Some(angles) = self.angles {
+            if angles.len() != self.particles.len() {
+                return Err(format!(
+                    "{} angles for {} particles",
+                    angles.len(),
+                    self.particles.len()
+                )
+                .into());
+            }
+            for (state, angles) in states.iter_mut().zip(angles) {
+                state.angles = angles;
+            }
+        }
+        if self.kind.is_orbit() {
+            let velocities = self
+                .velocities
+                .unwrap_or_else(|| self.orbit.initial_velocities(&self.particles, &coils));
+            if velocities.len() != self.particles.len() {
+                return Err(format!(
+                    "{} velocities for {} particles",
+                    velocities.len(),
+                    self.particles.len()
+                )
+                .into());
+            }
+            for (state, velocity) in states.iter_mut().zip(velocities) {
+                *state = state.with_velocity(velocity);
+            }
+        }
+        let integrator = match self.backend {
+            Backend::Gpu => gpu_integrator(self.kind, self.step_size, &coils)
+                .inspect_err(|err| warn!("Integrating on the CPU, the GPU backend failed: {}", err))
+                .ok(),
+            Backend::Cpu => None,
+        };
+        Ok(Simulation {
+            integrator: integrator.unwrap_or_else(|| {
+                self.kind
+                    .integrator(self.step_size, self.tolerances, self.orbit)
+            }),
+            coils,
+            particles: self.particles,
+            states,
+            writer: self.writer.unwrap_or_else(|| {
+                SnapshotWriter::new(Path::new("."), 0, TextFormat::default(), Decimation::Never)
+            }),
+            step: self.first_step,
+            rebalance_every: self.rebalance_every,
+            progress_every: self.progress_every,
+            shutdown: self.shutdown,
+            collisions,
+            precompute_time,
+            times: PhaseTimes {
+                step: self.first_step,
+                ..PhaseTimes::default()
+            },
+        })
+    }
+}
+
+#[cfg(feature = "gpu")]
+fn gpu_integrator(
+    kind: IntegratorKind,
+    step_size: f64,
+    coils: &CoilSet,
+) -> Result<Box<dyn Integrator>, Box<dyn Error>> {
+    Ok(Box::new(crate::gpu::GpuRk4::new(kind, step_size, coils)?))
+}
+
+#[cfg(not(feature = "gpu"))]
+fn gpu_integrator(
+    _kind: IntegratorKind,
+    _step_size: f64,
+    _coils: &CoilSet,
+) -> Result<Box<dyn Integrator>, Box<dyn Error>> {
+    Err("built without the gpu feature".into())
+}
+
+/// Particles traced through the field of a coil set, advanced a number of
+/// steps at a time, on one process or on one rank of a larger run
+pub struct Simulation {
+    coils: CoilSet,
+    integrator: Box<dyn Integrator>,
+    particles: Vec<Point>,
+    states: Vec<IntegrationState>,
+    writer: SnapshotWriter,
+    step: u32,
+    rebalance_every: Option<u32>,
+    progress_every: Option<u32>,
+    shutdown: Option<Shutdown>,
+    collisions: Option<Collisions>,
+    precompute_time: f64,
+    times: PhaseTimes,
+}
+
+impl Simulation {
+    pub fn builder() -> SimulationBuilder {
+        SimulationBuilder::new()
+    }
+
+    /// Advances every particle by `steps` steps in this process
+    pub fn run(&mut self, steps: u32) -> Result<StepCounts, Box<dyn Error>> {
+        self.run_on(steps, &SingleProcess)
+    }
+
+    /// Advances the particles of this rank by `steps` steps, along with
+    /// every other rank of `comm`, and returns the substeps taken so far.
+    /// Fails if a snapshot cannot be written, leaving the particles part
+    /// way through the steps.
+    pub fn run_on(
+        &mut self,
+        steps: u32,
+        comm: &impl Collectives,
+    ) -> Result<StepCounts, Box<dyn Error>> {
+        let schedule = Schedule {
+            first_step: self.step,
+            total_steps: self.step + steps,
+            rebalance_every: self.rebalance_every,
+            progress_every: self.progress_every,
+            collisions: self.collisions,
+            shutdown: self.shutdown,
+        };
+        let outcome = simulate_particles(
+            &mut self.particles,
+            &mut self.states,
+            schedule,
+            self.integrator.as_ref(),
+            &self.coils,
+            &mut self.writer,
+            comm,
+        );
+        self.step = outcome
+            .as_ref()
+            .map_or(schedule.total_steps, |(_, times)| times.step);
+        let (counts, times) = outcome?;
+        self.times = self.times.merge(times);
+        Ok(counts)
+    }
+
+    /// Positions of every particle after each of the next `steps` steps,
+    /// starting with where they are now. The track of a lost particle ends
+    /// at its last confined position.
+    pub fn trajectories(&mut self, steps: u32) -> Result<Vec<Vec<Point>>, Box<dyn Error>> {
+        let mut tracks: Vec<Vec<Point>> = self
+            .particles
+            .iter()
+            .map(|particle| vec![*particle])
+            .collect();
+        for _ in 0..steps {
+            if self.active_particles() == 0 {
+                break;
+            }
+            self.run(1)?;
+            for ((track, particle), state) in
+                tracks.iter_mut().zip(&self.particles).zip(&self.states)
+            {
+                if state.status.is_active() {
+                    track.push(*particle);
+                }
+            }
+        }
+        Ok(tracks)
+    }
+
+    /// Step the particles are at
+    pub fn step(&self) -> u32 {
+        self.step
+    }
+
+    /// Positions of the particles, lost ones at their last confined position
+    pub fn particles(&self) -> &[Point] {
+        &self.particles
+    }
+
+    pub fn states(&self) -> &[IntegrationState] {
+        &self.states
+    }
+
+    pub fn statuses(&self) -> Vec<ParticleState> {
+        self.states.iter().map(|state| state.status).collect()
+    }
+
+    /// Arc length travelled by each particle while confined
+    pub fn lengths(&self) -> Vec<f64> {
+        self.states.iter().map(|state| state.length).collect()
+    }
+
+    pub fn active_particles(&self) -> usize {
+        self.states
+            .iter()
+            .filter(|state| state.status.is_active())
+            .count()
+    }
+
+    pub fn coils(&self) -> &CoilSet {
+        &self.coils
+    }
+
+    /// Seconds spent building the coil segments when the simulation was built
+    pub fn precompute_time(&self) -> f64 {
+        self.precompute_time
+    }
+
+    /// Time spent in each phase of the step loop over every run so far
+    pub fn phase_times(&self) -> PhaseTimes {
+        self.times
+    }
+
+    /// Magnetic field of the coils at `point`
+    pub fn field_at(&self, point: &Point) -> Point {
+        compute_magnetic_field(point, &self.coils)
+    }
+
+    pub fn writer(&self) -> &SnapshotWriter {
+        &self.writer
+    }
+
+    /// Summary of the particles held here, see `RunSummary::local`
+    pub fn summary(&self, simulation_time: f64) -> RunSummary {
+        RunSummary::local(
+            &self.particles,
+            &self.statuses(),
+            &self.coils.physics,
+            total_step_counts(&self.states),
+            simulation_time,
+        )
+    }
+}
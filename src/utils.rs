@@ -1,12 +1,135 @@
 use log::{debug, info};
-use std::fs::DirBuilder;
+use std::fs::{DirBuilder, File};
+use std::io::{self, BufReader, Read};
+use std::ops::RangeInclusive;
 use std::path::Path;
 
-pub fn create_directory(path: &Path) {
+pub fn create_directory(path: &Path) -> io::Result<()> {
     let dirbuilder = DirBuilder::new();
     info!("Creating path: {}", path.display());
-    match dirbuilder.create(path) {
-        Ok(_) => debug!("Succesfully created directory: {}", path.display()),
-        Err(_) => todo!(),
+    dirbuilder.create(path)?;
+    debug!("Succesfully created directory: {}", path.display());
+    Ok(())
+}
+
+/// Human readable size in binary units, e.g. `1.5 MiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Whole seconds as hours, minutes and seconds, e.g. `1h 02m 05s`
+pub fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.max(0.0).round() as u64;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}h {:02}m {:02}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {:02}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Standard base64 with padding, used for buffers embedded in text formats
+pub fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// 64-bit FNV-1a checksum of a file's contents, as a self-describing hex string
+pub fn checksum_file(path: &Path) -> Result<String, io::Error> {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buffer = [0u8; 64 * 1024];
+    let mut hash = OFFSET_BASIS;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        for byte in &buffer[..read] {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
+    Ok(format!("fnv1a64:{:016x}", hash))
+}
+
+/// Parses comma separated ids and inclusive ranges such as "0-99,150"
+pub fn parse_id_ranges(value: &str) -> Result<Vec<RangeInclusive<usize>>, String> {
+    value
+        .split(',')
+        .map(|part| {
+            let (first, last) = part.split_once('-').unwrap_or((part, part));
+            let parse = |id: &str| {
+                id.trim()
+                    .parse::<usize>()
+                    .map_err(|err| format!("invalid id {:?}: {}", id, err))
+            };
+            Ok(parse(first)?..=parse(last)?)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_matches_reference_vector() {
+        let path = std::env::temp_dir().join("bs_solctra_checksum_test");
+        std::fs::write(&path, b"a").unwrap();
+        assert_eq!(checksum_file(&path).unwrap(), "fnv1a64:af63dc4c8601ec8c");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn durations_show_the_largest_units() {
+        assert_eq!(format_duration(5.4), "5s");
+        assert_eq!(format_duration(125.0), "2m 05s");
+        assert_eq!(format_duration(3725.0), "1h 02m 05s");
+    }
+
+    #[test]
+    fn sizes_use_binary_units() {
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 << 30), "5.0 GiB");
+        assert_eq!(format_size(u64::MAX), "16777216.0 TiB");
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
     }
 }
@@ -0,0 +1,176 @@
+use crate::{
+    commands::read_global_snapshot,
+    config::RunConfig,
+    integrator::Tolerances,
+    output::{ALL_RANKS, list_snapshots, read_snapshot},
+    point::Point,
+};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+/// Deviation of the particles of a run from the reference at one step
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepError {
+    pub step: u32,
+    /// Largest distance of a particle from its reference position
+    pub max_absolute: f64,
+    /// Largest such distance relative to the norm of the reference position
+    pub max_relative: f64,
+    /// Particles farther from the reference than the tolerances allow
+    pub diverged: usize,
+}
+
+impl StepError {
+    pub fn passed(&self) -> bool {
+        self.diverged == 0
+    }
+}
+
+impl fmt::Display for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "step {}: max absolute error {:e}, max relative error {:e}, {} diverged",
+            self.step, self.max_absolute, self.max_relative, self.diverged
+        )
+    }
+}
+
+/// Compares `points` with `reference` particle by particle. A particle
+/// diverges when its distance to the reference exceeds `absolute` plus
+/// `relative` times the norm of its reference position, or when exactly one
+/// of the two is not finite.
+pub fn compare_points(
+    step: u32,
+    reference: &[Point],
+    points: &[Point],
+    tolerances: Tolerances,
+) -> Result<StepError, Box<dyn Error>> {
+    if reference.len() != points.len() {
+        return Err(format!(
+            "Step {} holds {} particles, the reference {}",
+            step,
+            points.len(),
+            reference.len()
+        )
+        .into());
+    }
+    let finite = |point: &Point| point.x.is_finite() && point.y.is_finite() && point.z.is_finite();
+    let mut error = StepError {
+        step,
+        max_absolute: 0.0,
+        max_relative: 0.0,
+        diverged: 0,
+    };
+    for (expected, point) in reference.iter().zip(points) {
+        match (finite(expected), finite(point)) {
+            (true, true) => {
+                let absolute = point.get_distance(expected);
+                let norm = expected.get_norm();
+                error.max_absolute = error.max_absolute.max(absolute);
+                if norm > 0.0 {
+                    error.max_relative = error.max_relative.max(absolute / norm);
+                }
+                if absolute > tolerances.absolute + tolerances.relative * norm {
+                    error.diverged += 1;
+                }
+            }
+            (false, false) => {}
+            _ => error.diverged += 1,
+        }
+    }
+    Ok(error)
+}
+
+/// Reference snapshots of a directory by step: files named as `merge` writes
+/// them, or single-file snapshots of every rank
+pub fn reference_snapshots(reference_dir: &Path) -> Result<BTreeMap<u32, PathBuf>, Box<dyn Error>> {
+    let mut references: BTreeMap<u32, PathBuf> = list_snapshots(reference_dir)?
+        .into_iter()
+        .filter_map(|(step, mut rank_files)| Some((step, rank_files.remove(&ALL_RANKS)?)))
+        .collect();
+    for entry in fs::read_dir(reference_dir)? {
+        let path = entry?.path();
+        let step = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("merged_")?.strip_suffix(".csv"))
+            .and_then(|step| step.parse().ok());
+        if let Some(step) = step {
+            references.insert(step, path);
+        }
+    }
+    if references.is_empty() {
+        return Err(format!("No reference snapshots in {}", reference_dir.display()).into());
+    }
+    Ok(references)
+}
+
+/// Compares every reference snapshot with the snapshot of the run in
+/// `run_dir` at the same step, which must have been written
+pub fn validate(
+    run_dir: &Path,
+    reference_dir: &Path,
+    tolerances: Tolerances,
+) -> Result<Vec<StepError>, Box<dyn Error>> {
+    let config = RunConfig::read(run_dir)?;
+    let mut snapshots = list_snapshots(run_dir)?;
+    reference_snapshots(reference_dir)?
+        .into_iter()
+        .map(|(step, reference_path)| {
+            let rank_files = snapshots
+                .remove(&step)
+                .ok_or_else(|| format!("The run wrote no snapshot of reference step {}", step))?;
+            let points = read_global_snapshot(&config, step, &rank_files)?;
+            let reference = read_snapshot(&reference_path, config.delimiter as u8)?;
+            compare_points(step, &reference, &points, tolerances)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn particles_diverge_beyond_the_tolerances() {
+        let reference = [
+            Point {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Point {
+                x: 0.0,
+                y: 2.0,
+                z: 0.0,
+            },
+            Point {
+                x: f64::NAN,
+                y: 0.0,
+                z: 0.0,
+            },
+        ];
+        let mut points = reference;
+        points[0].x += 1e-7;
+        points[1].y += 1e-4;
+        let tolerances = Tolerances {
+            absolute: 0.0,
+            relative: 1e-6,
+        };
+        let error = compare_points(3, &reference, &points, tolerances).unwrap();
+        assert_eq!(error.diverged, 1);
+        assert!((error.max_absolute - 1e-4).abs() < 1e-12);
+        assert!((error.max_relative - 5e-5).abs() < 1e-12);
+
+        points[1] = reference[1];
+        points[2].x = 0.0;
+        let error = compare_points(3, &reference, &points, tolerances).unwrap();
+        assert_eq!(error.diverged, 1);
+        assert!(compare_points(3, &reference, &points[..2], tolerances).is_err());
+    }
+}
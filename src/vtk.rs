@@ -0,0 +1,217 @@
+use crate::point::Point;
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+/// Named per-point values attached to a VTK data set
+pub struct Scalars<'a> {
+    pub name: &'a str,
+    pub values: &'a [f64],
+}
+
+/// Named per-point vectors attached to a VTK data set
+pub struct Vectors<'a> {
+    pub name: &'a str,
+    pub values: &'a [Point],
+}
+
+fn write_data_array<W: Write, T: std::fmt::Display>(
+    writer: &mut W,
+    attributes: &str,
+    values: impl Iterator<Item = T>,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(
+        writer,
+        "        <DataArray {} format=\"ascii\">",
+        attributes
+    )?;
+    for value in values {
+        writeln!(writer, "          {}", value)?;
+    }
+    writeln!(writer, "        </DataArray>")?;
+    Ok(())
+}
+
+/// Writes points as vertices of an XML PolyData (`.vtp`) file
+pub fn write_vtp_points(
+    path: &Path,
+    points: &[Point],
+    scalars: &[Scalars],
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let n = points.len();
+    writeln!(writer, "<?xml version=\"1.0\"?>")?;
+    writeln!(
+        writer,
+        "<VTKFile type=\"PolyData\" version=\"0.1\" byte_order=\"LittleEndian\">"
+    )?;
+    writeln!(writer, "  <PolyData>")?;
+    writeln!(
+        writer,
+        "    <Piece NumberOfPoints=\"{}\" NumberOfVerts=\"{}\" NumberOfLines=\"0\" \
+         NumberOfStrips=\"0\" NumberOfPolys=\"0\">",
+        n, n
+    )?;
+    writeln!(writer, "      <PointData>")?;
+    for field in scalars {
+        let attributes = format!("type=\"Float64\" Name=\"{}\"", field.name);
+        write_data_array(&mut writer, &attributes, field.values.iter())?;
+    }
+    writeln!(writer, "      </PointData>")?;
+    writeln!(writer, "      <Points>")?;
+    write_data_array(
+        &mut writer,
+        "type=\"Float64\" NumberOfComponents=\"3\"",
+        points.iter().map(|p| format!("{} {} {}", p.x, p.y, p.z)),
+    )?;
+    writeln!(writer, "      </Points>")?;
+    writeln!(writer, "      <Verts>")?;
+    write_data_array(&mut writer, "type=\"Int64\" Name=\"connectivity\"", 0..n)?;
+    write_data_array(&mut writer, "type=\"Int64\" Name=\"offsets\"", 1..=n)?;
+    writeln!(writer, "      </Verts>")?;
+    writeln!(writer, "    </Piece>")?;
+    writeln!(writer, "  </PolyData>")?;
+    writeln!(writer, "</VTKFile>")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes a curvilinear grid of `shape` points per axis as an XML
+/// StructuredGrid (`.vts`) file. Points and their values are in VTK order,
+/// the first axis varying fastest.
+pub fn write_vts_grid(
+    path: &Path,
+    shape: [usize; 3],
+    points: &[Point],
+    vectors: &[Vectors],
+    scalars: &[Scalars],
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let extent = format!("0 {} 0 {} 0 {}", shape[0] - 1, shape[1] - 1, shape[2] - 1);
+    writeln!(writer, "<?xml version=\"1.0\"?>")?;
+    writeln!(
+        writer,
+        "<VTKFile type=\"StructuredGrid\" version=\"0.1\" byte_order=\"LittleEndian\">"
+    )?;
+    writeln!(writer, "  <StructuredGrid WholeExtent=\"{}\">", extent)?;
+    writeln!(writer, "    <Piece Extent=\"{}\">", extent)?;
+    writeln!(writer, "      <PointData>")?;
+    for field in vectors {
+        let attributes = format!(
+            "type=\"Float64\" Name=\"{}\" NumberOfComponents=\"3\"",
+            field.name
+        );
+        write_data_array(
+            &mut writer,
+            &attributes,
+            field
+                .values
+                .iter()
+                .map(|v| format!("{} {} {}", v.x, v.y, v.z)),
+        )?;
+    }
+    for field in scalars {
+        let attributes = format!("type=\"Float64\" Name=\"{}\"", field.name);
+        write_data_array(&mut writer, &attributes, field.values.iter())?;
+    }
+    writeln!(writer, "      </PointData>")?;
+    writeln!(writer, "      <Points>")?;
+    write_data_array(
+        &mut writer,
+        "type=\"Float64\" NumberOfComponents=\"3\"",
+        points.iter().map(|p| format!("{} {} {}", p.x, p.y, p.z)),
+    )?;
+    writeln!(writer, "      </Points>")?;
+    writeln!(writer, "    </Piece>")?;
+    writeln!(writer, "  </StructuredGrid>")?;
+    writeln!(writer, "</VTKFile>")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the points of an ASCII XML PolyData file such as those written by
+/// `write_vtp_points`
+pub fn read_vtp_points(path: &Path) -> Result<Vec<Point>, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+    let invalid = || format!("{} has no ASCII <Points> array", path.display());
+    let points = &contents[contents.find("<Points>").ok_or_else(invalid)?..];
+    let array = &points[points.find("<DataArray").ok_or_else(invalid)?..];
+    let values = &array[array.find('>').ok_or_else(invalid)? + 1
+        ..array.find("</DataArray>").ok_or_else(invalid)?];
+    let values = values
+        .split_whitespace()
+        .map(|value| value.parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("Invalid coordinate in {}: {}", path.display(), err))?;
+    if values.len() % 3 != 0 {
+        return Err(format!("{} holds a partial point", path.display()).into());
+    }
+    Ok(values
+        .chunks_exact(3)
+        .map(|xyz| Point {
+            x: xyz[0],
+            y: xyz[1],
+            z: xyz[2],
+        })
+        .collect())
+}
+
+/// Data set of a ParaView collection, one of several parts of a time value
+pub struct DataSet {
+    pub time: f64,
+    pub part: usize,
+    pub file: String,
+}
+
+/// Writes a ParaView collection (`.pvd`) referencing the given data sets
+pub fn write_pvd(path: &Path, data_sets: &[DataSet]) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "<?xml version=\"1.0\"?>")?;
+    writeln!(writer, "<VTKFile type=\"Collection\" version=\"0.1\">")?;
+    writeln!(writer, "  <Collection>")?;
+    for data_set in data_sets {
+        writeln!(
+            writer,
+            "    <DataSet timestep=\"{}\" group=\"\" part=\"{}\" file=\"{}\"/>",
+            data_set.time, data_set.part, data_set.file
+        )?;
+    }
+    writeln!(writer, "  </Collection>")?;
+    writeln!(writer, "</VTKFile>")?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn points_round_trip_through_vtp() {
+        let path = std::env::temp_dir().join("bs_solctra_vtp_test.vtp");
+        let points = vec![
+            Point {
+                x: 0.1,
+                y: -2.5e-7,
+                z: 3.0,
+            },
+            Point {
+                x: 0.2456,
+                y: 0.0,
+                z: -0.0031465,
+            },
+        ];
+        let values = [1.0, 0.0];
+        let scalars = [Scalars {
+            name: "lost",
+            values: &values,
+        }];
+        write_vtp_points(&path, &points, &scalars).unwrap();
+        let read = read_vtp_points(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read.unwrap(), points);
+    }
+}
@@ -1,7 +1,18 @@
+use bs_solctra_rs::collectives::SingleProcess;
+use bs_solctra_rs::collisions::Collisions;
+use bs_solctra_rs::diagnostics::{DRIFT_FILE, DriftLog, SweptAngles, read_drift};
+use bs_solctra_rs::integrator::{IntegrationState, IntegratorKind, Rk4};
+use bs_solctra_rs::output::{
+    Decimation, OutputLayout, SnapshotWriter, TRAJECTORY_DIR, TextFormat, trajectory_file_name,
+};
+use bs_solctra_rs::particle::{OrbitSettings, ParticleState};
 use bs_solctra_rs::point::*;
+use bs_solctra_rs::shutdown::Shutdown;
 use bs_solctra_rs::simulation::*;
+use bs_solctra_rs::tracer::Simulation;
 use std::fs::{create_dir, remove_dir_all};
 use std::path::Path;
+use std::time::Instant;
 
 #[test]
 fn test_simulation() {
@@ -30,20 +41,26 @@ fn test_simulation() {
         ),
     };
 
-    let displacements = compute_all_displacements(&coils);
-    let e_roof = compute_all_e_roof(&displacements);
+    let coils = CoilSet::new(&coils);
     let write_frequency = 1u32;
+    let mut writer = SnapshotWriter::new(
+        output_path,
+        0,
+        TextFormat::default(),
+        Decimation::Every(write_frequency),
+    );
 
+    let mut states = vec![IntegrationState::new(step_size); particle_vec.len()];
     simulate_particles(
         &mut particle_vec,
-        steps,
-        step_size,
+        &mut states,
+        Schedule::new(steps),
+        &Rk4 { step_size },
         &coils,
-        &displacements,
-        &e_roof,
-        output_path,
-        write_frequency,
-    );
+        &mut writer,
+        &SingleProcess,
+    )
+    .unwrap();
 
     let output_particle = Point {
         x: 0.1455416056924451,
@@ -51,7 +68,7 @@ fn test_simulation() {
         z: 0.0031465260786825264,
     };
 
-    let output_file = Path::new("tests/test_output/out_1.csv");
+    let output_file = Path::new("tests/test_output/out_0_1.csv");
 
     let final_vector = match read_from_file(output_file, 1) {
         Ok(particles) => particles,
@@ -63,6 +80,663 @@ fn test_simulation() {
         Err(err) => panic!("Error: {}", err),
     }
 
+    #[cfg(not(feature = "simd"))]
     let result = final_vector.iter().all(|v| *v == output_particle);
+    // The vector kernel sums segments in a different order
+    #[cfg(feature = "simd")]
+    let result = final_vector.iter().all(|v| {
+        (v.x - output_particle.x).abs() < 1e-12
+            && (v.y - output_particle.y).abs() < 1e-12
+            && (v.z - output_particle.z).abs() < 1e-12
+    });
     assert!(result);
 }
+
+#[test]
+fn field_jacobian_is_divergence_and_curl_free() {
+    let coils = CoilSet::new(
+        &read_coil_data_directory(Path::new("./tests/test-resources/resources")).unwrap(),
+    );
+    let point = Point {
+        x: 0.2,
+        y: 0.03,
+        z: 0.01,
+    };
+    let j = compute_field_jacobian(&point, &coils);
+    let scale = j
+        .iter()
+        .flatten()
+        .map(|value| value.abs())
+        .fold(0.0, f64::max);
+    let divergence = j[0][0] + j[1][1] + j[2][2];
+    let curl = [j[2][1] - j[1][2], j[0][2] - j[2][0], j[1][0] - j[0][1]];
+    assert!(divergence.abs() < 1e-6 * scale);
+    assert!(curl.iter().all(|value| value.abs() < 1e-6 * scale));
+}
+
+#[test]
+fn resumed_schedule_matches_uninterrupted_run() {
+    let step_size = 0.01;
+    let coils = CoilSet::new(
+        &read_coil_data_directory(Path::new("./tests/test-resources/resources")).unwrap(),
+    );
+    let start = vec![
+        Point {
+            x: 0.2,
+            y: 0.0,
+            z: 0.0,
+        },
+        Point {
+            x: 0.22,
+            y: 0.0,
+            z: 0.01,
+        },
+    ];
+    let run = |output_path: &Path, particles: &mut Vec<Point>, schedule: Schedule| {
+        let mut writer =
+            SnapshotWriter::new(output_path, 0, TextFormat::default(), Decimation::Every(1));
+        let mut states = vec![IntegrationState::new(step_size); particles.len()];
+        simulate_particles(
+            particles,
+            &mut states,
+            schedule,
+            &Rk4 { step_size },
+            &coils,
+            &mut writer,
+            &SingleProcess,
+        )
+        .unwrap();
+    };
+
+    let full_path = Path::new("tests/test_output_uninterrupted");
+    let resumed_path = Path::new("tests/test_output_resumed");
+    create_dir(full_path).unwrap();
+    create_dir(resumed_path).unwrap();
+    run(full_path, &mut start.clone(), Schedule::new(4));
+    run(resumed_path, &mut start.clone(), Schedule::new(2));
+    let mut particles = read_from_file(&resumed_path.join("out_0_2.csv"), start.len()).unwrap();
+    run(
+        resumed_path,
+        &mut particles,
+        Schedule {
+            first_step: 2,
+            ..Schedule::new(4)
+        },
+    );
+
+    let full = read_from_file(&full_path.join("out_0_4.csv"), start.len()).unwrap();
+    let resumed = read_from_file(&resumed_path.join("out_0_4.csv"), start.len()).unwrap();
+    remove_dir_all(full_path).unwrap();
+    remove_dir_all(resumed_path).unwrap();
+    assert_eq!(full, resumed);
+    assert_eq!(full, particles);
+}
+
+#[test]
+fn simulation_api_runs_without_mpi_or_output() {
+    let step_size = 0.01;
+    let coils = read_coil_data_directory(Path::new("./tests/test-resources/resources")).unwrap();
+    let start = vec![
+        Point {
+            x: 0.2,
+            y: 0.0,
+            z: 0.0,
+        },
+        Point {
+            x: 0.22,
+            y: 0.0,
+            z: 0.01,
+        },
+    ];
+    let mut simulation = Simulation::builder()
+        .coils(coils.clone())
+        .integrator(IntegratorKind::Rk4, step_size)
+        .add_particles(&start)
+        .build()
+        .unwrap();
+    simulation.run(2).unwrap();
+    simulation.run(2).unwrap();
+    assert_eq!(simulation.step(), 4);
+    assert_eq!(simulation.particles().len(), start.len());
+    assert_eq!(simulation.active_particles(), start.len());
+
+    let coil_set = CoilSet::new(&coils);
+    let mut particles = start.clone();
+    let mut states = vec![IntegrationState::new(step_size); particles.len()];
+    let mut writer =
+        SnapshotWriter::new(Path::new("."), 0, TextFormat::default(), Decimation::Never);
+    simulate_particles(
+        &mut particles,
+        &mut states,
+        Schedule::new(4),
+        &Rk4 { step_size },
+        &coil_set,
+        &mut writer,
+        &SingleProcess,
+    )
+    .unwrap();
+    assert_eq!(simulation.particles(), &particles[..]);
+    assert_eq!(
+        simulation.field_at(&start[0]),
+        compute_magnetic_field(&start[0], &coil_set)
+    );
+    assert!(Simulation::builder().add_particles(&start).build().is_err());
+    let single_compensated = Simulation::builder()
+        .coils(coils.clone())
+        .summation(Summation::Compensated)
+        .precision(Precision::F32)
+        .build();
+    assert!(single_compensated.is_err());
+    let double_compensated = Simulation::builder()
+        .coils(coils)
+        .summation(Summation::Compensated)
+        .precision(Precision::F64)
+        .build();
+    assert!(double_compensated.is_ok());
+}
+
+#[test]
+fn resumed_simulations_carry_losses_and_lengths() {
+    let coils = read_coil_data_directory(Path::new("./tests/test-resources/resources")).unwrap();
+    let start = [
+        Point {
+            x: 0.2,
+            y: 0.0,
+            z: 0.0,
+        },
+        Point {
+            x: 0.22,
+            y: 0.0,
+            z: 0.01,
+        },
+    ];
+    let builder = || {
+        Simulation::builder()
+            .coils(coils.clone())
+            .integrator(IntegratorKind::Rk4, 0.01)
+            .add_particles(&start)
+            .first_step(3)
+    };
+    let lost = ParticleState::Lost { step: 2 };
+    let mut simulation = builder()
+        .statuses(vec![ParticleState::Active, lost])
+        .lengths(vec![0.5, 0.25])
+        .build()
+        .unwrap();
+    simulation.run(2).unwrap();
+    assert_eq!(simulation.statuses(), vec![ParticleState::Active, lost]);
+    assert_eq!(simulation.particles()[1], start[1]);
+    let lengths = simulation.lengths();
+    assert!(lengths[0] > 0.5);
+    assert_eq!(lengths[1], 0.25);
+    assert!(builder().lengths(vec![0.5]).build().is_err());
+    assert!(builder().statuses(vec![lost]).build().is_err());
+    let swept = SweptAngles {
+        toroidal: 1.0,
+        poloidal: -0.5,
+    };
+    let resumed = builder()
+        .angles(vec![swept, swept])
+        .statuses(vec![ParticleState::Active, lost])
+        .build()
+        .unwrap();
+    assert_eq!(resumed.states()[1].angles, swept);
+    assert!(builder().angles(vec![swept]).build().is_err());
+}
+
+#[test]
+fn written_fields_match_the_field_at_the_particles() {
+    let step_size = 0.01;
+    let coils = CoilSet::new(
+        &read_coil_data_directory(Path::new("./tests/test-resources/resources")).unwrap(),
+    );
+    let start = vec![
+        Point {
+            x: 0.2,
+            y: 0.0,
+            z: 0.0,
+        },
+        Point {
+            x: 0.22,
+            y: 0.0,
+            z: 0.01,
+        },
+    ];
+    let output_path = Path::new("tests/test_output_fields");
+    create_dir(output_path).unwrap();
+    let run = |fields: bool| {
+        let mut particles = start.clone();
+        let mut states = vec![IntegrationState::new(step_size); particles.len()];
+        let mut writer =
+            SnapshotWriter::new(output_path, 0, TextFormat::default(), Decimation::Every(2))
+                .with_fields(fields);
+        simulate_particles(
+            &mut particles,
+            &mut states,
+            Schedule::new(4),
+            &Rk4 { step_size },
+            &coils,
+            &mut writer,
+            &SingleProcess,
+        )
+        .unwrap();
+        particles
+    };
+    let without_fields = run(false);
+    let with_fields = run(true);
+    let snapshot = read_from_file(&output_path.join("out_0_4.csv"), start.len()).unwrap();
+    let fields = std::fs::read_to_string(output_path.join("field_0_4.csv")).unwrap();
+    remove_dir_all(output_path).unwrap();
+
+    assert_eq!(with_fields, without_fields);
+    let rows: Vec<Vec<f64>> = fields
+        .lines()
+        .skip(1)
+        .map(|line| {
+            line.split(',')
+                .map(|value| value.parse().unwrap())
+                .collect()
+        })
+        .collect();
+    assert_eq!(rows.len(), start.len());
+    for (particle, row) in snapshot.iter().zip(&rows) {
+        let field = compute_magnetic_field(particle, &coils);
+        assert_eq!(row[..3], [field.x, field.y, field.z]);
+        assert!((row[3] - row[0].hypot(row[1]).hypot(row[2])).abs() < 1e-15);
+    }
+}
+
+#[test]
+fn indexed_snapshots_do_not_depend_on_the_split_of_particles() {
+    let coils = read_coil_data_directory(Path::new("./tests/test-resources/resources")).unwrap();
+    let start = read_from_file(Path::new("tests/test-resources/input_1000.csv"), 6).unwrap();
+    let output_path = Path::new("tests/test_output_reproducible");
+    create_dir(output_path).unwrap();
+    let run = |rank: i32, first_id: usize, count: usize, single_file: bool| {
+        let writer = SnapshotWriter::new(
+            output_path,
+            rank,
+            TextFormat::default(),
+            Decimation::Every(2),
+        )
+        .with_single_file(single_file)
+        .with_particle_ids(first_id);
+        Simulation::builder()
+            .coils(coils.clone())
+            .integrator(IntegratorKind::Rk4, 0.01)
+            .add_particles(&start[first_id..first_id + count])
+            .writer(writer)
+            .build()
+            .unwrap()
+            .run(4)
+            .unwrap();
+    };
+    run(0, 0, start.len(), true);
+    run(0, 0, 2, false);
+    run(1, 2, 3, false);
+    run(2, 5, 1, false);
+    let rows = |name: &str| {
+        let text = std::fs::read_to_string(output_path.join(name)).unwrap();
+        text.lines().skip(1).map(str::to_string).collect::<Vec<_>>()
+    };
+    let whole = rows("out_all_4.csv");
+    let split: Vec<String> = ["out_0_4.csv", "out_1_4.csv", "out_2_4.csv"]
+        .iter()
+        .flat_map(|name| rows(name))
+        .collect();
+    let snapshot = read_from_file(&output_path.join("out_all_4.csv"), usize::MAX).unwrap();
+    remove_dir_all(output_path).unwrap();
+
+    assert_eq!(whole.len(), start.len());
+    assert_eq!(split, whole);
+    for (id, row) in whole.iter().enumerate() {
+        assert!(row.starts_with(&format!("{},", id)));
+    }
+    assert_eq!(snapshot.len(), start.len());
+}
+
+#[test]
+fn unwritable_snapshots_fail_the_run() {
+    let coils = read_coil_data_directory(Path::new("./tests/test-resources/resources")).unwrap();
+    let writer = SnapshotWriter::new(
+        Path::new("tests/missing_output_dir"),
+        0,
+        TextFormat::default(),
+        Decimation::Every(1),
+    );
+    let mut simulation = Simulation::builder()
+        .coils(coils)
+        .add_particles(&[Point {
+            x: 0.2,
+            y: 0.0,
+            z: 0.0,
+        }])
+        .writer(writer)
+        .build()
+        .unwrap();
+    let err = simulation.run(2).unwrap_err();
+    assert!(err.to_string().contains("step 0"), "{}", err);
+}
+
+#[test]
+fn drift_diagnostics_cover_every_step_of_resumed_runs() {
+    let coils = read_coil_data_directory(Path::new("./tests/test-resources/resources")).unwrap();
+    let start = vec![
+        Point {
+            x: 0.2,
+            y: 0.0,
+            z: 0.0,
+        },
+        Point {
+            x: 0.22,
+            y: 0.0,
+            z: 0.01,
+        },
+    ];
+    let output_path = Path::new("tests/test_output_drift");
+    create_dir(output_path).unwrap();
+    let run = |particles: &[Point], first_step: u32, steps: u32| {
+        let writer =
+            SnapshotWriter::new(output_path, 0, TextFormat::default(), Decimation::Every(4))
+                .with_drift(DriftLog::new(output_path, first_step));
+        let mut simulation = Simulation::builder()
+            .coils(coils.clone())
+            .integrator(IntegratorKind::Rk4, 0.01)
+            .add_particles(particles)
+            .writer(writer)
+            .first_step(first_step)
+            .build()
+            .unwrap();
+        simulation.run(steps).unwrap();
+        simulation.particles().to_vec()
+    };
+    let resumed_from = run(&start, 0, 6);
+    run(&resumed_from, 6, 3);
+    let records = read_drift(&output_path.join(DRIFT_FILE)).unwrap();
+    remove_dir_all(output_path).unwrap();
+
+    let steps: Vec<u32> = records.iter().map(|record| record.step).collect();
+    assert_eq!(steps, (1..=9).collect::<Vec<_>>());
+    for record in &records {
+        assert_eq!(record.particles, start.len());
+        assert_eq!(record.non_finite, 0);
+        // RK4 chords of a curved field line fall just short of the step size
+        assert!(record.mean_length_error <= 0.0, "{:?}", record);
+        assert!(record.max_length_error < 1e-2, "{:?}", record);
+        assert!(record.rms_length_error <= record.max_length_error);
+        assert!(record.mean_radial_drift.abs() <= record.rms_radial_drift);
+        assert!(record.rms_radial_drift < 0.01, "{:?}", record);
+    }
+}
+
+#[test]
+fn trajectories_follow_each_particle_through_the_snapshots() {
+    let coils = read_coil_data_directory(Path::new("./tests/test-resources/resources")).unwrap();
+    let start: Vec<Point> = [(0.2, 0.0), (0.21, 0.005), (0.22, 0.01)]
+        .iter()
+        .map(|&(x, z)| Point { x, y: 0.0, z })
+        .collect();
+    let output_path = Path::new("tests/test_output_trajectories");
+    create_dir(output_path).unwrap();
+    let run = |first_id: usize, count: usize, layout: OutputLayout| {
+        let writer = SnapshotWriter::new(
+            output_path,
+            first_id as i32,
+            TextFormat::default(),
+            Decimation::Every(2),
+        )
+        .with_layout(layout)
+        .with_particle_ids(first_id);
+        Simulation::builder()
+            .coils(coils.clone())
+            .integrator(IntegratorKind::Rk4, 0.01)
+            .add_particles(&start[first_id..first_id + count])
+            .writer(writer)
+            .build()
+            .unwrap()
+            .run(4)
+            .unwrap();
+    };
+    run(0, start.len(), OutputLayout::Snapshots);
+    run(0, 1, OutputLayout::Trajectories);
+    run(1, 2, OutputLayout::Trajectories);
+    let trajectory = |id: usize| {
+        let path = output_path
+            .join(TRAJECTORY_DIR)
+            .join(trajectory_file_name(id));
+        (
+            std::fs::read_to_string(&path).unwrap(),
+            read_from_file(&path, usize::MAX).unwrap(),
+        )
+    };
+    let trajectories: Vec<_> = (0..start.len()).map(trajectory).collect();
+    let snapshots: Vec<Vec<Point>> = [0, 2, 4]
+        .iter()
+        .map(|step| {
+            let name = format!("out_0_{}.csv", step);
+            read_from_file(&output_path.join(name), usize::MAX).unwrap()
+        })
+        .collect();
+    remove_dir_all(output_path).unwrap();
+
+    for (id, (text, points)) in trajectories.iter().enumerate() {
+        let steps: Vec<&str> = text
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap())
+            .collect();
+        assert_eq!(steps, ["0", "2", "4"]);
+        for (point, snapshot) in points.iter().zip(&snapshots) {
+            assert_eq!(*point, snapshot[id]);
+        }
+    }
+}
+
+#[test]
+fn single_precision_trajectories_stay_close_to_double_precision() {
+    let coils = read_coil_data_directory(Path::new("./tests/test-resources/resources")).unwrap();
+    let start: Vec<Point> = [(0.2, 0.0), (0.22, 0.01)]
+        .iter()
+        .map(|&(x, z)| Point { x, y: 0.0, z })
+        .collect();
+    let trace = |precision: Precision| {
+        let mut simulation = Simulation::builder()
+            .coils(coils.clone())
+            .precision(precision)
+            .integrator(IntegratorKind::Rk4, 0.01)
+            .add_particles(&start)
+            .build()
+            .unwrap();
+        (0..10)
+            .map(|_| {
+                simulation.run(50).unwrap();
+                simulation.particles().to_vec()
+            })
+            .collect::<Vec<_>>()
+    };
+    let double = trace(Precision::F64);
+    let single = trace(Precision::F32);
+    assert_ne!(single, double);
+    let mut largest: f64 = 0.0;
+    for (single, double) in single.iter().zip(&double) {
+        for (a, b) in single.iter().zip(double) {
+            assert_ne!(*b, DIVERGENT_PARTICLE);
+            let distance = ((a.x - b.x).powi(2) + (a.y - b.y).powi(2) + (a.z - b.z).powi(2)).sqrt();
+            largest = largest.max(distance);
+        }
+    }
+    println!("largest separation after 500 steps: {:e} m", largest);
+    assert!(largest < 1e-6);
+}
+
+#[test]
+fn gpu_backend_traces_the_field_lines_of_the_cpu() {
+    let coils = read_coil_data_directory(Path::new("./tests/test-resources/resources")).unwrap();
+    let start: Vec<Point> = [(0.2, 0.0), (0.22, 0.01)]
+        .iter()
+        .map(|&(x, z)| Point { x, y: 0.0, z })
+        .collect();
+    let trace = |backend: Backend| {
+        let mut simulation = Simulation::builder()
+            .coils(coils.clone())
+            .backend(backend)
+            .integrator(IntegratorKind::Rk4, 0.01)
+            .add_particles(&start)
+            .build()
+            .unwrap();
+        simulation.run(100).unwrap();
+        simulation.particles().to_vec()
+    };
+    // Without a device, or the gpu feature, the run falls back to the CPU
+    let cpu = trace(Backend::Cpu);
+    let gpu = trace(Backend::Gpu);
+    for (a, b) in gpu.iter().zip(&cpu) {
+        let distance = ((a.x - b.x).powi(2) + (a.y - b.y).powi(2) + (a.z - b.z).powi(2)).sqrt();
+        assert!(distance < 1e-12, "{:?} is {:e} m from {:?}", a, distance, b);
+    }
+}
+
+#[test]
+fn collisions_repeat_across_ranks_and_resumes() {
+    let coils = read_coil_data_directory(Path::new("./tests/test-resources/resources")).unwrap();
+    let start: Vec<Point> = [(0.2, 0.0), (0.21, 0.005), (0.22, 0.01), (0.23, 0.0)]
+        .iter()
+        .map(|&(x, z)| Point { x, y: 0.0, z })
+        .collect();
+    let orbit = |collisions| OrbitSettings {
+        collisions,
+        ..OrbitSettings::default()
+    };
+    let collisions = Some(Collisions {
+        frequency: 1e7,
+        seed: 3,
+    });
+    let builder = |first: usize, particles: &[Point], collisions| {
+        Simulation::builder()
+            .coils(coils.clone())
+            .integrator(IntegratorKind::Boris, 1e-9)
+            .orbit(orbit(collisions))
+            .add_particles(particles)
+            .first_index(first)
+    };
+    let velocities = |simulation: &Simulation| -> Vec<Point> {
+        simulation
+            .states()
+            .iter()
+            .map(|state| state.velocity.unwrap())
+            .collect()
+    };
+
+    let mut whole = builder(0, &start, collisions).build().unwrap();
+    whole.run(20).unwrap();
+    let mut split = Vec::new();
+    for first in [0, 2] {
+        let mut half = builder(first, &start[first..first + 2], collisions)
+            .build()
+            .unwrap();
+        half.run(10).unwrap();
+        // Resumed from the positions and velocities of step 10
+        let mut resumed = builder(first, half.particles(), collisions)
+            .velocities(velocities(&half))
+            .first_step(10)
+            .build()
+            .unwrap();
+        resumed.run(10).unwrap();
+        split.extend(
+            resumed
+                .particles()
+                .iter()
+                .copied()
+                .zip(velocities(&resumed)),
+        );
+    }
+    let whole_states: Vec<(Point, Point)> = whole
+        .particles()
+        .iter()
+        .copied()
+        .zip(velocities(&whole))
+        .collect();
+    assert_eq!(split, whole_states);
+
+    let mut collisionless = builder(0, &start, None).build().unwrap();
+    collisionless.run(20).unwrap();
+    for (scattered, free) in velocities(&whole).iter().zip(velocities(&collisionless)) {
+        assert_ne!(*scattered, free);
+        let (speed, free_speed) = (
+            (scattered.x.powi(2) + scattered.y.powi(2) + scattered.z.powi(2)).sqrt(),
+            (free.x.powi(2) + free.y.powi(2) + free.z.powi(2)).sqrt(),
+        );
+        assert!((speed - free_speed).abs() < 1e-9 * free_speed);
+    }
+    let field_lines = Simulation::builder()
+        .coils(coils.clone())
+        .orbit(orbit(collisions))
+        .add_particles(&start)
+        .build();
+    assert!(field_lines.is_err());
+}
+
+#[test]
+fn requested_shutdowns_stop_with_a_snapshot_of_the_step_reached() {
+    let coils = read_coil_data_directory(Path::new("./tests/test-resources/resources")).unwrap();
+    let output_path = Path::new("tests/test_output_shutdown");
+    create_dir(output_path).unwrap();
+    let writer = SnapshotWriter::new(output_path, 0, TextFormat::default(), Decimation::Every(10));
+    let mut simulation = Simulation::builder()
+        .coils(coils)
+        .add_particles(&[Point {
+            x: 0.2,
+            y: 0.0,
+            z: 0.0,
+        }])
+        .writer(writer)
+        .shutdown(Shutdown {
+            deadline: Some(Instant::now()),
+            check_every: 3,
+        })
+        .build()
+        .unwrap();
+    simulation.run(10).unwrap();
+    let stopped = read_from_file(&output_path.join("out_0_3.csv"), 1);
+    let later_written = output_path.join("out_0_10.csv").exists();
+    remove_dir_all(output_path).unwrap();
+
+    assert_eq!(simulation.step(), 3);
+    assert_eq!(stopped.unwrap(), simulation.particles());
+    assert!(!later_written);
+}
+
+#[test]
+fn field_line_tracks_end_where_the_lines_leave_the_torus() {
+    let coils = read_coil_data_directory(Path::new("./tests/test-resources/resources")).unwrap();
+    let start = [
+        Point {
+            x: 0.2,
+            y: 0.0,
+            z: 0.0,
+        },
+        Point {
+            x: 0.5,
+            y: 0.0,
+            z: 0.0,
+        },
+    ];
+    let build = || {
+        Simulation::builder()
+            .coils(coils.clone())
+            .add_particles(&start)
+            .build()
+            .unwrap()
+    };
+    let mut traced = build();
+    let tracks = traced.trajectories(5).unwrap();
+    assert_eq!(tracks.len(), 2);
+    assert_eq!(tracks[0].len(), 6);
+    assert_eq!(tracks[0][0], start[0]);
+    assert_eq!(tracks[1], vec![start[1]]);
+    assert_eq!(traced.step(), 5);
+
+    let mut simulation = build();
+    simulation.run(5).unwrap();
+    assert_eq!(tracks[0][5], simulation.particles()[0]);
+}
@@ -0,0 +1,52 @@
+"""Tests of the Python module, built into the environment with
+`maturin develop` and run with `pytest tests/python`"""
+
+from pathlib import Path
+
+import numpy as np
+import pytest
+
+import bs_solctra
+
+RESOURCES = Path(__file__).resolve().parents[1] / "test-resources" / "resources"
+START = [0.2, 0.0, 0.0]
+
+
+@pytest.fixture(scope="module")
+def coils():
+    return bs_solctra.load_coils(str(RESOURCES))
+
+
+def test_load_coils(coils):
+    assert len(coils) > 0
+    points = coils.points(0)
+    assert points.ndim == 2 and points.shape[1] == 3
+    assert coils.current > 0
+    with pytest.raises(ValueError):
+        coils.points(len(coils))
+    with pytest.raises(ValueError):
+        bs_solctra.load_coils(str(RESOURCES), coil_format="unknown")
+
+
+def test_compute_magnetic_field(coils):
+    points = np.array([START, [0.22, 0.0, 0.01]])
+    fields = bs_solctra.compute_magnetic_field(coils, points)
+    assert fields.shape == (2, 3)
+    assert np.all(np.isfinite(fields))
+    assert np.all(np.linalg.norm(fields, axis=1) > 0)
+    with pytest.raises(ValueError):
+        bs_solctra.compute_magnetic_field(coils, np.zeros((2, 2)))
+
+
+def test_trace_field_line(coils):
+    steps = 10
+    track = bs_solctra.trace_field_line(coils, START, steps)
+    assert track.shape == (steps + 1, 3)
+    np.testing.assert_array_equal(track[0], START)
+    # The first step of 1 mm follows the field at the start
+    field = bs_solctra.compute_magnetic_field(coils, np.array([START]))[0]
+    step = track[1] - track[0]
+    cosine = step @ field / (np.linalg.norm(step) * np.linalg.norm(field))
+    assert abs(cosine) > 0.99
+    with pytest.raises(ValueError):
+        bs_solctra.trace_field_line(coils, START, steps, integrator="boris")